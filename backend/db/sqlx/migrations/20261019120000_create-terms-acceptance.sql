@@ -0,0 +1,7 @@
+-- terms of use
+CREATE TABLE terms_acceptance (
+    subject TEXT NOT NULL,
+    version TEXT NOT NULL,
+    accepted_at TEXT NOT NULL,
+    PRIMARY KEY (subject, version)
+);
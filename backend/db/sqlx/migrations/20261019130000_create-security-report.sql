@@ -0,0 +1,15 @@
+-- CSP and Reporting API violation reports, deduplicated by a hash of their normalized content
+CREATE TABLE security_report (
+    id TEXT PRIMARY KEY,
+    report_type TEXT NOT NULL,
+    document_uri TEXT NOT NULL,
+    directive TEXT NOT NULL,
+    blocked_uri TEXT NOT NULL,
+    source_file TEXT NOT NULL,
+    line_number INTEGER,
+    disposition TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    count INTEGER NOT NULL
+);
+CREATE INDEX security_report_last_seen ON security_report (last_seen);
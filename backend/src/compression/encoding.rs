@@ -0,0 +1,128 @@
+//! Content encodings and their negotiation with the Accept-Encoding header of the client
+
+use rocket::Request;
+use rocket::request::{self, FromRequest, Outcome};
+
+// Content encodings that the application can compress responses with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+// Encodings the client accepts with their quality (Accept-Encoding)
+#[derive(Debug, Clone, Default)]
+pub struct AcceptEncoding(Vec<(String, f32)>);
+
+impl Encoding {
+    /// Returns the encoding of a name of the Content-Encoding header, e.g. br
+    ///
+    /// # Arguments
+    /// * `name` - Name of the encoding
+    ///
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the encoding in the Content-Encoding header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Returns the file extension of files that were compressed with the encoding at build time, e.g. app.js.br
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+impl AcceptEncoding {
+    /// Parses the Accept-Encoding header, e.g. "gzip, br;q=0.8, *;q=0"
+    ///
+    /// # Arguments
+    /// * `header` - Value of the header (None if the client sent none)
+    ///
+    pub fn parse(header: Option<&str>) -> AcceptEncoding {
+        AcceptEncoding(
+            header
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|element| {
+                    let mut parameters = element.split(';');
+                    let name = parameters.next()?.trim().to_ascii_lowercase();
+                    let quality = parameters
+                        .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                        .find_map(|quality| quality.trim().parse::<f32>().ok())
+                        .unwrap_or(1.0);
+                    (!name.is_empty()).then_some((name, quality))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the quality with which the client accepts an encoding. An explicit entry takes precedence over the wildcard
+    fn quality(&self, encoding: Encoding) -> f32 {
+        let quality = |name: &str| {
+            self.0
+                .iter()
+                .find(|(accepted, _)| accepted == name)
+                .map(|(_, quality)| *quality)
+        };
+        quality(encoding.name())
+            .or_else(|| quality("*"))
+            .unwrap_or(0.0)
+    }
+
+    /// Selects the encoding for a response
+    ///
+    /// # Arguments
+    /// * `encodings` - Available encodings in the order of preference of the application
+    ///
+    /// # Returns
+    /// The encoding with the highest quality for the client (the first on equal quality) or None if the client accepts none of them
+    ///
+    pub fn preferred(&self, encodings: &[Encoding]) -> Option<Encoding> {
+        encodings
+            .iter()
+            .map(|encoding| (*encoding, self.quality(*encoding)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(
+                None,
+                |best: Option<(Encoding, f32)>, (encoding, quality)| match best {
+                    Some((_, best_quality)) if best_quality >= quality => best,
+                    _ => Some((encoding, quality)),
+                },
+            )
+            .map(|(encoding, _)| encoding)
+    }
+}
+
+// Implementation of the request guard for the encodings the client accepts
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptEncoding {
+    type Error = ();
+
+    /// Parses the Accept-Encoding header of the request
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(AcceptEncoding::parse(
+            req.headers().get_one("Accept-Encoding"),
+        ))
+    }
+}
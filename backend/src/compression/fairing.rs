@@ -0,0 +1,276 @@
+//! Rocket fairing that compresses text responses with the encoding negotiated with the client
+//!
+//! The fairing must be attached after the security HTTP headers fairing, so that nonces are inserted into HTML bodies before they are compressed (response fairings run in the order they are attached). Responses that are already encoded (e.g. precompressed static files) are not changed
+
+use async_compression::Level;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Method, Status};
+use rocket::tokio::io::BufReader;
+use rocket::{Request, Response};
+
+use crate::configuration::config::CustomAppCompressionConfig;
+
+use super::encoding::{AcceptEncoding, Encoding};
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+// lower than the maximum of brotli (11), which is too slow for compressing each response
+const DEFAULT_BROTLI_LEVEL: i32 = 4;
+const DEFAULT_CONTENT_TYPES: [&str; 8] = [
+    "text/*",
+    "application/json",
+    "application/problem+json",
+    "application/javascript",
+    "application/xml",
+    "application/manifest+json",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+// Errors in the compression configuration
+#[derive(Debug)]
+pub enum CompressionConfigError {
+    EmptyEncodings,
+    UnknownEncoding(String),
+    InvalidContentType(String),
+}
+
+// Compression fairing. It is enabled if encodings are configured
+pub struct Compression {
+    // in the order of preference
+    encodings: Vec<Encoding>,
+    // media types (type, subtype or * for all subtypes)
+    content_types: Vec<(String, String)>,
+    min_size: usize,
+    brotli_level: Level,
+    gzip_level: Level,
+    zstd_level: Level,
+}
+
+impl Compression {
+    /// Creates the compression fairing from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Compression configuration of the application
+    ///
+    /// # Returns
+    /// Compression fairing or an error if an encoding or content type is invalid
+    ///
+    pub fn new(config: &CustomAppCompressionConfig) -> Result<Compression, CompressionConfigError> {
+        let encodings = config
+            .encodings
+            .iter()
+            .flatten()
+            .map(|name| {
+                Encoding::parse(name)
+                    .ok_or_else(|| CompressionConfigError::UnknownEncoding(name.clone()))
+            })
+            .collect::<Result<Vec<Encoding>, CompressionConfigError>>()?;
+        if encodings.is_empty() {
+            return Err(CompressionConfigError::EmptyEncodings);
+        }
+        let content_types = match &config.content_types {
+            Some(content_types) => content_types.clone(),
+            None => DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|content_type| content_type.to_string())
+                .collect(),
+        };
+        let content_types = content_types
+            .iter()
+            .map(|content_type| match content_type.split_once('/') {
+                Some((top, sub)) if !top.is_empty() && !sub.is_empty() => {
+                    Ok((top.to_ascii_lowercase(), sub.to_ascii_lowercase()))
+                }
+                _ => Err(CompressionConfigError::InvalidContentType(
+                    content_type.clone(),
+                )),
+            })
+            .collect::<Result<Vec<(String, String)>, CompressionConfigError>>()?;
+        let level = |level: Option<i32>| level.map_or(Level::Default, Level::Precise);
+        Ok(Compression {
+            encodings,
+            content_types,
+            min_size: config.min_size.unwrap_or(DEFAULT_MIN_SIZE),
+            brotli_level: Level::Precise(config.brotli_level.unwrap_or(DEFAULT_BROTLI_LEVEL)),
+            gzip_level: level(config.gzip_level),
+            zstd_level: level(config.zstd_level),
+        })
+    }
+
+    /// Checks if responses of a content type are compressed (e.g. text, but not images that are already compressed)
+    fn compresses(&self, content_type: &ContentType) -> bool {
+        self.content_types.iter().any(|(top, sub)| {
+            content_type.top() == top.as_str() && (sub == "*" || content_type.sub() == sub.as_str())
+        })
+    }
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    /// Executed on every response. Compresses the body of text responses while it is streamed, if the client accepts one of the encodings
+    ///
+    /// # Arguments
+    /// * `self` - Struct Compression
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.headers().contains("Content-Encoding")
+            || !res
+                .content_type()
+                .is_some_and(|content_type| self.compresses(&content_type))
+        {
+            return;
+        }
+        // the response depends on the Accept-Encoding header, so caches must not mix them up
+        res.adjoin_raw_header("Vary", "Accept-Encoding");
+        if req.method() == Method::Head
+            || [
+                Status::NoContent,
+                Status::PartialContent,
+                Status::NotModified,
+            ]
+            .contains(&res.status())
+            || res.body().is_none()
+        {
+            return;
+        }
+        if let Some(size) = res.body_mut().size().await
+            && size < self.min_size
+        {
+            return;
+        }
+        let accept_encoding = AcceptEncoding::parse(req.headers().get_one("Accept-Encoding"));
+        let Some(encoding) = accept_encoding.preferred(&self.encodings) else {
+            return;
+        };
+        let body = BufReader::new(res.body_mut().take());
+        match encoding {
+            Encoding::Brotli => {
+                res.set_streamed_body(BrotliEncoder::with_quality(body, self.brotli_level))
+            }
+            Encoding::Zstd => {
+                res.set_streamed_body(ZstdEncoder::with_quality(body, self.zstd_level))
+            }
+            Encoding::Gzip => {
+                res.set_streamed_body(GzipEncoder::with_quality(body, self.gzip_level))
+            }
+        }
+        res.set_raw_header("Content-Encoding", encoding.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder};
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::response::content::{RawHtml, RawJson};
+    use rocket::tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::configuration::config::{
+        CustomAppHttpHeaderValuesConfig, CustomAppHttpHeadersConfig,
+    };
+    use crate::httpfirewall::securityhttpheaders::SecurityHttpHeaders;
+
+    #[get("/")]
+    fn page() -> RawHtml<String> {
+        RawHtml(format!(
+            "<html><script src=\"/app.js\"></script>{}</html>",
+            "<p>inventory</p>".repeat(100)
+        ))
+    }
+
+    #[get("/api")]
+    fn api() -> RawJson<&'static str> {
+        RawJson("{\"products\": []}")
+    }
+
+    #[rocket::async_test]
+    async fn compresses_after_nonce_injection() {
+        let security_http_headers = SecurityHttpHeaders::new(&CustomAppHttpHeadersConfig {
+            headers: CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("script-src 'self'".to_string()),
+                content_security_policy_inject_nonce_paths: Some(vec!["^/$".to_string()]),
+                content_security_policy_inject_nonce_tags: Some(vec!["script".to_string()]),
+                content_security_policy_nonce_headers: Some(vec!["script-src".to_string()]),
+                ..Default::default()
+            },
+            profiles: None,
+        })
+        .unwrap();
+        let compression = Compression::new(&CustomAppCompressionConfig {
+            encodings: Some(vec!["br".to_string(), "gzip".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let rocket = rocket::build()
+            .attach(security_http_headers)
+            .attach(compression)
+            .mount("/", routes![page, api]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        for (accept_encoding, expected) in [("gzip, br", "br"), ("br;q=0.5, gzip", "gzip")] {
+            let response = client
+                .get("/")
+                .header(Header::new("Accept-Encoding", accept_encoding))
+                .dispatch()
+                .await;
+            assert_eq!(
+                response.headers().get_one("Content-Encoding"),
+                Some(expected)
+            );
+            assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+            let csp = response
+                .headers()
+                .get_one("Content-Security-Policy")
+                .unwrap()
+                .to_string();
+            let compressed = response.into_bytes().await.unwrap();
+            let mut body = String::new();
+            match expected {
+                "br" => {
+                    BrotliDecoder::new(&compressed[..])
+                        .read_to_string(&mut body)
+                        .await
+                }
+                _ => {
+                    GzipDecoder::new(&compressed[..])
+                        .read_to_string(&mut body)
+                        .await
+                }
+            }
+            .unwrap();
+            let nonce = csp
+                .strip_prefix("script-src 'nonce-")
+                .and_then(|csp| csp.split('\'').next())
+                .unwrap();
+            assert!(body.contains(&format!("<script nonce=\"{}\" src=\"/app.js\">", nonce)));
+        }
+
+        let response = client
+            .get("/")
+            .header(Header::new("Accept-Encoding", "br;q=0, identity"))
+            .dispatch()
+            .await;
+        assert!(!response.headers().contains("Content-Encoding"));
+        // smaller than the minimum size
+        let response = client
+            .get("/api")
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch()
+            .await;
+        assert!(!response.headers().contains("Content-Encoding"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    }
+}
@@ -0,0 +1,190 @@
+//! Command line tools to inspect the configuration (Rocket.toml and ROCKET_* environment variables) without starting the application
+
+use std::process::ExitCode;
+
+use rocket::http::ContentType;
+
+use super::config::{Config, security_http_headers};
+use super::validation::{FindingSeverity, check_config};
+use crate::httpfirewall::securityhttpheaders::{CACHE_CONTROL, SecurityHttpHeaders};
+
+const USAGE: &str = "Usage:
+  rust-rocket-backend                                          start the application
+  rust-rocket-backend header-profile <path> [<content-type>]   print the security headers for a response
+  rust-rocket-backend check-config [--deny-warnings]           validate the configuration and print all errors and warnings";
+
+/// Runs a command given on the command line
+///
+/// # Arguments
+/// * `args` - Command line arguments including the name of the program
+///
+/// # Returns
+/// Exit code of the command or None if no command is given and the application should be started
+///
+pub fn run(args: &[String]) -> Option<ExitCode> {
+    let command = args.get(1)?;
+    let exit_code = match command.as_str() {
+        "header-profile" => header_profile(&args[2..]),
+        "check-config" => check_config_command(&args[2..]),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        _ => {
+            eprintln!("Unknown command: {}\n{}", command, USAGE);
+            ExitCode::FAILURE
+        }
+    };
+    Some(exit_code)
+}
+
+/// Prints which header profile applies to a response and its headers
+///
+/// # Arguments
+/// * `args` - Path of the request and optionally the content type of the response
+///
+/// # Returns
+/// Exit code
+///
+fn header_profile(args: &[String]) -> ExitCode {
+    let Some(path) = args.first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let content_type = match args
+        .get(1)
+        .map(|content_type| content_type.parse::<ContentType>())
+    {
+        Some(Ok(content_type)) => Some(content_type),
+        Some(Err(err)) => {
+            eprintln!("Invalid content type: {}", err);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    let config: Config = match rocket::Config::figment().extract() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let security_http_headers = match security_http_headers(&config) {
+        Ok(security_http_headers) => security_http_headers,
+        Err(err) => {
+            eprintln!("Invalid HTTP headers configuration: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    print!(
+        "{}",
+        describe_profile(&security_http_headers, path, content_type.as_ref())
+    );
+    ExitCode::SUCCESS
+}
+
+/// Validates the configuration and prints all errors and warnings with their key path
+///
+/// # Arguments
+/// * `args` - Optionally --deny-warnings to fail on warnings as well (e.g. in a CI pipeline)
+///
+/// # Returns
+/// Exit code: failure if the configuration has errors (or warnings with --deny-warnings)
+///
+fn check_config_command(args: &[String]) -> ExitCode {
+    let deny_warnings = match args {
+        [] => false,
+        [flag] if flag == "--deny-warnings" => true,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let report = check_config(&rocket::Config::figment());
+    for finding in report.findings.iter() {
+        println!("{}", finding);
+    }
+    let errors = report.count(FindingSeverity::Error);
+    let warnings = report.count(FindingSeverity::Warning);
+    println!("{} errors, {} warnings", errors, warnings);
+    match errors > 0 || (deny_warnings && warnings > 0) {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+/// Describes the header profile that applies to a response
+///
+/// # Arguments
+/// * `security_http_headers` - Default headers and header profiles
+/// * `path` - Path of the request
+/// * `content_type` - Content type of the response (if any)
+///
+/// # Returns
+/// Name of the profile and its headers, one per line with the statuses they are restricted to
+///
+fn describe_profile(
+    security_http_headers: &SecurityHttpHeaders,
+    path: &str,
+    content_type: Option<&ContentType>,
+) -> String {
+    let profile = security_http_headers.profile(path, content_type);
+    let mut description = format!("Profile: {}\n", profile.name);
+    for (name, value) in profile.header_values(false) {
+        description.push_str(&format!("{}: {}", name, value));
+        if let Some(status_policy) = profile.status_policy(name) {
+            description.push_str(&format!(" [{}]", status_policy.statuses.join(", ")));
+        }
+        description.push('\n');
+    }
+    if let Some(cache_control) = &profile.headers.cache_control_authenticated {
+        description.push_str(&format!(
+            "{} (authenticated): {}\n",
+            CACHE_CONTROL, cache_control
+        ));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::config::{
+        CustomAppHttpHeaderValuesConfig, CustomAppHttpHeadersConfig,
+        CustomAppHttpHeadersProfileConfig,
+    };
+
+    #[test]
+    fn describes_matching_profile_with_inherited_headers() {
+        let security_http_headers = SecurityHttpHeaders::new(&CustomAppHttpHeadersConfig {
+            headers: CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("default-src 'self'".to_string()),
+                referrer_policy: Some("no-referrer".to_string()),
+                ..Default::default()
+            },
+            profiles: Some(vec![CustomAppHttpHeadersProfileConfig {
+                name: "api".to_string(),
+                paths: Some(vec!["^/ui-api/".to_string()]),
+                content_types: Some(vec!["application/json".to_string()]),
+                headers: CustomAppHttpHeaderValuesConfig {
+                    content_security_policy: Some(String::new()),
+                    cache_control: Some("no-store".to_string()),
+                    ..Default::default()
+                },
+            }]),
+        })
+        .unwrap();
+        assert_eq!(
+            describe_profile(
+                &security_http_headers,
+                "/ui-api/inventory",
+                Some(&ContentType::JSON)
+            ),
+            "Profile: api\nReferrer-Policy: no-referrer\nCache-Control: no-store\n"
+        );
+        assert_eq!(
+            describe_profile(&security_http_headers, "/ui-api/inventory", None),
+            "Profile: default\nContent-Security-Policy: default-src 'self'\nReferrer-Policy: no-referrer\n"
+        );
+    }
+}
@@ -1,34 +1,93 @@
 //! Manage application-specific configuration
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+use rocket::fairing::AdHoc;
+use rocket::figment::{Figment, providers::Serialized};
 use rocket::{Build, Rocket, serde::Deserialize};
 use tracing::{Level, event};
 
 use crate::oidc::routes::{oidc_goto_auth, oidc_redirect, oidc_user_info};
 
-use crate::httpfirewall::securityhttpheaders::SecurityHttpHeaders;
+use crate::compression::fairing::Compression;
+use crate::database::DatabaseUrl;
+use crate::httpfirewall::cors::{Cors, cors_preflight};
+use crate::httpfirewall::csrf::{CsrfProtection, csrf_rejected};
+use crate::httpfirewall::ratelimit::{RateLimiter, rate_limit_statistics, rate_limited};
+use crate::httpfirewall::requestfilter::{RequestFilter, blocked};
+use crate::httpfirewall::securityhttpheaders::{HttpHeadersConfigError, SecurityHttpHeaders};
+use crate::httpfirewall::subresourceintegrity::SubresourceIntegrity;
 
-use crate::oidc::{self, oidcflow::OidcFlow};
+use super::reload::{ReloadFiles, Reloadable};
+use super::secrets::{Secret, SecretFile, read_secret_file};
+use super::validation::{FindingSeverity, check_config};
+
+use crate::mtls::principals::CertificatePrincipals;
+use crate::oidc::dpop::DpopVerifier;
+use crate::oidc::httpclient::{IdpBlockingHttpClient, IdpHttpClient};
+use crate::oidc::impersonation::{
+    Impersonation, ImpersonationAudit, impersonation_start, impersonation_stop,
+};
+use crate::oidc::tokenexchange::TokenExchange;
+use crate::oidc::{
+    self, cookies::OidcCookies, keyring::CookieKeyRing, oidcflow::OidcFlow,
+    oidcflow::OidcProviderSource,
+};
+use crate::reporting::collector::{DEFAULT_ENDPOINT_NAME, ReportCollector};
+use crate::reporting::routes::{receive_csp_report, receive_reports, security_reports};
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+use crate::terms::acceptance::TermsOfUse;
+use crate::terms::routes::{terms_accept, terms_forbidden, terms_page};
 /// Configuration of oidc authentication/authorization
 #[derive(Debug, Deserialize, Default, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct CustomAppOidcConfig {
     pub issuer_url: Option<String>,
+    pub provider_metadata_file: Option<String>,
+    pub jwks_file: Option<String>,
     pub redirect_url: Option<String>,
     pub client_id: Option<String>,
-    pub client_secret: Option<String>,
+    pub client_secret: Option<Secret>,
+    pub client_secret_file: Option<String>,
     pub roles_idtoken_claims: Option<Vec<String>>,
     pub roles_userinfoendpoint_claims: Option<Vec<String>>,
     pub claims_separator: Option<HashMap<String, String>>,
     pub scopes: Option<Vec<String>>,
+    pub http_ca_file: Option<String>,
+    pub http_client_certificate_file: Option<String>,
+    pub http_client_key_file: Option<String>,
+    pub http_proxy: Option<String>,
+    pub http_connect_timeout_seconds: Option<u64>,
+    pub http_timeout_seconds: Option<u64>,
+    pub http_retries: Option<u32>,
+    pub http_retry_backoff_milliseconds: Option<u64>,
 }
 
-/// Configuration of custom HttpHeaders
+/// Configuration of custom HttpHeaders. They are the default headers of all responses, header profiles can override them for specific paths and content types
 #[derive(Debug, Deserialize, Default, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct CustomAppHttpHeadersConfig {
+    #[serde(flatten)]
+    pub headers: CustomAppHttpHeaderValuesConfig,
+    pub profiles: Option<Vec<CustomAppHttpHeadersProfileConfig>>,
+}
+
+/// Values of the HTTP security headers
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppHttpHeaderValuesConfig {
     pub content_security_policy: Option<String>,
+    pub content_security_policy_preset: Option<String>,
+    pub content_security_policy_directives: Option<HashMap<String, Vec<String>>>,
+    pub content_security_policy_trusted_types: Option<Vec<String>>,
+    pub content_security_policy_report_only: Option<bool>,
+    pub content_security_policy_report_only_preset: Option<String>,
+    pub content_security_policy_report_only_directives: Option<HashMap<String, Vec<String>>>,
     pub content_security_policy_inject_nonce_paths: Option<Vec<String>>,
     pub content_security_policy_inject_nonce_tags: Option<Vec<String>>,
     pub content_security_policy_nonce_headers: Option<Vec<String>>,
@@ -37,6 +96,277 @@ pub struct CustomAppHttpHeadersConfig {
     pub cross_origin_embedder_policy: Option<String>,
     pub cross_origin_opener_policy: Option<String>,
     pub cross_origin_resource_policy: Option<String>,
+    pub strict_transport_security: Option<CustomAppStrictTransportSecurityConfig>,
+    pub x_content_type_options: Option<String>,
+    pub reporting_endpoints: Option<HashMap<String, String>>,
+    pub cache_control: Option<String>,
+    pub cache_control_authenticated: Option<String>,
+    pub status_policies: Option<HashMap<String, Vec<String>>>,
+}
+
+/// Configuration of the Strict-Transport-Security (HSTS) header
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppStrictTransportSecurityConfig {
+    pub max_age: u64,
+    pub include_subdomains: Option<bool>,
+    pub preload: Option<bool>,
+}
+
+/// Header profile that applies to responses matching one of the paths (regular expressions) and one of the content types. Headers that are not configured are inherited from the default headers, an empty value removes an inherited header
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppHttpHeadersProfileConfig {
+    pub name: String,
+    pub paths: Option<Vec<String>>,
+    pub content_types: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub headers: CustomAppHttpHeaderValuesConfig,
+}
+
+/// Configuration of cookies set by the application
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppCookiesConfig {
+    pub session_cookie_name: Option<String>,
+    pub redirect_destination_cookie_name: Option<String>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub same_site: Option<String>,
+    pub http_only: Option<bool>,
+    pub session_max_age: Option<i64>,
+    pub redirect_destination_max_age: Option<i64>,
+    pub allow_insecure_http: Option<bool>,
+}
+
+/// Configuration of the keys to encrypt private cookies. The active key replaces the Rocket secret key, previous keys are only used for decryption
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppKeyRingConfig {
+    pub active_secret_key: Option<Secret>,
+    pub active_secret_key_file: Option<String>,
+    pub previous_secret_keys: Option<Vec<Secret>>,
+    pub previous_secret_keys_file: Option<String>,
+}
+
+/// Configuration of the database credentials that are not part of the Rocket database configuration
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppDatabaseConfig {
+    pub url_file: Option<String>,
+}
+
+/// Mapping of a client certificate to a principal and its roles. Either subject (e.g. "CN=scanner-01, O=Warehouse") or san (e.g. a DNS name) must be configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppMtlsPrincipalConfig {
+    pub subject: Option<String>,
+    pub san: Option<String>,
+    pub principal: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// Configuration of authentication with client certificates (mutual TLS)
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppMtlsConfig {
+    pub paths: Option<Vec<String>>,
+    pub principals: Option<Vec<CustomAppMtlsPrincipalConfig>>,
+}
+
+/// Configuration of DPoP (RFC 9449) for access tokens of machine clients. It is enabled if public_url is configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppDpopConfig {
+    pub public_url: Option<String>,
+    pub paths: Option<Vec<String>>,
+    pub audience: Option<String>,
+    pub roles_claims: Option<Vec<String>>,
+    pub proof_max_age_seconds: Option<u64>,
+    pub clock_skew_seconds: Option<u64>,
+    pub replay_cache_size: Option<usize>,
+}
+
+/// Downstream API for which the access token of the user is exchanged. audience and resource are sent to the IdP as configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppTokenExchangeAudienceConfig {
+    pub audience: Option<String>,
+    pub resource: Option<String>,
+    pub scopes: Option<Vec<String>>,
+}
+
+/// Configuration of OAuth 2.0 Token Exchange (RFC 8693) for downstream APIs. The key of audiences is the name used by handlers
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppTokenExchangeConfig {
+    pub audiences: Option<HashMap<String, CustomAppTokenExchangeAudienceConfig>>,
+    pub expiry_margin_seconds: Option<u64>,
+    pub cache_size: Option<usize>,
+}
+
+/// Configuration of impersonation ("view as user"). It is enabled if admin_roles are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppImpersonationConfig {
+    pub admin_roles: Option<Vec<String>>,
+    pub allowed_roles: Option<Vec<String>>,
+    pub block_writes: Option<bool>,
+    pub cookie_name: Option<String>,
+    pub max_duration_seconds: Option<u64>,
+}
+
+/// Configuration of the terms of use that users must accept after login. It is enabled if a version is configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppTermsConfig {
+    pub version: Option<String>,
+    pub document: Option<String>,
+    pub paths: Option<Vec<String>>,
+}
+
+/// Configuration of a route group of the rate limiter
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppRateLimitGroupConfig {
+    pub name: String,
+    pub paths: Vec<String>,
+    pub key: Option<String>,
+    pub capacity: u32,
+    pub refill_per_second: f64,
+}
+
+/// Configuration of the rate limiter. It is enabled if groups are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppRateLimitConfig {
+    pub groups: Option<Vec<CustomAppRateLimitGroupConfig>>,
+    pub max_buckets: Option<usize>,
+    pub admin_roles: Option<Vec<String>>,
+}
+
+/// Configuration of a rule of the request firewall. It matches if one of its conditions matches
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppFirewallRuleConfig {
+    pub name: String,
+    pub action: Option<String>,
+    pub tag: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub paths: Option<Vec<String>>,
+    pub max_uri_length: Option<usize>,
+    pub max_query_length: Option<usize>,
+    pub max_header_length: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub headers: Option<HashMap<String, String>>,
+    pub user_agents: Option<Vec<String>>,
+}
+
+/// Configuration of an IP access list of the request firewall. It applies to all paths if no paths are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppIpAccessConfig {
+    pub paths: Option<Vec<String>>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+/// Configuration of the request firewall
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppFirewallConfig {
+    pub rules: Option<Vec<CustomAppFirewallRuleConfig>>,
+    pub request_id_header: Option<String>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub ip_access: Option<Vec<CustomAppIpAccessConfig>>,
+}
+
+/// Configuration of the CSRF protection of state-changing requests. It is enabled if origins are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppCsrfConfig {
+    pub origins: Option<Vec<String>>,
+    pub paths: Option<Vec<String>>,
+    pub exempt_paths: Option<Vec<String>>,
+    pub double_submit_token: Option<bool>,
+    pub token_cookie_name: Option<String>,
+    pub token_header_name: Option<String>,
+}
+
+/// Configuration of a CORS policy for a list of path prefixes
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppCorsPolicyConfig {
+    pub paths: Vec<String>,
+    pub origins: Option<Vec<String>>,
+    pub origin_patterns: Option<Vec<String>>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age: Option<u64>,
+}
+
+/// Configuration of Cross-Origin Resource Sharing (CORS). It is enabled if policies are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppCorsConfig {
+    pub policies: Option<Vec<CustomAppCorsPolicyConfig>>,
+}
+
+/// Configuration of the compression of responses. It is enabled if encodings are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppCompressionConfig {
+    pub encodings: Option<Vec<String>>,
+    pub content_types: Option<Vec<String>>,
+    pub min_size: Option<usize>,
+    pub brotli_level: Option<i32>,
+    pub gzip_level: Option<i32>,
+    pub zstd_level: Option<i32>,
+}
+
+/// Configuration of the security event log. Without sinks, security events are written to the application log
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppSecurityEventsConfig {
+    pub sinks: Option<Vec<String>>,
+    pub file: Option<String>,
+    pub syslog_socket: Option<String>,
+    pub syslog_facility: Option<String>,
+    pub min_severity: Option<String>,
+}
+
+/// Configuration of the collector for violation reports of browsers. It is enabled if an endpoint is configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppReportingConfig {
+    pub endpoint: Option<String>,
+    pub endpoint_name: Option<String>,
+    pub url: Option<String>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub max_report_size: Option<u64>,
+    pub max_reports: Option<u32>,
+    pub retention_days: Option<u32>,
+    pub admin_roles: Option<Vec<String>>,
+}
+
+/// Configuration of reloading files (e.g. secrets) at runtime
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppReloadConfig {
+    pub interval_seconds: Option<u64>,
+}
+
+/// Configuration of Subresource Integrity for the static files. It is enabled if documents are configured
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomAppIntegrityConfig {
+    pub documents: Option<Vec<String>>,
+    pub manifest: Option<String>,
+    pub extensions: Option<Vec<String>>,
+    pub inline_hashes: Option<bool>,
 }
 
 /// Configuration of static file serving
@@ -44,6 +374,8 @@ pub struct CustomAppHttpHeadersConfig {
 #[serde(crate = "rocket::serde")]
 pub struct CustomAppStaticFilesConfig {
     pub location: String,
+    #[serde(default)]
+    pub integrity: CustomAppIntegrityConfig,
 }
 
 /// Configuration of specific modules of the app
@@ -53,6 +385,38 @@ pub struct CustomAppConfig {
     pub httpheaders: CustomAppHttpHeadersConfig,
     pub oidc: CustomAppOidcConfig,
     pub fileserver: CustomAppStaticFilesConfig,
+    #[serde(default)]
+    pub cookies: CustomAppCookiesConfig,
+    #[serde(default)]
+    pub keyring: CustomAppKeyRingConfig,
+    #[serde(default)]
+    pub database: CustomAppDatabaseConfig,
+    #[serde(default)]
+    pub reload: CustomAppReloadConfig,
+    #[serde(default)]
+    pub mtls: CustomAppMtlsConfig,
+    #[serde(default)]
+    pub dpop: CustomAppDpopConfig,
+    #[serde(default)]
+    pub token_exchange: CustomAppTokenExchangeConfig,
+    #[serde(default)]
+    pub impersonation: CustomAppImpersonationConfig,
+    #[serde(default)]
+    pub terms: CustomAppTermsConfig,
+    #[serde(default)]
+    pub reporting: CustomAppReportingConfig,
+    #[serde(default)]
+    pub ratelimit: CustomAppRateLimitConfig,
+    #[serde(default)]
+    pub firewall: CustomAppFirewallConfig,
+    #[serde(default)]
+    pub csrf: CustomAppCsrfConfig,
+    #[serde(default)]
+    pub cors: CustomAppCorsConfig,
+    #[serde(default)]
+    pub compression: CustomAppCompressionConfig,
+    #[serde(default)]
+    pub security_events: CustomAppSecurityEventsConfig,
 }
 
 /// Custom app configuration serialized from a toml file
@@ -62,6 +426,67 @@ pub struct Config {
     pub app: CustomAppConfig,
 }
 
+/// Resolves secrets that Rocket itself needs before it is built. The active key of the key ring is used as Rocket secret key and the database URL can be read from a file
+///
+/// # Arguments
+/// * `figment` - Configuration of Rocket before it is built
+///
+/// # Returns
+/// Configuration of Rocket with the secret key and database URL set (if configured)
+///
+pub fn configure_secrets(figment: Figment) -> Figment {
+    let mut figment = figment;
+    let active_secret_key =
+        match figment.extract_inner::<Option<String>>("app.keyring.active_secret_key_file") {
+            Ok(Some(path)) => match read_secret_file(Path::new(&path)) {
+                Ok(secret) => Some(secret),
+                Err(err) => {
+                    event!(Level::ERROR, "Cannot read active_secret_key_file: {}", err);
+                    panic!("Cannot read active_secret_key_file: {}", err);
+                }
+            },
+            Ok(None) => None,
+            Err(err) if err.missing() => None,
+            Err(err) => {
+                event!(Level::ERROR, "Invalid active_secret_key_file: {}", err);
+                panic!("Invalid active_secret_key_file: {}", err);
+            }
+        };
+    let active_secret_key = match active_secret_key {
+        Some(active_secret_key) => Some(active_secret_key),
+        None => match figment.extract_inner::<Option<Secret>>("app.keyring.active_secret_key") {
+            Ok(active_secret_key) => active_secret_key,
+            Err(err) if err.missing() => None,
+            Err(err) => {
+                event!(Level::ERROR, "Invalid active_secret_key: {}", err);
+                panic!("Invalid active_secret_key: {}", err);
+            }
+        },
+    };
+    if let Some(active_secret_key) = active_secret_key {
+        figment = figment.merge(Serialized::global("secret_key", active_secret_key.expose()));
+    }
+    match figment.extract_inner::<Option<String>>("app.database.url_file") {
+        Ok(Some(path)) => match read_secret_file(Path::new(&path)) {
+            Ok(url) => {
+                figment =
+                    figment.merge(Serialized::global("databases.warehouse.url", url.expose()));
+            }
+            Err(err) => {
+                event!(Level::ERROR, "Cannot read database url_file: {}", err);
+                panic!("Cannot read database url_file: {}", err);
+            }
+        },
+        Ok(None) => (),
+        Err(err) if err.missing() => (),
+        Err(err) => {
+            event!(Level::ERROR, "Invalid database url_file: {}", err);
+            panic!("Invalid database url_file: {}", err);
+        }
+    }
+    figment
+}
+
 /// Reads from the Rocket app configuration http security headers and configures a fairing to add them to HTTP responses
 ///
 /// # Arguments
@@ -71,21 +496,240 @@ pub struct Config {
 /// Fairing that can be attached using rocket.attach
 ///
 pub fn read_security_http_headers_config(config: &Config) -> SecurityHttpHeaders {
-    let mut regex_paths = regex::RegexSet::new(Vec::<String>::new()).unwrap();
-    match &config
-        .app
-        .httpheaders
-        .content_security_policy_inject_nonce_paths
-    {
-        Some(regex_path_vec) => {
-            regex_paths = regex::RegexSet::new(regex_path_vec).unwrap();
+    match security_http_headers(config) {
+        Ok(security_http_headers) => security_http_headers,
+        Err(err) => {
+            event!(
+                Level::ERROR,
+                "Invalid HTTP headers configuration: {:?}",
+                err
+            );
+            panic!("Invalid HTTP headers configuration: {:?}", err);
+        }
+    }
+}
+
+/// Creates the security headers from the Rocket app configuration. If the report collector is enabled, its endpoint is added to the Reporting-Endpoints and to the Content-Security-Policy
+///
+/// # Arguments
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// Security headers or an error if the HTTP headers configuration is invalid
+///
+pub fn security_http_headers(
+    config: &Config,
+) -> Result<SecurityHttpHeaders, HttpHeadersConfigError> {
+    let security_http_headers = SecurityHttpHeaders::new(&config.app.httpheaders)?;
+    let reporting = &config.app.reporting;
+    match &reporting.endpoint {
+        Some(endpoint) => Ok(security_http_headers.with_report_endpoint(
+            reporting
+                .endpoint_name
+                .as_deref()
+                .unwrap_or(DEFAULT_ENDPOINT_NAME),
+            reporting.url.as_deref().unwrap_or(endpoint),
+        )),
+        None => Ok(security_http_headers),
+    }
+}
+
+/// Reads the application configuration. The whole configuration is validated first and all errors and warnings are logged with their key path. Errors are also recorded as security events, because the application does not start
+///
+/// # Arguments
+/// * `figment` - Configuration sources of Rocket
+///
+/// # Returns
+/// Configuration of the Rocket app
+///
+pub fn read_config(figment: &Figment) -> Config {
+    let report = check_config(figment);
+    for finding in report.findings.iter() {
+        match finding.severity {
+            FindingSeverity::Error => event!(Level::ERROR, "Invalid configuration: {}", finding),
+            FindingSeverity::Warning => event!(Level::WARN, "Configuration: {}", finding),
+        }
+    }
+    if report.has_errors() {
+        // the security event log is configured after the validation, so its sinks are opened here to record why the application does not start
+        let security_events = figment
+            .extract::<Config>()
+            .map(|config| config.app.security_events)
+            .unwrap_or_default();
+        let security_event_log = SecurityEventLog::new(&security_events).unwrap_or_else(|_| {
+            SecurityEventLog::new(&CustomAppSecurityEventsConfig::default()).unwrap()
+        });
+        for finding in report
+            .findings
+            .iter()
+            .filter(|finding| finding.severity == FindingSeverity::Error)
+        {
+            security_event_log.record(
+                SecurityEvent::new(
+                    SecurityEventType::ConfigurationError,
+                    SecuritySeverity::Error,
+                    SecurityOutcome::Aborted,
+                    format!("{}: {}", finding.key, finding.message),
+                )
+                .with_rule(finding.key.clone()),
+            );
+        }
+        panic!(
+            "Invalid configuration: {} errors",
+            report.count(FindingSeverity::Error)
+        );
+    }
+    figment.extract().expect("config")
+}
+
+/// Configure the security event log with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the security event log managed as state
+///
+pub fn configure_security_events(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    match SecurityEventLog::new(&config.app.security_events) {
+        Ok(security_event_log) => rocket.manage(security_event_log),
+        Err(err) => {
+            event!(
+                Level::ERROR,
+                "Invalid security event configuration: {:?}",
+                err
+            );
+            panic!("Invalid security event configuration: {:?}", err);
+        }
+    }
+}
+
+/// Configure the compression of responses with Rocket instance. It must be attached after the security HTTP headers, so that nonces are inserted before the body is compressed
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the compression fairing attached (if configured)
+///
+pub fn configure_compression(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.compression.encodings.is_none() {
+        return rocket;
+    }
+    match Compression::new(&config.app.compression) {
+        Ok(compression) => rocket.attach(compression),
+        Err(err) => {
+            event!(Level::ERROR, "Invalid compression configuration: {:?}", err);
+            panic!("Invalid compression configuration: {:?}", err);
+        }
+    }
+}
+
+/// Configure the request firewall with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the request firewall attached and the route for blocked requests mounted
+///
+pub fn configure_firewall(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    match RequestFilter::new(&config.app.firewall) {
+        Ok(request_filter) => rocket
+            .attach(request_filter)
+            .mount("/httpfirewall", routes![blocked]),
+        Err(err) => {
+            event!(Level::ERROR, "Invalid firewall configuration: {:?}", err);
+            panic!("Invalid firewall configuration: {:?}", err);
         }
-        None => (),
     }
+}
 
-    SecurityHttpHeaders {
-        config: config.app.clone(),
-        regex_paths: regex_paths,
+/// Configure CORS with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the CORS fairing attached and the route for preflight requests mounted (if configured)
+///
+pub fn configure_cors(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.cors.policies.is_none() {
+        return rocket;
+    }
+    match Cors::new(&config.app.cors) {
+        Ok(cors) => rocket
+            .attach(cors)
+            .mount("/httpfirewall", routes![cors_preflight]),
+        Err(err) => {
+            event!(Level::ERROR, "Invalid CORS configuration: {:?}", err);
+            panic!("Invalid CORS configuration: {:?}", err);
+        }
+    }
+}
+
+/// Configure the CSRF protection with Rocket instance. Without configured origins, the origin of the OIDC redirect URL is allowed. The routes of violation reports are exempted, because browsers send the reports cross-site
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the CSRF protection attached and the route for rejected requests mounted
+///
+pub fn configure_csrf(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    match CsrfProtection::new(
+        &config.app.csrf,
+        &config.app.cookies,
+        config.app.oidc.redirect_url.as_deref(),
+    ) {
+        Ok(csrf) => {
+            // identity providers may post the authorization response to the callback (response mode form_post)
+            let csrf = csrf.with_exempt_path("/oidc/redirect");
+            let csrf = match &config.app.reporting.endpoint {
+                Some(endpoint) => {
+                    csrf.with_exempt_routes(endpoint, routes![receive_csp_report, receive_reports])
+                }
+                None => csrf,
+            };
+            rocket
+                .attach(csrf)
+                .mount("/httpfirewall", routes![csrf_rejected])
+        }
+        Err(err) => {
+            event!(Level::ERROR, "Invalid CSRF configuration: {:?}", err);
+            panic!("Invalid CSRF configuration: {:?}", err);
+        }
+    }
+}
+
+/// Configure the rate limiter with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the rate limiter attached and the route for rejected requests mounted (if configured)
+///
+pub fn configure_ratelimit(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.ratelimit.groups.is_none() {
+        return rocket;
+    }
+    match RateLimiter::new(&config.app.ratelimit) {
+        Ok(rate_limiter) => rocket
+            .manage(rate_limiter.statistics())
+            .attach(rate_limiter)
+            .mount("/httpfirewall", routes![rate_limited])
+            .mount("/ui-api", routes![rate_limit_statistics]),
+        Err(err) => {
+            event!(Level::ERROR, "Invalid rate limit configuration: {:?}", err);
+            panic!("Invalid rate limit configuration: {:?}", err);
+        }
     }
 }
 
@@ -96,10 +740,108 @@ pub fn read_security_http_headers_config(config: &Config) -> SecurityHttpHeaders
 /// * `config` - Configuration of the Rocket app
 ///
 /// # Returns
-/// rocket representing Rocket instance with static file server configured
+/// rocket representing Rocket instance with static file server and Subresource Integrity of its documents configured
 ///
 pub fn configure_fileserver(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
-    rocket.manage(config.app.fileserver.clone())
+    let subresource_integrity = match SubresourceIntegrity::new(&config.app.fileserver) {
+        Ok(subresource_integrity) => subresource_integrity,
+        Err(err) => {
+            event!(
+                Level::ERROR,
+                "Invalid Subresource Integrity configuration: {:?}",
+                err
+            );
+            panic!("Invalid Subresource Integrity configuration: {:?}", err);
+        }
+    };
+    rocket
+        .manage(config.app.fileserver.clone())
+        .manage(subresource_integrity)
+}
+
+/// Configure authentication with client certificates (mutual TLS) with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the mapping of client certificates to principals managed (if configured)
+///
+pub fn configure_mtls(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.mtls.principals.is_none() {
+        return rocket;
+    }
+    let certificate_principals = match CertificatePrincipals::new(&config.app.mtls) {
+        Ok(certificate_principals) => certificate_principals,
+        Err(err) => {
+            event!(Level::ERROR, "Invalid mutual TLS configuration: {:?}", err);
+            panic!("Invalid mutual TLS configuration: {:?}", err);
+        }
+    };
+    rocket.manage(certificate_principals)
+}
+
+/// Configure the terms of use that users must accept after login with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the terms of use managed and their routes mounted (if configured)
+///
+pub fn configure_terms(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.terms.version.is_none() {
+        return rocket;
+    }
+    match TermsOfUse::new(&config.app.terms) {
+        Ok(terms) => {
+            // forbidden requests on the gated paths get a problem response, e.g. a POST to the API before the terms are accepted
+            let rocket = terms.paths().iter().fold(rocket, |rocket, path| {
+                rocket.register(path.as_str(), catchers![terms_forbidden])
+            });
+            rocket
+                .manage(terms)
+                .mount("/terms", routes![terms_page, terms_accept])
+        }
+        Err(err) => {
+            event!(
+                Level::ERROR,
+                "Invalid terms of use configuration: {:?}",
+                err
+            );
+            panic!("Invalid terms of use configuration: {:?}", err);
+        }
+    }
+}
+
+/// Configure the collector for violation reports of browsers with Rocket instance
+///
+/// # Arguments
+/// * `rocket` - variable representing a rocket instance
+/// * `config` - Configuration of the Rocket app
+///
+/// # Returns
+/// rocket representing rocket instance with the report collector managed and its routes mounted (if configured)
+///
+pub fn configure_reporting(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
+    if config.app.reporting.endpoint.is_none() {
+        return rocket;
+    }
+    match ReportCollector::new(&config.app.reporting) {
+        Ok(collector) => {
+            let endpoint = collector.endpoint.clone();
+            rocket
+                .manage(collector)
+                .mount(endpoint, routes![receive_csp_report, receive_reports])
+                .mount("/ui-api", routes![security_reports])
+        }
+        Err(err) => {
+            event!(Level::ERROR, "Invalid reporting configuration: {:?}", err);
+            panic!("Invalid reporting configuration: {:?}", err);
+        }
+    }
 }
 
 /// Configure OIDC authentication with Rocket instance
@@ -113,8 +855,87 @@ pub fn configure_fileserver(rocket: Rocket<Build>, config: &Config) -> Rocket<Bu
 ///
 pub fn configure_oidc(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
     let oidc_flow = read_oidc_config(&config);
+    let oidc_cookies = match OidcCookies::new(&config.app.cookies) {
+        Ok(oidc_cookies) => oidc_cookies,
+        Err(err) => {
+            event!(Level::ERROR, "Invalid cookie configuration: {:?}", err);
+            panic!("Invalid cookie configuration: {:?}", err);
+        }
+    };
+    let cookie_key_ring = match CookieKeyRing::new(&config.app.keyring) {
+        Ok(cookie_key_ring) => cookie_key_ring,
+        Err(err) => {
+            event!(Level::ERROR, "Invalid key ring configuration: {:?}", err);
+            panic!("Invalid key ring configuration: {:?}", err);
+        }
+    };
+    let rocket = match &config.app.dpop.public_url {
+        Some(_) => {
+            match DpopVerifier::new(&config.app.dpop, config.app.oidc.client_id.as_deref()) {
+                Ok(dpop_verifier) => rocket.manage(dpop_verifier),
+                Err(err) => {
+                    event!(Level::ERROR, "Invalid DPoP configuration: {:?}", err);
+                    panic!("Invalid DPoP configuration: {:?}", err);
+                }
+            }
+        }
+        None => rocket,
+    };
+    let rocket = match &config.app.token_exchange.audiences {
+        Some(_) => match TokenExchange::new(&config.app.token_exchange) {
+            Ok(token_exchange) => rocket.manage(token_exchange),
+            Err(err) => {
+                event!(
+                    Level::ERROR,
+                    "Invalid token exchange configuration: {:?}",
+                    err
+                );
+                panic!("Invalid token exchange configuration: {:?}", err);
+            }
+        },
+        None => rocket,
+    };
+    let rocket = match &config.app.impersonation.admin_roles {
+        Some(_) => rocket
+            .manage(Impersonation::new(&config.app.impersonation))
+            .attach(ImpersonationAudit)
+            .mount(
+                "/oidc/impersonation",
+                routes![impersonation_start, impersonation_stop],
+            ),
+        None => rocket,
+    };
+    let mut reloadables: Vec<Arc<dyn Reloadable>> = vec![
+        Arc::new(oidc_flow.client_secret.clone()),
+        Arc::new(cookie_key_ring.clone()),
+        oidc_flow.provider(),
+    ];
+    let rocket = match &config.app.database.url_file {
+        Some(path) => match DatabaseUrl::new(path) {
+            Ok(database_url) => {
+                let database_url = Arc::new(database_url);
+                reloadables.push(database_url.clone());
+                // the pool is created by the ignite fairing of Db, which is attached before
+                rocket.attach(AdHoc::on_ignite("Database URL", |rocket| async move {
+                    database_url.set_pool(&rocket);
+                    rocket
+                }))
+            }
+            Err(err) => {
+                event!(Level::ERROR, "Cannot read database url_file: {}", err);
+                panic!("Cannot read database url_file: {}", err);
+            }
+        },
+        None => rocket,
+    };
     rocket
+        .attach(ReloadFiles::new(
+            reloadables,
+            config.app.reload.interval_seconds,
+        ))
         .manage(oidc_flow)
+        .manage(oidc_cookies)
+        .manage(cookie_key_ring)
         .manage(config.app.oidc.clone())
         .mount(
             "/oidc",
@@ -131,9 +952,16 @@ pub fn configure_oidc(rocket: Rocket<Build>, config: &Config) -> Rocket<Build> {
 /// OIDC client that should be managed in a state in Rocket
 ///
 fn read_oidc_config(config: &Config) -> OidcFlow {
-    let issuer_url = match &config.app.oidc.issuer_url {
-        Some(issuer_url) => issuer_url,
-        None => {
+    let provider_source = match (
+        &config.app.oidc.provider_metadata_file,
+        &config.app.oidc.issuer_url,
+    ) {
+        (Some(provider_metadata_file), _) => OidcProviderSource::Files {
+            provider_metadata_file: provider_metadata_file.to_string(),
+            jwks_file: config.app.oidc.jwks_file.clone(),
+        },
+        (None, Some(issuer_url)) => OidcProviderSource::Discovery(issuer_url.to_string()),
+        (None, None) => {
             panic!("Invalid issuer_url.");
         }
     };
@@ -149,22 +977,42 @@ fn read_oidc_config(config: &Config) -> OidcFlow {
             panic!("Invalid client_id.");
         }
     };
-    let client_secret = match &config.app.oidc.client_secret {
-        Some(client_secret) => client_secret,
-        None => {
+    let client_secret = match SecretFile::new(
+        config.app.oidc.client_secret.as_ref(),
+        config.app.oidc.client_secret_file.as_deref(),
+    ) {
+        Ok(client_secret) if config.app.oidc.client_secret_file.is_some() => client_secret,
+        Ok(client_secret) if config.app.oidc.client_secret.is_some() => client_secret,
+        Ok(_) => {
             panic!("Invalid client_secret.");
         }
+        Err(err) => {
+            event!(Level::ERROR, "Cannot read client_secret_file: {}", err);
+            panic!("Invalid client_secret_file.");
+        }
     };
     let scopes = match &config.app.oidc.scopes {
         Some(scopes) => scopes.clone(),
         None => Vec::new(),
     };
+    let (http_client, discovery_http_client) = match (
+        IdpHttpClient::new(&config.app.oidc),
+        IdpBlockingHttpClient::new(&config.app.oidc),
+    ) {
+        (Ok(http_client), Ok(discovery_http_client)) => (http_client, discovery_http_client),
+        (Err(err), _) | (_, Err(err)) => {
+            event!(Level::ERROR, "Invalid HTTP client configuration: {:?}", err);
+            panic!("Invalid HTTP client configuration: {:?}", err);
+        }
+    };
     match OidcFlow::new(
-        issuer_url.to_string(),
+        provider_source,
         redirect_url.to_string(),
         client_id.to_string(),
-        client_secret.to_string(),
+        client_secret,
         scopes,
+        http_client,
+        &discovery_http_client,
     ) {
         Ok(oidc_flow) => oidc_flow,
         Err(err) => {
@@ -1 +1,5 @@
+pub mod cli;
 pub mod config;
+pub mod reload;
+pub mod secrets;
+pub mod validation;
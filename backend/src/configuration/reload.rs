@@ -0,0 +1,206 @@
+//! Re-reads configuration files (e.g. secrets) on SIGHUP or when they change without restarting the application
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::time::{self, Interval, MissedTickBehavior};
+use rocket::{Orbit, Rocket};
+use tracing::{Level, event};
+
+const DEFAULT_INTERVAL_SECONDS: u64 = 30;
+
+/// Value that is backed by a file and can be re-read at runtime
+pub trait Reloadable: Send + Sync {
+    /// Name for log output. It must not contain confidential information
+    fn name(&self) -> String;
+
+    /// Re-reads the file
+    ///
+    /// # Arguments
+    /// * `force` - Re-read the file even if its modification time did not change
+    ///
+    /// # Returns
+    /// true if the value changed or an error if the file cannot be read (the previous value is kept)
+    ///
+    fn reload(&self, force: bool) -> io::Result<bool>;
+}
+
+/// File that remembers its modification time when it was read last
+pub struct WatchedFile {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl WatchedFile {
+    pub fn new(path: &str) -> WatchedFile {
+        WatchedFile {
+            path: PathBuf::from(path),
+            modified: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the file was modified since it was read last
+    pub fn has_changed(&self) -> bool {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        modified.is_none() || modified != *self.modified.lock().unwrap()
+    }
+
+    /// Reads the file and remembers its modification time
+    pub fn read(&self) -> io::Result<String> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        let content = fs::read_to_string(&self.path)?;
+        *self.modified.lock().unwrap() = modified;
+        Ok(content)
+    }
+}
+
+// Configuration of Fairing
+pub struct ReloadFiles {
+    pub reloadables: Vec<Arc<dyn Reloadable>>,
+    pub interval: Duration,
+}
+
+impl ReloadFiles {
+    /// Creates the fairing
+    ///
+    /// # Arguments
+    /// * `reloadables` - Values to reload
+    /// * `interval_seconds` - Interval to check if files changed. 0 disables checking, then files are only re-read on SIGHUP
+    ///
+    pub fn new(
+        reloadables: Vec<Arc<dyn Reloadable>>,
+        interval_seconds: Option<u64>,
+    ) -> ReloadFiles {
+        ReloadFiles {
+            reloadables,
+            interval: Duration::from_secs(interval_seconds.unwrap_or(DEFAULT_INTERVAL_SECONDS)),
+        }
+    }
+}
+
+/// Reloads all values and logs errors
+///
+/// # Arguments
+/// * `reloadables` - Values to reload
+/// * `force` - Re-read the files even if their modification time did not change
+///
+pub fn reload_all(reloadables: &[Arc<dyn Reloadable>], force: bool) {
+    for reloadable in reloadables {
+        if let Err(err) = reloadable.reload(force) {
+            event!(
+                Level::ERROR,
+                "Cannot reload {}, keeping previous value: {}",
+                reloadable.name(),
+                err
+            );
+        }
+    }
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for ReloadFiles {
+    fn info(&self) -> Info {
+        Info {
+            name: "Configuration - Reload Files",
+            kind: Kind::Liftoff | Kind::Singleton,
+        }
+    }
+
+    /// Executed after launch. Starts a task that reloads the files on SIGHUP and in the configured interval
+    ///
+    /// # Arguments
+    /// * `self` - Struct Reload Files
+    /// * `rocket` - Rocket instance
+    ///
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if self.reloadables.is_empty() {
+            return;
+        }
+        let reloadables = self.reloadables.clone();
+        let interval = self.interval;
+        let mut shutdown = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            let mut ticker = (!interval.is_zero()).then(|| {
+                let mut ticker = time::interval(interval);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                ticker
+            });
+            let mut hangup = HangupSignal::new();
+            loop {
+                rocket::tokio::select! {
+                    _ = tick(&mut ticker) => reload_all(&reloadables, false),
+                    _ = hangup.recv() => {
+                        event!(Level::INFO, "Received SIGHUP, reloading files");
+                        reload_all(&reloadables, true)
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+        });
+    }
+}
+
+/// Waits for the next interval to check the files. Waits forever if checking is disabled
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending::<()>().await,
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        use rocket::tokio::signal::unix::{Signal, SignalKind, signal};
+
+        // SIGHUP is only available on unix
+        struct HangupSignal(Option<Signal>);
+
+        impl HangupSignal {
+            fn new() -> HangupSignal {
+                match signal(SignalKind::hangup()) {
+                    Ok(signal) => HangupSignal(Some(signal)),
+                    Err(err) => {
+                        event!(Level::WARN, "Cannot listen for SIGHUP: {}", err);
+                        HangupSignal(None)
+                    }
+                }
+            }
+
+            async fn recv(&mut self) {
+                if let Some(signal) = &mut self.0
+                    && signal.recv().await.is_some()
+                {
+                    return;
+                }
+                // no signals can be received anymore
+                self.0 = None;
+                std::future::pending::<()>().await
+            }
+        }
+    } else {
+        struct HangupSignal;
+
+        impl HangupSignal {
+            fn new() -> HangupSignal {
+                HangupSignal
+            }
+
+            async fn recv(&mut self) {
+                std::future::pending::<()>().await
+            }
+        }
+    }
+}
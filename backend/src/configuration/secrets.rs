@@ -0,0 +1,177 @@
+//! Credentials (e.g. OIDC client secret) that are redacted in all debug/log output and that can be read from files (e.g. Kubernetes or Docker secrets)
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use rocket::serde::Deserialize;
+use tracing::{Level, event};
+
+use super::reload::Reloadable;
+
+/// Confidential configuration value. It is never printed in debug or log output
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(crate = "rocket::serde", transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    /// Returns the confidential value. Do not log it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Secret that is either configured directly or read from a file. A secret read from a file is re-read when the file changes
+#[derive(Clone)]
+pub struct SecretFile {
+    inner: Arc<SecretFileInner>,
+}
+
+struct SecretFileInner {
+    path: Option<PathBuf>,
+    value: RwLock<Secret>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl SecretFile {
+    /// Creates a secret. The file takes precedence over the configured value
+    ///
+    /// # Arguments
+    /// * `value` - Secret configured directly (e.g. in Rocket.toml or via an environment variable)
+    /// * `path` - File containing the secret
+    ///
+    /// # Returns
+    /// Secret or an error if the file cannot be read
+    ///
+    pub fn new(value: Option<&Secret>, path: Option<&str>) -> io::Result<SecretFile> {
+        let path = path.map(PathBuf::from);
+        let (value, modified) = match &path {
+            Some(path) => (read_secret_file(path)?, modified_time(path)),
+            None => (value.cloned().unwrap_or_default(), None),
+        };
+        Ok(SecretFile {
+            inner: Arc::new(SecretFileInner {
+                path,
+                value: RwLock::new(value),
+                modified: Mutex::new(modified),
+            }),
+        })
+    }
+
+    /// Returns the current value of the secret
+    pub fn value(&self) -> Secret {
+        self.inner.value.read().unwrap().clone()
+    }
+
+    /// Returns true if no secret has been configured
+    pub fn is_empty(&self) -> bool {
+        self.inner.value.read().unwrap().expose().is_empty()
+    }
+}
+
+impl Reloadable for SecretFile {
+    fn name(&self) -> String {
+        match &self.inner.path {
+            Some(path) => format!("secret file {}", path.display()),
+            None => "secret".to_string(),
+        }
+    }
+
+    fn reload(&self, force: bool) -> io::Result<bool> {
+        self.reload_validated(force, |_| Ok(()))
+    }
+}
+
+impl SecretFile {
+    /// Re-reads the file and replaces the value only if it is valid. An invalid value is not remembered as read, so that it is reported again on the next check until the file is fixed
+    ///
+    /// # Arguments
+    /// * `force` - Re-read the file even if its modification time did not change
+    /// * `validate` - Checks the new value, e.g. parses it
+    ///
+    /// # Returns
+    /// true if the value changed or an error if the file cannot be read or the value is invalid (the previous value is kept)
+    ///
+    pub fn reload_validated(
+        &self,
+        force: bool,
+        validate: impl FnOnce(&Secret) -> io::Result<()>,
+    ) -> io::Result<bool> {
+        let Some(path) = &self.inner.path else {
+            return Ok(false);
+        };
+        let modified = modified_time(path);
+        let mut last_modified = self.inner.modified.lock().unwrap();
+        if !force && modified.is_some() && modified == *last_modified {
+            return Ok(false);
+        }
+        let value = read_secret_file(path)?;
+        if *self.inner.value.read().unwrap() != value {
+            validate(&value)?;
+        }
+        *last_modified = modified;
+        let mut current = self.inner.value.write().unwrap();
+        if *current == value {
+            return Ok(false);
+        }
+        *current = value;
+        event!(Level::INFO, "Reloaded {}", self.name());
+        Ok(true)
+    }
+}
+
+/// Reads a secret from a file. Trailing line breaks (e.g. added by editors) are not part of the secret
+///
+/// # Arguments
+/// * `path` - File containing the secret
+///
+/// # Returns
+/// Secret or an error if the file cannot be read
+///
+pub fn read_secret_file(path: &std::path::Path) -> io::Result<Secret> {
+    let content = fs::read_to_string(path)?;
+    Ok(Secret(content.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn modified_time(path: &std::path::Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let secret = Secret::new("very-secret".to_string());
+        assert_eq!(format!("{:?}", secret), "<redacted>");
+        assert_eq!(format!("{:?}", Some(secret)), "Some(<redacted>)");
+    }
+
+    #[test]
+    fn file_is_reloaded_when_changed() {
+        let path = std::env::temp_dir().join(format!("secret-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "first\n").unwrap();
+        let secret = SecretFile::new(None, path.to_str()).unwrap();
+        assert_eq!(secret.value().expose(), "first");
+        fs::write(&path, "second\n").unwrap();
+        assert!(secret.reload(true).unwrap());
+        assert_eq!(secret.value().expose(), "second");
+        assert!(!secret.reload(true).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1172 @@
+//! Validation of the whole configuration in a single pass
+//!
+//! All errors and warnings are collected with the key path of the configuration (e.g. app.httpheaders.profiles[1].paths[0]), so that they can be fixed at once. The validation runs at startup before the modules are configured and as command check-config (e.g. in a CI pipeline). It does not contact the IdP and does not open the sinks of the security events
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+use rocket::figment::Figment;
+use rocket::http::Method;
+
+use super::config::{
+    Config, CustomAppCookiesConfig, CustomAppCorsConfig, CustomAppCorsPolicyConfig,
+    CustomAppCsrfConfig, CustomAppFirewallConfig, CustomAppFirewallRuleConfig,
+    CustomAppHttpHeaderValuesConfig, CustomAppHttpHeadersConfig, CustomAppKeyRingConfig,
+    CustomAppOidcConfig, CustomAppRateLimitConfig, CustomAppSecurityEventsConfig,
+};
+use super::secrets::SecretFile;
+use crate::compression::fairing::{Compression, CompressionConfigError};
+use crate::httpfirewall::clientip::ClientIpResolver;
+use crate::httpfirewall::cors::{Cors, CorsConfigError};
+use crate::httpfirewall::csrf::{CsrfConfigError, CsrfProtection};
+use crate::httpfirewall::ratelimit::{RateLimitConfigError, RateLimiter};
+use crate::httpfirewall::requestfilter::{RequestFilter, RequestFilterConfigError};
+use crate::httpfirewall::securityhttpheaders::{
+    HeaderProfile, HttpHeadersConfigError, SecurityHttpHeaders,
+};
+use crate::httpfirewall::subresourceintegrity::{IntegrityConfigError, SubresourceIntegrity};
+use crate::mtls::principals::{CertificatePrincipals, MtlsConfigError};
+use crate::oidc::cookies::{CookiePolicyError, OidcCookies};
+use crate::oidc::dpop::{DpopConfigError, DpopVerifier};
+use crate::oidc::httpclient::{IdpHttpClient, IdpHttpClientError};
+use crate::oidc::keyring::{CookieKeyRing, KeyRingError};
+use crate::oidc::tokenexchange::TokenExchange;
+use crate::reporting::collector::{ReportCollector, ReportingConfigError};
+use crate::securityevents::log::{SecurityEventLog, SecurityEventsConfigError};
+use crate::terms::acceptance::{TermsConfigError, TermsOfUse};
+
+use openidconnect::url::Url;
+
+const HTTP_HEADERS_KEY: &str = "app.httpheaders";
+
+// Severity of a finding. The application does not start with errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FindingSeverity {
+    Error,
+    Warning,
+}
+
+// Error or warning in the configuration with the key path of the configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFinding {
+    pub severity: FindingSeverity,
+    pub key: String,
+    pub message: String,
+}
+
+// All errors and warnings of the configuration
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub findings: Vec<ConfigFinding>,
+}
+
+impl fmt::Display for ConfigFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            FindingSeverity::Error => "error",
+            FindingSeverity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.key, self.message)
+    }
+}
+
+impl ConfigReport {
+    /// Adds a finding. Findings that were already reported (e.g. for inherited headers) are ignored
+    fn add(
+        &mut self,
+        severity: FindingSeverity,
+        key: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        let finding = ConfigFinding {
+            severity,
+            key: key.into(),
+            message: message.into(),
+        };
+        if !self.findings.contains(&finding) {
+            self.findings.push(finding);
+        }
+    }
+
+    fn error(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.add(FindingSeverity::Error, key, message);
+    }
+
+    fn warning(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.add(FindingSeverity::Warning, key, message);
+    }
+
+    /// Adds an error if a module cannot be created from its configuration
+    ///
+    /// # Arguments
+    /// * `key` - Key of the module, e.g. app.cors.policies[0]
+    /// * `result` - Module or the error of its configuration
+    /// * `locate` - Returns the key of the invalid value below the key of the module (empty if the error has no single value) and the message
+    ///
+    fn check<T, E>(
+        &mut self,
+        key: &str,
+        result: Result<T, E>,
+        locate: impl FnOnce(E) -> (String, String),
+    ) {
+        if let Err(err) = result {
+            let (field, message) = locate(err);
+            match field.is_empty() {
+                true => self.error(key, message),
+                false => self.error(format!("{}.{}", key, field), message),
+            }
+        }
+    }
+
+    /// Returns the number of findings with a severity
+    pub fn count(&self, severity: FindingSeverity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+
+    /// Returns true if the application cannot start with the configuration
+    pub fn has_errors(&self) -> bool {
+        self.count(FindingSeverity::Error) > 0
+    }
+}
+
+/// Reads the configuration from Rocket.toml and the ROCKET_* environment variables and validates it
+///
+/// # Arguments
+/// * `figment` - Configuration sources of Rocket
+///
+/// # Returns
+/// Report with all errors and warnings, including values that cannot be read (e.g. missing keys or wrong types)
+///
+pub fn check_config(figment: &Figment) -> ConfigReport {
+    match figment.extract::<Config>() {
+        Ok(config) => validate(&config, figment),
+        Err(errors) => {
+            let mut report = ConfigReport::default();
+            for err in errors {
+                let key = match err.path.is_empty() {
+                    true => "app".to_string(),
+                    false => err.path.join("."),
+                };
+                report.error(key, err.kind.to_string());
+            }
+            report
+        }
+    }
+}
+
+/// Validates the configuration of all modules
+///
+/// # Arguments
+/// * `config` - Configuration of the Rocket app
+/// * `figment` - Configuration sources of Rocket, e.g. for the TLS configuration
+///
+/// # Returns
+/// Report with all errors and warnings
+///
+pub fn validate(config: &Config, figment: &Figment) -> ConfigReport {
+    let mut report = ConfigReport::default();
+    let app = &config.app;
+    validate_http_headers(&app.httpheaders, &mut report);
+    validate_oidc(config, &mut report);
+    if !Path::new(&app.fileserver.location).is_dir() {
+        report.warning(
+            "app.fileserver.location",
+            format!("directory {} does not exist", app.fileserver.location),
+        );
+    }
+    let integrity = &app.fileserver.integrity;
+    report.check(
+        "app.fileserver.integrity",
+        SubresourceIntegrity::new(&app.fileserver),
+        |err| match err {
+            IntegrityConfigError::DocumentNotReadable(message) => (
+                key_of(
+                    "documents",
+                    integrity.documents.as_deref().unwrap_or_default(),
+                    |document| message.starts_with(&format!("{}:", document)),
+                )
+                .unwrap_or_else(|| "documents".to_string()),
+                format!("cannot read {}", message),
+            ),
+            IntegrityConfigError::ManifestNotReadable(message) => {
+                ("manifest".to_string(), format!("cannot read: {}", message))
+            }
+            IntegrityConfigError::InvalidManifest(message) => (
+                "manifest".to_string(),
+                format!("invalid manifest: {}", message),
+            ),
+            // assets below the location of the static files
+            IntegrityConfigError::AssetNotReadable(message) => {
+                (String::new(), format!("cannot read asset {}", message))
+            }
+        },
+    );
+    report.check(
+        "app.security_events",
+        SecurityEventLog::check(&app.security_events),
+        |err| security_events_error(&app.security_events, err),
+    );
+    validate_firewall(&app.firewall, &mut report);
+    if app.compression.encodings.is_some() {
+        let compression = &app.compression;
+        report.check(
+            "app.compression",
+            Compression::new(compression),
+            |err| match err {
+                CompressionConfigError::EmptyEncodings => (
+                    "encodings".to_string(),
+                    "at least one encoding is required".to_string(),
+                ),
+                CompressionConfigError::UnknownEncoding(encoding) => (
+                    key_of(
+                        "encodings",
+                        compression.encodings.as_deref().unwrap_or_default(),
+                        |value| *value == encoding,
+                    )
+                    .unwrap_or_default(),
+                    format!("unknown encoding {} (br, zstd or gzip)", encoding),
+                ),
+                CompressionConfigError::InvalidContentType(content_type) => (
+                    key_of(
+                        "content_types",
+                        compression.content_types.as_deref().unwrap_or_default(),
+                        |value| *value == content_type,
+                    )
+                    .unwrap_or_default(),
+                    format!("invalid content type {}", content_type),
+                ),
+            },
+        );
+    }
+    if app.ratelimit.groups.is_some() {
+        validate_ratelimit(&app.ratelimit, &mut report);
+    }
+    if app.cors.policies.is_some() {
+        validate_cors(&app.cors, &mut report);
+    }
+    report.check(
+        "app.csrf",
+        CsrfProtection::new(&app.csrf, &app.cookies, app.oidc.redirect_url.as_deref()),
+        |err| csrf_error(&app.csrf, err),
+    );
+    if app.mtls.principals.is_some() {
+        let mtls = &app.mtls;
+        let principals = mtls.principals.as_deref().unwrap_or_default();
+        report.check(
+            "app.mtls",
+            CertificatePrincipals::new(mtls),
+            |err| match err {
+                MtlsConfigError::EmptyPrincipal => (
+                    principals
+                        .iter()
+                        .position(|principal| principal.principal.is_empty())
+                        .map(|index| format!("principals[{}].principal", index))
+                        .unwrap_or_default(),
+                    "principal is empty".to_string(),
+                ),
+                MtlsConfigError::SubjectOrSanRequired(_) => (
+                    principals
+                        .iter()
+                        .position(|principal| {
+                            principal.subject.is_some() == principal.san.is_some()
+                        })
+                        .map(|index| format!("principals[{}]", index))
+                        .unwrap_or_default(),
+                    "exactly one of subject and san is required".to_string(),
+                ),
+                MtlsConfigError::InvalidPath(path) => (
+                    key_of(
+                        "paths",
+                        mtls.paths.as_deref().unwrap_or_default(),
+                        |value| *value == path,
+                    )
+                    .unwrap_or_default(),
+                    format!("path {} must start with /", path),
+                ),
+            },
+        );
+        if !figment.contains("tls.mutual") {
+            report.warning(
+                "app.mtls.principals",
+                "tls.mutual is not configured, so clients cannot authenticate with certificates",
+            );
+        }
+    }
+    if app.terms.version.is_some() {
+        let terms = &app.terms;
+        report.check("app.terms", TermsOfUse::new(terms), |err| match err {
+            TermsConfigError::EmptyVersion => {
+                ("version".to_string(), "version is empty".to_string())
+            }
+            TermsConfigError::DocumentRequired => ("document".to_string(), "required".to_string()),
+            TermsConfigError::DocumentNotReadable(message) => {
+                ("document".to_string(), format!("cannot read: {}", message))
+            }
+            TermsConfigError::InvalidPath(path) => (
+                key_of(
+                    "paths",
+                    terms.paths.as_deref().unwrap_or_default(),
+                    |value| *value == path,
+                )
+                .unwrap_or_default(),
+                format!("invalid path {}", path),
+            ),
+        });
+    }
+    if app.reporting.endpoint.is_some() {
+        report.check(
+            "app.reporting",
+            ReportCollector::new(&app.reporting),
+            |err| match err {
+                ReportingConfigError::InvalidEndpoint(endpoint) => {
+                    ("endpoint".to_string(), format!("invalid path {}", endpoint))
+                }
+                ReportingConfigError::EmptyEndpointName => {
+                    ("endpoint_name".to_string(), "name is empty".to_string())
+                }
+                ReportingConfigError::InvalidRateLimit => (
+                    "rate_limit_per_minute".to_string(),
+                    "must be greater than 0".to_string(),
+                ),
+                ReportingConfigError::InvalidMaxReportSize => (
+                    "max_report_size".to_string(),
+                    "must be greater than 0".to_string(),
+                ),
+                ReportingConfigError::InvalidMaxReports => (
+                    "max_reports".to_string(),
+                    "must be greater than 0".to_string(),
+                ),
+                ReportingConfigError::InvalidRetention => (
+                    "retention_days".to_string(),
+                    "must be greater than 0".to_string(),
+                ),
+            },
+        );
+    }
+    report
+}
+
+/// Validates the rules of the firewall one by one, so that errors are reported with the index of the rule, and the other settings of the firewall
+fn validate_firewall(config: &CustomAppFirewallConfig, report: &mut ConfigReport) {
+    let rules = config.rules.as_deref().unwrap_or_default();
+    for (index, rule) in rules.iter().enumerate() {
+        let key = format!("app.firewall.rules[{}]", index);
+        if rules[..index]
+            .iter()
+            .any(|previous| previous.name == rule.name)
+        {
+            report.error(
+                format!("{}.name", key),
+                format!("rule {} is defined more than once", rule.name),
+            );
+        }
+        report.check(
+            &key,
+            RequestFilter::new(&CustomAppFirewallConfig {
+                rules: Some(vec![rule.clone()]),
+                ..Default::default()
+            }),
+            |err| firewall_rule_error(rule, err),
+        );
+    }
+    report.check(
+        "app.firewall",
+        RequestFilter::new(&CustomAppFirewallConfig {
+            rules: None,
+            ..config.clone()
+        }),
+        |err| match err {
+            RequestFilterConfigError::EmptyRequestIdHeader => (
+                "request_id_header".to_string(),
+                "header name is empty".to_string(),
+            ),
+            RequestFilterConfigError::InvalidNetwork(message) => (network_key(config), message),
+            err => (String::new(), format!("{:?}", err)),
+        },
+    );
+}
+
+/// Returns the key and message of an error of a firewall rule
+fn firewall_rule_error(
+    rule: &CustomAppFirewallRuleConfig,
+    err: RequestFilterConfigError,
+) -> (String, String) {
+    let invalid_regex = |regex: &String| Regex::new(regex).is_err();
+    match err {
+        RequestFilterConfigError::EmptyRuleName => {
+            ("name".to_string(), "name is empty".to_string())
+        }
+        RequestFilterConfigError::InvalidAction(_) => (
+            "action".to_string(),
+            format!(
+                "unknown action {} (block, log or tag)",
+                rule.action.as_deref().unwrap_or_default()
+            ),
+        ),
+        RequestFilterConfigError::InvalidMethod(_) => (
+            key_of(
+                "methods",
+                rule.methods.as_deref().unwrap_or_default(),
+                |method| Method::from_str(method).is_err(),
+            )
+            .unwrap_or_else(|| "methods".to_string()),
+            "invalid method".to_string(),
+        ),
+        RequestFilterConfigError::InvalidRegex(message) => (
+            key_of(
+                "paths",
+                rule.paths.as_deref().unwrap_or_default(),
+                invalid_regex,
+            )
+            .or_else(|| {
+                key_of(
+                    "user_agents",
+                    rule.user_agents.as_deref().unwrap_or_default(),
+                    invalid_regex,
+                )
+            })
+            .or_else(|| {
+                rule.headers
+                    .iter()
+                    .flatten()
+                    .find(|(_, value)| invalid_regex(value))
+                    .map(|(name, _)| format!("headers.{}", name))
+            })
+            .unwrap_or_default(),
+            message,
+        ),
+        RequestFilterConfigError::RuleWithoutCondition(_) => (
+            String::new(),
+            "rule needs at least one condition".to_string(),
+        ),
+        err => (String::new(), format!("{:?}", err)),
+    }
+}
+
+/// Returns the key of the first network of the firewall that is invalid, e.g. ip_access[1].allow[0]
+fn network_key(config: &CustomAppFirewallConfig) -> String {
+    let invalid = |network: &String| ClientIpResolver::new(std::slice::from_ref(network)).is_err();
+    if let Some(key) = key_of(
+        "trusted_proxies",
+        config.trusted_proxies.as_deref().unwrap_or_default(),
+        invalid,
+    ) {
+        return key;
+    }
+    for (index, access) in config.ip_access.iter().flatten().enumerate() {
+        for (field, networks) in [("allow", &access.allow), ("deny", &access.deny)] {
+            if let Some(key) = key_of(field, networks.as_deref().unwrap_or_default(), invalid) {
+                return format!("ip_access[{}].{}", index, key);
+            }
+        }
+    }
+    String::new()
+}
+
+/// Validates the groups of the rate limiter one by one, so that errors are reported with the index of the group
+fn validate_ratelimit(config: &CustomAppRateLimitConfig, report: &mut ConfigReport) {
+    let groups = config.groups.as_deref().unwrap_or_default();
+    for (index, group) in groups.iter().enumerate() {
+        let key = format!("app.ratelimit.groups[{}]", index);
+        if groups[..index]
+            .iter()
+            .any(|previous| previous.name == group.name)
+        {
+            report.error(
+                format!("{}.name", key),
+                format!("group {} is defined more than once", group.name),
+            );
+        }
+        report.check(
+            &key,
+            RateLimiter::new(&CustomAppRateLimitConfig {
+                groups: Some(vec![group.clone()]),
+                ..Default::default()
+            }),
+            |err| match err {
+                RateLimitConfigError::EmptyGroupName => {
+                    ("name".to_string(), "name is empty".to_string())
+                }
+                RateLimitConfigError::InvalidPathRegex(message) => (
+                    key_of("paths", &group.paths, |path| Regex::new(path).is_err())
+                        .unwrap_or_else(|| "paths".to_string()),
+                    message,
+                ),
+                RateLimitConfigError::InvalidKey(_) => (
+                    "key".to_string(),
+                    format!(
+                        "unknown key {} (ip, subject or token)",
+                        group.key.as_deref().unwrap_or_default()
+                    ),
+                ),
+                RateLimitConfigError::InvalidLimit(_) if group.capacity == 0 => {
+                    ("capacity".to_string(), "must be greater than 0".to_string())
+                }
+                RateLimitConfigError::InvalidLimit(_) => (
+                    "refill_per_second".to_string(),
+                    "must be a positive number".to_string(),
+                ),
+                err => (String::new(), format!("{:?}", err)),
+            },
+        );
+    }
+}
+
+/// Validates the CORS policies one by one, so that errors are reported with the index of the policy
+fn validate_cors(config: &CustomAppCorsConfig, report: &mut ConfigReport) {
+    for (index, policy) in config.policies.iter().flatten().enumerate() {
+        report.check(
+            &format!("app.cors.policies[{}]", index),
+            Cors::new(&CustomAppCorsConfig {
+                policies: Some(vec![policy.clone()]),
+            }),
+            |err| cors_policy_error(policy, err),
+        );
+    }
+}
+
+/// Returns the key and message of an error of a CORS policy
+fn cors_policy_error(policy: &CustomAppCorsPolicyConfig, err: CorsConfigError) -> (String, String) {
+    let origins = policy.origins.as_deref().unwrap_or_default();
+    match err {
+        CorsConfigError::EmptyPaths => (
+            "paths".to_string(),
+            "at least one path is required".to_string(),
+        ),
+        CorsConfigError::InvalidPath(path) => (
+            key_of("paths", &policy.paths, |value| *value == path).unwrap_or_default(),
+            format!("path {} must start with /", path),
+        ),
+        CorsConfigError::EmptyOrigins => (
+            "origins".to_string(),
+            "origins or origin_patterns are required".to_string(),
+        ),
+        CorsConfigError::InvalidOrigin(origin) => (
+            key_of("origins", origins, |value| *value == origin).unwrap_or_default(),
+            format!(
+                "invalid origin {} (scheme, host and port without path)",
+                origin
+            ),
+        ),
+        CorsConfigError::InvalidOriginPattern(message) => (
+            key_of(
+                "origin_patterns",
+                policy.origin_patterns.as_deref().unwrap_or_default(),
+                |pattern| Regex::new(pattern).is_err(),
+            )
+            .unwrap_or_else(|| "origin_patterns".to_string()),
+            message,
+        ),
+        CorsConfigError::InvalidMethod(method) => (
+            key_of(
+                "methods",
+                policy.methods.as_deref().unwrap_or_default(),
+                |value| *value == method,
+            )
+            .unwrap_or_default(),
+            format!("invalid method {}", method),
+        ),
+        CorsConfigError::InvalidHeader(header) => (
+            key_of(
+                "headers",
+                policy.headers.as_deref().unwrap_or_default(),
+                |value| value.eq_ignore_ascii_case(&header),
+            )
+            .or_else(|| {
+                key_of(
+                    "exposed_headers",
+                    policy.exposed_headers.as_deref().unwrap_or_default(),
+                    |value| *value == header,
+                )
+            })
+            .unwrap_or_default(),
+            format!("invalid header {}", header),
+        ),
+        CorsConfigError::WildcardWithCredentials => (
+            "allow_credentials".to_string(),
+            "credentials are not allowed for the origin *".to_string(),
+        ),
+    }
+}
+
+/// Returns the key and message of an error of the CSRF protection
+fn csrf_error(config: &CustomAppCsrfConfig, err: CsrfConfigError) -> (String, String) {
+    match err {
+        CsrfConfigError::MissingOrigins => (
+            "origins".to_string(),
+            "at least one origin is required without app.oidc.redirect_url".to_string(),
+        ),
+        CsrfConfigError::InvalidOrigin(origin) => (
+            key_of(
+                "origins",
+                config.origins.as_deref().unwrap_or_default(),
+                |value| *value == origin,
+            )
+            .unwrap_or_default(),
+            format!("invalid origin {}", origin),
+        ),
+        CsrfConfigError::InvalidPath(path) => (
+            key_of(
+                "paths",
+                config.paths.as_deref().unwrap_or_default(),
+                |value| *value == path,
+            )
+            .or_else(|| {
+                key_of(
+                    "exempt_paths",
+                    config.exempt_paths.as_deref().unwrap_or_default(),
+                    |value| *value == path,
+                )
+            })
+            .unwrap_or_default(),
+            format!("path {} must start with /", path),
+        ),
+        CsrfConfigError::EmptyTokenName => (
+            match config.token_cookie_name.as_deref() {
+                Some("") => "token_cookie_name".to_string(),
+                _ => "token_header_name".to_string(),
+            },
+            "name is empty".to_string(),
+        ),
+    }
+}
+
+/// Returns the key and message of an error of the security events
+fn security_events_error(
+    config: &CustomAppSecurityEventsConfig,
+    err: SecurityEventsConfigError,
+) -> (String, String) {
+    match err {
+        SecurityEventsConfigError::EmptySinks => (
+            "sinks".to_string(),
+            "at least one sink is required".to_string(),
+        ),
+        SecurityEventsConfigError::UnknownSink(sink) => (
+            key_of(
+                "sinks",
+                config.sinks.as_deref().unwrap_or_default(),
+                |value| *value == sink,
+            )
+            .unwrap_or_default(),
+            format!("unknown sink {} (log, file or syslog)", sink),
+        ),
+        SecurityEventsConfigError::MissingFile => {
+            ("file".to_string(), "required for the sink file".to_string())
+        }
+        SecurityEventsConfigError::CannotOpenFile(message) => {
+            ("file".to_string(), format!("cannot open {}", message))
+        }
+        SecurityEventsConfigError::CannotConnectSyslog(message) => (
+            "syslog_socket".to_string(),
+            format!("cannot connect {}", message),
+        ),
+        SecurityEventsConfigError::UnknownSyslogFacility(facility) => (
+            "syslog_facility".to_string(),
+            format!("unknown facility {}", facility),
+        ),
+        SecurityEventsConfigError::UnknownSeverity(severity) => (
+            "min_severity".to_string(),
+            format!("unknown severity {} (info, warning or error)", severity),
+        ),
+    }
+}
+
+/// Returns the key and message of an error of the HTTP client for the IdP
+fn idp_http_client_error(
+    config: &CustomAppOidcConfig,
+    err: IdpHttpClientError,
+) -> (String, String) {
+    match err {
+        IdpHttpClientError::CaFile(message) => ("http_ca_file".to_string(), message),
+        IdpHttpClientError::ClientCertificate(message) => (
+            match &config.http_client_key_file {
+                Some(key_file)
+                    if config.http_client_certificate_file.is_some()
+                        && !Path::new(key_file).is_file() =>
+                {
+                    "http_client_key_file".to_string()
+                }
+                None => "http_client_key_file".to_string(),
+                _ => "http_client_certificate_file".to_string(),
+            },
+            message,
+        ),
+        IdpHttpClientError::Proxy(message) => ("http_proxy".to_string(), message),
+        IdpHttpClientError::Build(message) => (String::new(), message),
+    }
+}
+
+/// Returns the key and message of an error of the cookie policy
+fn cookies_error(config: &CustomAppCookiesConfig, err: CookiePolicyError) -> (String, String) {
+    // the name of the cookie that violates the rules of its prefix
+    let name_key = |name: &str| match config.session_cookie_name.as_deref() {
+        Some(session_cookie_name) if session_cookie_name == name => {
+            "session_cookie_name".to_string()
+        }
+        _ => "redirect_destination_cookie_name".to_string(),
+    };
+    match err {
+        CookiePolicyError::InvalidSameSite(same_site) => (
+            "same_site".to_string(),
+            format!("unknown value {} (strict, lax or none)", same_site),
+        ),
+        CookiePolicyError::SameSiteNoneRequiresSecure => (
+            "same_site".to_string(),
+            "none requires secure cookies (allow_insecure_http)".to_string(),
+        ),
+        CookiePolicyError::HostPrefixRequiresSecure(name)
+        | CookiePolicyError::SecurePrefixRequiresSecure(name) => (
+            name_key(&name),
+            format!("{} requires secure cookies (allow_insecure_http)", name),
+        ),
+        CookiePolicyError::HostPrefixForbidsDomain(name) => {
+            ("domain".to_string(), format!("not allowed for {}", name))
+        }
+        CookiePolicyError::HostPrefixRequiresRootPath(name) => {
+            ("path".to_string(), format!("{} requires the path /", name))
+        }
+    }
+}
+
+/// Returns the key and message of an error of the cookie key ring. Keys are numbered from the active key (0) over the previous keys to the keys of the previous keys file
+fn keyring_error(config: &CustomAppKeyRingConfig, err: KeyRingError) -> (String, String) {
+    let key_of_index = |index: usize| match index {
+        0 if config.active_secret_key_file.is_some() => "active_secret_key_file".to_string(),
+        0 => "active_secret_key".to_string(),
+        index => match config
+            .previous_secret_keys
+            .as_deref()
+            .unwrap_or_default()
+            .len()
+        {
+            previous if index <= previous => format!("previous_secret_keys[{}]", index - 1),
+            _ => "previous_secret_keys_file".to_string(),
+        },
+    };
+    match err {
+        KeyRingError::InvalidEncoding(index) => {
+            (key_of_index(index), "invalid base64 encoding".to_string())
+        }
+        KeyRingError::InvalidKeyLength(index) => (
+            key_of_index(index),
+            "key must have 256 or 512 bits".to_string(),
+        ),
+        KeyRingError::UnreadableFile(message) => (
+            match &config.active_secret_key_file {
+                Some(file) if !Path::new(file).is_file() => "active_secret_key_file".to_string(),
+                _ => "previous_secret_keys_file".to_string(),
+            },
+            format!("cannot read: {}", message),
+        ),
+    }
+}
+
+/// Returns the key of the first value of a list that matches, e.g. origins[1]
+///
+/// # Arguments
+/// * `field` - Key of the list
+/// * `values` - Values of the list
+/// * `matches` - Returns true for the value that is searched
+///
+fn key_of(field: &str, values: &[String], matches: impl Fn(&String) -> bool) -> Option<String> {
+    values
+        .iter()
+        .position(matches)
+        .map(|index| format!("{}[{}]", field, index))
+}
+
+/// Validates the default headers and each header profile, so that errors of all profiles are reported
+fn validate_http_headers(config: &CustomAppHttpHeadersConfig, report: &mut ConfigReport) {
+    let profiles = config.profiles.clone().unwrap_or_default();
+    validate_regexes(
+        &format!(
+            "{}.content_security_policy_inject_nonce_paths",
+            HTTP_HEADERS_KEY
+        ),
+        &config.headers.content_security_policy_inject_nonce_paths,
+        report,
+    );
+    for (index, profile) in profiles.iter().enumerate() {
+        let key = format!("{}.profiles[{}]", HTTP_HEADERS_KEY, index);
+        validate_regexes(&format!("{}.paths", key), &profile.paths, report);
+        validate_regexes(
+            &format!("{}.content_security_policy_inject_nonce_paths", key),
+            &profile.headers.content_security_policy_inject_nonce_paths,
+            report,
+        );
+        if profiles[..index]
+            .iter()
+            .any(|previous| previous.name == profile.name)
+        {
+            report.error(
+                format!("{}.name", key),
+                format!("profile {} is defined more than once", profile.name),
+            );
+        }
+    }
+    let default_headers = match SecurityHttpHeaders::new(&CustomAppHttpHeadersConfig {
+        headers: config.headers.clone(),
+        profiles: None,
+    }) {
+        Ok(security_http_headers) => security_http_headers,
+        Err(err) => {
+            report_http_headers_error(HTTP_HEADERS_KEY, err, report);
+            return;
+        }
+    };
+    validate_nonce(
+        HTTP_HEADERS_KEY,
+        &default_headers.default_profile,
+        &config.headers,
+        report,
+    );
+    for (index, profile) in profiles.into_iter().enumerate() {
+        let key = format!("{}.profiles[{}]", HTTP_HEADERS_KEY, index);
+        let profile_headers = profile.headers.clone();
+        match SecurityHttpHeaders::new(&CustomAppHttpHeadersConfig {
+            headers: config.headers.clone(),
+            profiles: Some(vec![profile]),
+        }) {
+            Ok(security_http_headers) => validate_nonce(
+                &key,
+                &security_http_headers.profiles[0],
+                &profile_headers,
+                report,
+            ),
+            Err(err) => report_http_headers_error(&key, err, report),
+        }
+    }
+}
+
+/// Adds an error for each regular expression that is invalid
+fn validate_regexes(key: &str, regexes: &Option<Vec<String>>, report: &mut ConfigReport) {
+    for (index, regex) in regexes.iter().flatten().enumerate() {
+        if let Err(err) = Regex::new(regex) {
+            report.error(format!("{}[{}]", key, index), err.to_string());
+        }
+    }
+}
+
+/// Adds the error of the default headers or a header profile with the key of the invalid value
+fn report_http_headers_error(key: &str, err: HttpHeadersConfigError, report: &mut ConfigReport) {
+    let (field, message) = match err {
+        // reported with the index of the regular expression by validate_regexes
+        HttpHeadersConfigError::InvalidPathRegex(..) => return,
+        HttpHeadersConfigError::InvalidContentType(_, content_type) => (
+            "content_types",
+            format!("invalid content type {}", content_type),
+        ),
+        HttpHeadersConfigError::EmptyProfileName => ("name", "name is empty".to_string()),
+        HttpHeadersConfigError::DuplicateProfileName(name) => {
+            ("name", format!("profile name {} is reserved", name))
+        }
+        HttpHeadersConfigError::ProfileWithoutMatcher(_) => {
+            ("paths", "profile needs paths or content_types".to_string())
+        }
+        HttpHeadersConfigError::NonceTagsRequired(_) => (
+            "content_security_policy_inject_nonce_tags",
+            "required if nonces are inserted".to_string(),
+        ),
+        HttpHeadersConfigError::NonceHeadersRequired(_) => (
+            "content_security_policy_nonce_headers",
+            "required if nonces are inserted".to_string(),
+        ),
+        HttpHeadersConfigError::InvalidStrictTransportSecurity(_, reason) => {
+            ("strict_transport_security", reason)
+        }
+        HttpHeadersConfigError::UnknownStatusPolicyHeader(_, header) => {
+            ("status_policies", format!("unknown header {}", header))
+        }
+        HttpHeadersConfigError::InvalidStatusPolicy(_, status) => {
+            ("status_policies", format!("invalid status {}", status))
+        }
+        HttpHeadersConfigError::InvalidContentSecurityPolicy(_, err) => {
+            (err.field(), format!("{:?}", err))
+        }
+    };
+    report.error(format!("{}.{}", key, field), message);
+}
+
+/// Adds warnings for nonce settings that have no effect. The headers of profiles are resolved, so the key of the profile is used unless only the default headers configure the value
+fn validate_nonce(
+    key: &str,
+    profile: &HeaderProfile,
+    configured: &CustomAppHttpHeaderValuesConfig,
+    report: &mut ConfigReport,
+) {
+    let headers = &profile.headers;
+    let key_of = |field: &str, configured: bool| match configured {
+        true => format!("{}.{}", key, field),
+        false => format!("{}.{}", HTTP_HEADERS_KEY, field),
+    };
+    let nonce_paths = headers
+        .content_security_policy_inject_nonce_paths
+        .as_ref()
+        .is_some_and(|paths| !paths.is_empty());
+    let policies: Vec<_> = profile.content_security_policies().collect();
+    if policies.is_empty() && nonce_paths {
+        report.warning(
+            key_of(
+                "content_security_policy_inject_nonce_paths",
+                configured
+                    .content_security_policy_inject_nonce_paths
+                    .is_some(),
+            ),
+            "has no effect without content_security_policy",
+        );
+    } else if !nonce_paths && policies.iter().any(|csp| csp.has_nonce_placeholder()) {
+        report.warning(
+            key_of(
+                "content_security_policy_inject_nonce_paths",
+                configured
+                    .content_security_policy_inject_nonce_paths
+                    .is_some(),
+            ),
+            "is required for the placeholder 'nonce', which is removed from content_security_policy otherwise",
+        );
+    }
+    for csp in policies.iter().filter(|_| nonce_paths) {
+        for nonce_header in headers
+            .content_security_policy_nonce_headers
+            .iter()
+            .flatten()
+        {
+            if !csp.has_directive(nonce_header) {
+                report.warning(
+                    key_of(
+                        "content_security_policy_nonce_headers",
+                        configured.content_security_policy_nonce_headers.is_some(),
+                    ),
+                    format!(
+                        "directive {} is not in {}, so elements with the nonce are blocked",
+                        nonce_header,
+                        csp.header_name()
+                    ),
+                );
+            }
+        }
+    }
+    if !nonce_paths
+        && configured
+            .content_security_policy_inject_nonce_tags
+            .is_some()
+    {
+        report.warning(
+            format!("{}.content_security_policy_inject_nonce_tags", key),
+            "has no effect without content_security_policy_inject_nonce_paths",
+        );
+    }
+}
+
+/// Validates the OIDC configuration and the modules that depend on it without contacting the IdP
+fn validate_oidc(config: &Config, report: &mut ConfigReport) {
+    let app = &config.app;
+    let oidc = &app.oidc;
+    match (&oidc.provider_metadata_file, &oidc.issuer_url) {
+        (None, None) => report.error(
+            "app.oidc.issuer_url",
+            "required unless provider_metadata_file is configured",
+        ),
+        (None, Some(issuer_url)) => validate_url("app.oidc.issuer_url", issuer_url, report),
+        (Some(provider_metadata_file), _) => {
+            if !Path::new(provider_metadata_file).is_file() {
+                report.error(
+                    "app.oidc.provider_metadata_file",
+                    format!("file {} does not exist", provider_metadata_file),
+                );
+            }
+        }
+    }
+    match &oidc.redirect_url {
+        Some(redirect_url) => validate_url("app.oidc.redirect_url", redirect_url, report),
+        None => report.error("app.oidc.redirect_url", "required"),
+    }
+    if oidc.client_id.as_deref().is_none_or(str::is_empty) {
+        report.error("app.oidc.client_id", "required");
+    }
+    match (&oidc.client_secret, &oidc.client_secret_file) {
+        (None, None) => report.error(
+            "app.oidc.client_secret",
+            "required unless client_secret_file is configured",
+        ),
+        (_, Some(client_secret_file)) => {
+            if let Err(err) = SecretFile::new(None, Some(client_secret_file)) {
+                report.error(
+                    "app.oidc.client_secret_file",
+                    format!("cannot read {}: {}", client_secret_file, err),
+                );
+            }
+        }
+        (Some(_), None) => (),
+    }
+    report.check("app.oidc", IdpHttpClient::new(oidc), |err| {
+        idp_http_client_error(oidc, err)
+    });
+    report.check("app.cookies", OidcCookies::new(&app.cookies), |err| {
+        cookies_error(&app.cookies, err)
+    });
+    report.check("app.keyring", CookieKeyRing::new(&app.keyring), |err| {
+        keyring_error(&app.keyring, err)
+    });
+    if app.dpop.public_url.is_some() {
+        let dpop = &app.dpop;
+        let verifier = DpopVerifier::new(dpop, app.oidc.client_id.as_deref());
+        report.check("app.dpop", verifier, |err| match err {
+            DpopConfigError::InvalidPublicUrl(public_url) => (
+                "public_url".to_string(),
+                format!("invalid URL {} (without query and fragment)", public_url),
+            ),
+            DpopConfigError::InvalidPath(path) => (
+                key_of(
+                    "paths",
+                    dpop.paths.as_deref().unwrap_or_default(),
+                    |value| *value == path,
+                )
+                .unwrap_or_default(),
+                format!("path {} must start with /", path),
+            ),
+            DpopConfigError::MissingAudience => (
+                "audience".to_string(),
+                "required without app.oidc.client_id".to_string(),
+            ),
+        });
+    }
+    if app.token_exchange.audiences.is_some() {
+        report.check(
+            "app.token_exchange",
+            TokenExchange::new(&app.token_exchange),
+            |err| (String::new(), format!("{:?}", err)),
+        );
+    }
+}
+
+/// Adds an error if a value is not an absolute URL
+fn validate_url(key: &str, url: &str, report: &mut ConfigReport) {
+    if let Err(err) = Url::parse(url) {
+        report.error(key, format!("invalid URL {}: {}", url, err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::providers::Serialized;
+    use rocket::serde::json::json;
+
+    use super::*;
+    use crate::configuration::config::{
+        CustomAppHttpHeadersProfileConfig, CustomAppIpAccessConfig, CustomAppOidcConfig,
+    };
+    use crate::configuration::secrets::Secret;
+
+    #[test]
+    fn collects_all_errors_and_warnings_with_key_paths() {
+        let mut config = Config {
+            app: Default::default(),
+        };
+        config.app.fileserver.location = "/nonexistent-static-files".to_string();
+        config.app.oidc = CustomAppOidcConfig {
+            issuer_url: Some("not a url".to_string()),
+            redirect_url: Some("https://app.example.com/oidc/redirect".to_string()),
+            client_secret: Some(Secret::new("secret".to_string())),
+            ..Default::default()
+        };
+        config.app.httpheaders = CustomAppHttpHeadersConfig {
+            headers: CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("default-src 'self'".to_string()),
+                content_security_policy_inject_nonce_paths: Some(vec!["^/$".to_string()]),
+                content_security_policy_inject_nonce_tags: Some(vec!["script".to_string()]),
+                content_security_policy_nonce_headers: Some(vec!["script-src".to_string()]),
+                ..Default::default()
+            },
+            profiles: Some(vec![
+                CustomAppHttpHeadersProfileConfig {
+                    name: "api".to_string(),
+                    paths: Some(vec!["^/ui-api/(".to_string()]),
+                    ..Default::default()
+                },
+                CustomAppHttpHeadersProfileConfig {
+                    name: "export".to_string(),
+                    paths: None,
+                    content_types: Some(vec!["text/csv".to_string()]),
+                    headers: CustomAppHttpHeaderValuesConfig {
+                        content_security_policy_inject_nonce_paths: Some(vec![
+                            "^/export/[".to_string(),
+                        ]),
+                        ..Default::default()
+                    },
+                },
+            ]),
+        };
+        config.app.cors.policies = Some(vec![
+            Default::default(),
+            CustomAppCorsPolicyConfig {
+                paths: vec!["/ui-api".to_string()],
+                origins: Some(vec!["*".to_string()]),
+                allow_credentials: Some(true),
+                ..Default::default()
+            },
+        ]);
+        config.app.firewall.rules = Some(vec![
+            CustomAppFirewallRuleConfig {
+                name: "scanner".to_string(),
+                user_agents: Some(vec!["(?i)sqlmap".to_string()]),
+                ..Default::default()
+            },
+            CustomAppFirewallRuleConfig {
+                name: "traversal".to_string(),
+                paths: Some(vec!["%2e%2e".to_string(), "[".to_string()]),
+                ..Default::default()
+            },
+        ]);
+        config.app.firewall.ip_access = Some(vec![CustomAppIpAccessConfig {
+            allow: Some(vec!["10.0.0.0/8".to_string(), "10.0.0.0/33".to_string()]),
+            ..Default::default()
+        }]);
+        config.app.csrf = CustomAppCsrfConfig {
+            origins: Some(vec!["https://app.example.com".to_string()]),
+            exempt_paths: Some(vec!["webhooks".to_string()]),
+            ..Default::default()
+        };
+
+        let report = validate(&config, &Figment::new());
+        let findings: Vec<String> = report
+            .findings
+            .iter()
+            .map(|finding| format!("{:?} {}", finding.severity, finding.key))
+            .collect();
+        for expected in [
+            "Error app.httpheaders.profiles[0].paths[0]",
+            "Error app.httpheaders.profiles[1].content_security_policy_inject_nonce_paths[0]",
+            "Warning app.httpheaders.content_security_policy_nonce_headers",
+            "Error app.oidc.issuer_url",
+            "Error app.oidc.client_id",
+            "Warning app.fileserver.location",
+            "Error app.cors.policies[0].paths",
+            "Error app.cors.policies[1].allow_credentials",
+            "Error app.firewall.rules[1].paths[1]",
+            "Error app.firewall.ip_access[0].allow[1]",
+            "Error app.csrf.exempt_paths[0]",
+        ] {
+            assert!(
+                findings.contains(&expected.to_string()),
+                "{} not in {:?}",
+                expected,
+                findings
+            );
+        }
+        assert!(report.has_errors());
+        assert_eq!(report.count(FindingSeverity::Warning), 2);
+
+        // missing sections are reported with the message of Rocket
+        let report = check_config(&Figment::from(Serialized::defaults(
+            json!({"app": {"httpheaders": {}}}),
+        )));
+        assert!(report.has_errors());
+        assert!(report.findings[0].message.contains("oidc"));
+    }
+}
@@ -1,9 +1,19 @@
 //! Manage database and create/update schemas
 
+use std::io;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
 use rocket::fairing::{self};
 use rocket::{Build, Rocket};
 use rocket_db_pools::Database;
-use rocket_db_pools::sqlx::{self};
+use rocket_db_pools::sqlx::sqlite::SqliteConnectOptions;
+use rocket_db_pools::sqlx::{self, ConnectOptions};
+use tracing::{Level, event};
+
+use crate::configuration::reload::Reloadable;
+use crate::configuration::secrets::SecretFile;
 
 #[derive(Database)]
 #[database("warehouse")]
@@ -11,6 +21,84 @@ pub struct Db(sqlx::SqlitePool);
 
 pub type Result<T, E = rocket::response::Debug<sqlx::Error>> = std::result::Result<T, E>;
 
+// Database URL read from a file (e.g. a Kubernetes secret). When the file changes, new connections of the pool use the new URL (e.g. rotated credentials), open connections are kept until the pool closes them
+pub struct DatabaseUrl {
+    file: SecretFile,
+    // pool and its connect timeout in seconds, set after the pool is created
+    pool: OnceLock<(sqlx::SqlitePool, u64)>,
+}
+
+impl DatabaseUrl {
+    /// Creates the database URL
+    ///
+    /// # Arguments
+    /// * `path` - File containing the URL
+    ///
+    /// # Returns
+    /// Database URL or an error if the file cannot be read
+    ///
+    pub fn new(path: &str) -> io::Result<DatabaseUrl> {
+        Ok(DatabaseUrl {
+            file: SecretFile::new(None, Some(path))?,
+            pool: OnceLock::new(),
+        })
+    }
+
+    /// Sets the pool whose connections use the URL. It must be called after the pool was created (ie after the ignite fairing of Db)
+    ///
+    /// # Arguments
+    /// * `rocket` - Rocket instance with the pool
+    ///
+    pub fn set_pool(&self, rocket: &Rocket<Build>) {
+        let Some(db) = Db::fetch(rocket) else {
+            event!(
+                Level::WARN,
+                "Database pool is not available, the database URL is not reloaded"
+            );
+            return;
+        };
+        let connect_timeout = rocket
+            .figment()
+            .extract_inner::<rocket_db_pools::Config>("databases.warehouse")
+            .map(|config| config.connect_timeout)
+            .unwrap_or(5);
+        let _ = self.pool.set(((**db).clone(), connect_timeout));
+    }
+}
+
+impl Reloadable for DatabaseUrl {
+    fn name(&self) -> String {
+        format!("database url of {}", self.file.name())
+    }
+
+    fn reload(&self, force: bool) -> io::Result<bool> {
+        // the URL is parsed before it replaces the current one. The error is not passed on, because it may contain the credentials
+        let mut connect_options = None;
+        let changed = self.file.reload_validated(force, |url| {
+            let options = SqliteConnectOptions::from_str(url.expose())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid database URL"))?;
+            connect_options = Some(options);
+            Ok(())
+        })?;
+        if !changed {
+            return Ok(false);
+        }
+        let (Some(connect_options), Some((pool, connect_timeout))) =
+            (connect_options, self.pool.get())
+        else {
+            return Ok(true);
+        };
+        // the same options as rocket_db_pools uses when it creates the pool
+        pool.set_connect_options(
+            connect_options
+                .busy_timeout(Duration::from_secs(*connect_timeout))
+                .create_if_missing(true)
+                .disable_statement_logging(),
+        );
+        Ok(true)
+    }
+}
+
 /// Create/update schemas in database
 ///
 /// # Arguments
@@ -31,3 +119,45 @@ pub async fn run_migrations(rocket: Rocket<Build>) -> fairing::Result {
         None => Err(rocket),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn new_connections_use_reloaded_url() {
+        let dir = std::env::temp_dir().join(format!("database-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let url_file = dir.join("url");
+        let url = |name: &str| format!("sqlite://{}", dir.join(name).display());
+        std::fs::write(&url_file, url("first.db") + "\n").unwrap();
+        let pool = sqlx::SqlitePool::connect_with(
+            SqliteConnectOptions::from_str(&url("first.db"))
+                .unwrap()
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        let database_url = DatabaseUrl::new(url_file.to_str().unwrap()).unwrap();
+        database_url.pool.set((pool.clone(), 5)).unwrap();
+        assert!(!database_url.reload(false).unwrap());
+
+        std::fs::write(&url_file, url("second.db")).unwrap();
+        assert!(database_url.reload(true).unwrap());
+        assert_eq!(
+            (*pool.connect_options()).clone().get_filename(),
+            dir.join("second.db")
+        );
+        std::fs::write(&url_file, url("third.db?mode=invalid")).unwrap();
+        assert!(database_url.reload(true).is_err());
+        // the invalid URL is reported on every check, not only once
+        assert!(database_url.reload(false).is_err());
+        assert_eq!(database_url.file.value().expose(), url("second.db"));
+        assert_eq!(
+            (*pool.connect_options()).clone().get_filename(),
+            dir.join("second.db")
+        );
+        pool.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! IP address of the client behind trusted reverse proxies and IP allow/deny lists with CIDR ranges
+
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+use rocket::Request;
+
+use crate::configuration::config::CustomAppIpAccessConfig;
+use crate::oidc::guard::path_in;
+
+// Derives the IP address of the client from the headers X-Forwarded-For or Forwarded, if the request comes from a trusted reverse proxy
+#[derive(Debug)]
+pub struct ClientIpResolver {
+    trusted_proxies: Vec<IpNet>,
+}
+
+// Allow and deny lists of IP addresses for all paths or for the configured path prefixes
+#[derive(Debug)]
+pub struct IpAccessList {
+    paths: Option<Vec<String>>,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl ClientIpResolver {
+    /// Creates the resolver
+    ///
+    /// # Arguments
+    /// * `trusted_proxies` - CIDR ranges or IP addresses of the trusted reverse proxies
+    ///
+    /// # Returns
+    /// Resolver or the invalid network
+    ///
+    pub fn new(trusted_proxies: &[String]) -> Result<ClientIpResolver, String> {
+        Ok(ClientIpResolver {
+            trusted_proxies: parse_networks(trusted_proxies)?,
+        })
+    }
+
+    /// Derives the IP address of the client. Starting with the peer of the connection, the addresses of the forwarding headers are walked from right to left as long as they are trusted proxies
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    /// # Returns
+    /// IP address of the client or None if the peer of the connection is unknown
+    ///
+    pub fn resolve(&self, req: &Request<'_>) -> Option<IpAddr> {
+        let mut client = req.remote()?.ip().to_canonical();
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+        for hop in forwarded_for(req).iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = *ip;
+                    if !self.is_trusted(client) {
+                        break;
+                    }
+                }
+                // obfuscated or invalid addresses cannot be traced further
+                None => break,
+            }
+        }
+        Some(client)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(&ip))
+    }
+}
+
+impl IpAccessList {
+    /// Creates the access list
+    ///
+    /// # Arguments
+    /// * `config` - Configuration of the access list
+    ///
+    /// # Returns
+    /// Access list or the invalid network
+    ///
+    pub fn new(config: &CustomAppIpAccessConfig) -> Result<IpAccessList, String> {
+        Ok(IpAccessList {
+            paths: config.paths.clone(),
+            allow: parse_networks(config.allow.as_deref().unwrap_or_default())?,
+            deny: parse_networks(config.deny.as_deref().unwrap_or_default())?,
+        })
+    }
+
+    /// Checks if a client may access a path. The deny list takes precedence over the allow list. An empty allow list allows all addresses that are not denied
+    ///
+    /// # Arguments
+    /// * `ip` - IP address of the client (None if it is unknown)
+    /// * `path` - Path of the request
+    ///
+    /// # Returns
+    /// true if the access list does not cover the path or the client is allowed
+    ///
+    pub fn permits(&self, ip: Option<IpAddr>, path: &str) -> bool {
+        if let Some(paths) = &self.paths
+            && !path_in(paths, path)
+        {
+            return true;
+        }
+        match ip {
+            Some(ip) => {
+                !self.deny.iter().any(|network| network.contains(&ip))
+                    && (self.allow.is_empty()
+                        || self.allow.iter().any(|network| network.contains(&ip)))
+            }
+            None => self.allow.is_empty() && self.deny.is_empty(),
+        }
+    }
+}
+
+/// Parses CIDR ranges (e.g. 10.0.0.0/8) and single IP addresses
+///
+/// # Arguments
+/// * `networks` - CIDR ranges or IP addresses
+///
+/// # Returns
+/// Networks or the first invalid one
+///
+fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>, String> {
+    networks
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| network.clone())
+        })
+        .collect()
+}
+
+/// Reads the addresses of the clients and proxies that forwarded the request, from the header Forwarded (RFC 7239) or, if it is not present, X-Forwarded-For
+///
+/// # Arguments
+/// * `req` - Request object
+///
+/// # Returns
+/// Addresses from the client to the last proxy. None for obfuscated or invalid addresses
+///
+fn forwarded_for(req: &Request<'_>) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = req.headers().get("Forwarded").collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|header| header.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    req.headers()
+        .get("X-Forwarded-For")
+        .flat_map(|header| header.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses an address of a forwarding header, e.g. 192.0.2.60, 192.0.2.60:4711 or "[2001:db8::17]:4711"
+///
+/// # Arguments
+/// * `node` - Address of the header
+///
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(node) = node.strip_prefix('[') {
+        return node.split(']').next()?.parse::<IpAddr>().ok();
+    }
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|address| address.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Header, Status};
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::configuration::config::CustomAppFirewallConfig;
+    use crate::httpfirewall::requestfilter::{RequestFilter, blocked};
+
+    #[get("/<_path..>")]
+    fn client(_path: std::path::PathBuf, ip: IpAddr) -> String {
+        ip.to_string()
+    }
+
+    #[test]
+    fn uses_client_ip_behind_trusted_proxies_for_access_lists() {
+        let request_filter = RequestFilter::new(&CustomAppFirewallConfig {
+            trusted_proxies: Some(vec!["10.0.0.0/8".to_string(), "2001:db8::1".to_string()]),
+            ip_access: Some(vec![
+                CustomAppIpAccessConfig {
+                    deny: Some(vec!["198.51.100.0/24".to_string()]),
+                    ..Default::default()
+                },
+                CustomAppIpAccessConfig {
+                    paths: Some(vec!["/ui".to_string()]),
+                    allow: Some(vec!["192.0.2.0/24".to_string()]),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        let rocket = rocket::build()
+            .attach(request_filter)
+            .mount("/", routes![client])
+            .mount("/httpfirewall", routes![blocked]);
+        let client = Client::tracked(rocket).unwrap();
+        let get = |path: &'static str, remote: &str, header: Option<Header<'static>>| {
+            let mut request = client.get(path).remote(remote.parse().unwrap());
+            if let Some(header) = header {
+                request = request.header(header);
+            }
+            let response = request.dispatch();
+            (
+                response.status(),
+                response.into_string().unwrap_or_default(),
+            )
+        };
+
+        // spoofed headers of untrusted clients are ignored
+        let spoofed = get(
+            "/api",
+            "203.0.113.7:50000",
+            Some(Header::new("X-Forwarded-For", "192.0.2.1")),
+        );
+        assert_eq!(spoofed, (Status::Ok, "203.0.113.7".to_string()));
+        let spoofed = get(
+            "/api",
+            "203.0.113.7:50000",
+            Some(Header::new("X-Real-IP", "192.0.2.1")),
+        );
+        assert_eq!(spoofed, (Status::Ok, "203.0.113.7".to_string()));
+        let forwarded = get(
+            "/ui/inventory",
+            "10.0.0.2:50000",
+            Some(Header::new(
+                "X-Forwarded-For",
+                "203.0.113.7, 192.0.2.1, 10.1.1.1",
+            )),
+        );
+        assert_eq!(forwarded, (Status::Ok, "192.0.2.1".to_string()));
+        let forwarded = get(
+            "/api",
+            "[2001:db8::1]:50000",
+            Some(Header::new(
+                "Forwarded",
+                "for=192.0.2.60;proto=https, for=\"[2001:db8:cafe::17]:4711\"",
+            )),
+        );
+        assert_eq!(forwarded, (Status::Ok, "2001:db8:cafe::17".to_string()));
+
+        let outside_site = get(
+            "/ui/inventory",
+            "10.0.0.2:50000",
+            Some(Header::new("X-Forwarded-For", "203.0.113.7")),
+        );
+        assert_eq!(outside_site.0, Status::Forbidden);
+        assert_eq!(get("/api", "198.51.100.9:50000", None).0, Status::Forbidden);
+        assert_eq!(get("/uiother", "203.0.113.7:50000", None).0, Status::Ok);
+    }
+
+    #[test]
+    fn ignores_ip_header_for_access_lists_without_trusted_proxies() {
+        let request_filter = RequestFilter::new(&CustomAppFirewallConfig {
+            ip_access: Some(vec![CustomAppIpAccessConfig {
+                paths: Some(vec!["/admin".to_string()]),
+                allow: Some(vec!["192.0.2.0/24".to_string()]),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+        .unwrap();
+        let rocket = rocket::build()
+            .attach(request_filter)
+            .mount("/", routes![client])
+            .mount("/httpfirewall", routes![blocked]);
+        let client = Client::tracked(rocket).unwrap();
+
+        let forged = client
+            .get("/admin")
+            .remote("203.0.113.7:50000".parse().unwrap())
+            .header(Header::new("X-Real-IP", "192.0.2.1"))
+            .dispatch();
+        assert_eq!(forged.status(), Status::Forbidden);
+        // the guard of the route (Rocket's client_ip) gets the peer as well
+        let forged = client
+            .get("/api")
+            .remote("203.0.113.7:50000".parse().unwrap())
+            .header(Header::new("X-Real-IP", "192.0.2.1"))
+            .dispatch();
+        assert_eq!(forged.status(), Status::Ok);
+        assert_eq!(forged.into_string().unwrap(), "203.0.113.7");
+        let allowed = client
+            .get("/admin")
+            .remote("192.0.2.1:50000".parse().unwrap())
+            .dispatch();
+        assert_eq!(allowed.status(), Status::Ok);
+    }
+}
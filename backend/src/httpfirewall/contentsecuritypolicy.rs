@@ -0,0 +1,470 @@
+//! Content-Security-Policy modelled as directives with their source lists
+//!
+//! A policy is either parsed from the configured header value or built from a named preset with additional directives and Trusted Types policies. A second policy can be sent as Content-Security-Policy-Report-Only alongside, e.g. to trial a stricter policy while the current one is enforced. Nonces and hashes are inserted into the source lists of single directives, e.g. into script-src but not into script-src-elem
+//! The presets follow the strict CSP recommended by Google (https://csp.withgoogle.com/docs/strict-csp.html): scripts are only allowed with the nonce of the response and scripts loaded by them ('strict-dynamic')
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::configuration::config::CustomAppHttpHeaderValuesConfig;
+
+/// Placeholder in a source list that is replaced by the nonce of the response. It is removed on responses without a nonce
+pub const NONCE_PLACEHOLDER: &str = "'nonce'";
+const TRUSTED_TYPES: &str = "trusted-types";
+const REQUIRE_TRUSTED_TYPES_FOR: &str = "require-trusted-types-for";
+
+// Strict CSP with nonces, 'strict-dynamic' and Trusted Types for browsers supporting CSP level 3
+const STRICT_PRESET: [(&str, &[&str]); 4] = [
+    ("script-src", &[NONCE_PLACEHOLDER, "'strict-dynamic'"]),
+    ("object-src", &["'none'"]),
+    ("base-uri", &["'none'"]),
+    (REQUIRE_TRUSTED_TYPES_FOR, &["'script'"]),
+];
+// Strict CSP with fallbacks that browsers supporting CSP level 3 ignore ('unsafe-inline' and https: are ignored if a nonce and 'strict-dynamic' are present)
+const COMPATIBLE_PRESET: [(&str, &[&str]); 3] = [
+    (
+        "script-src",
+        &[
+            NONCE_PLACEHOLDER,
+            "'strict-dynamic'",
+            "https:",
+            "'unsafe-inline'",
+        ],
+    ),
+    ("object-src", &["'none'"]),
+    ("base-uri", &["'none'"]),
+];
+
+// Errors in the Content-Security-Policy configuration
+#[derive(Debug)]
+pub enum CspConfigError {
+    UnknownPreset(String),
+    PresetWithPolicy(String),
+    InvalidDirective(String),
+    InvalidSource(String, String),
+    InvalidTrustedTypesPolicy(String),
+    // error in the separate report-only policy
+    ReportOnly(Box<CspConfigError>),
+    DuplicateReportOnly,
+}
+
+// Content-Security-Policy as ordered list of directives and their sources
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(String, Vec<String>)>,
+    // sent as Content-Security-Policy-Report-Only, so that violations are reported, but not blocked
+    pub report_only: bool,
+}
+
+impl CspConfigError {
+    /// Returns the key of the configuration that is invalid, e.g. content_security_policy_directives
+    pub fn field(&self) -> &'static str {
+        match self {
+            CspConfigError::UnknownPreset(_) | CspConfigError::PresetWithPolicy(_) => {
+                "content_security_policy_preset"
+            }
+            CspConfigError::InvalidDirective(_) | CspConfigError::InvalidSource(..) => {
+                "content_security_policy_directives"
+            }
+            CspConfigError::InvalidTrustedTypesPolicy(_) => "content_security_policy_trusted_types",
+            CspConfigError::ReportOnly(err) => match **err {
+                CspConfigError::UnknownPreset(_) => "content_security_policy_report_only_preset",
+                _ => "content_security_policy_report_only_directives",
+            },
+            CspConfigError::DuplicateReportOnly => "content_security_policy_report_only_preset",
+        }
+    }
+}
+
+impl ContentSecurityPolicy {
+    /// Parses a Content-Security-Policy header value, e.g. "default-src 'self'; script-src 'self'"
+    ///
+    /// # Arguments
+    /// * `policy` - Value of the header
+    ///
+    pub fn parse(policy: &str) -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            directives: policy
+                .split(';')
+                .filter_map(|directive| {
+                    let mut tokens = directive.split_whitespace();
+                    let name = tokens.next()?.to_string();
+                    Some((name, tokens.map(str::to_string).collect()))
+                })
+                .collect(),
+            report_only: false,
+        }
+    }
+
+    /// Creates the policy of resolved header values. A preset or the configured header value is the base, to which the configured directives are applied: directives replace those of the base and directives without sources remove them
+    ///
+    /// # Arguments
+    /// * `headers` - Resolved header values of a header profile
+    ///
+    /// # Returns
+    /// Policy, None if no policy is configured or an error if the preset, a directive or a Trusted Types policy is invalid
+    ///
+    pub fn from_config(
+        headers: &CustomAppHttpHeaderValuesConfig,
+    ) -> Result<Option<ContentSecurityPolicy>, CspConfigError> {
+        let mut policy = match (
+            &headers.content_security_policy_preset,
+            &headers.content_security_policy,
+        ) {
+            (Some(preset), Some(_)) => {
+                return Err(CspConfigError::PresetWithPolicy(preset.clone()));
+            }
+            (Some(preset), None) => ContentSecurityPolicy::preset(preset)?,
+            (None, Some(policy)) => ContentSecurityPolicy::parse(policy),
+            (None, None) if headers.content_security_policy_directives.is_some() => {
+                ContentSecurityPolicy::parse("")
+            }
+            (None, None) => return Ok(None),
+        };
+        policy.set_directives(&headers.content_security_policy_directives)?;
+        if let Some(trusted_types) = &headers.content_security_policy_trusted_types {
+            if let Some(name) = trusted_types.iter().find(|name| {
+                name.is_empty()
+                    || !name.chars().all(|character| {
+                        character.is_ascii_alphanumeric() || "-#=_/@.%'*".contains(character)
+                    })
+            }) {
+                return Err(CspConfigError::InvalidTrustedTypesPolicy(name.clone()));
+            }
+            policy.set_directive(TRUSTED_TYPES, trusted_types.clone());
+        }
+        if let Some(report_only) = headers.content_security_policy_report_only {
+            policy.report_only = report_only;
+        }
+        Ok(Some(policy))
+    }
+
+    /// Creates the separate report-only policy of resolved header values from its preset and directives, which are applied like the directives of the policy
+    ///
+    /// # Arguments
+    /// * `headers` - Resolved header values of a header profile
+    ///
+    /// # Returns
+    /// Report-only policy, None if it is not configured or an error if the preset or a directive is invalid
+    ///
+    pub fn report_only_from_config(
+        headers: &CustomAppHttpHeaderValuesConfig,
+    ) -> Result<Option<ContentSecurityPolicy>, CspConfigError> {
+        let directives = &headers.content_security_policy_report_only_directives;
+        let mut policy = match (
+            &headers.content_security_policy_report_only_preset,
+            directives,
+        ) {
+            (Some(preset), _) => ContentSecurityPolicy::preset(preset),
+            (None, Some(_)) => Ok(ContentSecurityPolicy::parse("")),
+            (None, None) => return Ok(None),
+        }
+        .map_err(|err| CspConfigError::ReportOnly(Box::new(err)))?;
+        policy
+            .set_directives(directives)
+            .map_err(|err| CspConfigError::ReportOnly(Box::new(err)))?;
+        policy.report_only = true;
+        Ok(Some(policy))
+    }
+
+    /// Validates configured directives and applies them with set_directive
+    fn set_directives(
+        &mut self,
+        directives: &Option<HashMap<String, Vec<String>>>,
+    ) -> Result<(), CspConfigError> {
+        let mut directives: Vec<(&String, &Vec<String>)> = directives.iter().flatten().collect();
+        // the order of the configuration is not preserved, so added directives are sorted
+        directives.sort();
+        for (name, sources) in directives {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|character| character.is_ascii_lowercase() || character == '-')
+            {
+                return Err(CspConfigError::InvalidDirective(name.clone()));
+            }
+            if let Some(source) = sources.iter().find(|source| {
+                source.is_empty()
+                    || source.contains(|character: char| {
+                        character == ';' || character == ',' || character.is_whitespace()
+                    })
+            }) {
+                return Err(CspConfigError::InvalidSource(name.clone(), source.clone()));
+            }
+            self.set_directive(name, sources.clone());
+        }
+        Ok(())
+    }
+
+    /// Returns the policy of a preset: strict, compatible or report-only (strict, but only reported)
+    fn preset(name: &str) -> Result<ContentSecurityPolicy, CspConfigError> {
+        let (directives, report_only): (&[(&str, &[&str])], bool) = match name {
+            "strict" => (&STRICT_PRESET, false),
+            "compatible" => (&COMPATIBLE_PRESET, false),
+            "report-only" => (&STRICT_PRESET, true),
+            _ => return Err(CspConfigError::UnknownPreset(name.to_string())),
+        };
+        Ok(ContentSecurityPolicy {
+            directives: directives
+                .iter()
+                .map(|(name, sources)| {
+                    (
+                        name.to_string(),
+                        sources.iter().map(|source| source.to_string()).collect(),
+                    )
+                })
+                .collect(),
+            report_only,
+        })
+    }
+
+    /// Replaces the sources of a directive or adds it at the end. A directive without sources is removed, unless it is a directive that has no sources (e.g. upgrade-insecure-requests)
+    fn set_directive(&mut self, name: &str, sources: Vec<String>) {
+        let position = self
+            .directives
+            .iter()
+            .position(|(directive, _)| directive.eq_ignore_ascii_case(name));
+        match (
+            position,
+            sources.is_empty() && name != "upgrade-insecure-requests",
+        ) {
+            (Some(position), true) => {
+                self.directives.remove(position);
+            }
+            (Some(position), false) => self.directives[position].1 = sources,
+            (None, true) => (),
+            (None, false) => self.directives.push((name.to_string(), sources)),
+        }
+    }
+
+    /// Returns the name of the header: Content-Security-Policy or Content-Security-Policy-Report-Only
+    pub fn header_name(&self) -> &'static str {
+        match self.report_only {
+            true => super::securityhttpheaders::CONTENT_SECURITY_POLICY_REPORT_ONLY,
+            false => super::securityhttpheaders::CONTENT_SECURITY_POLICY,
+        }
+    }
+
+    /// Returns true if the policy has a directive, e.g. script-src
+    pub fn has_directive(&self, name: &str) -> bool {
+        self.directives
+            .iter()
+            .any(|(directive, _)| directive.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns true if a directive has the nonce placeholder, so that responses need a nonce
+    pub fn has_nonce_placeholder(&self) -> bool {
+        self.directives
+            .iter()
+            .any(|(_, sources)| sources.iter().any(|source| source == NONCE_PLACEHOLDER))
+    }
+
+    /// Adds the directive if the policy has none of the same name
+    ///
+    /// # Arguments
+    /// * `name` - Name of the directive
+    /// * `sources` - Sources of the directive
+    ///
+    pub fn add_directive_if_absent(&mut self, name: &str, sources: &[&str]) {
+        if !self.has_directive(name) {
+            self.directives.push((
+                name.to_string(),
+                sources.iter().map(|source| source.to_string()).collect(),
+            ));
+        }
+    }
+
+    /// Appends sources (e.g. hashes of inline scripts) to a directive. Directives that are not in the policy are not added, because they would replace default-src
+    ///
+    /// # Arguments
+    /// * `name` - Name of the directive
+    /// * `sources` - Sources to append
+    ///
+    pub fn add_sources(&mut self, name: &str, sources: &[String]) {
+        for (_, directive_sources) in self
+            .directives
+            .iter_mut()
+            .filter(|(directive, _)| directive.eq_ignore_ascii_case(name))
+        {
+            directive_sources.extend(sources.iter().cloned());
+        }
+    }
+
+    /// Inserts a nonce into the directives with the nonce placeholder (at its position) and into the directives configured for nonces (as first source)
+    ///
+    /// # Arguments
+    /// * `nonce` - Nonce of the response
+    /// * `nonce_directives` - Directives configured for nonces, e.g. script-src
+    ///
+    pub fn insert_nonce(&mut self, nonce: &str, nonce_directives: &[String]) {
+        let nonce_source = format!("'nonce-{}'", nonce);
+        for (name, sources) in self.directives.iter_mut() {
+            let mut has_nonce = false;
+            for source in sources
+                .iter_mut()
+                .filter(|source| *source == NONCE_PLACEHOLDER)
+            {
+                *source = nonce_source.clone();
+                has_nonce = true;
+            }
+            if !has_nonce
+                && nonce_directives
+                    .iter()
+                    .any(|directive| directive.eq_ignore_ascii_case(name))
+            {
+                sources.insert(0, nonce_source.clone());
+            }
+        }
+    }
+}
+
+impl fmt::Display for ContentSecurityPolicy {
+    /// Formats the policy as header value. Nonce placeholders that were not replaced are left out
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directives: Vec<String> = self
+            .directives
+            .iter()
+            .map(|(name, sources)| {
+                sources
+                    .iter()
+                    .filter(|source| *source != NONCE_PLACEHOLDER)
+                    .fold(name.clone(), |directive, source| {
+                        format!("{} {}", directive, source)
+                    })
+            })
+            .collect();
+        f.write_str(&directives.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn builds_presets_with_directives_and_nonces() {
+        let headers = CustomAppHttpHeaderValuesConfig {
+            content_security_policy_preset: Some("strict".to_string()),
+            content_security_policy_directives: Some(HashMap::from([
+                ("base-uri".to_string(), vec!["'self'".to_string()]),
+                (
+                    "style-src".to_string(),
+                    vec!["'self'".to_string(), NONCE_PLACEHOLDER.to_string()],
+                ),
+                ("object-src".to_string(), vec![]),
+            ])),
+            content_security_policy_trusted_types: Some(vec![
+                "default".to_string(),
+                "svelte-trusted-html".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let mut policy = ContentSecurityPolicy::from_config(&headers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            policy.to_string(),
+            "script-src 'strict-dynamic'; base-uri 'self'; require-trusted-types-for 'script'; style-src 'self'; trusted-types default svelte-trusted-html"
+        );
+        assert_eq!(policy.header_name(), "Content-Security-Policy");
+        policy.insert_nonce("abc", &[]);
+        policy.add_sources("script-src", &["'sha384-x'".to_string()]);
+        assert_eq!(
+            policy.to_string(),
+            "script-src 'nonce-abc' 'strict-dynamic' 'sha384-x'; base-uri 'self'; require-trusted-types-for 'script'; style-src 'self' 'nonce-abc'; trusted-types default svelte-trusted-html"
+        );
+
+        let policy = ContentSecurityPolicy::from_config(&CustomAppHttpHeaderValuesConfig {
+            content_security_policy_preset: Some("report-only".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(policy.header_name(), "Content-Security-Policy-Report-Only");
+        let report_only = CustomAppHttpHeaderValuesConfig {
+            content_security_policy: Some("default-src 'self'".to_string()),
+            content_security_policy_report_only_preset: Some("strict".to_string()),
+            content_security_policy_report_only_directives: Some(HashMap::from([(
+                "require-trusted-types-for".to_string(),
+                vec![],
+            )])),
+            ..Default::default()
+        };
+        let policy = ContentSecurityPolicy::report_only_from_config(&report_only)
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.header_name(), "Content-Security-Policy-Report-Only");
+        assert_eq!(
+            policy.to_string(),
+            "script-src 'strict-dynamic'; object-src 'none'; base-uri 'none'"
+        );
+        assert!(
+            !ContentSecurityPolicy::from_config(&report_only)
+                .unwrap()
+                .unwrap()
+                .report_only
+        );
+        assert_eq!(
+            ContentSecurityPolicy::report_only_from_config(&CustomAppHttpHeaderValuesConfig {
+                content_security_policy_report_only_preset: Some("lenient".to_string()),
+                ..Default::default()
+            })
+            .unwrap_err()
+            .field(),
+            "content_security_policy_report_only_preset"
+        );
+        let mut policy = ContentSecurityPolicy::parse(
+            "default-src 'self'; script-src-elem 'self'; script-src 'self'; upgrade-insecure-requests;",
+        );
+        policy.insert_nonce("abc", &["script-src".to_string()]);
+        assert_eq!(
+            policy.to_string(),
+            "default-src 'self'; script-src-elem 'self'; script-src 'nonce-abc' 'self'; upgrade-insecure-requests"
+        );
+
+        for (invalid, field) in [
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy_preset: Some("lenient".to_string()),
+                    ..Default::default()
+                },
+                "content_security_policy_preset",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy: Some("default-src 'self'".to_string()),
+                    content_security_policy_preset: Some("strict".to_string()),
+                    ..Default::default()
+                },
+                "content_security_policy_preset",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy_directives: Some(HashMap::from([(
+                        "script-src".to_string(),
+                        vec!["'self'; object-src *".to_string()],
+                    )])),
+                    ..Default::default()
+                },
+                "content_security_policy_directives",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy_preset: Some("strict".to_string()),
+                    content_security_policy_trusted_types: Some(vec!["a b".to_string()]),
+                    ..Default::default()
+                },
+                "content_security_policy_trusted_types",
+            ),
+        ] {
+            assert_eq!(
+                ContentSecurityPolicy::from_config(&invalid)
+                    .unwrap_err()
+                    .field(),
+                field
+            );
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! Rocket fairing that implements Cross-Origin Resource Sharing (CORS) with policies per path
+//!
+//! Preflight requests (OPTIONS with Access-Control-Request-Method) are rerouted to an internal route, so that they are answered before authentication guards of the original route run. The CORS headers of preflight and actual requests are determined on the request and added on the response
+
+use std::str::FromStr;
+
+use regex::RegexSet;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+
+use crate::configuration::config::{CustomAppCorsConfig, CustomAppCorsPolicyConfig};
+use crate::oidc::guard::path_in;
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+
+use super::csrf::normalize_origin;
+
+pub const CORS_PREFLIGHT_PATH: &str = "/httpfirewall/cors-preflight";
+const WILDCARD: &str = "*";
+const DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+// Errors in the CORS configuration
+#[derive(Debug)]
+pub enum CorsConfigError {
+    EmptyPaths,
+    InvalidPath(String),
+    EmptyOrigins,
+    InvalidOrigin(String),
+    InvalidOriginPattern(String),
+    InvalidMethod(String),
+    InvalidHeader(String),
+    WildcardWithCredentials,
+}
+
+// CORS policy for a list of path prefixes
+#[derive(Debug)]
+struct CorsPolicy {
+    paths: Vec<String>,
+    // any origin
+    wildcard: bool,
+    origins: Vec<String>,
+    origin_patterns: RegexSet,
+    methods: Vec<String>,
+    // lower case
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+}
+
+// CORS headers of the response to the current request
+struct CorsHeaders(Vec<Header<'static>>);
+
+// Marks a preflight request with its outcome (true if it is allowed)
+struct PreflightRequest(Option<bool>);
+
+// Preflight request that was rerouted by the CORS fairing
+pub struct CorsPreflight(bool);
+
+// CORS fairing. It is enabled if policies are configured
+pub struct Cors {
+    policies: Vec<CorsPolicy>,
+}
+
+impl Cors {
+    /// Creates the CORS fairing from the configuration and validates it
+    ///
+    /// # Arguments
+    /// * `config` - CORS configuration of the application
+    ///
+    /// # Returns
+    /// CORS fairing or an error if a policy is invalid, e.g. the wildcard origin with credentials
+    ///
+    pub fn new(config: &CustomAppCorsConfig) -> Result<Cors, CorsConfigError> {
+        Ok(Cors {
+            policies: config
+                .policies
+                .iter()
+                .flatten()
+                .map(CorsPolicy::new)
+                .collect::<Result<Vec<CorsPolicy>, CorsConfigError>>()?,
+        })
+    }
+
+    /// Determines the CORS headers for a request
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    /// # Returns
+    /// Headers and, for preflight requests, if the request is allowed. None if the request has no Origin header or no policy covers its path
+    ///
+    fn headers(&self, req: &Request<'_>) -> Option<(Vec<Header<'static>>, Option<bool>)> {
+        let origin = req.headers().get_one("Origin")?;
+        let policy = self
+            .policies
+            .iter()
+            .find(|policy| path_in(&policy.paths, req.uri().path().as_str()))?;
+        // the response depends on the origin, so caches must not mix them up
+        let mut headers = vec![Header::new("Vary", "Origin")];
+        let preflight = req.method() == Method::Options
+            && req.headers().contains("Access-Control-Request-Method");
+        let allowed =
+            policy.allows_origin(origin) && (!preflight || policy.allows_preflight(req).is_ok());
+        if !allowed {
+            if preflight && let Err(reason) = policy.allows_preflight(req) {
+                SecurityEventLog::of(req).record(
+                    SecurityEvent::new(
+                        SecurityEventType::CorsPreflightDenied,
+                        SecuritySeverity::Info,
+                        SecurityOutcome::Rejected,
+                        format!(
+                            "Denied CORS preflight of {} for {}: {}",
+                            origin,
+                            req.uri(),
+                            reason
+                        ),
+                    )
+                    .with_request(req),
+                );
+            }
+            return Some((headers, preflight.then_some(false)));
+        }
+        let allow_origin = match policy.wildcard && !policy.allow_credentials {
+            true => WILDCARD.to_string(),
+            false => origin.to_string(),
+        };
+        headers.push(Header::new("Access-Control-Allow-Origin", allow_origin));
+        if policy.allow_credentials {
+            headers.push(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+        if preflight {
+            headers.push(Header::new(
+                "Access-Control-Allow-Methods",
+                policy.methods.join(", "),
+            ));
+            if !policy.headers.is_empty() {
+                headers.push(Header::new(
+                    "Access-Control-Allow-Headers",
+                    policy.headers.join(", "),
+                ));
+            }
+            if let Some(max_age) = policy.max_age {
+                headers.push(Header::new("Access-Control-Max-Age", max_age.to_string()));
+            }
+        } else if !policy.exposed_headers.is_empty() {
+            headers.push(Header::new(
+                "Access-Control-Expose-Headers",
+                policy.exposed_headers.join(", "),
+            ));
+        }
+        Some((headers, preflight.then_some(true)))
+    }
+}
+
+impl CorsPolicy {
+    fn new(config: &CustomAppCorsPolicyConfig) -> Result<CorsPolicy, CorsConfigError> {
+        if config.paths.is_empty() {
+            return Err(CorsConfigError::EmptyPaths);
+        }
+        if let Some(path) = config.paths.iter().find(|path| !path.starts_with('/')) {
+            return Err(CorsConfigError::InvalidPath(path.clone()));
+        }
+        let configured_origins = config.origins.clone().unwrap_or_default();
+        let origin_patterns = config.origin_patterns.clone().unwrap_or_default();
+        if configured_origins.is_empty() && origin_patterns.is_empty() {
+            return Err(CorsConfigError::EmptyOrigins);
+        }
+        let wildcard = configured_origins.iter().any(|origin| origin == WILDCARD);
+        let allow_credentials = config.allow_credentials.unwrap_or(false);
+        // browsers refuse credentials for the wildcard and reflecting any origin instead would allow every site to read the responses of the user
+        if wildcard && allow_credentials {
+            return Err(CorsConfigError::WildcardWithCredentials);
+        }
+        let mut origins = Vec::new();
+        for origin in configured_origins
+            .iter()
+            .filter(|origin| *origin != WILDCARD)
+        {
+            match normalize_origin(origin) {
+                Some(normalized) if normalized == *origin => origins.push(normalized),
+                _ => return Err(CorsConfigError::InvalidOrigin(origin.clone())),
+            }
+        }
+        // patterns must match the whole origin, e.g. not https://apps.example.com.evil.com for https://apps\.example\.com
+        let origin_patterns = RegexSet::new(
+            origin_patterns
+                .iter()
+                .map(|pattern| format!("^(?:{})$", pattern)),
+        )
+        .map_err(|err| CorsConfigError::InvalidOriginPattern(err.to_string()))?;
+        let methods = match &config.methods {
+            Some(methods) => methods.clone(),
+            None => DEFAULT_METHODS
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+        };
+        if let Some(method) = methods
+            .iter()
+            .find(|method| Method::from_str(method).is_err())
+        {
+            return Err(CorsConfigError::InvalidMethod(method.clone()));
+        }
+        let headers: Vec<String> = config
+            .headers
+            .iter()
+            .flatten()
+            .map(|header| header.to_ascii_lowercase())
+            .collect();
+        let exposed_headers = config.exposed_headers.clone().unwrap_or_default();
+        if let Some(header) = headers.iter().chain(exposed_headers.iter()).find(|header| {
+            header.is_empty()
+                || *header == WILDCARD
+                || !header
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        }) {
+            return Err(CorsConfigError::InvalidHeader(header.clone()));
+        }
+        Ok(CorsPolicy {
+            paths: config.paths.clone(),
+            wildcard,
+            origins,
+            origin_patterns,
+            methods: methods.iter().map(|method| method.to_uppercase()).collect(),
+            headers,
+            exposed_headers,
+            allow_credentials,
+            max_age: config.max_age,
+        })
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.wildcard
+            || self.origins.iter().any(|allowed| allowed == origin)
+            || self.origin_patterns.is_match(origin)
+    }
+
+    /// Checks the method and headers that a preflight request asks for
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    /// # Returns
+    /// Ok if they are allowed or the reason why not
+    ///
+    fn allows_preflight(&self, req: &Request<'_>) -> Result<(), String> {
+        if let Some(method) = req.headers().get_one("Access-Control-Request-Method")
+            && !self.methods.iter().any(|allowed| allowed == method)
+        {
+            return Err(format!("method {} is not allowed", method));
+        }
+        for header in req
+            .headers()
+            .get("Access-Control-Request-Headers")
+            .flat_map(|headers| headers.split(','))
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+        {
+            if !self.headers.contains(&header) {
+                return Err(format!("header {} is not allowed", header));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Implementation of the request guard for the route of preflight requests
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CorsPreflight {
+    type Error = ();
+
+    /// Forwards requests that were not rerouted as preflight requests, e.g. if a client requests the route directly
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.local_cache(|| PreflightRequest(None)).0 {
+            Some(allowed) => Outcome::Success(CorsPreflight(allowed)),
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Answers a preflight request. The CORS headers are added by the fairing
+///
+/// # Arguments
+/// * `preflight` - Outcome of the preflight request (no access for requests that were not rerouted)
+///
+/// # Returns
+/// NoContent if the preflight request is allowed or Forbidden
+///
+#[get("/cors-preflight")]
+pub fn cors_preflight(preflight: CorsPreflight) -> Status {
+    match preflight.0 {
+        true => Status::NoContent,
+        false => Status::Forbidden,
+    }
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPFirewall - CORS",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Executed on every request. Determines the CORS headers and reroutes preflight requests to the route of preflight requests
+    ///
+    /// # Arguments
+    /// * `self` - Struct CORS
+    /// * `req` - Request object
+    ///
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some((headers, preflight)) = self.headers(req) else {
+            return;
+        };
+        req.local_cache(|| CorsHeaders(headers));
+        if preflight.is_some() {
+            req.local_cache(|| PreflightRequest(preflight));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(CORS_PREFLIGHT_PATH).unwrap());
+        }
+    }
+
+    /// Executed on every response. Adds the CORS headers determined on the request
+    ///
+    /// # Arguments
+    /// * `self` - Struct CORS
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        for header in &req.local_cache(|| CorsHeaders(Vec::new())).0 {
+            if header.name() == "Vary" {
+                res.adjoin_header(header.clone());
+            } else {
+                res.set_header(header.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::oidc::guard::OidcUser;
+
+    #[get("/ui-api/inventory")]
+    fn inventory(_user: OidcUser) -> &'static str {
+        "inventory"
+    }
+
+    #[get("/public")]
+    fn public() -> &'static str {
+        "public"
+    }
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> CustomAppCorsPolicyConfig {
+        CustomAppCorsPolicyConfig {
+            paths: vec!["/ui-api".to_string()],
+            origins: Some(origins.iter().map(|origin| origin.to_string()).collect()),
+            allow_credentials: Some(allow_credentials),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn answers_preflight_before_authentication() {
+        let cors = Cors::new(&CustomAppCorsConfig {
+            policies: Some(vec![
+                CustomAppCorsPolicyConfig {
+                    origin_patterns: Some(vec![
+                        r"^https://[a-z0-9-]+\.apps\.example\.com$".to_string(),
+                    ]),
+                    methods: Some(vec!["GET".to_string(), "POST".to_string()]),
+                    headers: Some(vec!["Content-Type".to_string(), "X-CSRF-Token".to_string()]),
+                    exposed_headers: Some(vec!["X-Request-Id".to_string()]),
+                    max_age: Some(600),
+                    ..policy(&["https://admin.example.com"], true)
+                },
+                CustomAppCorsPolicyConfig {
+                    paths: vec!["/public".to_string()],
+                    ..policy(&["*"], false)
+                },
+            ]),
+        })
+        .unwrap();
+        let rocket = rocket::build()
+            .attach(cors)
+            .mount("/", routes![inventory, public])
+            .mount("/httpfirewall", routes![cors_preflight]);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client
+            .options("/ui-api/inventory")
+            .header(Header::new("Origin", "https://reports.apps.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                "content-type,x-csrf-token",
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("https://reports.apps.example.com")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("600"));
+
+        let response = client
+            .options("/ui-api/inventory")
+            .header(Header::new("Origin", "https://admin.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "DELETE"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+        let response = client
+            .get("/ui-api/inventory")
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .dispatch();
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+        let response = client
+            .get("/public")
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+        assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+
+        assert!(matches!(
+            Cors::new(&CustomAppCorsConfig {
+                policies: Some(vec![policy(&["*"], true)]),
+            }),
+            Err(CorsConfigError::WildcardWithCredentials)
+        ));
+        assert!(matches!(
+            Cors::new(&CustomAppCorsConfig {
+                policies: Some(vec![policy(&["https://admin.example.com/"], false)]),
+            }),
+            Err(CorsConfigError::InvalidOrigin(..))
+        ));
+    }
+
+    #[test]
+    fn matches_origin_patterns_against_whole_origin() {
+        let cors = Cors::new(&CustomAppCorsConfig {
+            policies: Some(vec![CustomAppCorsPolicyConfig {
+                origin_patterns: Some(vec![r"https://apps\.example\.com".to_string()]),
+                ..policy(&[], true)
+            }]),
+        })
+        .unwrap();
+        let rocket = rocket::build()
+            .attach(cors)
+            .mount("/", routes![inventory])
+            .mount("/httpfirewall", routes![cors_preflight]);
+        let client = Client::tracked(rocket).unwrap();
+        let allowed_origin = |origin: &'static str| {
+            client
+                .get("/ui-api/inventory")
+                .header(Header::new("Origin", origin))
+                .dispatch()
+                .headers()
+                .get_one("Access-Control-Allow-Origin")
+                .map(|origin| origin.to_string())
+        };
+
+        assert_eq!(
+            allowed_origin("https://apps.example.com"),
+            Some("https://apps.example.com".to_string())
+        );
+        assert_eq!(allowed_origin("https://apps.example.com.evil.com"), None);
+        assert_eq!(
+            allowed_origin("https://evil.com/https://apps.example.com"),
+            None
+        );
+    }
+}
@@ -0,0 +1,553 @@
+//! Rocket fairing that protects state-changing requests against cross-site request forgery (CSRF)
+//!
+//! Requests with a method that is not safe (e.g. POST, PUT, DELETE) to the protected paths must come from one of the configured origins according to the headers Sec-Fetch-Site, Origin or, if a browser sends neither, Referer. Optionally, they must also carry a double-submit token: the fairing issues a random token in a cookie that the SPA reads and sends back in a header
+//! Rejected requests are rerouted to an internal route that responds with 403 Forbidden and a problem response (RFC 9457), so that the handler of the original route is not executed
+//! Paths and single routes can be exempted, e.g. routes that receive requests of other sites by design. Routes are matched by the fairing itself, because it runs before Rocket routes the request
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD};
+use openidconnect::url;
+use rand::prelude::*;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Cookie, Method, SameSite, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::serde::json::{json, serde_json};
+use rocket::{Data, Request, Response, Route};
+
+use crate::configuration::config::{CustomAppCookiesConfig, CustomAppCsrfConfig};
+use crate::oidc::guard::path_in;
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+
+pub const CSRF_REJECTED_PATH: &str = "/httpfirewall/csrf-rejected";
+const DEFAULT_PATH: &str = "/";
+const DEFAULT_TOKEN_COOKIE_NAME: &str = "csrf_token";
+const DEFAULT_TOKEN_HEADER_NAME: &str = "X-CSRF-Token";
+
+// Errors in the CSRF configuration
+#[derive(Debug)]
+pub enum CsrfConfigError {
+    MissingOrigins,
+    InvalidOrigin(String),
+    InvalidPath(String),
+    EmptyTokenName,
+}
+
+// Double-submit token issued in a cookie that is readable by the SPA
+#[derive(Debug)]
+struct CsrfToken {
+    cookie_name: String,
+    header_name: String,
+    domain: Option<String>,
+    secure: bool,
+}
+
+// Marks a request that was rejected with the reason
+struct RejectedRequest(Option<String>);
+
+// Request that was rejected by the CSRF protection
+pub struct CsrfRejection(String);
+
+// Problem response (RFC 9457) of a rejected request
+#[derive(Responder)]
+#[response(status = 403, content_type = "application/problem+json")]
+pub struct CsrfProblem(String);
+
+// CSRF protection. It is always enabled, by default for the origin of the application
+pub struct CsrfProtection {
+    origins: Vec<String>,
+    paths: Vec<String>,
+    exempt_paths: Vec<String>,
+    // method and mounted URI (e.g. /ui-api/webhooks/<source>) of exempted routes
+    exempt_routes: Vec<(Method, String)>,
+    token: Option<CsrfToken>,
+}
+
+impl CsrfProtection {
+    /// Creates the CSRF protection from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - CSRF configuration of the application
+    /// * `cookies` - Cookie configuration of the application (domain and secure attribute of the token cookie)
+    /// * `default_origin` - URL of the application, e.g. the OIDC redirect URL. Its origin is used if no origins are configured
+    ///
+    /// # Returns
+    /// CSRF protection or an error if an origin or path is invalid or there is no origin at all
+    ///
+    pub fn new(
+        config: &CustomAppCsrfConfig,
+        cookies: &CustomAppCookiesConfig,
+        default_origin: Option<&str>,
+    ) -> Result<CsrfProtection, CsrfConfigError> {
+        let origins = match (&config.origins, default_origin) {
+            (Some(origins), _) if !origins.is_empty() => origins.clone(),
+            (_, Some(default_origin)) => vec![default_origin.to_string()],
+            _ => return Err(CsrfConfigError::MissingOrigins),
+        };
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                normalize_origin(origin)
+                    .ok_or_else(|| CsrfConfigError::InvalidOrigin(origin.clone()))
+            })
+            .collect::<Result<Vec<String>, CsrfConfigError>>()?;
+        let paths = config
+            .paths
+            .clone()
+            .unwrap_or_else(|| vec![DEFAULT_PATH.to_string()]);
+        let exempt_paths = config.exempt_paths.clone().unwrap_or_default();
+        if let Some(path) = paths
+            .iter()
+            .chain(exempt_paths.iter())
+            .find(|path| !path.starts_with('/'))
+        {
+            return Err(CsrfConfigError::InvalidPath(path.clone()));
+        }
+        let token = match config.double_submit_token.unwrap_or(false) {
+            true => {
+                let token = CsrfToken {
+                    cookie_name: config
+                        .token_cookie_name
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_TOKEN_COOKIE_NAME.to_string()),
+                    header_name: config
+                        .token_header_name
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_TOKEN_HEADER_NAME.to_string()),
+                    domain: cookies.domain.clone(),
+                    // only for local development via plain http on localhost
+                    secure: !cookies.allow_insecure_http.unwrap_or(false),
+                };
+                if token.cookie_name.is_empty() || token.header_name.is_empty() {
+                    return Err(CsrfConfigError::EmptyTokenName);
+                }
+                Some(token)
+            }
+            false => None,
+        };
+        Ok(CsrfProtection {
+            origins,
+            paths,
+            exempt_paths,
+            exempt_routes: Vec::new(),
+            token,
+        })
+    }
+
+    /// Exempts further paths, e.g. endpoints that receive requests of other sites by design
+    ///
+    /// # Arguments
+    /// * `path` - Path prefix that is not protected
+    ///
+    /// # Returns
+    /// CSRF protection with the exempted path
+    ///
+    pub fn with_exempt_path(mut self, path: &str) -> CsrfProtection {
+        self.exempt_paths.push(path.to_string());
+        self
+    }
+
+    /// Exempts routes, e.g. a route that receives requests of other sites by design, with the same arguments as they are mounted. Requests are exempted if their method and path match one of the routes (regardless of format and query)
+    ///
+    /// # Arguments
+    /// * `base` - Base path where the routes are mounted
+    /// * `routes` - Routes that are not protected
+    ///
+    /// # Returns
+    /// CSRF protection with the exempted routes
+    ///
+    pub fn with_exempt_routes(mut self, base: &str, routes: Vec<Route>) -> CsrfProtection {
+        for route in routes {
+            let path = route.uri.path().trim_start_matches('/');
+            self.exempt_routes.push((
+                route.method,
+                format!("{}/{}", base.trim_end_matches('/'), path),
+            ));
+        }
+        self
+    }
+
+    /// Checks a request
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    /// # Returns
+    /// None if the request may pass or the reason why it is rejected
+    ///
+    fn check(&self, req: &Request<'_>) -> Option<String> {
+        if matches!(
+            req.method(),
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        ) {
+            return None;
+        }
+        let path = req.uri().path();
+        if !path_in(&self.paths, path.as_str())
+            || path_in(&self.exempt_paths, path.as_str())
+            || self
+                .exempt_routes
+                .iter()
+                .any(|(method, uri)| *method == req.method() && route_matches(uri, path.as_str()))
+        {
+            return None;
+        }
+        // without cookies, the browser cannot act on behalf of the user (e.g. API clients with access tokens)
+        req.cookies().iter().next()?;
+        let origin = req.headers().get_one("Origin");
+        let allowed = |origin: Option<&str>| {
+            origin
+                .and_then(normalize_origin)
+                .is_some_and(|origin| self.origins.contains(&origin))
+        };
+        match (req.headers().get_one("Sec-Fetch-Site"), origin) {
+            // requests of the same origin and requests the user started (e.g. via the address bar)
+            (Some("same-origin"), _) | (Some("none"), _) => (),
+            (Some(site), _) if !allowed(origin) => {
+                return Some(format!("request from a {} origin", site));
+            }
+            (None, Some(origin)) if !allowed(Some(origin)) => {
+                return Some(format!("origin {} is not allowed", origin));
+            }
+            // older browsers send neither header on some requests, then the referer must be one of the origins
+            (None, None) => match req.headers().get_one("Referer") {
+                Some(referer) if allowed(Some(referer)) => (),
+                Some(_) => return Some("referer is not allowed".to_string()),
+                None => return Some("missing headers Origin and Referer".to_string()),
+            },
+            _ => (),
+        }
+        if let Some(token) = &self.token {
+            let cookie = req
+                .cookies()
+                .get(&token.cookie_name)
+                .map(|cookie| cookie.value());
+            let header = req.headers().get_one(&token.header_name);
+            match (cookie, header) {
+                (Some(cookie), Some(header)) if equals(cookie.as_bytes(), header.as_bytes()) => (),
+                (None, _) => return Some("missing CSRF token cookie".to_string()),
+                (_, None) => return Some(format!("missing header {}", token.header_name)),
+                _ => return Some("CSRF token does not match".to_string()),
+            }
+        }
+        None
+    }
+}
+
+// Implementation of the request guard for the route of rejected requests
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfRejection {
+    type Error = ();
+
+    /// Forwards requests that were not rejected, e.g. if a client requests the route directly
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match &req.local_cache(|| RejectedRequest(None)).0 {
+            Some(reason) => Outcome::Success(CsrfRejection(reason.clone())),
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Responds to a request that was rejected by the CSRF protection
+///
+/// # Arguments
+/// * `rejection` - Reason of the rejection (no access for requests that were not rejected)
+///
+/// # Returns
+/// 403 Forbidden with a problem response
+///
+#[get("/csrf-rejected")]
+pub fn csrf_rejected(rejection: CsrfRejection) -> CsrfProblem {
+    CsrfProblem(
+        serde_json::to_string(&json!({
+            "type": "about:blank",
+            "title": "Forbidden",
+            "status": 403,
+            "detail": format!("CSRF check failed: {}", rejection.0),
+        }))
+        .unwrap_or_default(),
+    )
+}
+
+/// Normalizes an origin, e.g. https://warehouse.example.com:443 to https://warehouse.example.com
+///
+/// # Arguments
+/// * `origin` - Origin or URL
+///
+/// # Returns
+/// Origin (scheme, host and port if it is not the default) or None if it is opaque or invalid
+///
+pub(crate) fn normalize_origin(origin: &str) -> Option<String> {
+    let origin = url::Url::parse(origin).ok()?.origin();
+    match origin.is_tuple() {
+        true => Some(origin.ascii_serialization()),
+        false => None,
+    }
+}
+
+/// Checks if a path matches the URI of a route. Dynamic segments (<name>) match one segment and trailing ones (<name..>) all remaining segments
+///
+/// # Arguments
+/// * `uri` - Mounted URI of the route without query, e.g. /ui-api/webhooks/<source>
+/// * `path` - Path of the request
+///
+fn route_matches(uri: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    for pattern in uri.split('/').filter(|pattern| !pattern.is_empty()) {
+        if pattern.starts_with('<') && pattern.ends_with("..>") {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if pattern.starts_with('<') || pattern == segment => (),
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Compares two values in constant time
+fn equals(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Returns a securely random token (base64url)
+fn random_token() -> String {
+    let mut rng = rand::rng();
+    let mut random_bytes = [0u8; 32];
+    rng.fill_bytes(&mut random_bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(random_bytes)
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for CsrfProtection {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPFirewall - CSRF Protection",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Executed on every request. Rejected requests are rerouted to the route of rejected requests
+    ///
+    /// # Arguments
+    /// * `self` - Struct CSRF Protection
+    /// * `req` - Request object
+    ///
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(reason) = self.check(req) else {
+            return;
+        };
+        SecurityEventLog::of(req).record(
+            SecurityEvent::new(
+                SecurityEventType::CsrfRejected,
+                SecuritySeverity::Warning,
+                SecurityOutcome::Rejected,
+                format!("Rejected {} {}: {}", req.method(), req.uri(), reason),
+            )
+            .with_request(req),
+        );
+        req.local_cache(|| RejectedRequest(Some(reason)));
+        req.set_method(Method::Get);
+        req.set_uri(Origin::parse(CSRF_REJECTED_PATH).unwrap());
+    }
+
+    /// Executed on every response. Issues the double-submit token if the client has none
+    ///
+    /// # Arguments
+    /// * `self` - Struct CSRF Protection
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(token) = &self.token else {
+            return;
+        };
+        if req.cookies().get(&token.cookie_name).is_some() {
+            return;
+        }
+        let mut cookie = Cookie::build((token.cookie_name.clone(), random_token()))
+            .path("/")
+            .secure(token.secure)
+            // the SPA reads the token to send it in the header
+            .http_only(false)
+            .same_site(SameSite::Strict);
+        if let Some(domain) = &token.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        res.adjoin_header(cookie.build());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Cookie, Header};
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[post("/ui-api/order")]
+    fn order() -> &'static str {
+        "ordered"
+    }
+
+    #[post("/reports")]
+    fn report() -> &'static str {
+        "reported"
+    }
+
+    #[post("/terms/accept")]
+    fn accept() -> &'static str {
+        "accepted"
+    }
+
+    #[post("/webhooks/<_source>")]
+    fn webhook(_source: &str) -> &'static str {
+        "received"
+    }
+
+    #[get("/ui-api/inventory")]
+    fn inventory() -> &'static str {
+        "inventory"
+    }
+
+    #[test]
+    fn rejects_cross_site_requests_without_token() {
+        let csrf = CsrfProtection::new(
+            &CustomAppCsrfConfig {
+                origins: Some(vec!["https://warehouse.example.com:443".to_string()]),
+                double_submit_token: Some(true),
+                ..Default::default()
+            },
+            &CustomAppCookiesConfig::default(),
+            Some("https://other.example.com/oidc/redirect"),
+        )
+        .unwrap()
+        .with_exempt_path("/reports")
+        .with_exempt_routes("/ui-api", routes![webhook]);
+        let rocket = rocket::build()
+            .attach(csrf)
+            .mount("/", routes![order, report, accept, inventory])
+            .mount("/ui-api", routes![webhook])
+            .mount("/httpfirewall", routes![csrf_rejected]);
+        let client = Client::untracked(rocket).unwrap();
+
+        let response = client.get("/ui-api/inventory").dispatch();
+        let token = response.cookies().get("csrf_token").unwrap().clone();
+        assert!(!token.http_only().unwrap_or(false));
+        let session = Cookie::new("oidc_user_session", "session");
+        let post = |origin: &'static str, site: &'static str, header: &str| {
+            client
+                .post("/ui-api/order")
+                .cookie(session.clone())
+                .cookie(token.clone())
+                .header(Header::new("Origin", origin))
+                .header(Header::new("Sec-Fetch-Site", site))
+                .header(Header::new("X-CSRF-Token", header.to_string()))
+                .dispatch()
+        };
+
+        let response = post(
+            "https://warehouse.example.com",
+            "same-origin",
+            token.value(),
+        );
+        assert_eq!(response.into_string().unwrap(), "ordered");
+        let response = post("https://evil.example.com", "cross-site", token.value());
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "problem+json"))
+        );
+        let problem: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(
+            problem["detail"],
+            "CSRF check failed: request from a cross-site origin"
+        );
+        let response = post("https://warehouse.example.com", "same-origin", "forged");
+        assert_eq!(response.status(), Status::Forbidden);
+        // all paths are protected by default, e.g. form posts
+        let response = client
+            .post("/terms/accept")
+            .cookie(session.clone())
+            .cookie(token.clone())
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .header(Header::new("X-CSRF-Token", token.value().to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // older browsers without Sec-Fetch-Site
+        let response = client
+            .post("/ui-api/order")
+            .cookie(session.clone())
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        // neither Sec-Fetch-Site nor Origin
+        let response = client
+            .post("/ui-api/order")
+            .cookie(session.clone())
+            .cookie(token.clone())
+            .header(Header::new("X-CSRF-Token", token.value().to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post("/ui-api/order")
+            .cookie(session.clone())
+            .cookie(token.clone())
+            .header(Header::new("X-CSRF-Token", token.value().to_string()))
+            .header(Header::new(
+                "Referer",
+                "https://warehouse.example.com/ui/orders",
+            ))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "ordered");
+        // API clients without cookies and exempted paths
+        let response = client.post("/ui-api/order").dispatch();
+        assert_eq!(response.into_string().unwrap(), "ordered");
+        let response = client
+            .post("/reports")
+            .cookie(session.clone())
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "reported");
+        let response = client
+            .post("/ui-api/webhooks/carrier")
+            .cookie(session.clone())
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "received");
+        let response = client
+            .post("/ui-api/webhooks/carrier/status")
+            .cookie(session)
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn protects_origin_of_application_by_default() {
+        let csrf = CsrfProtection::new(
+            &CustomAppCsrfConfig::default(),
+            &CustomAppCookiesConfig::default(),
+            Some("http://localhost:8000/oidc/redirect"),
+        )
+        .unwrap();
+        assert_eq!(csrf.origins, vec!["http://localhost:8000".to_string()]);
+        assert!(matches!(
+            CsrfProtection::new(
+                &CustomAppCsrfConfig::default(),
+                &CustomAppCookiesConfig::default(),
+                None,
+            ),
+            Err(CsrfConfigError::MissingOrigins)
+        ));
+    }
+}
@@ -1 +1,9 @@
+pub mod clientip;
+pub mod contentsecuritypolicy;
+pub mod cors;
+pub mod csrf;
+pub mod noncerewriter;
+pub mod ratelimit;
+pub mod requestfilter;
 pub mod securityhttpheaders;
+pub mod subresourceintegrity;
@@ -0,0 +1,655 @@
+//! Streaming HTML rewriter that adds a Content-Security-Policy nonce to the start tags of configured elements (e.g. script, style, link rel=stylesheet)
+//!
+//! The rewriter works on bytes in chunks of any size. It only changes real start tags, ie not text, comments, the content of raw text elements (e.g. inside a script) or custom elements with a similar name (e.g. scripts-foo). All other bytes are passed through unchanged, so the encoding of the document is preserved
+//! The tokenizer is generic over a TagRewriter, so that start tags can also be rewritten differently (e.g. for Subresource Integrity)
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rocket::tokio::io::{AsyncRead, ReadBuf};
+
+// Elements whose content is text until their end tag
+const RAW_TEXT_ELEMENTS: [&[u8]; 9] = [
+    b"script",
+    b"style",
+    b"textarea",
+    b"title",
+    b"xmp",
+    b"iframe",
+    b"noembed",
+    b"noframes",
+    b"noscript",
+];
+
+// Tag names longer than this are passed through without buffering. They can never match a configured element
+const MAX_BUFFERED_TAG_NAME: usize = 64;
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+// Decides which start tags are rewritten and how
+pub trait TagRewriter {
+    /// Returns true if start tags of the element are rewritten. Only these tags are buffered until they are complete
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the element in lower case
+    ///
+    fn rewrites(&self, tag_name: &[u8]) -> bool;
+
+    /// Rewrites a complete start tag
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the element in lower case
+    /// * `tag` - Start tag as in the document, from < to >
+    ///
+    /// # Returns
+    /// Rewritten start tag
+    ///
+    fn rewrite_tag(&mut self, tag_name: &[u8], tag: &[u8]) -> Vec<u8>;
+
+    /// Receives the content of a raw text element (e.g. an inline script) whose start tag was rewritten. The content itself is not changed
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the element in lower case
+    /// * `content` - Content between the start and end tag
+    ///
+    fn raw_text(&mut self, _tag_name: &[u8], _content: &[u8]) {}
+}
+
+// State of the tokenizer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    TagOpen,
+    TagName,
+    // quotes only start an attribute value after =
+    StartTag {
+        quote: Option<u8>,
+        after_equals: bool,
+    },
+    EndTag,
+    MarkupDeclaration,
+    Comment,
+    BogusComment,
+    RawText,
+    PlainText,
+}
+
+// Inserts a nonce into the start tags of the configured elements
+#[derive(Debug)]
+pub struct NonceTags {
+    nonce: String,
+    elements: Vec<Vec<u8>>,
+}
+
+// Rewriter for one HTML document
+#[derive(Debug)]
+pub struct HtmlRewriter<T> {
+    tags: T,
+    state: State,
+    // start tag that is buffered until it is complete, because it may be rewritten
+    pending: Vec<u8>,
+    buffering: bool,
+    tag_name: Vec<u8>,
+    tag_name_overflow: bool,
+    markup: Vec<u8>,
+    comment_length: usize,
+    dashes: usize,
+    raw_text_end: Vec<u8>,
+    raw_text_matched: usize,
+    // content of a raw text element whose start tag was rewritten
+    raw_text: Option<Vec<u8>>,
+}
+
+pub type NonceRewriter = HtmlRewriter<NonceTags>;
+
+impl NonceRewriter {
+    /// Creates a rewriter for one document
+    ///
+    /// # Arguments
+    /// * `nonce` - Nonce of the Content-Security-Policy of the response
+    /// * `elements` - Names of the elements that get the nonce. link elements only get it if they reference a stylesheet
+    ///
+    pub fn new(nonce: &str, elements: &[String]) -> NonceRewriter {
+        HtmlRewriter::with_tags(NonceTags {
+            nonce: nonce.to_string(),
+            elements: elements
+                .iter()
+                .map(|element| element.to_ascii_lowercase().into_bytes())
+                .collect(),
+        })
+    }
+}
+
+impl<T: TagRewriter> HtmlRewriter<T> {
+    /// Creates a rewriter for one document
+    ///
+    /// # Arguments
+    /// * `tags` - Decides which start tags are rewritten and how
+    ///
+    pub fn with_tags(tags: T) -> HtmlRewriter<T> {
+        HtmlRewriter {
+            tags,
+            state: State::Data,
+            pending: Vec::new(),
+            buffering: false,
+            tag_name: Vec::new(),
+            tag_name_overflow: false,
+            markup: Vec::new(),
+            comment_length: 0,
+            dashes: 0,
+            raw_text_end: Vec::new(),
+            raw_text_matched: 0,
+            raw_text: None,
+        }
+    }
+
+    /// Returns the tag rewriter, e.g. to read what it collected from the document
+    pub fn into_tags(self) -> T {
+        self.tags
+    }
+
+    /// Rewrites a complete document
+    ///
+    /// # Arguments
+    /// * `document` - HTML document
+    ///
+    /// # Returns
+    /// Document with the rewritten start tags
+    ///
+    pub fn rewrite_all(mut self, document: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(document.len() + 256);
+        self.rewrite(document, &mut output);
+        self.finish(&mut output);
+        output
+    }
+
+    /// Rewrites the next chunk of the document. Bytes of a start tag that is not complete yet are kept until the next chunk
+    ///
+    /// # Arguments
+    /// * `chunk` - Next bytes of the document
+    /// * `output` - Rewritten bytes are appended to it
+    ///
+    pub fn rewrite(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+        for &byte in chunk {
+            self.next(byte, output);
+        }
+    }
+
+    /// Writes the remaining bytes at the end of the document (e.g. a start tag that was not closed)
+    ///
+    /// # Arguments
+    /// * `output` - Remaining bytes are appended to it
+    ///
+    pub fn finish(&mut self, output: &mut Vec<u8>) {
+        output.append(&mut self.pending);
+        self.buffering = false;
+        self.raw_text = None;
+        self.state = State::Data;
+    }
+
+    fn next(&mut self, byte: u8, output: &mut Vec<u8>) {
+        match self.state {
+            State::Data => {
+                if byte == b'<' {
+                    self.pending.push(byte);
+                    self.state = State::TagOpen;
+                } else {
+                    output.push(byte);
+                }
+            }
+            State::TagOpen => {
+                if byte.is_ascii_alphabetic() {
+                    self.tag_name.clear();
+                    self.tag_name.push(byte.to_ascii_lowercase());
+                    self.tag_name_overflow = false;
+                    self.pending.push(byte);
+                    self.state = State::TagName;
+                    return;
+                }
+                output.append(&mut self.pending);
+                match byte {
+                    b'<' => {
+                        self.pending.push(byte);
+                        return;
+                    }
+                    b'/' => self.state = State::EndTag,
+                    b'!' => {
+                        self.markup.clear();
+                        self.state = State::MarkupDeclaration;
+                    }
+                    b'?' => self.state = State::BogusComment,
+                    _ => self.state = State::Data,
+                }
+                output.push(byte);
+            }
+            State::TagName => {
+                if is_tag_name_end(byte) {
+                    self.buffering = !self.tag_name_overflow && self.tags.rewrites(&self.tag_name);
+                    if self.buffering {
+                        self.pending.push(byte);
+                    } else {
+                        output.append(&mut self.pending);
+                        output.push(byte);
+                    }
+                    if byte == b'>' {
+                        self.end_start_tag(output);
+                    } else {
+                        self.state = State::StartTag {
+                            quote: None,
+                            after_equals: false,
+                        };
+                    }
+                    return;
+                }
+                if self.tag_name_overflow {
+                    output.push(byte);
+                    return;
+                }
+                self.tag_name.push(byte.to_ascii_lowercase());
+                self.pending.push(byte);
+                if self.tag_name.len() > MAX_BUFFERED_TAG_NAME {
+                    self.tag_name_overflow = true;
+                    output.append(&mut self.pending);
+                }
+            }
+            State::StartTag {
+                quote,
+                after_equals,
+            } => {
+                if self.buffering {
+                    self.pending.push(byte);
+                } else {
+                    output.push(byte);
+                }
+                let quote = match quote {
+                    Some(quote) if byte == quote => None,
+                    Some(quote) => Some(quote),
+                    None if byte == b'>' => {
+                        self.end_start_tag(output);
+                        return;
+                    }
+                    None if after_equals && (byte == b'"' || byte == b'\'') => Some(byte),
+                    None => None,
+                };
+                self.state = State::StartTag {
+                    quote,
+                    after_equals: quote.is_none()
+                        && (byte == b'=' || (after_equals && byte.is_ascii_whitespace())),
+                };
+            }
+            State::EndTag | State::BogusComment => {
+                output.push(byte);
+                if byte == b'>' {
+                    self.state = State::Data;
+                }
+            }
+            State::MarkupDeclaration => {
+                output.push(byte);
+                self.markup.push(byte);
+                if self.markup == b"--" {
+                    self.comment_length = 0;
+                    self.dashes = 0;
+                    self.state = State::Comment;
+                } else if byte == b'>' {
+                    self.state = State::Data;
+                } else if self.markup != b"-" {
+                    self.state = State::BogusComment;
+                }
+            }
+            State::Comment => {
+                output.push(byte);
+                // also closes abrupt comments, such as <!--> and <!--->
+                if byte == b'>' && (self.dashes >= 2 || self.comment_length == self.dashes) {
+                    self.state = State::Data;
+                    return;
+                }
+                self.dashes = if byte == b'-' { self.dashes + 1 } else { 0 };
+                self.comment_length += 1;
+            }
+            State::RawText => {
+                output.push(byte);
+                if self.raw_text_matched == self.raw_text_end.len() {
+                    if is_tag_name_end(byte) {
+                        self.end_raw_text();
+                        self.state = if byte == b'>' {
+                            State::Data
+                        } else {
+                            State::EndTag
+                        };
+                        return;
+                    }
+                    self.raw_text_matched = 0;
+                }
+                if let Some(raw_text) = &mut self.raw_text {
+                    raw_text.push(byte);
+                }
+                if byte.to_ascii_lowercase() == self.raw_text_end[self.raw_text_matched] {
+                    self.raw_text_matched += 1;
+                } else {
+                    self.raw_text_matched = usize::from(byte == b'<');
+                }
+            }
+            State::PlainText => output.push(byte),
+        }
+    }
+
+    /// Writes the (rewritten) start tag and continues with its content
+    fn end_start_tag(&mut self, output: &mut Vec<u8>) {
+        let rewritten = self.buffering;
+        if self.buffering {
+            let tag = std::mem::take(&mut self.pending);
+            output.extend(self.tags.rewrite_tag(&self.tag_name, &tag));
+            self.buffering = false;
+        }
+        if self.tag_name == b"plaintext" {
+            self.state = State::PlainText;
+        } else if RAW_TEXT_ELEMENTS.contains(&self.tag_name.as_slice()) {
+            self.raw_text_end = [b"</", self.tag_name.as_slice()].concat();
+            self.raw_text_matched = 0;
+            self.raw_text = rewritten.then(Vec::new);
+            self.state = State::RawText;
+        } else {
+            self.state = State::Data;
+        }
+    }
+
+    /// Passes the content of a raw text element without the end tag to the tag rewriter
+    fn end_raw_text(&mut self) {
+        if let Some(mut raw_text) = self.raw_text.take() {
+            raw_text.truncate(raw_text.len() - self.raw_text_end.len());
+            self.tags.raw_text(&self.tag_name, &raw_text);
+        }
+    }
+}
+
+impl TagRewriter for NonceTags {
+    fn rewrites(&self, tag_name: &[u8]) -> bool {
+        self.elements.iter().any(|element| element == tag_name)
+    }
+
+    /// Inserts the nonce after the tag name, unless the tag already has a nonce or is a link that does not reference a stylesheet
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of a configured element
+    /// * `tag` - Complete start tag of the element
+    ///
+    /// # Returns
+    /// Start tag with the nonce
+    ///
+    fn rewrite_tag(&mut self, tag_name: &[u8], tag: &[u8]) -> Vec<u8> {
+        let name_end = 1 + tag_name.len();
+        let attributes = attributes(&tag[name_end..]);
+        let has_nonce = attributes.iter().any(|(name, _)| name == b"nonce");
+        if has_nonce || (tag_name == b"link" && !has_rel(&attributes, b"stylesheet")) {
+            return tag.to_vec();
+        }
+        insert_attribute(tag, tag_name, b"nonce", &self.nonce)
+    }
+}
+
+/// Returns true if the rel attribute contains the link type
+///
+/// # Arguments
+/// * `attributes` - Attributes of a start tag
+/// * `link_type` - Link type, e.g. stylesheet
+///
+pub fn has_rel(attributes: &[(Vec<u8>, Vec<u8>)], link_type: &[u8]) -> bool {
+    attributes.iter().any(|(name, value)| {
+        name == b"rel"
+            && value
+                .split(|byte| byte.is_ascii_whitespace())
+                .any(|rel| rel.eq_ignore_ascii_case(link_type))
+    })
+}
+
+/// Inserts an attribute after the tag name
+///
+/// # Arguments
+/// * `tag` - Complete start tag
+/// * `tag_name` - Name of the element
+/// * `name` - Name of the attribute
+/// * `value` - Value of the attribute, which must not contain quotes
+///
+/// # Returns
+/// Start tag with the attribute
+///
+pub fn insert_attribute(tag: &[u8], tag_name: &[u8], name: &[u8], value: &str) -> Vec<u8> {
+    let name_end = 1 + tag_name.len();
+    [
+        &tag[..name_end],
+        b" ",
+        name,
+        b"=\"",
+        value.as_bytes(),
+        b"\"",
+        &tag[name_end..],
+    ]
+    .concat()
+}
+
+fn is_tag_name_end(byte: u8) -> bool {
+    byte.is_ascii_whitespace() || byte == b'/' || byte == b'>'
+}
+
+/// Parses the attributes of a start tag
+///
+/// # Arguments
+/// * `bytes` - Start tag after the tag name
+///
+/// # Returns
+/// Names (lower case) and values of the attributes
+///
+pub fn attributes(bytes: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut attributes = Vec::new();
+    let mut position = 0;
+    let skip = |position: &mut usize, skipped: fn(u8) -> bool| {
+        while *position < bytes.len() && skipped(bytes[*position]) {
+            *position += 1;
+        }
+    };
+    loop {
+        skip(&mut position, |byte| {
+            byte.is_ascii_whitespace() || byte == b'/'
+        });
+        if position >= bytes.len() || bytes[position] == b'>' {
+            return attributes;
+        }
+        let name_start = position;
+        position += 1;
+        skip(&mut position, |byte| {
+            !(byte.is_ascii_whitespace() || byte == b'=' || byte == b'>' || byte == b'/')
+        });
+        let name = bytes[name_start..position].to_ascii_lowercase();
+        skip(&mut position, |byte| byte.is_ascii_whitespace());
+        let mut value = Vec::new();
+        if position < bytes.len() && bytes[position] == b'=' {
+            position += 1;
+            skip(&mut position, |byte| byte.is_ascii_whitespace());
+            if position < bytes.len() && (bytes[position] == b'"' || bytes[position] == b'\'') {
+                let quote = bytes[position];
+                let value_start = position + 1;
+                position = value_start;
+                while position < bytes.len() && bytes[position] != quote {
+                    position += 1;
+                }
+                value = bytes[value_start..position.min(bytes.len())].to_vec();
+                position += 1;
+            } else {
+                let value_start = position;
+                skip(&mut position, |byte| {
+                    !(byte.is_ascii_whitespace() || byte == b'>')
+                });
+                value = bytes[value_start..position].to_vec();
+            }
+        }
+        attributes.push((name, value));
+    }
+}
+
+// Response body that is rewritten while it is streamed
+pub struct NonceRewriterStream<R> {
+    inner: R,
+    rewriter: NonceRewriter,
+    output: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> NonceRewriterStream<R> {
+    /// Creates the stream
+    ///
+    /// # Arguments
+    /// * `inner` - Original response body
+    /// * `rewriter` - Rewriter for the document
+    ///
+    pub fn new(inner: R, rewriter: NonceRewriter) -> NonceRewriterStream<R> {
+        NonceRewriterStream {
+            inner,
+            rewriter,
+            output: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NonceRewriterStream<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.output.len() {
+                let length = buf.remaining().min(this.output.len() - this.position);
+                buf.put_slice(&this.output[this.position..this.position + length]);
+                this.position += length;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            this.output.clear();
+            this.position = 0;
+            let mut chunk = [0u8; READ_BUFFER_SIZE];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {
+                    let chunk = chunk_buf.filled();
+                    if chunk.is_empty() {
+                        this.rewriter.finish(&mut this.output);
+                        this.finished = true;
+                    } else {
+                        this.rewriter.rewrite(chunk, &mut this.output);
+                    }
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn rewrite(document: &str) -> String {
+        let elements = ["script", "style", "link"].map(str::to_string);
+        String::from_utf8(NonceRewriter::new("n0nce", &elements).rewrite_all(document.as_bytes()))
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrites_only_real_start_tags() {
+        let cases = [
+            (
+                "<script>a()</script>",
+                "<script nonce=\"n0nce\">a()</script>",
+            ),
+            (
+                "<SCRIPT SRC='/a.js'></SCRIPT>",
+                "<SCRIPT nonce=\"n0nce\" SRC='/a.js'></SCRIPT>",
+            ),
+            (
+                "<style\n>p{}</style>",
+                "<style nonce=\"n0nce\"\n>p{}</style>",
+            ),
+            (
+                "<link rel=\"preload stylesheet\" href=/a.css>",
+                "<link nonce=\"n0nce\" rel=\"preload stylesheet\" href=/a.css>",
+            ),
+            ("<link rel=icon href=/a.ico>", "<link rel=icon href=/a.ico>"),
+            ("<script nonce=\"fixed\">", "<script nonce=\"fixed\">"),
+            ("<scripts-foo></scripts-foo>", "<scripts-foo></scripts-foo>"),
+            ("<!-- <script> -->", "<!-- <script> -->"),
+            ("<!--><script>", "<!--><script nonce=\"n0nce\">"),
+            (
+                "<!DOCTYPE html><script>",
+                "<!DOCTYPE html><script nonce=\"n0nce\">",
+            ),
+            ("a &lt;script> b < script", "a &lt;script> b < script"),
+            (
+                "<div title=\"<script>\"></div>",
+                "<div title=\"<script>\"></div>",
+            ),
+            (
+                "<script data-x=\"a>b\">'<script>'</SCRIPT ><script>",
+                "<script nonce=\"n0nce\" data-x=\"a>b\">'<script>'</SCRIPT ><script nonce=\"n0nce\">",
+            ),
+            (
+                "<textarea><style></textarea><style>",
+                "<textarea><style></textarea><style nonce=\"n0nce\">",
+            ),
+            (
+                "<img alt=it's><script>",
+                "<img alt=it's><script nonce=\"n0nce\">",
+            ),
+            ("<script", "<script"),
+        ];
+        for (document, expected) in cases {
+            assert_eq!(rewrite(document), expected, "{}", document);
+        }
+    }
+
+    #[test]
+    fn result_does_not_depend_on_chunks() {
+        let document = "<!doctype html><html><head><link rel=stylesheet href=a.css><script>if (a<b) {}</script></head><body>\u{e4}\u{1F600}<style>p{}</style></body></html>".as_bytes();
+        let elements = ["script", "style", "link"].map(str::to_string);
+        let expected = NonceRewriter::new("n0nce", &elements).rewrite_all(document);
+        for chunk_size in 1..8 {
+            let mut rewriter = NonceRewriter::new("n0nce", &elements);
+            let mut output = Vec::new();
+            for chunk in document.chunks(chunk_size) {
+                rewriter.rewrite(chunk, &mut output);
+            }
+            rewriter.finish(&mut output);
+            assert_eq!(output, expected);
+        }
+        assert_eq!(
+            expected
+                .windows(b"nonce=".len())
+                .filter(|window| *window == b"nonce=")
+                .count(),
+            3
+        );
+    }
+
+    #[rocket::async_test]
+    async fn streams_binary_content_unchanged() {
+        let mut document: Vec<u8> = (0..=255u8).cycle().take(3 * READ_BUFFER_SIZE).collect();
+        document.extend(b"<script>");
+        let mut stream = NonceRewriterStream::new(
+            document.as_slice(),
+            NonceRewriter::new("n0nce", &["script".to_string()]),
+        );
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output.len(), document.len() + " nonce=\"n0nce\"".len());
+        assert!(output.ends_with(b"<script nonce=\"n0nce\">"));
+    }
+}
@@ -0,0 +1,651 @@
+//! Rocket fairing that limits the rate of requests per client with token buckets
+//!
+//! Route groups are matched by regular expressions on the path of the request. The first group that matches applies. Each group has its own buckets, keyed by the IP address of the client, the authenticated subject or the validated API token of the request
+//! Requests over the limit are rerouted to an internal route that responds with 429 Too Many Requests and Retry-After, so that the handler of the original route is not executed. All responses of a group carry RateLimit-* headers
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use regex::RegexSet;
+use rocket::State;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::serde::Serialize;
+use rocket::serde::json::Json;
+use rocket::{Data, Request, Response};
+use sha2::{Digest, Sha256};
+
+use crate::configuration::config::{CustomAppRateLimitConfig, CustomAppRateLimitGroupConfig};
+use crate::oidc::guard::{AuthenticatedRequest, OidcUser, TokenAuthenticatedRequest};
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+
+pub const RATE_LIMITED_PATH: &str = "/httpfirewall/rate-limited";
+const DEFAULT_MAX_BUCKETS: usize = 100000;
+
+// Errors in the rate limit configuration
+#[derive(Debug)]
+pub enum RateLimitConfigError {
+    EmptyGroupName,
+    DuplicateGroupName(String),
+    InvalidPathRegex(String),
+    InvalidKey(String),
+    InvalidLimit(String),
+}
+
+// What identifies a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitKey {
+    Ip,
+    // authenticated subject or the IP address if the request is not authenticated
+    Subject,
+    // hash of the Authorization header if its access token authenticated the request, otherwise the IP address
+    Token,
+}
+
+// Size and refill rate of the buckets of a group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketLimit {
+    pub capacity: u32,
+    pub refill_per_second: f64,
+}
+
+// Tokens left in a bucket after a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketState {
+    pub allowed: bool,
+    pub tokens: f64,
+}
+
+// Storage of the token buckets, e.g. in memory or in a store shared by several instances of the application
+#[rocket::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Refills the bucket of a key for the elapsed time and takes a token, if there is one
+    ///
+    /// # Arguments
+    /// * `key` - Key of the bucket
+    /// * `limit` - Size and refill rate of the bucket
+    /// * `now` - Current time in seconds since the Unix epoch
+    ///
+    /// # Returns
+    /// If a token was taken and the tokens that are left
+    ///
+    async fn take(&self, key: &str, limit: &BucketLimit, now: f64) -> BucketState;
+}
+
+// Token bucket of a client
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: f64,
+    // time when the bucket is full again, so that it can be removed
+    full_at: f64,
+}
+
+// Buckets in the memory of the application
+#[derive(Debug)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    max_buckets: usize,
+}
+
+// Route group with its own limit
+#[derive(Debug)]
+struct RateLimitGroup {
+    name: String,
+    paths: RegexSet,
+    key: RateLimitKey,
+    limit: BucketLimit,
+    // shared with the statistics
+    rejections: Arc<AtomicU64>,
+}
+
+// Limit that applied to a request, for the RateLimit-* headers and the response of a rejected request
+#[derive(Debug, Clone, Copy)]
+struct RateLimitDecision {
+    limit: BucketLimit,
+    state: BucketState,
+}
+
+// Decision of the rate limiter for the current request (None if no group applies)
+struct RateLimitedRequest(Option<RateLimitDecision>);
+
+// Request that was rejected by the rate limiter
+pub struct RateLimitRejection {
+    retry_after: u64,
+}
+
+// Response to a rejected request
+#[derive(Responder)]
+#[response(status = 429, content_type = "text")]
+pub struct TooManyRequests {
+    body: &'static str,
+    retry_after: Header<'static>,
+}
+
+// Number of rejected requests of a group since the start of the application
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct GroupRejections {
+    pub group: String,
+    pub rejections: u64,
+}
+
+// Counters of the rate limiter. It is managed as state in Rocket, so that admins can query them
+pub struct RateLimitStatistics {
+    groups: Vec<(String, Arc<AtomicU64>)>,
+    admin_roles: Vec<String>,
+}
+
+// Rate limiter. It is attached as fairing if route groups are configured
+pub struct RateLimiter {
+    groups: Vec<RateLimitGroup>,
+    store: Box<dyn RateLimitStore>,
+    admin_roles: Vec<String>,
+}
+
+impl RateLimiter {
+    /// Creates the rate limiter with buckets in memory
+    ///
+    /// # Arguments
+    /// * `config` - Rate limit configuration of the application
+    ///
+    /// # Returns
+    /// Rate limiter or an error if a group is invalid
+    ///
+    pub fn new(config: &CustomAppRateLimitConfig) -> Result<RateLimiter, RateLimitConfigError> {
+        let mut groups: Vec<RateLimitGroup> = Vec::new();
+        for group in config.groups.iter().flatten() {
+            if group.name.is_empty() {
+                return Err(RateLimitConfigError::EmptyGroupName);
+            }
+            if groups.iter().any(|existing| existing.name == group.name) {
+                return Err(RateLimitConfigError::DuplicateGroupName(group.name.clone()));
+            }
+            groups.push(RateLimitGroup::new(group)?);
+        }
+        Ok(RateLimiter {
+            groups,
+            store: Box::new(MemoryRateLimitStore::new(
+                config.max_buckets.unwrap_or(DEFAULT_MAX_BUCKETS),
+            )),
+            admin_roles: config.admin_roles.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Returns the counters of the groups. They are shared with the rate limiter, so they are updated while it rejects requests
+    pub fn statistics(&self) -> RateLimitStatistics {
+        RateLimitStatistics {
+            groups: self
+                .groups
+                .iter()
+                .map(|group| (group.name.clone(), group.rejections.clone()))
+                .collect(),
+            admin_roles: self.admin_roles.clone(),
+        }
+    }
+
+    /// Replaces the store of the buckets
+    ///
+    /// # Arguments
+    /// * `store` - Store of the buckets
+    ///
+    /// # Returns
+    /// Rate limiter with the store
+    ///
+    pub fn with_store(mut self, store: Box<dyn RateLimitStore>) -> RateLimiter {
+        self.store = store;
+        self
+    }
+}
+
+impl RateLimitGroup {
+    fn new(config: &CustomAppRateLimitGroupConfig) -> Result<RateLimitGroup, RateLimitConfigError> {
+        let paths = RegexSet::new(&config.paths).map_err(|err| {
+            RateLimitConfigError::InvalidPathRegex(format!("{}: {}", config.name, err))
+        })?;
+        let key = match config.key.as_deref() {
+            None | Some("ip") => RateLimitKey::Ip,
+            Some("subject") => RateLimitKey::Subject,
+            Some("token") => RateLimitKey::Token,
+            Some(key) => {
+                return Err(RateLimitConfigError::InvalidKey(format!(
+                    "{}: {}",
+                    config.name, key
+                )));
+            }
+        };
+        if config.capacity == 0
+            || !config.refill_per_second.is_finite()
+            || config.refill_per_second <= 0.0
+        {
+            return Err(RateLimitConfigError::InvalidLimit(config.name.clone()));
+        }
+        Ok(RateLimitGroup {
+            name: config.name.clone(),
+            paths,
+            key,
+            limit: BucketLimit {
+                capacity: config.capacity,
+                refill_per_second: config.refill_per_second,
+            },
+            rejections: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns the key of the bucket of the client of a request. Requests without subject or validated token fall back to the IP address, which the request firewall resolved as peer of the request
+    /// A token is only used after it authenticated the request, otherwise each new (invalid) token would get a new bucket
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn client_key(&self, req: &Request<'_>) -> String {
+        let client = match self.key {
+            RateLimitKey::Subject => match req.guard::<OidcUser>().await {
+                Outcome::Success(user) => Some(format!("subject:{}", user.subject.as_str())),
+                _ => None,
+            },
+            RateLimitKey::Token => match req.headers().get_one("Authorization") {
+                Some(authorization)
+                    if req.guard::<OidcUser>().await.is_success()
+                        && req.local_cache(|| TokenAuthenticatedRequest(false)).0 =>
+                {
+                    Some(format!(
+                        "token:{}",
+                        BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(authorization))
+                    ))
+                }
+                _ => None,
+            },
+            RateLimitKey::Ip => None,
+        };
+        let client = client.unwrap_or_else(|| match req.remote() {
+            Some(remote) => format!("ip:{}", remote.ip()),
+            None => "ip:unknown".to_string(),
+        });
+        format!("{}:{}", self.name, client)
+    }
+}
+
+impl RateLimitStatistics {
+    /// Returns the number of rejected requests per group in the order of the groups
+    pub fn rejections(&self) -> Vec<GroupRejections> {
+        self.groups
+            .iter()
+            .map(|(group, rejections)| GroupRejections {
+                group: group.clone(),
+                rejections: rejections.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns true if the user has one of the roles that may query the statistics
+    ///
+    /// # Arguments
+    /// * `user` - Authenticated user
+    ///
+    pub fn is_admin(&self, user: &OidcUser) -> bool {
+        user.mapped_roles
+            .iter()
+            .any(|role| self.admin_roles.contains(role))
+    }
+}
+
+impl MemoryRateLimitStore {
+    /// Creates an empty store
+    ///
+    /// # Arguments
+    /// * `max_buckets` - Number of buckets after which full buckets are removed
+    ///
+    pub fn new(max_buckets: usize) -> MemoryRateLimitStore {
+        MemoryRateLimitStore {
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, limit: &BucketLimit, now: f64) -> BucketState {
+        let capacity = f64::from(limit.capacity);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(key) {
+            // a full bucket is the same as no bucket
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = (now - bucket.updated).max(0.0);
+        bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_second).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        bucket.full_at = now + (capacity - bucket.tokens) / limit.refill_per_second;
+        BucketState {
+            allowed,
+            tokens: bucket.tokens,
+        }
+    }
+}
+
+impl RateLimitDecision {
+    /// Returns the RateLimit-* headers of the decision
+    fn headers(&self) -> [Header<'static>; 3] {
+        let missing = f64::from(self.limit.capacity) - self.state.tokens;
+        [
+            Header::new("RateLimit-Limit", self.limit.capacity.to_string()),
+            Header::new(
+                "RateLimit-Remaining",
+                (self.state.tokens.floor() as u64).to_string(),
+            ),
+            Header::new(
+                "RateLimit-Reset",
+                seconds(missing / self.limit.refill_per_second).to_string(),
+            ),
+        ]
+    }
+
+    /// Returns the seconds until the client can send the next request
+    fn retry_after(&self) -> u64 {
+        seconds((1.0 - self.state.tokens) / self.limit.refill_per_second).max(1)
+    }
+}
+
+fn seconds(value: f64) -> u64 {
+    value.max(0.0).ceil() as u64
+}
+
+/// Returns the current time in seconds since the Unix epoch, the time of the buckets
+pub(crate) fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+// Implementation of the request guard for the route of rejected requests
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimitRejection {
+    type Error = ();
+
+    /// Forwards requests that were not rejected by the rate limiter, e.g. if a client requests the route directly
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.local_cache(|| RateLimitedRequest(None)).0 {
+            Some(decision) if !decision.state.allowed => Outcome::Success(RateLimitRejection {
+                retry_after: decision.retry_after(),
+            }),
+            _ => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Responds to a request that was rejected by the rate limiter
+///
+/// # Arguments
+/// * `rejection` - Rejection of the rate limiter (no access for requests that were not rejected)
+///
+/// # Returns
+/// 429 Too Many Requests with Retry-After
+///
+#[get("/rate-limited")]
+pub fn rate_limited(rejection: RateLimitRejection) -> TooManyRequests {
+    TooManyRequests {
+        body: "Too Many Requests",
+        retry_after: Header::new("Retry-After", rejection.retry_after.to_string()),
+    }
+}
+
+/// Returns the number of rejected requests per group, e.g. to find clients that are throttled too often
+///
+/// # Arguments
+/// * `user` - Authenticated user (no access for unauthenticated users)
+/// * `statistics` - Counters of the rate limiter (injected by Rocket)
+///
+/// # Returns
+/// Rejections per group as JSON or an error if the user is not an admin
+///
+#[get("/rate-limits")]
+pub fn rate_limit_statistics(
+    user: OidcUser,
+    statistics: &State<RateLimitStatistics>,
+) -> Result<Json<Vec<GroupRejections>>, Status> {
+    if !statistics.is_admin(&user) {
+        return Err(Status::Forbidden);
+    }
+    Ok(Json(statistics.rejections()))
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPFirewall - Rate Limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Executed on every request. Takes a token from the bucket of the client in the first matching group and reroutes the request to the route of rejected requests if there is none
+    ///
+    /// # Arguments
+    /// * `self` - Struct Rate Limiter
+    /// * `req` - Request object
+    ///
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let path = req.uri().path().to_string();
+        let Some(group) = self.groups.iter().find(|group| group.paths.is_match(&path)) else {
+            return;
+        };
+        let key = group.client_key(req).await;
+        let state = self.store.take(&key, &group.limit, now()).await;
+        let decision = RateLimitDecision {
+            limit: group.limit,
+            state,
+        };
+        req.local_cache(|| RateLimitedRequest(Some(decision)));
+        if state.allowed {
+            return;
+        }
+        let rejections = group.rejections.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut security_event = SecurityEvent::new(
+            SecurityEventType::RateLimitExceeded,
+            SecuritySeverity::Warning,
+            SecurityOutcome::Rejected,
+            format!(
+                "Rate limit of bucket {} exceeded by {} {} ({} rejections)",
+                key,
+                req.method(),
+                req.uri(),
+                rejections
+            ),
+        )
+        .with_request(req)
+        .with_rule(group.name.clone());
+        // the user is only known if the key needed the authentication, which is cached for the request
+        if req.local_cache(|| AuthenticatedRequest(false)).0
+            && let Outcome::Success(user) = req.guard::<OidcUser>().await
+        {
+            security_event = security_event.with_subject(user.subject.as_str());
+        }
+        SecurityEventLog::of(req).record(security_event);
+        req.set_method(Method::Get);
+        req.set_uri(Origin::parse(RATE_LIMITED_PATH).unwrap());
+    }
+
+    /// Executed on every response. Adds the RateLimit-* headers if a group applied to the request
+    ///
+    /// # Arguments
+    /// * `self` - Struct Rate Limiter
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(decision) = req.local_cache(|| RateLimitedRequest(None)).0 {
+            for header in decision.headers() {
+                res.set_header(header);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::configuration::config::{CustomAppCookiesConfig, CustomAppKeyRingConfig};
+    use crate::oidc::cookies::OidcCookies;
+    use crate::oidc::keyring::CookieKeyRing;
+
+    #[post("/order")]
+    fn order() -> &'static str {
+        "ordered"
+    }
+
+    #[get("/inventory")]
+    fn inventory() -> &'static str {
+        "inventory"
+    }
+
+    fn group(name: &str, path: &str, key: &str, capacity: u32) -> CustomAppRateLimitGroupConfig {
+        CustomAppRateLimitGroupConfig {
+            name: name.to_string(),
+            paths: vec![path.to_string()],
+            key: Some(key.to_string()),
+            capacity,
+            refill_per_second: 0.5,
+        }
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limit_per_group() {
+        let rate_limiter = RateLimiter::new(&CustomAppRateLimitConfig {
+            groups: Some(vec![
+                group("orders", "^/ui-api/order", "ip", 2),
+                group("api", "^/ui-api/", "token", 1),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        let statistics = rate_limiter.statistics();
+        let (security_event_log, security_events) = SecurityEventLog::capture();
+        // tokens are only validated by the OIDC request guard
+        let rocket = rocket::build()
+            .manage(security_event_log)
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .attach(rate_limiter)
+            .mount("/ui-api", routes![order, inventory])
+            .mount("/httpfirewall", routes![rate_limited]);
+        let client = Client::tracked(rocket).unwrap();
+        let remote = "192.0.2.1:50000".parse().unwrap();
+
+        for remaining in ["1", "0"] {
+            let response = client.post("/ui-api/order").remote(remote).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("RateLimit-Limit"), Some("2"));
+            assert_eq!(
+                response.headers().get_one("RateLimit-Remaining"),
+                Some(remaining)
+            );
+        }
+        let response = client.post("/ui-api/order").remote(remote).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("2"));
+        assert_eq!(response.headers().get_one("RateLimit-Reset"), Some("4"));
+        let security_event = security_events.lock().unwrap().pop().unwrap();
+        assert_eq!(security_event.client_ip.as_deref(), Some("192.0.2.1"));
+        assert_eq!(security_event.subject, None);
+        assert_eq!(security_event.rule_id.as_deref(), Some("orders"));
+        assert!(security_event.message.contains("orders:ip:192.0.2.1"));
+        // Rocket's ip_header does not get a new bucket
+        let response = client
+            .post("/ui-api/order")
+            .remote(remote)
+            .header(Header::new("X-Real-IP", "192.0.2.99"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let other = "192.0.2.2:50000".parse().unwrap();
+        let response = client.post("/ui-api/order").remote(other).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let token = Header::new("Authorization", "Bearer a");
+        let response = client
+            .get("/ui-api/inventory")
+            .remote(remote)
+            .header(token.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/ui-api/inventory")
+            .remote(remote)
+            .header(token)
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        // tokens that did not authenticate the request count against the IP address
+        let response = client
+            .get("/ui-api/inventory")
+            .remote(remote)
+            .header(Header::new("Authorization", "Bearer b"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client
+            .get("/ui-api/inventory")
+            .remote(other)
+            .header(Header::new("Authorization", "Bearer b"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get(RATE_LIMITED_PATH).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            statistics.rejections(),
+            vec![
+                GroupRejections {
+                    group: "orders".to_string(),
+                    rejections: 2,
+                },
+                GroupRejections {
+                    group: "api".to_string(),
+                    rejections: 2,
+                },
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn refills_buckets_and_removes_full_ones() {
+        let store = MemoryRateLimitStore::new(1);
+        let limit = BucketLimit {
+            capacity: 2,
+            refill_per_second: 1.0,
+        };
+        assert!(store.take("a", &limit, 100.0).await.allowed);
+        assert!(store.take("a", &limit, 100.0).await.allowed);
+        assert!(!store.take("a", &limit, 100.5).await.allowed);
+        let state = store.take("a", &limit, 101.0).await;
+        assert!(state.allowed);
+        assert_eq!(state.tokens, 0.0);
+        // a is full again at 103
+        assert!(store.take("b", &limit, 103.0).await.allowed);
+        assert_eq!(store.buckets.lock().unwrap().len(), 1);
+    }
+}
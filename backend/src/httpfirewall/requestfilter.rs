@@ -0,0 +1,634 @@
+//! Rocket fairing that filters requests with configurable firewall rules
+//!
+//! Each request gets a request ID that is recorded in the security events of the rules it matched and returned in a response header. A rule matches if one of its conditions matches: a method that is not allowed, a path that matches a regular expression (e.g. encoded path traversal or null bytes), a URI, query, header or body that is too long, a forbidden header value or a suspicious user agent
+//! The action of a rule decides what happens: block reroutes the request to an internal route that responds with 403 Forbidden, so that the handler of the original route is not executed, log only logs it and tag logs it and adds a tag that routes can read
+//! Before the rules, the firewall derives the IP address of the client behind trusted reverse proxies and blocks clients outside of the IP access lists. The derived address replaces the peer of the connection, so that rate limiting, routes and logs use it as well
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use regex::{Regex, RegexSet};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+
+use crate::configuration::config::{CustomAppFirewallConfig, CustomAppFirewallRuleConfig};
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+
+use super::clientip::{ClientIpResolver, IpAccessList};
+
+pub const BLOCKED_PATH: &str = "/httpfirewall/blocked";
+// Rocket reads at most 512 bytes of the body ahead of the route
+const BODY_PEEK_LIMIT: u64 = 512;
+const DEFAULT_REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// Errors in the firewall configuration
+#[derive(Debug)]
+pub enum RequestFilterConfigError {
+    EmptyRuleName,
+    DuplicateRuleName(String),
+    InvalidAction(String),
+    InvalidMethod(String),
+    InvalidRegex(String),
+    RuleWithoutCondition(String),
+    EmptyRequestIdHeader,
+    InvalidNetwork(String),
+}
+
+// What happens if a rule matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterAction {
+    Block,
+    Log,
+    Tag,
+}
+
+// Firewall rule with its conditions
+#[derive(Debug)]
+struct FilterRule {
+    name: String,
+    action: FilterAction,
+    tag: String,
+    methods: Option<Vec<Method>>,
+    paths: Option<RegexSet>,
+    max_uri_length: Option<usize>,
+    max_query_length: Option<usize>,
+    max_header_length: Option<usize>,
+    max_body_size: Option<u64>,
+    // forbidden values by header name
+    headers: Vec<(String, Regex)>,
+    user_agents: Option<RegexSet>,
+}
+
+// ID of the current request, e.g. to correlate log entries
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Tags that rules with the action tag added to the current request
+#[derive(Debug, Clone, Default)]
+pub struct FirewallTags(pub Vec<String>);
+
+// Marks a request that was blocked by a rule
+struct BlockedRequest(bool);
+
+// Request that was blocked by a rule
+pub struct FirewallRejection;
+
+// Request firewall. It is always attached, so that each request gets a request ID
+pub struct RequestFilter {
+    rules: Vec<FilterRule>,
+    request_id_header: String,
+    client_ip: ClientIpResolver,
+    ip_access: Vec<IpAccessList>,
+}
+
+impl RequestFilter {
+    /// Creates the firewall from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Firewall configuration of the application
+    ///
+    /// # Returns
+    /// Firewall or an error if a rule, a network or the request ID header is invalid
+    ///
+    pub fn new(
+        config: &CustomAppFirewallConfig,
+    ) -> Result<RequestFilter, RequestFilterConfigError> {
+        let request_id_header = config
+            .request_id_header
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REQUEST_ID_HEADER.to_string());
+        if request_id_header.is_empty() {
+            return Err(RequestFilterConfigError::EmptyRequestIdHeader);
+        }
+        let mut rules: Vec<FilterRule> = Vec::new();
+        for rule in config.rules.iter().flatten() {
+            if rule.name.is_empty() {
+                return Err(RequestFilterConfigError::EmptyRuleName);
+            }
+            if rules.iter().any(|existing| existing.name == rule.name) {
+                return Err(RequestFilterConfigError::DuplicateRuleName(
+                    rule.name.clone(),
+                ));
+            }
+            rules.push(FilterRule::new(rule)?);
+        }
+        let client_ip =
+            ClientIpResolver::new(config.trusted_proxies.as_deref().unwrap_or_default())
+                .map_err(RequestFilterConfigError::InvalidNetwork)?;
+        let ip_access = config
+            .ip_access
+            .iter()
+            .flatten()
+            .map(IpAccessList::new)
+            .collect::<Result<Vec<IpAccessList>, String>>()
+            .map_err(RequestFilterConfigError::InvalidNetwork)?;
+        Ok(RequestFilter {
+            rules,
+            request_id_header,
+            client_ip,
+            ip_access,
+        })
+    }
+}
+
+impl FilterRule {
+    fn new(config: &CustomAppFirewallRuleConfig) -> Result<FilterRule, RequestFilterConfigError> {
+        let invalid_regex = |err: regex::Error| {
+            RequestFilterConfigError::InvalidRegex(format!("{}: {}", config.name, err))
+        };
+        let action = match config.action.as_deref() {
+            None | Some("block") => FilterAction::Block,
+            Some("log") => FilterAction::Log,
+            Some("tag") => FilterAction::Tag,
+            Some(action) => {
+                return Err(RequestFilterConfigError::InvalidAction(format!(
+                    "{}: {}",
+                    config.name, action
+                )));
+            }
+        };
+        let methods = match &config.methods {
+            Some(methods) => Some(
+                methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_str(method).map_err(|_| {
+                            RequestFilterConfigError::InvalidMethod(format!(
+                                "{}: {}",
+                                config.name, method
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<Method>, RequestFilterConfigError>>()?,
+            ),
+            None => None,
+        };
+        let paths = match &config.paths {
+            Some(paths) => Some(RegexSet::new(paths).map_err(invalid_regex)?),
+            None => None,
+        };
+        let user_agents = match &config.user_agents {
+            Some(user_agents) => Some(RegexSet::new(user_agents).map_err(invalid_regex)?),
+            None => None,
+        };
+        let mut headers = Vec::new();
+        for (name, value) in config.headers.iter().flatten() {
+            headers.push((name.clone(), Regex::new(value).map_err(invalid_regex)?));
+        }
+        let rule = FilterRule {
+            name: config.name.clone(),
+            action,
+            tag: config.tag.clone().unwrap_or_else(|| config.name.clone()),
+            methods,
+            paths,
+            max_uri_length: config.max_uri_length,
+            max_query_length: config.max_query_length,
+            max_header_length: config.max_header_length,
+            max_body_size: config.max_body_size,
+            headers,
+            user_agents,
+        };
+        let has_condition = rule.methods.is_some()
+            || rule.paths.is_some()
+            || rule.max_uri_length.is_some()
+            || rule.max_query_length.is_some()
+            || rule.max_header_length.is_some()
+            || rule.max_body_size.is_some()
+            || !rule.headers.is_empty()
+            || rule.user_agents.is_some();
+        if !has_condition {
+            return Err(RequestFilterConfigError::RuleWithoutCondition(rule.name));
+        }
+        Ok(rule)
+    }
+
+    /// Checks the conditions of the rule
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    /// * `data` - Body of the request (only read ahead for bodies without Content-Length)
+    ///
+    /// # Returns
+    /// Description of the first condition that matches or None if the request does not match the rule
+    ///
+    async fn matches(&self, req: &Request<'_>, data: &mut Data<'_>) -> Option<String> {
+        if let Some(methods) = &self.methods
+            && !methods.contains(&req.method())
+        {
+            return Some(format!("method {} is not allowed", req.method()));
+        }
+        // the URI as sent by the client, ie not percent-decoded
+        let uri = req.uri().to_string();
+        if let Some(max_uri_length) = self.max_uri_length
+            && uri.len() > max_uri_length
+        {
+            return Some(format!("URI is longer than {}", max_uri_length));
+        }
+        if let Some(max_query_length) = self.max_query_length
+            && let Some(query) = req.uri().query()
+            && query.as_str().len() > max_query_length
+        {
+            return Some(format!("query is longer than {}", max_query_length));
+        }
+        // only the path, so that the query cannot match or defeat anchors (e.g. ^/admin$)
+        if let Some(paths) = &self.paths
+            && paths.is_match(req.uri().path().as_str())
+        {
+            return Some("path matches".to_string());
+        }
+        if let Some(max_header_length) = self.max_header_length
+            && let Some(header) = req
+                .headers()
+                .iter()
+                .find(|header| header.name().len() + header.value().len() > max_header_length)
+        {
+            return Some(format!(
+                "header {} is longer than {}",
+                header.name(),
+                max_header_length
+            ));
+        }
+        if let Some(max_body_size) = self.max_body_size
+            && is_body_larger(req, data, max_body_size).await
+        {
+            return Some(format!("body is larger than {}", max_body_size));
+        }
+        for (name, forbidden) in &self.headers {
+            if req
+                .headers()
+                .get(name)
+                .any(|value| forbidden.is_match(value))
+            {
+                return Some(format!("header {} has a forbidden value", name));
+            }
+        }
+        if let Some(user_agents) = &self.user_agents
+            && req
+                .headers()
+                .get("User-Agent")
+                .any(|user_agent| user_agents.is_match(user_agent))
+        {
+            return Some("user agent is suspicious".to_string());
+        }
+        None
+    }
+}
+
+/// Checks the size of the body against the Content-Length. Bodies without Content-Length (e.g. chunked uploads) are read ahead, but Rocket reads at most 512 bytes ahead, so larger bodies of unknown size count as too large
+///
+/// # Arguments
+/// * `req` - Request object
+/// * `data` - Body of the request
+/// * `max_body_size` - Maximum size in bytes
+///
+/// # Returns
+/// true if the body is or may be larger than the maximum size
+///
+async fn is_body_larger(req: &Request<'_>, data: &mut Data<'_>, max_body_size: u64) -> bool {
+    if let Some(length) = req.headers().get_one("Content-Length") {
+        return length
+            .parse::<u64>()
+            .map_or(true, |length| length > max_body_size);
+    }
+    let peek_size = max_body_size.saturating_add(1).min(BODY_PEEK_LIMIT);
+    let peeked = data.peek(peek_size as usize).await.len() as u64;
+    peeked > max_body_size || (peeked == peek_size && !data.peek_complete())
+}
+
+// Implementation of the request guard for the ID of the current request
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    /// Returns the ID that the firewall assigned to the request
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(request_id(req))
+    }
+}
+
+// Implementation of the request guard for the tags of the current request
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FirewallTags {
+    type Error = ();
+
+    /// Returns the tags that rules added to the request
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(FirewallTags::default).clone())
+    }
+}
+
+// Implementation of the request guard for the route of blocked requests
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FirewallRejection {
+    type Error = ();
+
+    /// Forwards requests that were not blocked, e.g. if a client requests the route directly
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.local_cache(|| BlockedRequest(false)).0 {
+            true => Outcome::Success(FirewallRejection),
+            false => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Returns the ID of a request. It is created when it is read for the first time
+///
+/// # Arguments
+/// * `req` - Request object
+///
+pub fn request_id(req: &Request<'_>) -> RequestId {
+    req.local_cache(|| RequestId(uuid::Uuid::new_v4().to_string()))
+        .clone()
+}
+
+/// Responds to a request that was blocked by a firewall rule
+///
+/// # Arguments
+/// * `_rejection` - Rejection of the firewall (no access for requests that were not blocked)
+///
+/// # Returns
+/// 403 Forbidden
+///
+#[get("/blocked")]
+pub fn blocked(_rejection: FirewallRejection) -> Status {
+    Status::Forbidden
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for RequestFilter {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPFirewall - Request Filter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Executed on every request. Assigns the request ID, derives the IP address of the client, checks the IP access lists and applies the action of all rules that match. A request that is blocked is rerouted to the route of blocked requests
+    ///
+    /// # Arguments
+    /// * `self` - Struct Request Filter
+    /// * `req` - Request object
+    ///
+    async fn on_request(&self, req: &mut Request<'_>, data: &mut Data<'_>) {
+        // assign the request ID before any security event is recorded
+        request_id(req);
+        let client_ip = self.client_ip.resolve(req);
+        if let Some(ip) = client_ip {
+            let port = req.remote().map_or(0, |remote| remote.port());
+            req.set_remote(SocketAddr::new(ip, port));
+        }
+        // Rocket prefers the configured ip_header, which any client can set. It is overwritten with the resolved address (or an empty value that Rocket ignores), so that client_ip() returns the same address as remote()
+        if let Some(ip_header) = &req.rocket().config().ip_header {
+            req.replace_header(Header::new(
+                ip_header.as_str().to_string(),
+                client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            ));
+        }
+        // the access lists use the peer (or the address derived behind trusted proxies), never Rocket's ip_header, which any client can set
+        let client_ip = req.remote().map(|remote| remote.ip());
+        let security_event_log = SecurityEventLog::of(req);
+        let mut tags: Vec<String> = Vec::new();
+        let mut block = false;
+        if self
+            .ip_access
+            .iter()
+            .any(|access| !access.permits(client_ip, req.uri().path().as_str()))
+        {
+            security_event_log.record(
+                SecurityEvent::new(
+                    SecurityEventType::IpAccessDenied,
+                    SecuritySeverity::Warning,
+                    SecurityOutcome::Blocked,
+                    format!(
+                        "Blocked {} {} from an IP address outside of the access lists",
+                        req.method(),
+                        req.uri()
+                    ),
+                )
+                .with_request(req),
+            );
+            block = true;
+        }
+        for rule in self.rules.iter().filter(|_| !block) {
+            let Some(condition) = rule.matches(req, data).await else {
+                continue;
+            };
+            let (severity, outcome) = match rule.action {
+                FilterAction::Block => (SecuritySeverity::Warning, SecurityOutcome::Blocked),
+                FilterAction::Log => (SecuritySeverity::Warning, SecurityOutcome::Logged),
+                FilterAction::Tag => (SecuritySeverity::Info, SecurityOutcome::Tagged),
+            };
+            security_event_log.record(
+                SecurityEvent::new(
+                    SecurityEventType::FirewallRule,
+                    severity,
+                    outcome,
+                    format!("{} {}: {}", req.method(), req.uri(), condition),
+                )
+                .with_request(req)
+                .with_rule(rule.name.clone()),
+            );
+            match rule.action {
+                FilterAction::Block => {
+                    block = true;
+                    break;
+                }
+                FilterAction::Log => (),
+                FilterAction::Tag => {
+                    if !tags.contains(&rule.tag) {
+                        tags.push(rule.tag.clone());
+                    }
+                }
+            }
+        }
+        req.local_cache(|| FirewallTags(tags));
+        if block {
+            req.local_cache(|| BlockedRequest(true));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(BLOCKED_PATH).unwrap());
+        }
+    }
+
+    /// Executed on every response. Adds the request ID header
+    ///
+    /// # Arguments
+    /// * `self` - Struct Request Filter
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_header(Header::new(
+            self.request_id_header.clone(),
+            request_id(req).0,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[get("/<_path..>")]
+    fn tags(_path: std::path::PathBuf, tags: FirewallTags) -> String {
+        tags.0.join(",")
+    }
+
+    #[post("/order")]
+    fn order() -> &'static str {
+        "ordered"
+    }
+
+    fn rule(name: &str, action: &str) -> CustomAppFirewallRuleConfig {
+        CustomAppFirewallRuleConfig {
+            name: name.to_string(),
+            action: Some(action.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn applies_actions_of_matching_rules() {
+        let request_filter = RequestFilter::new(&CustomAppFirewallConfig {
+            rules: Some(vec![
+                CustomAppFirewallRuleConfig {
+                    methods: Some(vec!["GET".to_string(), "POST".to_string()]),
+                    ..rule("methods", "block")
+                },
+                CustomAppFirewallRuleConfig {
+                    paths: Some(vec!["(?i)%2e%2e|%00".to_string()]),
+                    ..rule("traversal", "block")
+                },
+                CustomAppFirewallRuleConfig {
+                    max_body_size: Some(8),
+                    ..rule("body", "block")
+                },
+                CustomAppFirewallRuleConfig {
+                    headers: Some(HashMap::from([(
+                        "X-Forwarded-Host".to_string(),
+                        "[^a-z.]".to_string(),
+                    )])),
+                    ..rule("host", "block")
+                },
+                CustomAppFirewallRuleConfig {
+                    user_agents: Some(vec!["(?i)sqlmap|nikto".to_string()]),
+                    tag: Some("scanner".to_string()),
+                    ..rule("scanners", "tag")
+                },
+                CustomAppFirewallRuleConfig {
+                    max_query_length: Some(4),
+                    ..rule("query", "log")
+                },
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        let (security_event_log, security_events) = SecurityEventLog::capture();
+        let rocket = rocket::build()
+            .manage(security_event_log)
+            .attach(request_filter)
+            .mount("/", routes![tags, order])
+            .mount("/httpfirewall", routes![blocked]);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/ui/inventory?page=12345").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let first_id = response
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_string();
+        assert_eq!(first_id.len(), 36);
+        let response = client
+            .get("/ui/inventory")
+            .header(Header::new("User-Agent", "sqlmap/1.8"))
+            .dispatch();
+        assert_ne!(
+            response.headers().get_one("X-Request-Id"),
+            Some(first_id.as_str())
+        );
+        assert_eq!(response.into_string().unwrap(), "scanner");
+        {
+            let security_events = security_events.lock().unwrap();
+            assert_eq!(security_events.len(), 2);
+            assert_eq!(security_events[0].rule_id.as_deref(), Some("query"));
+            assert_eq!(security_events[0].outcome, SecurityOutcome::Logged);
+            assert_eq!(security_events[0].request_id, Some(first_id.clone()));
+            assert_eq!(security_events[1].rule_id.as_deref(), Some("scanners"));
+            assert_eq!(security_events[1].outcome, SecurityOutcome::Tagged);
+            assert_eq!(security_events[1].severity, SecuritySeverity::Info);
+        }
+
+        // path rules do not match the query
+        let response = client.get("/ui/inventory?file=%2e%2e").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        security_events.lock().unwrap().clear();
+
+        let blocked_requests = [
+            client.delete("/order"),
+            client.get("/static/%2E%2E/Rocket.toml"),
+            client.get("/ui/a%00b"),
+            client
+                .post("/order")
+                .header(Header::new("Content-Length", "10"))
+                .body("0123456789"),
+            // body without Content-Length, e.g. a chunked upload
+            client.post("/order").body("0123456789"),
+            client
+                .get("/ui/inventory")
+                .header(Header::new("X-Forwarded-Host", "evil.example.com:1/")),
+        ];
+        for request in blocked_requests {
+            let response = request.dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            assert!(response.headers().contains("X-Request-Id"));
+            let security_event = security_events.lock().unwrap().pop().unwrap();
+            assert_eq!(security_event.event_type, SecurityEventType::FirewallRule);
+            assert_eq!(security_event.outcome, SecurityOutcome::Blocked);
+            assert_eq!(
+                security_event.request_id.as_deref(),
+                response.headers().get_one("X-Request-Id")
+            );
+        }
+        let response = client
+            .post("/order")
+            .header(Header::new("Content-Length", "8"))
+            .body("01234567")
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "ordered");
+        let response = client.post("/order").body("0123").dispatch();
+        assert_eq!(response.into_string().unwrap(), "ordered");
+        let response = client.get(BLOCKED_PATH).dispatch();
+        assert_eq!(response.into_string().unwrap(), "");
+
+        assert!(matches!(
+            RequestFilter::new(&CustomAppFirewallConfig {
+                rules: Some(vec![rule("empty", "block")]),
+                ..Default::default()
+            }),
+            Err(RequestFilterConfigError::RuleWithoutCondition(..))
+        ));
+    }
+}
@@ -1,44 +1,723 @@
 //! Rocket fairing to inject HTTP security headers, such as content security policies, into responses
+//!
+//! Responses get the headers of the first header profile that matches their path and content type or the default headers if no profile matches
+//! Each header is set independently of the others on all response statuses, unless a status policy restricts it to specific statuses
 
+use std::collections::HashMap;
 use std::io;
+use std::ops::RangeInclusive;
 
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use rand::prelude::*;
+use regex::RegexSet;
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Status;
-use rocket::{Data, Request, Response};
+use rocket::http::{ContentType, Status};
+use rocket::{Request, Response};
 use tracing::{Level, event};
 
+use crate::configuration::config::{
+    CustomAppHttpHeaderValuesConfig, CustomAppHttpHeadersConfig, CustomAppHttpHeadersProfileConfig,
+    CustomAppStrictTransportSecurityConfig,
+};
+use crate::oidc::guard::AuthenticatedRequest;
+
+use super::contentsecuritypolicy::{ContentSecurityPolicy, CspConfigError};
+use super::noncerewriter::{NonceRewriter, NonceRewriterStream};
+use super::subresourceintegrity::SubresourceIntegrity;
+
+const DEFAULT_PROFILE_NAME: &str = "default";
+
+pub const CONTENT_SECURITY_POLICY: &str = "Content-Security-Policy";
+pub const CONTENT_SECURITY_POLICY_REPORT_ONLY: &str = "Content-Security-Policy-Report-Only";
+pub const PERMISSIONS_POLICY: &str = "Permissions-Policy";
+pub const REFERRER_POLICY: &str = "Referrer-Policy";
+pub const CROSS_ORIGIN_EMBEDDER_POLICY: &str = "Cross-Origin-Embedder-Policy";
+pub const CROSS_ORIGIN_OPENER_POLICY: &str = "Cross-Origin-Opener-Policy";
+pub const CROSS_ORIGIN_RESOURCE_POLICY: &str = "Cross-Origin-Resource-Policy";
+pub const STRICT_TRANSPORT_SECURITY: &str = "Strict-Transport-Security";
+pub const X_CONTENT_TYPE_OPTIONS: &str = "X-Content-Type-Options";
+pub const REPORTING_ENDPOINTS: &str = "Reporting-Endpoints";
+pub const CACHE_CONTROL: &str = "Cache-Control";
+
+// All headers set by the fairing in the order they are set
+const HEADERS: [&str; 11] = [
+    CONTENT_SECURITY_POLICY,
+    CONTENT_SECURITY_POLICY_REPORT_ONLY,
+    PERMISSIONS_POLICY,
+    REFERRER_POLICY,
+    CROSS_ORIGIN_EMBEDDER_POLICY,
+    CROSS_ORIGIN_OPENER_POLICY,
+    CROSS_ORIGIN_RESOURCE_POLICY,
+    STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS,
+    REPORTING_ENDPOINTS,
+    CACHE_CONTROL,
+];
+
+// Minimum max-age for the HSTS preload list
+const STRICT_TRANSPORT_SECURITY_PRELOAD_MIN_MAX_AGE: u64 = 31536000;
+
+// Errors in the HTTP headers configuration
+#[derive(Debug)]
+pub enum HttpHeadersConfigError {
+    InvalidPathRegex(String, String),
+    InvalidContentType(String, String),
+    EmptyProfileName,
+    DuplicateProfileName(String),
+    ProfileWithoutMatcher(String),
+    NonceTagsRequired(String),
+    NonceHeadersRequired(String),
+    InvalidStrictTransportSecurity(String, String),
+    UnknownStatusPolicyHeader(String, String),
+    InvalidStatusPolicy(String, String),
+    InvalidContentSecurityPolicy(String, CspConfigError),
+}
+
+// Statuses of responses on which a header is set, e.g. ["2xx", "304"]
+#[derive(Debug, Clone)]
+pub struct StatusPolicy {
+    pub statuses: Vec<String>,
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+// Headers that apply to responses matching paths and content types. The headers are resolved, ie they contain the inherited default headers
+#[derive(Debug, Clone)]
+pub struct HeaderProfile {
+    pub name: String,
+    paths: RegexSet,
+    content_types: Vec<ContentType>,
+    pub headers: CustomAppHttpHeaderValuesConfig,
+    pub content_security_policy: Option<ContentSecurityPolicy>,
+    // separate policy sent as Content-Security-Policy-Report-Only alongside the policy
+    pub content_security_policy_report_only: Option<ContentSecurityPolicy>,
+    nonce_paths: RegexSet,
+    // key is the header name in lower case
+    status_policies: HashMap<String, StatusPolicy>,
+}
+
 // Configuration of Fairing
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct SecurityHttpHeaders {
-    pub config: crate::configuration::config::CustomAppConfig,
-    pub regex_paths: regex::RegexSet,
+    pub profiles: Vec<HeaderProfile>,
+    pub default_profile: HeaderProfile,
 }
 
-// Fairing implementation
-#[rocket::async_trait]
-impl Fairing for SecurityHttpHeaders {
-    fn info(&self) -> Info {
-        Info {
-            name: "HTTPFirewall - Security HTTP Headers",
-            kind: Kind::Request | Kind::Response | Kind::Singleton,
+impl SecurityHttpHeaders {
+    /// Creates the fairing with the default headers and header profiles
+    ///
+    /// # Arguments
+    /// * `config` - HTTP headers configuration of the application
+    ///
+    /// # Returns
+    /// Fairing or an error if a regular expression, content type, profile name, header value or status policy is invalid
+    ///
+    pub fn new(
+        config: &CustomAppHttpHeadersConfig,
+    ) -> Result<SecurityHttpHeaders, HttpHeadersConfigError> {
+        let default_profile = HeaderProfile::new(
+            DEFAULT_PROFILE_NAME,
+            &[],
+            &[],
+            inherit_headers(&config.headers, &CustomAppHttpHeaderValuesConfig::default()),
+        )?;
+        let mut profiles: Vec<HeaderProfile> = Vec::new();
+        for profile in config.profiles.clone().unwrap_or_default() {
+            if profile.name.is_empty() {
+                return Err(HttpHeadersConfigError::EmptyProfileName);
+            }
+            if profile.name == DEFAULT_PROFILE_NAME
+                || profiles
+                    .iter()
+                    .any(|existing| existing.name == profile.name)
+            {
+                return Err(HttpHeadersConfigError::DuplicateProfileName(profile.name));
+            }
+            profiles.push(HeaderProfile::from_config(&profile, &config.headers)?);
         }
+        Ok(SecurityHttpHeaders {
+            profiles,
+            default_profile,
+        })
     }
 
-    /// Executed on every request (we do not need it, but it is part of the Fairing trait)
+    /// Returns the header profile for a response
     ///
     /// # Arguments
-    /// * `self` - Struct Security HTTP Headers
-    /// * `req` - Request object
+    /// * `path` - Path of the request
+    /// * `content_type` - Content type of the response (if any)
     ///
+    /// # Returns
+    /// The first profile that matches or the default profile
     ///
-    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
-        // do nothing
+    pub fn profile(&self, path: &str, content_type: Option<&ContentType>) -> &HeaderProfile {
+        self.profiles
+            .iter()
+            .find(|profile| profile.matches(path, content_type))
+            .unwrap_or(&self.default_profile)
+    }
+
+    /// Adds a report endpoint to the Reporting-Endpoints of all profiles and lets their Content-Security-Policy report to it
+    ///
+    /// # Arguments
+    /// * `name` - Name of the endpoint
+    /// * `url` - URL of the endpoint
+    ///
+    /// # Returns
+    /// Fairing with the report endpoint
+    ///
+    pub fn with_report_endpoint(mut self, name: &str, url: &str) -> SecurityHttpHeaders {
+        for profile in self
+            .profiles
+            .iter_mut()
+            .chain(std::iter::once(&mut self.default_profile))
+        {
+            profile.add_report_endpoint(name, url);
+        }
+        self
+    }
+}
+
+impl StatusPolicy {
+    /// Parses the statuses of a status policy
+    ///
+    /// # Arguments
+    /// * `statuses` - "all", classes (e.g. "2xx") or single statuses (e.g. "404")
+    ///
+    /// # Returns
+    /// Status policy or the status that cannot be parsed
+    ///
+    fn new(statuses: &[String]) -> Result<StatusPolicy, String> {
+        let ranges = statuses
+            .iter()
+            .map(|status| {
+                let status = status.trim().to_lowercase();
+                if status == "all" {
+                    return Ok(100..=599);
+                }
+                if let Some(class) = status.strip_suffix("xx")
+                    && let Ok(class @ 1..=5) = class.parse::<u16>()
+                {
+                    return Ok(class * 100..=class * 100 + 99);
+                }
+                match status.parse::<u16>() {
+                    Ok(code @ 100..=599) => Ok(code..=code),
+                    _ => Err(status),
+                }
+            })
+            .collect::<Result<Vec<RangeInclusive<u16>>, String>>()?;
+        Ok(StatusPolicy {
+            statuses: statuses.to_vec(),
+            ranges,
+        })
+    }
+
+    /// Returns true if a header with this policy is set on responses with the status
+    pub fn covers(&self, status: Status) -> bool {
+        self.ranges.iter().any(|range| range.contains(&status.code))
+    }
+}
+
+impl HeaderProfile {
+    fn new(
+        name: &str,
+        paths: &[String],
+        content_types: &[String],
+        headers: CustomAppHttpHeaderValuesConfig,
+    ) -> Result<HeaderProfile, HttpHeadersConfigError> {
+        let paths = regex_set(name, paths)?;
+        let nonce_paths = regex_set(
+            name,
+            &headers
+                .content_security_policy_inject_nonce_paths
+                .clone()
+                .unwrap_or_default(),
+        )?;
+        let (content_security_policy, content_security_policy_report_only) =
+            ContentSecurityPolicy::from_config(&headers)
+                .and_then(|content_security_policy| {
+                    let report_only = ContentSecurityPolicy::report_only_from_config(&headers)?;
+                    // only one policy can be sent as Content-Security-Policy-Report-Only
+                    match &content_security_policy {
+                        Some(policy) if policy.report_only && report_only.is_some() => {
+                            Err(CspConfigError::DuplicateReportOnly)
+                        }
+                        _ => Ok((content_security_policy, report_only)),
+                    }
+                })
+                .map_err(|err| {
+                    HttpHeadersConfigError::InvalidContentSecurityPolicy(name.to_string(), err)
+                })?;
+        let mut policies = content_security_policy
+            .iter()
+            .chain(content_security_policy_report_only.iter())
+            .peekable();
+        if !nonce_paths.is_empty() && policies.peek().is_some() {
+            if headers.content_security_policy_inject_nonce_tags.is_none() {
+                return Err(HttpHeadersConfigError::NonceTagsRequired(name.to_string()));
+            }
+            // the nonce placeholder marks the directives for nonces
+            if headers.content_security_policy_nonce_headers.is_none()
+                && !policies.any(ContentSecurityPolicy::has_nonce_placeholder)
+            {
+                return Err(HttpHeadersConfigError::NonceHeadersRequired(
+                    name.to_string(),
+                ));
+            }
+        }
+        if let Some(strict_transport_security) = &headers.strict_transport_security {
+            validate_strict_transport_security(name, strict_transport_security)?;
+        }
+        let content_types = content_types
+            .iter()
+            .map(|content_type| {
+                content_type.parse::<ContentType>().map_err(|_| {
+                    HttpHeadersConfigError::InvalidContentType(
+                        name.to_string(),
+                        content_type.clone(),
+                    )
+                })
+            })
+            .collect::<Result<Vec<ContentType>, HttpHeadersConfigError>>()?;
+        let mut status_policies = HashMap::new();
+        for (header, statuses) in headers.status_policies.clone().unwrap_or_default() {
+            if !HEADERS
+                .iter()
+                .any(|known_header| known_header.eq_ignore_ascii_case(&header))
+            {
+                return Err(HttpHeadersConfigError::UnknownStatusPolicyHeader(
+                    name.to_string(),
+                    header,
+                ));
+            }
+            let status_policy = StatusPolicy::new(&statuses).map_err(|status| {
+                HttpHeadersConfigError::InvalidStatusPolicy(name.to_string(), status)
+            })?;
+            status_policies.insert(header.to_lowercase(), status_policy);
+        }
+        Ok(HeaderProfile {
+            name: name.to_string(),
+            paths,
+            content_types,
+            headers,
+            content_security_policy,
+            content_security_policy_report_only,
+            nonce_paths,
+            status_policies,
+        })
+    }
+
+    fn from_config(
+        config: &CustomAppHttpHeadersProfileConfig,
+        default_headers: &CustomAppHttpHeaderValuesConfig,
+    ) -> Result<HeaderProfile, HttpHeadersConfigError> {
+        let paths = config.paths.clone().unwrap_or_default();
+        let content_types = config.content_types.clone().unwrap_or_default();
+        if paths.is_empty() && content_types.is_empty() {
+            return Err(HttpHeadersConfigError::ProfileWithoutMatcher(
+                config.name.clone(),
+            ));
+        }
+        HeaderProfile::new(
+            &config.name,
+            &paths,
+            &content_types,
+            inherit_headers(&config.headers, default_headers),
+        )
+    }
+
+    /// Returns true if the profile applies to a response. Paths and content types that are not configured match all responses
+    ///
+    /// # Arguments
+    /// * `path` - Path of the request
+    /// * `content_type` - Content type of the response (if any)
+    ///
+    pub fn matches(&self, path: &str, content_type: Option<&ContentType>) -> bool {
+        let path_matches = self.paths.is_empty() || self.paths.is_match(path);
+        let content_type_matches = self.content_types.is_empty()
+            || content_type.is_some_and(|content_type| {
+                self.content_types.iter().any(|expected| {
+                    expected.top() == content_type.top()
+                        && (expected.sub() == "*" || expected.sub() == content_type.sub())
+                })
+            });
+        path_matches && content_type_matches
+    }
+
+    /// Returns the policy and the separate report-only policy of the profile, if configured
+    pub fn content_security_policies(&self) -> impl Iterator<Item = &ContentSecurityPolicy> {
+        self.content_security_policy
+            .iter()
+            .chain(self.content_security_policy_report_only.iter())
+    }
+
+    /// Returns the status policy of a header. Headers without a status policy are set on all responses. The status policy of Content-Security-Policy also applies to Content-Security-Policy-Report-Only, unless it has its own
+    ///
+    /// # Arguments
+    /// * `header` - Name of the header
+    ///
+    pub fn status_policy(&self, header: &str) -> Option<&StatusPolicy> {
+        let header = header.to_lowercase();
+        self.status_policies.get(&header).or_else(|| {
+            match header == CONTENT_SECURITY_POLICY_REPORT_ONLY.to_lowercase() {
+                true => self
+                    .status_policies
+                    .get(&CONTENT_SECURITY_POLICY.to_lowercase()),
+                false => None,
+            }
+        })
+    }
+
+    /// Returns true if the header is set on responses with the status
+    ///
+    /// # Arguments
+    /// * `header` - Name of the header
+    /// * `status` - Status of the response
+    ///
+    pub fn covers(&self, header: &str, status: Status) -> bool {
+        self.status_policy(header)
+            .is_none_or(|status_policy| status_policy.covers(status))
+    }
+
+    /// Adds a report endpoint to the Reporting-Endpoints, unless an endpoint with the same name is configured. The Content-Security-Policy and the separate report-only policy get the directives report-uri (for browsers without Reporting API) and report-to, unless it has one of them already
+    ///
+    /// # Arguments
+    /// * `name` - Name of the endpoint
+    /// * `url` - URL of the endpoint
+    ///
+    fn add_report_endpoint(&mut self, name: &str, url: &str) {
+        self.headers
+            .reporting_endpoints
+            .get_or_insert_with(HashMap::new)
+            .entry(name.to_string())
+            .or_insert_with(|| url.to_string());
+        for content_security_policy in self
+            .content_security_policy
+            .iter_mut()
+            .chain(self.content_security_policy_report_only.iter_mut())
+            .filter(|policy| {
+                !policy.has_directive("report-uri") && !policy.has_directive("report-to")
+            })
+        {
+            content_security_policy.add_directive_if_absent("report-uri", &[url]);
+            content_security_policy.add_directive_if_absent("report-to", &[name]);
+        }
+    }
+
+    /// Returns the headers of the profile as they are sent (nonces are inserted per response)
+    ///
+    /// # Arguments
+    /// * `authenticated` - If a user was authenticated for the request. Then Cache-Control for authenticated content is used, if configured
+    ///
+    pub fn header_values(&self, authenticated: bool) -> Vec<(&'static str, String)> {
+        let headers = &self.headers;
+        let cache_control = match &headers.cache_control_authenticated {
+            Some(cache_control_authenticated) if authenticated => {
+                Some(cache_control_authenticated.clone())
+            }
+            _ => headers.cache_control.clone(),
+        };
+        [
+            (
+                self.content_security_policy
+                    .as_ref()
+                    .map_or(CONTENT_SECURITY_POLICY, ContentSecurityPolicy::header_name),
+                self.content_security_policy
+                    .as_ref()
+                    .map(ContentSecurityPolicy::to_string),
+            ),
+            (
+                CONTENT_SECURITY_POLICY_REPORT_ONLY,
+                self.content_security_policy_report_only
+                    .as_ref()
+                    .map(ContentSecurityPolicy::to_string),
+            ),
+            (PERMISSIONS_POLICY, headers.permissions_policy.clone()),
+            (REFERRER_POLICY, headers.referrer_policy.clone()),
+            (
+                CROSS_ORIGIN_EMBEDDER_POLICY,
+                headers.cross_origin_embedder_policy.clone(),
+            ),
+            (
+                CROSS_ORIGIN_OPENER_POLICY,
+                headers.cross_origin_opener_policy.clone(),
+            ),
+            (
+                CROSS_ORIGIN_RESOURCE_POLICY,
+                headers.cross_origin_resource_policy.clone(),
+            ),
+            (
+                STRICT_TRANSPORT_SECURITY,
+                headers
+                    .strict_transport_security
+                    .as_ref()
+                    .map(strict_transport_security_value),
+            ),
+            (
+                X_CONTENT_TYPE_OPTIONS,
+                headers.x_content_type_options.clone(),
+            ),
+            (
+                REPORTING_ENDPOINTS,
+                headers
+                    .reporting_endpoints
+                    .as_ref()
+                    .map(reporting_endpoints_value),
+            ),
+            (CACHE_CONTROL, cache_control),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|value| (name, value)))
+        .collect()
+    }
+
+    /// Checks if the body of a response needs a nonce. Only then it is read, all other bodies (e.g. large scripts or exports) stream through untouched
+    ///
+    /// # Arguments
+    /// * `path` - Path of the request
+    /// * `res` - Response object
+    ///
+    /// # Returns
+    /// true for HTML bodies on the nonce paths that are not encoded
+    ///
+    fn rewrites_body(&self, path: &str, res: &Response<'_>) -> bool {
+        res.content_type()
+            .is_some_and(|content_type| content_type.is_html())
+            && !res.headers().contains("Content-Encoding")
+            && self.nonce_paths.is_match(path)
+    }
+
+    /// Inserts a new nonce into the directives of the Content-Security-Policies and the configured tags of the response body. It must only be called for HTML bodies that are not encoded (e.g. compressed), see rewrites_body
+    /// Bodies with a known size are rewritten at once, so that the Content-Length matches the rewritten body. Other bodies are rewritten while they are streamed
+    ///
+    /// # Arguments
+    /// * `content_security_policies` - Content-Security-Policies of the response, which share the nonce
+    /// * `res` - Response object
+    ///
+    async fn insert_nonce(
+        &self,
+        content_security_policies: &mut [ContentSecurityPolicy],
+        res: &mut Response<'_>,
+    ) {
+        let random_nonce = random_nonce();
+        for content_security_policy in content_security_policies.iter_mut() {
+            content_security_policy.insert_nonce(
+                &random_nonce,
+                self.headers
+                    .content_security_policy_nonce_headers
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+        }
+        event!(Level::DEBUG, "Inserting nonce in selected tags");
+        let rewriter = NonceRewriter::new(
+            &random_nonce,
+            self.headers
+                .content_security_policy_inject_nonce_tags
+                .as_deref()
+                .unwrap_or_default(),
+        );
+        match res.body_mut().size().await {
+            Some(_) => match res.body_mut().to_bytes().await {
+                Ok(body_bytes) => {
+                    let body_bytes = rewriter.rewrite_all(&body_bytes);
+                    res.set_sized_body(body_bytes.len(), io::Cursor::new(body_bytes));
+                }
+                Err(err) => event!(
+                    Level::ERROR,
+                    "Cannot read response body to insert nonce: {}",
+                    err
+                ),
+            },
+            None => {
+                let body = res.body_mut().take();
+                res.set_streamed_body(NonceRewriterStream::new(body, rewriter));
+            }
+        }
     }
+}
 
-    /// Executed on every response. We inject here the HTTP Security Headers
+/// Resolves the headers of a profile. Headers that are not configured are inherited from the default headers and empty values remove a header
+/// A profile that configures a Content-Security-Policy, preset or directives does not inherit any of them, so that it does not mix its policy with the default policy. The same applies to the preset and directives of the separate report-only policy
+///
+/// # Arguments
+/// * `headers` - Headers configured for the profile
+/// * `default_headers` - Default headers
+///
+/// # Returns
+/// Resolved headers
+///
+fn inherit_headers(
+    headers: &CustomAppHttpHeaderValuesConfig,
+    default_headers: &CustomAppHttpHeaderValuesConfig,
+) -> CustomAppHttpHeaderValuesConfig {
+    fn inherit<T: Clone + Default + PartialEq>(
+        value: &Option<T>,
+        default: &Option<T>,
+    ) -> Option<T> {
+        value
+            .clone()
+            .or_else(|| default.clone())
+            .filter(|value| *value != T::default())
+    }
+    let policy = match headers.content_security_policy.is_some()
+        || headers.content_security_policy_preset.is_some()
+        || headers.content_security_policy_directives.is_some()
+    {
+        true => headers,
+        false => default_headers,
+    };
+    let report_only_policy = match headers.content_security_policy_report_only_preset.is_some()
+        || headers
+            .content_security_policy_report_only_directives
+            .is_some()
+    {
+        true => headers,
+        false => default_headers,
+    };
+    CustomAppHttpHeaderValuesConfig {
+        content_security_policy: inherit(&policy.content_security_policy, &None),
+        content_security_policy_preset: inherit(&policy.content_security_policy_preset, &None),
+        content_security_policy_directives: inherit(
+            &policy.content_security_policy_directives,
+            &None,
+        ),
+        content_security_policy_trusted_types: inherit(
+            &headers.content_security_policy_trusted_types,
+            &default_headers.content_security_policy_trusted_types,
+        ),
+        content_security_policy_report_only: headers
+            .content_security_policy_report_only
+            .or(default_headers.content_security_policy_report_only),
+        content_security_policy_report_only_preset: inherit(
+            &report_only_policy.content_security_policy_report_only_preset,
+            &None,
+        ),
+        content_security_policy_report_only_directives: inherit(
+            &report_only_policy.content_security_policy_report_only_directives,
+            &None,
+        ),
+        content_security_policy_inject_nonce_paths: inherit(
+            &headers.content_security_policy_inject_nonce_paths,
+            &default_headers.content_security_policy_inject_nonce_paths,
+        ),
+        content_security_policy_inject_nonce_tags: inherit(
+            &headers.content_security_policy_inject_nonce_tags,
+            &default_headers.content_security_policy_inject_nonce_tags,
+        ),
+        content_security_policy_nonce_headers: inherit(
+            &headers.content_security_policy_nonce_headers,
+            &default_headers.content_security_policy_nonce_headers,
+        ),
+        permissions_policy: inherit(
+            &headers.permissions_policy,
+            &default_headers.permissions_policy,
+        ),
+        referrer_policy: inherit(&headers.referrer_policy, &default_headers.referrer_policy),
+        cross_origin_embedder_policy: inherit(
+            &headers.cross_origin_embedder_policy,
+            &default_headers.cross_origin_embedder_policy,
+        ),
+        cross_origin_opener_policy: inherit(
+            &headers.cross_origin_opener_policy,
+            &default_headers.cross_origin_opener_policy,
+        ),
+        cross_origin_resource_policy: inherit(
+            &headers.cross_origin_resource_policy,
+            &default_headers.cross_origin_resource_policy,
+        ),
+        strict_transport_security: inherit(
+            &headers.strict_transport_security,
+            &default_headers.strict_transport_security,
+        ),
+        x_content_type_options: inherit(
+            &headers.x_content_type_options,
+            &default_headers.x_content_type_options,
+        ),
+        reporting_endpoints: inherit(
+            &headers.reporting_endpoints,
+            &default_headers.reporting_endpoints,
+        ),
+        cache_control: inherit(&headers.cache_control, &default_headers.cache_control),
+        cache_control_authenticated: inherit(
+            &headers.cache_control_authenticated,
+            &default_headers.cache_control_authenticated,
+        ),
+        status_policies: inherit(&headers.status_policies, &default_headers.status_policies),
+    }
+}
+
+/// Returns a securely random nonce (base64)
+fn random_nonce() -> String {
+    let mut rng = rand::rng();
+    let mut random_bytes = [0u8; 64];
+    rng.fill_bytes(&mut random_bytes);
+    BASE64_STANDARD.encode(random_bytes)
+}
+
+fn regex_set(profile: &str, regexes: &[String]) -> Result<RegexSet, HttpHeadersConfigError> {
+    RegexSet::new(regexes).map_err(|err| {
+        HttpHeadersConfigError::InvalidPathRegex(profile.to_string(), err.to_string())
+    })
+}
+
+/// Checks that the HSTS configuration can be submitted to the preload list, if preload is configured
+fn validate_strict_transport_security(
+    profile: &str,
+    config: &CustomAppStrictTransportSecurityConfig,
+) -> Result<(), HttpHeadersConfigError> {
+    if config.preload == Some(true) {
+        if config.include_subdomains != Some(true) {
+            return Err(HttpHeadersConfigError::InvalidStrictTransportSecurity(
+                profile.to_string(),
+                "preload requires include_subdomains".to_string(),
+            ));
+        }
+        if config.max_age < STRICT_TRANSPORT_SECURITY_PRELOAD_MIN_MAX_AGE {
+            return Err(HttpHeadersConfigError::InvalidStrictTransportSecurity(
+                profile.to_string(),
+                format!(
+                    "preload requires a max_age of at least {}",
+                    STRICT_TRANSPORT_SECURITY_PRELOAD_MIN_MAX_AGE
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn strict_transport_security_value(config: &CustomAppStrictTransportSecurityConfig) -> String {
+    let mut value = format!("max-age={}", config.max_age);
+    if config.include_subdomains == Some(true) {
+        value.push_str("; includeSubDomains");
+    }
+    if config.preload == Some(true) {
+        value.push_str("; preload");
+    }
+    value
+}
+
+fn reporting_endpoints_value(endpoints: &HashMap<String, String>) -> String {
+    let mut endpoints: Vec<(&String, &String)> = endpoints.iter().collect();
+    endpoints.sort();
+    endpoints
+        .iter()
+        .map(|(name, url)| format!("{}=\"{}\"", name, url))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for SecurityHttpHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPFirewall - Security HTTP Headers",
+            kind: Kind::Response | Kind::Singleton,
+        }
+    }
+
+    /// Executed on every response. We inject here the HTTP Security Headers of the matching profile that cover the status of the response. HTML responses get the hashes of inline scripts and styles of the documents with Subresource Integrity in their Content-Security-Policy
     ///
     /// # Arguments
     /// * `self` - Struct Security HTTP Headers
@@ -46,118 +725,660 @@ impl Fairing for SecurityHttpHeaders {
     /// * `res` - Response object
     ///
     async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
-        if (res.status() == Status::Ok) {
-            // Configure Content-Security Policy Header and insert nonces
-            let mut body_bytes = res.body_mut().to_bytes().await.unwrap();
-            match self.config.clone().httpheaders.content_security_policy {
-                Some(csp) => {
-                    // check if csp is set
-                    let mut csp_value = csp;
-                    // check if nonce should be inserted
-                    if self.regex_paths.len() > 0 {
-                        let mut rng = rand::rng();
-                        let mut random_bytes = [0u8; 64];
-                        rng.fill_bytes(&mut random_bytes);
-                        let random_nonce = BASE64_STANDARD.encode(random_bytes);
-                        // insert into tag
-                        match self
-                            .config
-                            .clone()
-                            .httpheaders
-                            .content_security_policy_inject_nonce_tags
-                        {
-                            Some(csp_tag_list) => {
-                                if self.regex_paths.is_match(req.uri().path().as_str()) {
-                                    event!(Level::DEBUG, "Inserting nonce in selected tags");
-                                    match self
-                                        .config
-                                        .clone()
-                                        .httpheaders
-                                        .content_security_policy_nonce_headers
-                                    {
-                                        Some(csp_nonce_headers) => {
-                                            for csp_nonce_header in csp_nonce_headers {
-                                                csp_value = csp_value.replace(
-                                                    &csp_nonce_header,
-                                                    format!(
-                                                        "{} 'nonce-{}'",
-                                                        csp_nonce_header, random_nonce
-                                                    )
-                                                    .as_str(),
-                                                );
-                                            }
-                                            let mut updated_body: String =
-                                                String::from_utf8_lossy(&body_bytes).into();
-                                            for csp_tag in csp_tag_list {
-                                                updated_body = updated_body.replace(
-                                                    format!("<{}", csp_tag).as_str(),
-                                                    format!(
-                                                        "<{} nonce=\"{}\"",
-                                                        csp_tag, random_nonce
-                                                    )
-                                                    .as_str(),
-                                                );
-                                            }
-                                            body_bytes = updated_body.into_bytes();
-                                        }
-                                        None => event!(
-                                            Level::ERROR,
-                                            "Configuration: Content-Security-Policy: You did not specify for which CSP attribute a nonce should be added"
-                                        ),
-                                    }
-                                }
-                            }
-                            None => event!(
-                                Level::ERROR,
-                                "Configuration: Content-Security-Policy: You did not specify a tag to inject a nonce"
-                            ),
-                        }
-                        res.set_raw_header("Content-Security-Policy", csp_value);
-                        res.set_sized_body(body_bytes.len(), io::Cursor::new(body_bytes));
-                        // Set other HTTP Security Headers
-                        match self.config.clone().httpheaders.permissions_policy {
-                            Some(permissions_policy) => {
-                                res.set_raw_header("Permissions-Policy", permissions_policy);
-                            }
-                            None => (),
-                        };
-                        match self.config.clone().httpheaders.referrer_policy {
-                            Some(referrer_policy) => {
-                                res.set_raw_header("Referrer-Policy", referrer_policy);
-                            }
-                            None => (),
-                        };
-                        match self.config.clone().httpheaders.cross_origin_embedder_policy {
-                            Some(cross_origin_embedder_policy) => {
-                                res.set_raw_header(
-                                    "Cross-Origin-Embedder-Policy",
-                                    cross_origin_embedder_policy,
-                                );
-                            }
-                            None => (),
-                        };
-                        match self.config.clone().httpheaders.cross_origin_opener_policy {
-                            Some(cross_origin_opener_policy) => {
-                                res.set_raw_header(
-                                    "Cross-Origin-Opener-Policy",
-                                    cross_origin_opener_policy,
-                                );
-                            }
-                            None => (),
-                        };
-                        match self.config.clone().httpheaders.cross_origin_resource_policy {
-                            Some(cross_origin_resource_policy) => {
-                                res.set_raw_header(
-                                    "Cross-Origin-Resource-Policy",
-                                    cross_origin_resource_policy,
-                                );
-                            }
-                            None => (),
-                        };
-                    }
-                }
-                None => (),
+        let path = req.uri().path().as_str();
+        let profile = self.profile(path, res.content_type().as_ref());
+        let authenticated = req.local_cache(|| AuthenticatedRequest(false)).0;
+        let status = res.status();
+        let is_html = res
+            .content_type()
+            .is_some_and(|content_type| content_type.is_html());
+        let subresource_integrity = req.rocket().state::<SubresourceIntegrity>();
+        let rewrites_body = profile.rewrites_body(path, res);
+        let mut content_security_policies: Vec<ContentSecurityPolicy> = profile
+            .content_security_policies()
+            .filter(|policy| profile.covers(policy.header_name(), status))
+            .cloned()
+            .collect();
+        if let Some(subresource_integrity) = subresource_integrity
+            && is_html
+        {
+            for content_security_policy in content_security_policies.iter_mut() {
+                subresource_integrity.add_hash_sources(content_security_policy);
+            }
+        }
+        // the body is rewritten once with a nonce for all policies
+        if rewrites_body && !content_security_policies.is_empty() {
+            profile
+                .insert_nonce(&mut content_security_policies, res)
+                .await;
+        }
+        for (name, value) in profile.header_values(authenticated) {
+            if !profile.covers(name, status) {
+                continue;
+            }
+            let value = content_security_policies
+                .iter()
+                .find(|policy| policy.header_name() == name)
+                .map_or(value, ContentSecurityPolicy::to_string);
+            res.set_raw_header(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+    use rocket::request::{self, FromRequest, Outcome};
+    use rocket::response::stream::ByteStream;
+    use rocket::response::{Redirect, content::RawHtml};
+    use rocket::serde::json::{Json, serde_json};
+    use rocket::shield::Shield;
+
+    use super::*;
+
+    // Marks the request as authenticated like the OIDC request guard
+    struct Authenticated;
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for Authenticated {
+        type Error = ();
+
+        async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+            req.local_cache(|| AuthenticatedRequest(true));
+            Outcome::Success(Authenticated)
+        }
+    }
+
+    #[get("/page")]
+    fn page() -> RawHtml<&'static str> {
+        RawHtml("<html><script src=\"/app.js\"></script></html>")
+    }
+
+    #[get("/moved")]
+    fn moved() -> Redirect {
+        Redirect::to("/page")
+    }
+
+    #[get("/broken")]
+    fn broken() -> Status {
+        Status::InternalServerError
+    }
+
+    #[get("/private")]
+    fn private(_user: Authenticated) -> &'static str {
+        "private"
+    }
+
+    #[get("/api")]
+    fn api() -> Json<serde_json::Value> {
+        Json(serde_json::json!({"products": []}))
+    }
+
+    #[get("/download")]
+    fn download() -> (ContentType, &'static [u8]) {
+        (ContentType::Binary, b"\xff<script>\xfe")
+    }
+
+    #[get("/export")]
+    fn export() -> (
+        ContentType,
+        ByteStream<futures::stream::Iter<std::vec::IntoIter<Vec<u8>>>>,
+    ) {
+        let rows = vec![b"id,name\n".to_vec(), b"1,<script>\n".to_vec()];
+        (
+            ContentType::CSV,
+            ByteStream::from(futures::stream::iter(rows)),
+        )
+    }
+
+    const STATUSES: [(&str, Status); 4] = [
+        ("/page", Status::Ok),
+        ("/moved", Status::SeeOther),
+        ("/missing", Status::NotFound),
+        ("/broken", Status::InternalServerError),
+    ];
+
+    fn client(config: CustomAppHttpHeadersConfig) -> Client {
+        // no default headers of Rocket's shield
+        let rocket = rocket::build()
+            .attach(Shield::new())
+            .attach(SecurityHttpHeaders::new(&config).unwrap())
+            .mount(
+                "/",
+                routes![page, moved, broken, private, api, download, export],
+            );
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn headers(config: CustomAppHttpHeaderValuesConfig) -> CustomAppHttpHeadersConfig {
+        CustomAppHttpHeadersConfig {
+            headers: config,
+            profiles: None,
+        }
+    }
+
+    /// Returns the values of all headers that the fairing can set
+    fn security_headers(client: &Client, path: &str) -> Vec<(&'static str, String)> {
+        let response = client.get(path).dispatch();
+        HEADERS
+            .iter()
+            .filter_map(|name| {
+                response
+                    .headers()
+                    .get_one(name)
+                    .map(|value| (*name, value.to_string()))
+            })
+            .collect()
+    }
+
+    fn status_policies(policies: &[(&str, &[&str])]) -> Option<HashMap<String, Vec<String>>> {
+        Some(
+            policies
+                .iter()
+                .map(|(header, statuses)| {
+                    (
+                        header.to_string(),
+                        statuses.iter().map(|status| status.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn sets_each_header_independently_on_all_statuses() {
+        let hsts = CustomAppStrictTransportSecurityConfig {
+            max_age: 63072000,
+            include_subdomains: Some(true),
+            preload: Some(true),
+        };
+        let cases: Vec<(CustomAppHttpHeaderValuesConfig, &str, &str)> = vec![
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy: Some("default-src 'none'".to_string()),
+                    ..Default::default()
+                },
+                CONTENT_SECURITY_POLICY,
+                "default-src 'none'",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    content_security_policy_preset: Some("report-only".to_string()),
+                    ..Default::default()
+                },
+                CONTENT_SECURITY_POLICY_REPORT_ONLY,
+                "script-src 'strict-dynamic'; object-src 'none'; base-uri 'none'; require-trusted-types-for 'script'",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    permissions_policy: Some("camera=()".to_string()),
+                    ..Default::default()
+                },
+                PERMISSIONS_POLICY,
+                "camera=()",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    referrer_policy: Some("no-referrer".to_string()),
+                    ..Default::default()
+                },
+                REFERRER_POLICY,
+                "no-referrer",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    cross_origin_embedder_policy: Some("require-corp".to_string()),
+                    ..Default::default()
+                },
+                CROSS_ORIGIN_EMBEDDER_POLICY,
+                "require-corp",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    cross_origin_opener_policy: Some("same-origin".to_string()),
+                    ..Default::default()
+                },
+                CROSS_ORIGIN_OPENER_POLICY,
+                "same-origin",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    cross_origin_resource_policy: Some("same-site".to_string()),
+                    ..Default::default()
+                },
+                CROSS_ORIGIN_RESOURCE_POLICY,
+                "same-site",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    strict_transport_security: Some(hsts),
+                    ..Default::default()
+                },
+                STRICT_TRANSPORT_SECURITY,
+                "max-age=63072000; includeSubDomains; preload",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    x_content_type_options: Some("nosniff".to_string()),
+                    ..Default::default()
+                },
+                X_CONTENT_TYPE_OPTIONS,
+                "nosniff",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    reporting_endpoints: Some(HashMap::from([
+                        ("default".to_string(), "https://example.com/r".to_string()),
+                        ("csp".to_string(), "https://example.com/csp".to_string()),
+                    ])),
+                    ..Default::default()
+                },
+                REPORTING_ENDPOINTS,
+                "csp=\"https://example.com/csp\", default=\"https://example.com/r\"",
+            ),
+            (
+                CustomAppHttpHeaderValuesConfig {
+                    cache_control: Some("no-cache".to_string()),
+                    ..Default::default()
+                },
+                CACHE_CONTROL,
+                "no-cache",
+            ),
+        ];
+        assert_eq!(cases.len(), HEADERS.len());
+        for (config, header, value) in cases {
+            let client = client(headers(config));
+            for (path, status) in STATUSES {
+                assert_eq!(client.get(path).dispatch().status(), status);
+                assert_eq!(
+                    security_headers(&client, path),
+                    vec![(header, value.to_string())],
+                    "{} on {}",
+                    header,
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sends_report_only_policy_alongside_policy() {
+        let trial = client(headers(CustomAppHttpHeaderValuesConfig {
+            content_security_policy: Some("default-src 'self'".to_string()),
+            content_security_policy_report_only_preset: Some("strict".to_string()),
+            content_security_policy_inject_nonce_paths: Some(vec!["^/page$".to_string()]),
+            content_security_policy_inject_nonce_tags: Some(vec!["script".to_string()]),
+            // the status policy of Content-Security-Policy also covers the report-only policy
+            status_policies: status_policies(&[("Content-Security-Policy", &["2xx"])]),
+            ..Default::default()
+        }));
+        let response = trial.get("/page").dispatch();
+        assert_eq!(
+            response.headers().get_one(CONTENT_SECURITY_POLICY),
+            Some("default-src 'self'")
+        );
+        let report_only = response
+            .headers()
+            .get_one(CONTENT_SECURITY_POLICY_REPORT_ONLY)
+            .unwrap()
+            .to_string();
+        let nonce = report_only
+            .split_once("'nonce-")
+            .and_then(|(_, rest)| rest.split_once('\''))
+            .unwrap()
+            .0
+            .to_string();
+        assert_eq!(
+            report_only,
+            format!(
+                "script-src 'nonce-{}' 'strict-dynamic'; object-src 'none'; base-uri 'none'; require-trusted-types-for 'script'",
+                nonce
+            )
+        );
+        assert!(
+            response
+                .into_string()
+                .unwrap()
+                .contains(&format!("nonce=\"{}\"", nonce))
+        );
+        assert_eq!(security_headers(&trial, "/missing"), vec![]);
+
+        let switched = client(headers(CustomAppHttpHeaderValuesConfig {
+            content_security_policy: Some("default-src 'self'".to_string()),
+            content_security_policy_report_only: Some(true),
+            status_policies: status_policies(&[("content-security-policy", &["404"])]),
+            ..Default::default()
+        }));
+        assert_eq!(security_headers(&switched, "/page"), vec![]);
+        assert_eq!(
+            security_headers(&switched, "/missing"),
+            vec![(
+                CONTENT_SECURITY_POLICY_REPORT_ONLY,
+                "default-src 'self'".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn status_policies_restrict_headers_to_statuses() {
+        let client = client(headers(CustomAppHttpHeaderValuesConfig {
+            content_security_policy: Some("default-src 'none'".to_string()),
+            x_content_type_options: Some("nosniff".to_string()),
+            cache_control: Some("no-cache".to_string()),
+            referrer_policy: Some("no-referrer".to_string()),
+            status_policies: status_policies(&[
+                ("content-security-policy", &["2xx", "404"]),
+                ("Cache-Control", &["200"]),
+                ("referrer-policy", &["3xx", "5xx"]),
+                ("x-content-type-options", &["all"]),
+            ]),
+            ..Default::default()
+        }));
+        let expected: [(&str, &[&str]); 4] = [
+            (
+                "/page",
+                &[
+                    CONTENT_SECURITY_POLICY,
+                    X_CONTENT_TYPE_OPTIONS,
+                    CACHE_CONTROL,
+                ],
+            ),
+            ("/moved", &[REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS]),
+            (
+                "/missing",
+                &[CONTENT_SECURITY_POLICY, X_CONTENT_TYPE_OPTIONS],
+            ),
+            ("/broken", &[REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS]),
+        ];
+        for (path, headers) in expected {
+            let names: Vec<&str> = security_headers(&client, path)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            assert_eq!(names, headers, "{}", path);
+        }
+    }
+
+    #[test]
+    fn renders_strict_transport_security() {
+        let cases = [
+            (None, None, "max-age=300"),
+            (Some(false), Some(false), "max-age=300"),
+            (Some(true), None, "max-age=300; includeSubDomains"),
+        ];
+        for (include_subdomains, preload, value) in cases {
+            let config = CustomAppStrictTransportSecurityConfig {
+                max_age: 300,
+                include_subdomains,
+                preload,
             };
+            assert_eq!(strict_transport_security_value(&config), value);
+        }
+        let preload = |max_age, include_subdomains| {
+            SecurityHttpHeaders::new(&headers(CustomAppHttpHeaderValuesConfig {
+                strict_transport_security: Some(CustomAppStrictTransportSecurityConfig {
+                    max_age,
+                    include_subdomains,
+                    preload: Some(true),
+                }),
+                ..Default::default()
+            }))
+        };
+        assert!(preload(31536000, Some(true)).is_ok());
+        assert!(matches!(
+            preload(31536000, None),
+            Err(HttpHeadersConfigError::InvalidStrictTransportSecurity(..))
+        ));
+        assert!(matches!(
+            preload(300, Some(true)),
+            Err(HttpHeadersConfigError::InvalidStrictTransportSecurity(..))
+        ));
+    }
+
+    #[test]
+    fn adds_report_endpoint_to_all_profiles() {
+        let security_http_headers = SecurityHttpHeaders::new(&CustomAppHttpHeadersConfig {
+            headers: CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("default-src 'self';".to_string()),
+                reporting_endpoints: Some(HashMap::from([(
+                    "coep".to_string(),
+                    "https://reports.example.com/coep".to_string(),
+                )])),
+                ..Default::default()
+            },
+            profiles: Some(vec![CustomAppHttpHeadersProfileConfig {
+                name: "legacy".to_string(),
+                paths: Some(vec!["^/legacy/".to_string()]),
+                content_types: None,
+                headers: CustomAppHttpHeaderValuesConfig {
+                    content_security_policy: Some(
+                        "default-src 'self'; report-uri /legacy-reports".to_string(),
+                    ),
+                    ..Default::default()
+                },
+            }]),
+        })
+        .unwrap()
+        .with_report_endpoint("default", "/reports");
+        let headers = security_http_headers.default_profile.header_values(false);
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    CONTENT_SECURITY_POLICY,
+                    "default-src 'self'; report-uri /reports; report-to default".to_string()
+                ),
+                (
+                    REPORTING_ENDPOINTS,
+                    "coep=\"https://reports.example.com/coep\", default=\"/reports\"".to_string()
+                ),
+            ]
+        );
+        let legacy = security_http_headers.profile("/legacy/", None);
+        assert_eq!(
+            legacy.headers.content_security_policy.as_deref(),
+            Some("default-src 'self'; report-uri /legacy-reports")
+        );
+    }
+
+    #[test]
+    fn uses_cache_control_for_authenticated_content() {
+        let cases = [
+            (
+                Some("public, max-age=60"),
+                Some("no-store"),
+                "/page",
+                Some("public, max-age=60"),
+            ),
+            (
+                Some("public, max-age=60"),
+                Some("no-store"),
+                "/private",
+                Some("no-store"),
+            ),
+            (None, Some("no-store"), "/page", None),
+            (None, Some("no-store"), "/private", Some("no-store")),
+            (Some("no-cache"), None, "/private", Some("no-cache")),
+        ];
+        for (cache_control, cache_control_authenticated, path, expected) in cases {
+            let client = client(headers(CustomAppHttpHeaderValuesConfig {
+                cache_control: cache_control.map(str::to_string),
+                cache_control_authenticated: cache_control_authenticated.map(str::to_string),
+                ..Default::default()
+            }));
+            let response = client.get(path).dispatch();
+            assert_eq!(
+                response.headers().get_one(CACHE_CONTROL),
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn inserts_nonce_on_nonce_paths_of_matching_profile() {
+        let client = client(CustomAppHttpHeadersConfig {
+            headers: CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("script-src 'self'".to_string()),
+                content_security_policy_inject_nonce_paths: Some(vec![
+                    "^/page$".to_string(),
+                    "^/download$".to_string(),
+                    "^/export$".to_string(),
+                ]),
+                content_security_policy_inject_nonce_tags: Some(vec!["script".to_string()]),
+                content_security_policy_nonce_headers: Some(vec!["script-src".to_string()]),
+                ..Default::default()
+            },
+            profiles: Some(vec![CustomAppHttpHeadersProfileConfig {
+                name: "api".to_string(),
+                content_types: Some(vec!["application/json".to_string()]),
+                headers: CustomAppHttpHeaderValuesConfig {
+                    content_security_policy: Some(String::new()),
+                    cache_control: Some("no-store".to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }]),
+        });
+        let response = client.get("/page").dispatch();
+        let csp = response
+            .headers()
+            .get_one(CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_string();
+        let nonce = csp
+            .strip_prefix("script-src 'nonce-")
+            .and_then(|csp| csp.strip_suffix("' 'self'"))
+            .unwrap()
+            .to_string();
+        let size = response.body().preset_size();
+        let body = response.into_bytes().unwrap();
+        assert_eq!(size, Some(body.len()));
+        assert!(
+            String::from_utf8(body)
+                .unwrap()
+                .contains(&format!("<script nonce=\"{}\" src=", nonce))
+        );
+        let response = client.get("/download").dispatch();
+        assert_eq!(
+            response.headers().get_one(CONTENT_SECURITY_POLICY),
+            Some("script-src 'self'")
+        );
+        assert_eq!(response.into_bytes().unwrap(), b"\xff<script>\xfe");
+        // other content types are not buffered, but stream through
+        let response = client.get("/export").dispatch();
+        assert_eq!(response.body().preset_size(), None);
+        assert_eq!(response.into_string().unwrap(), "id,name\n1,<script>\n");
+        let response = client.get("/moved").dispatch();
+        assert_eq!(
+            response.headers().get_one(CONTENT_SECURITY_POLICY),
+            Some("script-src 'self'")
+        );
+        assert_eq!(
+            security_headers(&client, "/api"),
+            vec![(CACHE_CONTROL, "no-store".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let invalid = |config: CustomAppHttpHeaderValuesConfig| {
+            SecurityHttpHeaders::new(&headers(config)).unwrap_err()
+        };
+        assert!(matches!(
+            invalid(CustomAppHttpHeaderValuesConfig {
+                status_policies: status_policies(&[("x-frame-options", &["all"])]),
+                ..Default::default()
+            }),
+            HttpHeadersConfigError::UnknownStatusPolicyHeader(..)
+        ));
+        for status in ["6xx", "0xx", "99", "600", "2x", "ok"] {
+            assert!(matches!(
+                invalid(CustomAppHttpHeaderValuesConfig {
+                    status_policies: status_policies(&[("cache-control", &[status])]),
+                    ..Default::default()
+                }),
+                HttpHeadersConfigError::InvalidStatusPolicy(..)
+            ));
+        }
+        assert!(matches!(
+            invalid(CustomAppHttpHeaderValuesConfig {
+                content_security_policy: Some("script-src 'self'".to_string()),
+                content_security_policy_inject_nonce_paths: Some(vec!["^/$".to_string()]),
+                content_security_policy_nonce_headers: Some(vec!["script-src".to_string()]),
+                ..Default::default()
+            }),
+            HttpHeadersConfigError::NonceTagsRequired(..)
+        ));
+        assert!(matches!(
+            invalid(CustomAppHttpHeaderValuesConfig {
+                content_security_policy_preset: Some("report-only".to_string()),
+                content_security_policy_report_only_preset: Some("strict".to_string()),
+                ..Default::default()
+            }),
+            HttpHeadersConfigError::InvalidContentSecurityPolicy(
+                _,
+                CspConfigError::DuplicateReportOnly
+            )
+        ));
+    }
+
+    const BENCHMARK_SIZE: usize = 32 * 1024 * 1024;
+
+    #[get("/asset.js")]
+    fn asset_script() -> (ContentType, Vec<u8>) {
+        (
+            ContentType::JavaScript,
+            b"<script>".repeat(BENCHMARK_SIZE / 8),
+        )
+    }
+
+    #[get("/asset.html")]
+    fn asset_document() -> (ContentType, Vec<u8>) {
+        (ContentType::HTML, b"<script>".repeat(BENCHMARK_SIZE / 8))
+    }
+
+    // cargo test --release benchmark_large_static_assets -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn benchmark_large_static_assets() {
+        const ROUNDS: u32 = 10;
+        // the release profile requires a secret key
+        let config = headers(CustomAppHttpHeaderValuesConfig {
+            content_security_policy: Some("script-src 'self'".to_string()),
+            content_security_policy_inject_nonce_paths: Some(vec!["^/asset".to_string()]),
+            content_security_policy_inject_nonce_tags: Some(vec!["script".to_string()]),
+            content_security_policy_nonce_headers: Some(vec!["script-src".to_string()]),
+            ..Default::default()
+        });
+        let without_fairing = Client::tracked(
+            rocket::custom(rocket::Config::debug_default())
+                .mount("/", routes![asset_script, asset_document]),
+        )
+        .unwrap();
+        let with_fairing = Client::tracked(
+            rocket::custom(rocket::Config::debug_default())
+                .attach(SecurityHttpHeaders::new(&config).unwrap())
+                .mount("/", routes![asset_script, asset_document]),
+        )
+        .unwrap();
+        for (name, client, path) in [
+            ("script without fairing", &without_fairing, "/asset.js"),
+            ("script with fairing", &with_fairing, "/asset.js"),
+            ("HTML with nonce injection", &with_fairing, "/asset.html"),
+        ] {
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                assert!(client.get(path).dispatch().into_bytes().unwrap().len() >= BENCHMARK_SIZE);
+            }
+            let seconds = start.elapsed().as_secs_f64();
+            println!(
+                "{}: {:.1} ms per response, {:.0} MiB/s",
+                name,
+                seconds * 1000.0 / ROUNDS as f64,
+                (BENCHMARK_SIZE as f64 * ROUNDS as f64) / (1024.0 * 1024.0) / seconds
+            );
         }
     }
 }
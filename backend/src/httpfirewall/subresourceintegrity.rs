@@ -0,0 +1,368 @@
+//! Subresource Integrity (SRI) for the static frontend files
+//!
+//! At startup the SHA-384 hashes of the assets (e.g. JS and CSS) are computed or read from a build manifest. The configured HTML documents (e.g. index.html) are rewritten once, so that their script, stylesheet and preload tags carry an integrity attribute. Optionally the hashes of their inline scripts and styles are added to the Content-Security-Policy, so that a strict policy does not depend on per-response nonces and the documents stay cacheable
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use rocket::serde::json::serde_json;
+use sha2::{Digest, Sha384};
+
+use crate::configuration::config::CustomAppStaticFilesConfig;
+
+use super::contentsecuritypolicy::ContentSecurityPolicy;
+use super::noncerewriter::{HtmlRewriter, TagRewriter, attributes, has_rel, insert_attribute};
+
+const DEFAULT_EXTENSIONS: [&str; 3] = ["js", "mjs", "css"];
+const INTEGRITY_ELEMENTS: [&[u8]; 3] = [b"script", b"link", b"style"];
+// link types whose target is fetched with the integrity of the link
+const INTEGRITY_LINK_TYPES: [&[u8]; 3] = [b"stylesheet", b"modulepreload", b"preload"];
+
+// Errors in the Subresource Integrity configuration
+#[derive(Debug)]
+pub enum IntegrityConfigError {
+    DocumentNotReadable(String),
+    ManifestNotReadable(String),
+    InvalidManifest(String),
+    AssetNotReadable(String),
+}
+
+// Documents with integrity attributes and the hashes of their inline scripts and styles. It is managed as state in Rocket and empty if no documents are configured
+#[derive(Debug, Default)]
+pub struct SubresourceIntegrity {
+    // rewritten documents by their path in the file system
+    documents: HashMap<PathBuf, Vec<u8>>,
+    script_hashes: Vec<String>,
+    style_hashes: Vec<String>,
+}
+
+// Adds integrity attributes to the tags of one document and collects the hashes of its inline scripts and styles
+struct IntegrityTags<'a> {
+    // integrity by path of the asset relative to the location of the static files
+    hashes: &'a HashMap<String, String>,
+    // directory of the document relative to the location of the static files
+    directory: PathBuf,
+    inline_hashes: bool,
+    // inline content of the last script tag is only hashed if it has no src
+    external_script: bool,
+    script_hashes: Vec<String>,
+    style_hashes: Vec<String>,
+}
+
+impl SubresourceIntegrity {
+    /// Computes or reads the hashes of the assets and rewrites the configured documents
+    ///
+    /// # Arguments
+    /// * `config` - Configuration of the static file server
+    ///
+    /// # Returns
+    /// Rewritten documents or an error if a document, the manifest or an asset cannot be read
+    ///
+    pub fn new(
+        config: &CustomAppStaticFilesConfig,
+    ) -> Result<SubresourceIntegrity, IntegrityConfigError> {
+        let integrity = &config.integrity;
+        let Some(documents) = &integrity.documents else {
+            return Ok(SubresourceIntegrity::default());
+        };
+        let location = Path::new(&config.location);
+        let hashes = match &integrity.manifest {
+            Some(manifest) => read_manifest(Path::new(manifest))?,
+            None => {
+                let extensions = integrity.extensions.clone().unwrap_or_else(|| {
+                    DEFAULT_EXTENSIONS
+                        .iter()
+                        .map(|extension| extension.to_string())
+                        .collect()
+                });
+                let mut hashes = HashMap::new();
+                hash_assets(location, Path::new(""), &extensions, &mut hashes)?;
+                hashes
+            }
+        };
+        let mut subresource_integrity = SubresourceIntegrity::default();
+        for document in documents {
+            let content = fs::read(location.join(document)).map_err(|err| {
+                IntegrityConfigError::DocumentNotReadable(format!("{}: {}", document, err))
+            })?;
+            let mut rewriter = HtmlRewriter::with_tags(IntegrityTags {
+                hashes: &hashes,
+                directory: Path::new(document)
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default(),
+                inline_hashes: integrity.inline_hashes.unwrap_or(false),
+                external_script: false,
+                script_hashes: Vec::new(),
+                style_hashes: Vec::new(),
+            });
+            let mut rewritten = Vec::with_capacity(content.len() + 1024);
+            rewriter.rewrite(&content, &mut rewritten);
+            rewriter.finish(&mut rewritten);
+            let tags = rewriter.into_tags();
+            for hash in tags.script_hashes {
+                if !subresource_integrity.script_hashes.contains(&hash) {
+                    subresource_integrity.script_hashes.push(hash);
+                }
+            }
+            for hash in tags.style_hashes {
+                if !subresource_integrity.style_hashes.contains(&hash) {
+                    subresource_integrity.style_hashes.push(hash);
+                }
+            }
+            subresource_integrity
+                .documents
+                .insert(location.join(document), rewritten);
+        }
+        Ok(subresource_integrity)
+    }
+
+    /// Returns a document with integrity attributes
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file in the file system
+    ///
+    /// # Returns
+    /// Rewritten document or None if the file is not a configured document
+    ///
+    pub fn document(&self, path: &Path) -> Option<&[u8]> {
+        self.documents.get(path).map(Vec::as_slice)
+    }
+
+    /// Adds the hashes of the inline scripts and styles of the documents to the directives script-src and style-src. Directives that are not in the policy are not added, because they would replace default-src
+    ///
+    /// # Arguments
+    /// * `content_security_policy` - Content-Security-Policy of the response
+    ///
+    pub fn add_hash_sources(&self, content_security_policy: &mut ContentSecurityPolicy) {
+        let quoted = |hashes: &[String]| -> Vec<String> {
+            hashes.iter().map(|hash| format!("'{}'", hash)).collect()
+        };
+        content_security_policy.add_sources("script-src", &quoted(&self.script_hashes));
+        content_security_policy.add_sources("style-src", &quoted(&self.style_hashes));
+    }
+}
+
+impl TagRewriter for IntegrityTags<'_> {
+    fn rewrites(&self, tag_name: &[u8]) -> bool {
+        INTEGRITY_ELEMENTS.contains(&tag_name)
+    }
+
+    /// Inserts the integrity of the referenced asset after the tag name, unless the tag has an integrity already or references an asset without a hash (e.g. of another origin)
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the element (script, link or style)
+    /// * `tag` - Complete start tag of the element
+    ///
+    /// # Returns
+    /// Start tag with the integrity
+    ///
+    fn rewrite_tag(&mut self, tag_name: &[u8], tag: &[u8]) -> Vec<u8> {
+        let attributes = attributes(&tag[1 + tag_name.len()..]);
+        let reference = match tag_name {
+            b"script" => attributes.iter().find(|(name, _)| name == b"src"),
+            b"link"
+                if INTEGRITY_LINK_TYPES
+                    .iter()
+                    .any(|link_type| has_rel(&attributes, link_type)) =>
+            {
+                attributes.iter().find(|(name, _)| name == b"href")
+            }
+            _ => None,
+        };
+        if tag_name == b"script" {
+            self.external_script = reference.is_some();
+        }
+        let has_integrity = attributes.iter().any(|(name, _)| name == b"integrity");
+        let integrity = reference
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+            .and_then(|reference| self.asset(reference))
+            .and_then(|asset| self.hashes.get(&asset));
+        match integrity {
+            Some(integrity) if !has_integrity => {
+                insert_attribute(tag, tag_name, b"integrity", integrity)
+            }
+            _ => tag.to_vec(),
+        }
+    }
+
+    fn raw_text(&mut self, tag_name: &[u8], content: &[u8]) {
+        if !self.inline_hashes || content.is_empty() {
+            return;
+        }
+        let hash = format!("sha384-{}", BASE64_STANDARD.encode(Sha384::digest(content)));
+        match tag_name {
+            b"script" if !self.external_script => self.script_hashes.push(hash),
+            b"style" => self.style_hashes.push(hash),
+            _ => (),
+        }
+    }
+}
+
+impl IntegrityTags<'_> {
+    /// Resolves a reference of the document to the path of an asset relative to the location of the static files
+    ///
+    /// # Arguments
+    /// * `reference` - Value of src or href
+    ///
+    /// # Returns
+    /// Path with / as separator or None if the reference is not a local file (e.g. of another origin)
+    ///
+    fn asset(&self, reference: &str) -> Option<String> {
+        let reference = reference.trim();
+        let reference = reference.split(['?', '#']).next().unwrap_or_default();
+        if reference.is_empty() || reference.contains(':') || reference.starts_with("//") {
+            return None;
+        }
+        let path = match reference.strip_prefix('/') {
+            Some(absolute) => PathBuf::from(absolute),
+            None => self.directory.join(reference),
+        };
+        let mut segments: Vec<String> = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(segment) => segments.push(segment.to_string_lossy().to_string()),
+                Component::ParentDir => {
+                    segments.pop()?;
+                }
+                _ => (),
+            }
+        }
+        Some(segments.join("/"))
+    }
+}
+
+/// Reads a build manifest that maps the paths of assets relative to the location of the static files to their integrity (e.g. {"_app/immutable/entry/start.js": "sha384-..."})
+///
+/// # Arguments
+/// * `manifest` - Path of the manifest
+///
+/// # Returns
+/// Integrity by path of the asset
+///
+fn read_manifest(manifest: &Path) -> Result<HashMap<String, String>, IntegrityConfigError> {
+    let content = fs::read_to_string(manifest)
+        .map_err(|err| IntegrityConfigError::ManifestNotReadable(err.to_string()))?;
+    let hashes: HashMap<String, String> = serde_json::from_str(&content)
+        .map_err(|err| IntegrityConfigError::InvalidManifest(err.to_string()))?;
+    Ok(hashes
+        .into_iter()
+        .map(|(path, integrity)| (path.trim_start_matches('/').to_string(), integrity))
+        .collect())
+}
+
+/// Computes the integrity of all assets with the given extensions in a directory and its subdirectories
+///
+/// # Arguments
+/// * `location` - Location of the static files
+/// * `directory` - Directory relative to the location
+/// * `extensions` - Extensions of the assets
+/// * `hashes` - Integrity by path of the asset with / as separator
+///
+fn hash_assets(
+    location: &Path,
+    directory: &Path,
+    extensions: &[String],
+    hashes: &mut HashMap<String, String>,
+) -> Result<(), IntegrityConfigError> {
+    let entries = fs::read_dir(location.join(directory))
+        .map_err(|err| IntegrityConfigError::AssetNotReadable(err.to_string()))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| IntegrityConfigError::AssetNotReadable(err.to_string()))?;
+        let path = directory.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .map_err(|err| IntegrityConfigError::AssetNotReadable(err.to_string()))?;
+        if file_type.is_dir() {
+            hash_assets(location, &path, extensions, hashes)?;
+            continue;
+        }
+        let is_asset = path
+            .extension()
+            .is_some_and(|extension| extensions.iter().any(|asset| extension == asset.as_str()));
+        if !is_asset {
+            continue;
+        }
+        let content = fs::read(entry.path()).map_err(|err| {
+            IntegrityConfigError::AssetNotReadable(format!("{}: {}", path.display(), err))
+        })?;
+        let asset = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("/");
+        hashes.insert(
+            asset,
+            format!("sha384-{}", BASE64_STANDARD.encode(Sha384::digest(content))),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::config::CustomAppIntegrityConfig;
+
+    #[test]
+    fn adds_integrity_and_inline_hashes() {
+        let location = std::env::temp_dir().join(format!("static-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(location.join("_app/immutable")).unwrap();
+        fs::write(location.join("_app/immutable/start.js"), "export {}").unwrap();
+        fs::write(location.join("_app/immutable/app.css"), "p{}").unwrap();
+        fs::write(
+            location.join("index.html"),
+            "<link href=\"/_app/immutable/app.css\" rel=\"stylesheet\"><link rel=\"modulepreload\" href=\"./_app/immutable/start.js?v=1\"><link rel=icon href=/_app/immutable/app.css><script src=\"https://cdn.example.com/a.js\"></script><script>import(\"./_app/immutable/start.js\")</script><style>p{}</style>",
+        )
+        .unwrap();
+        let integrity = SubresourceIntegrity::new(&CustomAppStaticFilesConfig {
+            location: location.to_string_lossy().to_string(),
+            integrity: CustomAppIntegrityConfig {
+                documents: Some(vec!["index.html".to_string()]),
+                inline_hashes: Some(true),
+                ..Default::default()
+            },
+        })
+        .unwrap();
+
+        let script = format!(
+            "sha384-{}",
+            BASE64_STANDARD.encode(Sha384::digest("export {}"))
+        );
+        let style = format!("sha384-{}", BASE64_STANDARD.encode(Sha384::digest("p{}")));
+        let document = String::from_utf8(
+            integrity
+                .document(&location.join("index.html"))
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            document,
+            format!(
+                "<link integrity=\"{}\" href=\"/_app/immutable/app.css\" rel=\"stylesheet\"><link integrity=\"{}\" rel=\"modulepreload\" href=\"./_app/immutable/start.js?v=1\"><link rel=icon href=/_app/immutable/app.css><script src=\"https://cdn.example.com/a.js\"></script><script>import(\"./_app/immutable/start.js\")</script><style>p{{}}</style>",
+                style, script
+            )
+        );
+        let inline_script = format!(
+            "sha384-{}",
+            BASE64_STANDARD.encode(Sha384::digest("import(\"./_app/immutable/start.js\")"))
+        );
+        let mut content_security_policy =
+            ContentSecurityPolicy::parse("default-src 'none'; script-src 'self'; style-src 'self'");
+        integrity.add_hash_sources(&mut content_security_policy);
+        assert_eq!(
+            content_security_policy.to_string(),
+            format!(
+                "default-src 'none'; script-src 'self' '{}'; style-src 'self' '{}'",
+                inline_script, style
+            )
+        );
+        assert!(integrity.document(&location.join("other.html")).is_none());
+
+        let _ = fs::remove_dir_all(location);
+    }
+}
@@ -3,31 +3,59 @@
 #[macro_use]
 extern crate rocket;
 
+use configuration::config::configure_compression;
+use configuration::config::configure_cors;
+use configuration::config::configure_csrf;
 use configuration::config::configure_fileserver;
+use configuration::config::configure_firewall;
+use configuration::config::configure_mtls;
 use configuration::config::configure_oidc;
+use configuration::config::configure_ratelimit;
+use configuration::config::configure_reporting;
+use configuration::config::configure_secrets;
+use configuration::config::configure_security_events;
+use configuration::config::configure_terms;
 
+use configuration::config::read_config;
 use configuration::config::read_security_http_headers_config;
 
 use rocket::fairing::{self, AdHoc};
 use rocket::fs::{FileServer, Options, relative};
 
 use rocket::shield::Shield;
+use rocket::{Build, Rocket};
+
+use std::process::ExitCode;
 
 use rocket_db_pools::Database;
 
+pub mod compression;
 pub mod configuration;
 pub mod database;
 pub mod httpfirewall;
 pub mod inventory;
+pub mod mtls;
 pub mod oidc;
 pub mod order;
+pub mod reporting;
 pub mod routes;
+pub mod securityevents;
 pub mod services;
+pub mod terms;
+
+/// Runs a command given on the command line (e.g. header-profile) or launches the application
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(exit_code) = configuration::cli::run(&args) {
+        return exit_code;
+    }
+    let _ = rocket::async_main(rocket().launch());
+    ExitCode::SUCCESS
+}
 
-/// Launch our application configured with custom routes, OIDC autentication and static file serving
-#[launch]
-fn rocket() -> _ {
-    let rocket = rocket::build()
+/// Our application configured with custom routes, OIDC autentication and static file serving
+fn rocket() -> Rocket<Build> {
+    let rocket = rocket::custom(configure_secrets(rocket::Config::figment()))
         // shield
         .attach(Shield::default())
         // database
@@ -56,11 +84,30 @@ fn rocket() -> _ {
     // read application config
     let figment = rocket.figment();
 
-    let config: crate::configuration::config::Config = figment.extract().expect("config");
+    // validate the whole configuration before the modules are configured
+    let config = read_config(figment);
+    // write security events to the configured sinks
+    let rocket = configure_security_events(rocket, &config);
     // configure file server
     let rocket = configure_fileserver(rocket, &config);
     // configure fairing for http security headers
     let rocket = rocket.attach(read_security_http_headers_config(&config));
+    // compress responses after the nonces were inserted
+    let rocket = configure_compression(rocket, &config);
+    // filter requests with the rules of the firewall
+    let rocket = configure_firewall(rocket, &config);
+    // limit the rate of requests per client
+    let rocket = configure_ratelimit(rocket, &config);
+    // answer CORS preflight requests before the routes
+    let rocket = configure_cors(rocket, &config);
+    // protect state-changing requests against CSRF
+    let rocket = configure_csrf(rocket, &config);
+    // map client certificates to principals
+    let rocket = configure_mtls(rocket, &config);
     // create oidc
-    configure_oidc(rocket, &config)
+    let rocket = configure_oidc(rocket, &config);
+    // gate the application until users accepted the terms of use
+    let rocket = configure_terms(rocket, &config);
+    // collect violation reports of browsers
+    configure_reporting(rocket, &config)
 }
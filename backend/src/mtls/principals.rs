@@ -0,0 +1,301 @@
+//! Authentication of devices (e.g. handheld scanners, PLC gateways) with X.509 client certificates (mutual TLS) as an alternative to OIDC
+//!
+//! The certificate is verified by Rocket against the configured CAs (tls.mutual). The subject or a Subject Alternative Name (SAN) of the verified certificate is mapped to a principal and roles using a configured table
+
+use openidconnect::{EndUserUsername, SubjectIdentifier};
+use rocket::mtls::Certificate;
+use rocket::mtls::x509::{GeneralName, ParsedExtension};
+
+use crate::configuration::config::{CustomAppMtlsConfig, CustomAppMtlsPrincipalConfig};
+use crate::oidc::guard::{OidcUser, path_in};
+
+const DEFAULT_PATHS: [&str; 1] = ["/ui-api"];
+
+// Errors in the mutual TLS configuration
+#[derive(Debug, PartialEq)]
+pub enum MtlsConfigError {
+    SubjectOrSanRequired(String),
+    EmptyPrincipal,
+    InvalidPath(String),
+}
+
+// Table that maps client certificates to principals. It is managed as state in Rocket, if principals are configured
+#[derive(Debug, Clone)]
+pub struct CertificatePrincipals {
+    principals: Vec<CustomAppMtlsPrincipalConfig>,
+    paths: Vec<String>,
+}
+
+impl CertificatePrincipals {
+    /// Creates the mapping table from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Mutual TLS configuration of the application
+    ///
+    /// # Returns
+    /// Mapping table or an error, if an entry does not match exactly one of subject or san
+    ///
+    pub fn new(config: &CustomAppMtlsConfig) -> Result<CertificatePrincipals, MtlsConfigError> {
+        let principals = config.principals.clone().unwrap_or_default();
+        for principal in &principals {
+            if principal.principal.is_empty() {
+                return Err(MtlsConfigError::EmptyPrincipal);
+            }
+            if principal.subject.is_some() == principal.san.is_some() {
+                return Err(MtlsConfigError::SubjectOrSanRequired(
+                    principal.principal.clone(),
+                ));
+            }
+        }
+        let paths = match &config.paths {
+            Some(paths) => paths.clone(),
+            None => DEFAULT_PATHS.iter().map(|path| path.to_string()).collect(),
+        };
+        if let Some(path) = paths.iter().find(|path| !path.starts_with('/')) {
+            return Err(MtlsConfigError::InvalidPath(path.clone()));
+        }
+        Ok(CertificatePrincipals { principals, paths })
+    }
+
+    /// Returns true if client certificates are accepted for the path
+    ///
+    /// # Arguments
+    /// * `path` - Path of the request
+    ///
+    pub fn applies_to(&self, path: &str) -> bool {
+        path_in(&self.paths, path)
+    }
+
+    /// Maps a verified client certificate to a user
+    ///
+    /// # Arguments
+    /// * `certificate` - Client certificate verified by Rocket
+    ///
+    /// # Returns
+    /// User with the configured principal and roles or None if the certificate is not in the table
+    ///
+    pub fn authenticate(&self, certificate: &Certificate<'_>) -> Option<OidcUser> {
+        let subject = certificate.subject().to_string();
+        let sans = subject_alternative_names(certificate);
+        let principal = self.principals.iter().find(|principal| {
+            principal.subject.as_deref() == Some(subject.as_str())
+                || principal
+                    .san
+                    .as_ref()
+                    .is_some_and(|san| sans.iter().any(|name| name.eq_ignore_ascii_case(san)))
+        })?;
+        Some(OidcUser {
+            subject: SubjectIdentifier::new(principal.principal.clone()),
+            preferred_username: Some(EndUserUsername::new(principal.principal.clone())),
+            mapped_roles: principal.roles.clone(),
+            access_token: None,
+            impersonated_by: None,
+        })
+    }
+}
+
+/// DNS names, e-mail addresses and URIs in the Subject Alternative Name extension of the certificate
+fn subject_alternative_names(certificate: &Certificate<'_>) -> Vec<String> {
+    let mut names = Vec::new();
+    for extension in certificate.extensions() {
+        if let ParsedExtension::SubjectAlternativeName(san) = extension.parsed_extension() {
+            for name in &san.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push(name.to_string()),
+                    _ => (),
+                }
+            }
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use openidconnect::reqwest;
+    use rocket::config::{MutualTls, TlsConfig};
+    use rocket::fairing::AdHoc;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::configuration::config::{CustomAppCookiesConfig, CustomAppKeyRingConfig};
+    use crate::oidc::cookies::OidcCookies;
+    use crate::oidc::keyring::CookieKeyRing;
+
+    const SCANNER_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/scanner.pem");
+    const GATEWAY_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/gateway.pem");
+    const UNKNOWN_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/unknown.pem");
+    const CA_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/ca.pem");
+    const SERVER_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../../tests/fixtures/mtls/server-key.pem");
+    const SCANNER_KEY: &[u8] = include_bytes!("../../tests/fixtures/mtls/scanner-key.pem");
+    // same subject as the scanner, but issued by a CA that the server does not trust
+    const FOREIGN_CERTIFICATE: &[u8] = include_bytes!("../../tests/fixtures/mtls/foreign.pem");
+    const FOREIGN_KEY: &[u8] = include_bytes!("../../tests/fixtures/mtls/foreign-key.pem");
+
+    #[get("/ui-api/whoami")]
+    fn whoami(user: OidcUser) -> String {
+        format!("{}:{}", user.subject.as_str(), user.mapped_roles.join(","))
+    }
+
+    #[get("/ui/whoami")]
+    fn ui_whoami(user: OidcUser) -> String {
+        user.subject.as_str().to_string()
+    }
+
+    fn config() -> CustomAppMtlsConfig {
+        CustomAppMtlsConfig {
+            paths: None,
+            principals: Some(vec![
+                CustomAppMtlsPrincipalConfig {
+                    subject: Some("CN=scanner-01, O=Warehouse".to_string()),
+                    principal: "scanner-01".to_string(),
+                    roles: vec!["scanner".to_string()],
+                    ..Default::default()
+                },
+                CustomAppMtlsPrincipalConfig {
+                    san: Some("PLC-GW-01.plant.local".to_string()),
+                    principal: "plc-gateway-01".to_string(),
+                    roles: vec!["gateway".to_string(), "orders".to_string()],
+                    ..Default::default()
+                },
+            ]),
+        }
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::build()
+            .manage(CertificatePrincipals::new(&config()).unwrap())
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .mount("/", routes![whoami, ui_whoami]);
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn maps_subject_and_san_to_principal() {
+        let client = client();
+        let response = client
+            .get("/ui-api/whoami")
+            .identity(SCANNER_CERTIFICATE)
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "scanner-01:scanner");
+        let response = client
+            .get("/ui-api/whoami")
+            .identity(GATEWAY_CERTIFICATE)
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "plc-gateway-01:gateway,orders"
+        );
+    }
+
+    #[test]
+    fn rejects_unmapped_certificate() {
+        let client = client();
+        let response = client
+            .get("/ui-api/whoami")
+            .identity(UNKNOWN_CERTIFICATE)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    /// Returns the body of GET /ui-api/whoami on a server with TLS, if the request succeeds
+    ///
+    /// # Arguments
+    /// * `port` - Port of the server
+    /// * `certificate` - PEM client certificate
+    /// * `key` - PEM private key of the client certificate
+    ///
+    fn whoami_over_tls(port: u16, certificate: &[u8], key: &[u8]) -> Option<String> {
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(CA_CERTIFICATE).unwrap())
+            .identity(reqwest::Identity::from_pem(&[certificate, key].concat()).unwrap())
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{}/ui-api/whoami", port))
+            .send()
+            .ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        response.text().ok()
+    }
+
+    #[test]
+    fn does_not_map_certificate_of_untrusted_ca() {
+        // unlike Client::identity, a server with TLS verifies the chain of the client certificate
+        let tls = TlsConfig::from_bytes(SERVER_CERTIFICATE, SERVER_KEY)
+            .with_mutual(MutualTls::from_bytes(CA_CERTIFICATE).mandatory(false));
+        let rocket_config = rocket::Config {
+            port: 0,
+            tls: Some(tls),
+            ..rocket::Config::debug_default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let rocket = rocket::custom(rocket_config)
+            .manage(CertificatePrincipals::new(&config()).unwrap())
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .mount("/", routes![whoami])
+            .attach(AdHoc::on_liftoff("Port", move |rocket| {
+                Box::pin(async move {
+                    sender
+                        .send((rocket.config().port, rocket.shutdown()))
+                        .unwrap();
+                })
+            }));
+        std::thread::spawn(move || {
+            let _ = rocket::execute(rocket.launch());
+        });
+        let (port, shutdown) = receiver.recv().unwrap();
+
+        assert_eq!(
+            whoami_over_tls(port, SCANNER_CERTIFICATE, SCANNER_KEY).as_deref(),
+            Some("scanner-01:scanner")
+        );
+        assert_eq!(
+            whoami_over_tls(port, FOREIGN_CERTIFICATE, FOREIGN_KEY),
+            None
+        );
+        shutdown.notify();
+    }
+
+    #[test]
+    fn ignores_certificate_outside_configured_paths() {
+        let client = client();
+        // without a session cookie the OIDC guard forwards to the next route (e.g. redirect to the login)
+        let response = client
+            .get("/ui/whoami")
+            .identity(SCANNER_CERTIFICATE)
+            .dispatch();
+        assert_ne!(response.into_string().unwrap(), "scanner-01");
+        let response = client.get("/ui-api/whoami").dispatch();
+        assert!(!response.into_string().unwrap().starts_with("scanner-01"));
+    }
+
+    #[test]
+    fn requires_exactly_one_of_subject_or_san() {
+        let config = CustomAppMtlsConfig {
+            paths: None,
+            principals: Some(vec![CustomAppMtlsPrincipalConfig {
+                subject: Some("CN=scanner-01".to_string()),
+                san: Some("scanner-01".to_string()),
+                principal: "scanner-01".to_string(),
+                roles: Vec::new(),
+            }]),
+        };
+        assert_eq!(
+            CertificatePrincipals::new(&config).err(),
+            Some(MtlsConfigError::SubjectOrSanRequired(
+                "scanner-01".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Cookie policy applied to all cookies that the OIDC module sets or removes
+
+use rocket::http::{Cookie, SameSite};
+use rocket::time::Duration;
+
+use crate::configuration::config::CustomAppCookiesConfig;
+
+const DEFAULT_SESSION_COOKIE_NAME: &str = "oidc_user_session";
+const DEFAULT_REDIRECT_DESTINATION_COOKIE_NAME: &str = "oidc_redirect_destination";
+const DEFAULT_PATH: &str = "/";
+
+// Errors in the cookie configuration
+#[derive(Debug, PartialEq)]
+pub enum CookiePolicyError {
+    InvalidSameSite(String),
+    HostPrefixRequiresSecure(String),
+    HostPrefixForbidsDomain(String),
+    HostPrefixRequiresRootPath(String),
+    SecurePrefixRequiresSecure(String),
+    SameSiteNoneRequiresSecure,
+}
+
+// Cookie policy derived from the application configuration. It is managed as state in Rocket so that the guard and the OIDC routes create cookies with the same attributes
+#[derive(Debug, Clone)]
+pub struct OidcCookies {
+    pub session_cookie_name: String,
+    pub redirect_destination_cookie_name: String,
+    domain: Option<String>,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    session_max_age: Option<Duration>,
+    redirect_destination_max_age: Option<Duration>,
+}
+
+impl OidcCookies {
+    /// Creates the cookie policy from the configuration and validates it
+    ///
+    /// # Arguments
+    /// * `config` - Cookie configuration of the application
+    ///
+    /// # Returns
+    /// Cookie policy or an error if the configuration is inconsistent (e.g. a cookie name with the __Host- prefix and a domain)
+    ///
+    pub fn new(config: &CustomAppCookiesConfig) -> Result<OidcCookies, CookiePolicyError> {
+        let same_site = match config.same_site.as_deref() {
+            None => SameSite::Lax,
+            Some(value) => match value.to_ascii_lowercase().as_str() {
+                "strict" => SameSite::Strict,
+                "lax" => SameSite::Lax,
+                "none" => SameSite::None,
+                _ => return Err(CookiePolicyError::InvalidSameSite(value.to_string())),
+            },
+        };
+        let policy = OidcCookies {
+            session_cookie_name: config
+                .session_cookie_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SESSION_COOKIE_NAME.to_string()),
+            redirect_destination_cookie_name: config
+                .redirect_destination_cookie_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REDIRECT_DESTINATION_COOKIE_NAME.to_string()),
+            domain: config.domain.clone(),
+            path: config
+                .path
+                .clone()
+                .unwrap_or_else(|| DEFAULT_PATH.to_string()),
+            // only for local development via plain http on localhost
+            secure: !config.allow_insecure_http.unwrap_or(false),
+            http_only: config.http_only.unwrap_or(true),
+            same_site,
+            session_max_age: config.session_max_age.map(Duration::seconds),
+            redirect_destination_max_age: config
+                .redirect_destination_max_age
+                .map(Duration::seconds),
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Checks the cookie prefix rules (see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#cookie_prefixes)
+    fn validate(&self) -> Result<(), CookiePolicyError> {
+        if self.same_site == SameSite::None && !self.secure {
+            return Err(CookiePolicyError::SameSiteNoneRequiresSecure);
+        }
+        for name in [
+            &self.session_cookie_name,
+            &self.redirect_destination_cookie_name,
+        ] {
+            if name.starts_with("__Host-") {
+                if !self.secure {
+                    return Err(CookiePolicyError::HostPrefixRequiresSecure(name.clone()));
+                }
+                if self.domain.is_some() {
+                    return Err(CookiePolicyError::HostPrefixForbidsDomain(name.clone()));
+                }
+                if self.path != "/" {
+                    return Err(CookiePolicyError::HostPrefixRequiresRootPath(name.clone()));
+                }
+            } else if name.starts_with("__Secure-") && !self.secure {
+                return Err(CookiePolicyError::SecurePrefixRequiresSecure(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the cookie containing the OIDC session
+    ///
+    /// # Arguments
+    /// * `value` - serialized session
+    ///
+    /// # Returns
+    /// Cookie with all attributes of the policy
+    ///
+    pub fn session_cookie(&self, value: String) -> Cookie<'static> {
+        self.build(
+            self.session_cookie_name.clone(),
+            value,
+            self.session_max_age,
+        )
+    }
+
+    /// Creates the cookie containing the route the user requested before authentication
+    ///
+    /// # Arguments
+    /// * `value` - route the user should be redirected to after authentication
+    ///
+    /// # Returns
+    /// Cookie with all attributes of the policy
+    ///
+    pub fn redirect_destination_cookie(&self, value: String) -> Cookie<'static> {
+        self.build(
+            self.redirect_destination_cookie_name.clone(),
+            value,
+            self.redirect_destination_max_age,
+        )
+    }
+
+    /// Creates one of the cookies of the OIDC module by name, e.g. to re-issue it with a new key
+    ///
+    /// # Arguments
+    /// * `name` - name of the cookie
+    /// * `value` - value of the cookie
+    ///
+    /// # Returns
+    /// Cookie with all attributes of the policy
+    ///
+    pub fn cookie(&self, name: &str, value: String) -> Cookie<'static> {
+        if name == self.session_cookie_name {
+            self.session_cookie(value)
+        } else if name == self.redirect_destination_cookie_name {
+            self.redirect_destination_cookie(value)
+        } else {
+            self.build(name.to_string(), value, None)
+        }
+    }
+
+    /// Creates a cookie that can be used to remove a cookie. Path and domain need to match the cookie that was set, otherwise the browser keeps it
+    ///
+    /// # Arguments
+    /// * `name` - name of the cookie to remove
+    ///
+    /// # Returns
+    /// Cookie to be passed to CookieJar::remove or CookieJar::remove_private
+    ///
+    pub fn removal_cookie(&self, name: &str) -> Cookie<'static> {
+        self.build(name.to_string(), String::new(), None)
+    }
+
+    fn build(&self, name: String, value: String, max_age: Option<Duration>) -> Cookie<'static> {
+        let mut cookie = Cookie::build((name, value))
+            .path(self.path.clone())
+            .secure(self.secure)
+            .http_only(self.http_only)
+            .same_site(self.same_site);
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if let Some(max_age) = max_age {
+            cookie = cookie.max_age(max_age);
+        }
+        cookie.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_match_previous_session_cookie() {
+        let policy = OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap();
+        let cookie = policy.session_cookie("value".to_string());
+        assert_eq!(cookie.name(), "oidc_user_session");
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.max_age(), None);
+    }
+
+    #[test]
+    fn host_prefix_rejects_domain_and_subpath() {
+        let mut config = CustomAppCookiesConfig {
+            session_cookie_name: Some("__Host-session".to_string()),
+            domain: Some("example.org".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            OidcCookies::new(&config).unwrap_err(),
+            CookiePolicyError::HostPrefixForbidsDomain("__Host-session".to_string())
+        );
+        config.domain = None;
+        config.path = Some("/warehouse".to_string());
+        assert_eq!(
+            OidcCookies::new(&config).unwrap_err(),
+            CookiePolicyError::HostPrefixRequiresRootPath("__Host-session".to_string())
+        );
+        config.allow_insecure_http = Some(true);
+        config.path = None;
+        assert_eq!(
+            OidcCookies::new(&config).unwrap_err(),
+            CookiePolicyError::HostPrefixRequiresSecure("__Host-session".to_string())
+        );
+    }
+
+    #[test]
+    fn removal_cookie_matches_domain_and_path() {
+        let config = CustomAppCookiesConfig {
+            domain: Some("example.org".to_string()),
+            path: Some("/warehouse".to_string()),
+            redirect_destination_max_age: Some(300),
+            ..Default::default()
+        };
+        let policy = OidcCookies::new(&config).unwrap();
+        let redirect = policy.redirect_destination_cookie("/ui".to_string());
+        assert_eq!(redirect.max_age(), Some(Duration::seconds(300)));
+        let removal = policy.removal_cookie(&policy.redirect_destination_cookie_name);
+        assert_eq!(removal.domain(), Some("example.org"));
+        assert_eq!(removal.path(), Some("/warehouse"));
+    }
+}
@@ -0,0 +1,681 @@
+//! DPoP (RFC 9449) proof-of-possession for access tokens of machine clients (e.g. calling /ui-api)
+//!
+//! The client sends the access token in the Authorization header with the scheme DPoP and a proof in the DPoP header. The proof is a JWT signed with a key of the client. The access token is issued by the IdP and bound to this key via the claim cnf.jkt (JWK SHA-256 thumbprint). A leaked access token cannot be used without the key of the client
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use openidconnect::core::{CoreJsonWebKey, CoreJwsSigningAlgorithm, CoreProviderMetadata};
+use openidconnect::{EndUserUsername, JsonWebKey, SubjectIdentifier, url};
+use rocket::serde::json::serde_json;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::config::{CustomAppDpopConfig, CustomAppOidcConfig};
+
+use super::guard::{OidcUser, path_in};
+use super::routes::parse_claims;
+
+const DEFAULT_PATHS: [&str; 1] = ["/ui-api"];
+const DEFAULT_PROOF_MAX_AGE_SECONDS: u64 = 60;
+const DEFAULT_CLOCK_SKEW_SECONDS: u64 = 5;
+const DEFAULT_REPLAY_CACHE_SIZE: usize = 100_000;
+const PROOF_TYPE: &str = "dpop+jwt";
+// type of JWT access tokens (RFC 9068), so that e.g. ID tokens are not accepted
+const ACCESS_TOKEN_TYPES: [&str; 2] = ["at+jwt", "application/at+jwt"];
+
+// Errors in the DPoP configuration
+#[derive(Debug, PartialEq)]
+pub enum DpopConfigError {
+    InvalidPublicUrl(String),
+    InvalidPath(String),
+    MissingAudience,
+}
+
+// Reasons why a request with a DPoP-bound access token is rejected
+#[derive(Debug, PartialEq)]
+pub enum DpopError {
+    InvalidAuthorizationScheme,
+    MissingProof,
+    MultipleProofs,
+    MalformedJwt,
+    InvalidProofType,
+    UnsupportedAlgorithm,
+    InvalidProofKey,
+    InvalidSignature,
+    MethodMismatch,
+    UriMismatch,
+    ProofExpired,
+    AccessTokenHashMismatch,
+    ReplayedProof,
+    ReplayCacheFull,
+    InvalidAccessToken(String),
+    MissingTokenBinding,
+    TokenBindingMismatch,
+}
+
+// Verifies DPoP proofs and the access tokens bound to them. It is managed as state in Rocket, if DPoP is configured
+pub struct DpopVerifier {
+    public_url: url::Url,
+    paths: Vec<String>,
+    audience: String,
+    roles_claims: Option<Vec<String>>,
+    proof_max_age: u64,
+    clock_skew: u64,
+    replay_cache: ReplayCache,
+}
+
+// Identifiers (jti) of proofs that have been used already. Entries are removed after the proof expired
+struct ReplayCache {
+    entries: Mutex<HashMap<String, u64>>,
+    capacity: usize,
+}
+
+// JSON Web Token split into its parts
+struct Jwt {
+    header: serde_json::Value,
+    claims: serde_json::Value,
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+impl DpopVerifier {
+    /// Creates the verifier from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - DPoP configuration of the application
+    /// * `client_id` - OIDC client ID of the application, the audience if none is configured
+    ///
+    /// # Returns
+    /// Verifier or an error if the public URL is missing or invalid, a path is invalid or there is no audience
+    ///
+    pub fn new(
+        config: &CustomAppDpopConfig,
+        client_id: Option<&str>,
+    ) -> Result<DpopVerifier, DpopConfigError> {
+        let public_url = config.public_url.clone().unwrap_or_default();
+        let public_url = match url::Url::parse(&public_url) {
+            Ok(url) if url.query().is_none() && url.fragment().is_none() => url,
+            _ => return Err(DpopConfigError::InvalidPublicUrl(public_url)),
+        };
+        let paths = match &config.paths {
+            Some(paths) => paths.clone(),
+            None => DEFAULT_PATHS.iter().map(|path| path.to_string()).collect(),
+        };
+        if let Some(path) = paths.iter().find(|path| !path.starts_with('/')) {
+            return Err(DpopConfigError::InvalidPath(path.clone()));
+        }
+        Ok(DpopVerifier {
+            public_url,
+            paths,
+            audience: config
+                .audience
+                .as_deref()
+                .or(client_id)
+                .filter(|audience| !audience.is_empty())
+                .ok_or(DpopConfigError::MissingAudience)?
+                .to_string(),
+            roles_claims: config.roles_claims.clone(),
+            proof_max_age: config
+                .proof_max_age_seconds
+                .unwrap_or(DEFAULT_PROOF_MAX_AGE_SECONDS),
+            clock_skew: config
+                .clock_skew_seconds
+                .unwrap_or(DEFAULT_CLOCK_SKEW_SECONDS),
+            replay_cache: ReplayCache {
+                entries: Mutex::new(HashMap::new()),
+                capacity: config
+                    .replay_cache_size
+                    .unwrap_or(DEFAULT_REPLAY_CACHE_SIZE),
+            },
+        })
+    }
+
+    /// Returns true if DPoP-bound access tokens are accepted for the path
+    ///
+    /// # Arguments
+    /// * `path` - Path of the request
+    ///
+    pub fn applies_to(&self, path: &str) -> bool {
+        path_in(&self.paths, path)
+    }
+
+    /// Verifies the DPoP proof and the access token of a request and maps the access token to a user
+    ///
+    /// # Arguments
+    /// * `method` - HTTP method of the request
+    /// * `path` - Path of the request (without query)
+    /// * `authorization` - Authorization header of the request
+    /// * `proofs` - DPoP headers of the request
+    /// * `provider_metadata` - Provider metadata of the IdP including its keys to verify the access token
+    /// * `oidc_config` - Application-specific OIDC configuration - contains also the mapping of claims to roles
+    ///
+    /// # Returns
+    /// User with subject and mapped roles of the access token or the reason why the request is rejected
+    ///
+    pub fn authenticate(
+        &self,
+        method: &str,
+        path: &str,
+        authorization: &str,
+        proofs: &[&str],
+        provider_metadata: &CoreProviderMetadata,
+        oidc_config: &CustomAppOidcConfig,
+    ) -> Result<OidcUser, DpopError> {
+        let access_token = match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("DPoP") => token.trim(),
+            _ => return Err(DpopError::InvalidAuthorizationScheme),
+        };
+        let proof = match proofs {
+            [] => return Err(DpopError::MissingProof),
+            [proof] => Jwt::parse(proof)?,
+            _ => return Err(DpopError::MultipleProofs),
+        };
+        let now = now();
+        let jwk_thumbprint = self.verify_proof(&proof, method, path, access_token, now)?;
+        let access_token_claims = self.verify_access_token(access_token, provider_metadata, now)?;
+        match access_token_claims["cnf"]["jkt"].as_str() {
+            Some(jkt) if jkt == jwk_thumbprint => (),
+            Some(_) => return Err(DpopError::TokenBindingMismatch),
+            None => return Err(DpopError::MissingTokenBinding),
+        }
+        // only proofs of valid requests are remembered, otherwise invalid requests could fill the cache
+        let jti = proof.claims["jti"]
+            .as_str()
+            .ok_or(DpopError::MalformedJwt)?;
+        self.replay_cache.insert(
+            format!("{}:{}", jwk_thumbprint, jti),
+            now + self.proof_max_age + 2 * self.clock_skew,
+            now,
+        )?;
+        self.map_user(access_token_claims, oidc_config)
+    }
+
+    /// Verifies the proof according to RFC 9449, section 4.3
+    ///
+    /// # Returns
+    /// JWK SHA-256 thumbprint of the key of the client
+    ///
+    fn verify_proof(
+        &self,
+        proof: &Jwt,
+        method: &str,
+        path: &str,
+        access_token: &str,
+        now: u64,
+    ) -> Result<String, DpopError> {
+        if proof.header["typ"].as_str() != Some(PROOF_TYPE) {
+            return Err(DpopError::InvalidProofType);
+        }
+        let algorithm = signing_algorithm(&proof.header)?;
+        let jwk = &proof.header["jwk"];
+        // the proof must contain the public key only
+        if !jwk.is_object() || jwk.get("d").is_some() {
+            return Err(DpopError::InvalidProofKey);
+        }
+        let key: CoreJsonWebKey =
+            serde_json::from_value(jwk.clone()).map_err(|_| DpopError::InvalidProofKey)?;
+        key.verify_signature(&algorithm, proof.signing_input.as_bytes(), &proof.signature)
+            .map_err(|_| DpopError::InvalidSignature)?;
+        if proof.claims["htm"].as_str() != Some(method) {
+            return Err(DpopError::MethodMismatch);
+        }
+        let htu = proof.claims["htu"]
+            .as_str()
+            .and_then(|htu| url::Url::parse(htu).ok())
+            .map(|mut htu| {
+                htu.set_query(None);
+                htu.set_fragment(None);
+                htu
+            });
+        let expected_htu = format!("{}{}", self.public_url.as_str().trim_end_matches('/'), path);
+        if htu != url::Url::parse(&expected_htu).ok() {
+            return Err(DpopError::UriMismatch);
+        }
+        let iat = proof.claims["iat"]
+            .as_u64()
+            .ok_or(DpopError::MalformedJwt)?;
+        if iat + self.proof_max_age + self.clock_skew < now || iat > now + self.clock_skew {
+            return Err(DpopError::ProofExpired);
+        }
+        if proof.claims["jti"].as_str().is_none_or(str::is_empty) {
+            return Err(DpopError::MalformedJwt);
+        }
+        let access_token_hash = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(access_token));
+        if proof.claims["ath"].as_str() != Some(access_token_hash.as_str()) {
+            return Err(DpopError::AccessTokenHashMismatch);
+        }
+        jwk_thumbprint(jwk)
+    }
+
+    /// Verifies type, signature, issuer, lifetime and audience of the access token
+    ///
+    /// # Returns
+    /// Claims of the access token
+    ///
+    fn verify_access_token(
+        &self,
+        access_token: &str,
+        provider_metadata: &CoreProviderMetadata,
+        now: u64,
+    ) -> Result<serde_json::Value, DpopError> {
+        let access_token = Jwt::parse(access_token)?;
+        if !access_token.header["typ"].as_str().is_some_and(|typ| {
+            ACCESS_TOKEN_TYPES
+                .iter()
+                .any(|access_token_type| typ.eq_ignore_ascii_case(access_token_type))
+        }) {
+            return Err(DpopError::InvalidAccessToken("typ".to_string()));
+        }
+        let algorithm = signing_algorithm(&access_token.header)?;
+        let kid = access_token.header["kid"].as_str();
+        let verified = provider_metadata
+            .jwks()
+            .keys()
+            .iter()
+            .filter(|key| kid.is_none() || key.key_id().map(|key_id| key_id.as_str()) == kid)
+            .any(|key| {
+                key.verify_signature(
+                    &algorithm,
+                    access_token.signing_input.as_bytes(),
+                    &access_token.signature,
+                )
+                .is_ok()
+            });
+        if !verified {
+            return Err(DpopError::InvalidSignature);
+        }
+        let claims = access_token.claims;
+        if claims["iss"].as_str() != Some(provider_metadata.issuer().as_str()) {
+            return Err(DpopError::InvalidAccessToken("iss".to_string()));
+        }
+        match claims["exp"].as_u64() {
+            Some(exp) if exp + self.clock_skew >= now => (),
+            _ => return Err(DpopError::InvalidAccessToken("exp".to_string())),
+        }
+        if claims["nbf"]
+            .as_u64()
+            .is_some_and(|nbf| nbf > now + self.clock_skew)
+        {
+            return Err(DpopError::InvalidAccessToken("nbf".to_string()));
+        }
+        let audience = self.audience.as_str();
+        let matches = match &claims["aud"] {
+            serde_json::Value::String(aud) => aud == audience,
+            serde_json::Value::Array(auds) => auds.iter().any(|aud| aud == audience),
+            _ => false,
+        };
+        if !matches {
+            return Err(DpopError::InvalidAccessToken("aud".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Maps the claims of the access token to a user. Roles are mapped as configured for IdTokens unless roles_claims is configured
+    fn map_user(
+        &self,
+        claims: serde_json::Value,
+        oidc_config: &CustomAppOidcConfig,
+    ) -> Result<OidcUser, DpopError> {
+        let subject = claims["sub"]
+            .as_str()
+            .ok_or(DpopError::InvalidAccessToken("sub".to_string()))?
+            .to_string();
+        let preferred_username = claims["preferred_username"]
+            .as_str()
+            .map(|username| EndUserUsername::new(username.to_string()));
+        let roles_claims = self
+            .roles_claims
+            .clone()
+            .or_else(|| oidc_config.roles_idtoken_claims.clone())
+            .unwrap_or_default();
+        let claims = match claims {
+            serde_json::Value::Object(claims) => claims.into_iter().collect(),
+            _ => HashMap::new(),
+        };
+        Ok(OidcUser {
+            subject: SubjectIdentifier::new(subject),
+            preferred_username,
+            mapped_roles: parse_claims(claims, &roles_claims, oidc_config),
+            access_token: None,
+            impersonated_by: None,
+        })
+    }
+}
+
+impl ReplayCache {
+    /// Remembers a proof. Fails if the proof has been used already
+    fn insert(&self, key: String, expires: u64, now: u64) -> Result<(), DpopError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry_expires| *entry_expires >= now);
+        }
+        match entries.get(&key) {
+            Some(entry_expires) if *entry_expires >= now => Err(DpopError::ReplayedProof),
+            _ if entries.len() >= self.capacity => Err(DpopError::ReplayCacheFull),
+            _ => {
+                entries.insert(key, expires);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Jwt {
+    fn parse(jwt: &str) -> Result<Jwt, DpopError> {
+        let mut parts = jwt.split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(DpopError::MalformedJwt);
+        };
+        Ok(Jwt {
+            header: decode_json(header)?,
+            claims: decode_json(claims)?,
+            signing_input: format!("{}.{}", header, claims),
+            signature: BASE64_URL_SAFE_NO_PAD
+                .decode(signature)
+                .map_err(|_| DpopError::MalformedJwt)?,
+        })
+    }
+}
+
+fn decode_json(part: &str) -> Result<serde_json::Value, DpopError> {
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| DpopError::MalformedJwt)?;
+    match serde_json::from_slice(&bytes) {
+        Ok(value @ serde_json::Value::Object(_)) => Ok(value),
+        _ => Err(DpopError::MalformedJwt),
+    }
+}
+
+/// Only asymmetric algorithms are accepted, ie none and HMAC are rejected
+fn signing_algorithm(header: &serde_json::Value) -> Result<CoreJwsSigningAlgorithm, DpopError> {
+    let algorithm: CoreJwsSigningAlgorithm = serde_json::from_value(header["alg"].clone())
+        .map_err(|_| DpopError::UnsupportedAlgorithm)?;
+    match algorithm {
+        CoreJwsSigningAlgorithm::None
+        | CoreJwsSigningAlgorithm::HmacSha256
+        | CoreJwsSigningAlgorithm::HmacSha384
+        | CoreJwsSigningAlgorithm::HmacSha512 => Err(DpopError::UnsupportedAlgorithm),
+        algorithm => Ok(algorithm),
+    }
+}
+
+/// JWK SHA-256 thumbprint (RFC 7638) of a public key. The required members are serialized in lexicographic order without whitespace
+fn jwk_thumbprint(jwk: &serde_json::Value) -> Result<String, DpopError> {
+    let members: &[&str] = match jwk["kty"].as_str() {
+        Some("RSA") => &["e", "kty", "n"],
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("OKP") => &["crv", "kty", "x"],
+        _ => return Err(DpopError::InvalidProofKey),
+    };
+    let mut canonical = Vec::new();
+    for member in members {
+        let value = jwk[*member].as_str().ok_or(DpopError::InvalidProofKey)?;
+        canonical.push(format!("\"{}\":{}", member, serde_json::Value::from(value)));
+    }
+    let canonical = format!("{{{}}}", canonical.join(","));
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(canonical)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use openidconnect::core::{
+        CoreEdDsaPrivateSigningKey, CoreJsonWebKeySet, CoreRsaPrivateSigningKey,
+    };
+    use openidconnect::{JsonWebKeyId, PrivateSigningKey};
+
+    use super::*;
+
+    const IDP_KEY: &str = include_str!("../../tests/fixtures/idp_signing_key.pem");
+    const CLIENT_KEY: &str = include_str!("../../tests/fixtures/dpop_client_key.pem");
+    const ISSUER: &str = "https://idp.example.org";
+    const PUBLIC_URL: &str = "https://warehouse.example.org";
+
+    fn idp_key() -> CoreRsaPrivateSigningKey {
+        CoreRsaPrivateSigningKey::from_pem(IDP_KEY, Some(JsonWebKeyId::new("idp".to_string())))
+            .unwrap()
+    }
+
+    fn client_key() -> CoreEdDsaPrivateSigningKey {
+        CoreEdDsaPrivateSigningKey::from_ed25519_pem(CLIENT_KEY, None).unwrap()
+    }
+
+    fn sign<K>(
+        key: &K,
+        algorithm: CoreJwsSigningAlgorithm,
+        header: serde_json::Value,
+        claims: serde_json::Value,
+    ) -> String
+    where
+        K: PrivateSigningKey<VerificationKey = CoreJsonWebKey>,
+    {
+        let signing_input = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = key.sign(&algorithm, signing_input.as_bytes()).unwrap();
+        format!(
+            "{}.{}",
+            signing_input,
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    fn provider_metadata() -> CoreProviderMetadata {
+        let provider_metadata: CoreProviderMetadata = serde_json::from_value(serde_json::json!({
+            "issuer": ISSUER,
+            "authorization_endpoint": format!("{}/authorize", ISSUER),
+            "jwks_uri": format!("{}/jwks", ISSUER),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"]
+        }))
+        .unwrap();
+        provider_metadata.set_jwks(CoreJsonWebKeySet::new(vec![
+            idp_key().as_verification_key(),
+        ]))
+    }
+
+    /// Access token of the IdP bound to the key of the client
+    fn access_token(jwk: &CoreJsonWebKey) -> String {
+        token(jwk, "at+jwt", "warehouse")
+    }
+
+    /// JWT of the IdP with the type and audience bound to the key of the client
+    fn token(jwk: &CoreJsonWebKey, typ: &str, audience: &str) -> String {
+        let jkt = jwk_thumbprint(&serde_json::to_value(jwk).unwrap()).unwrap();
+        sign(
+            &idp_key(),
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            serde_json::json!({ "alg": "RS256", "typ": typ, "kid": "idp" }),
+            serde_json::json!({
+                "iss": ISSUER,
+                "sub": "scanner-01",
+                "aud": audience,
+                "exp": now() + 300,
+                "cnf": { "jkt": jkt },
+                "groups": ["scanner"]
+            }),
+        )
+    }
+
+    fn proof(method: &str, uri: &str, access_token: &str, jti: &str) -> String {
+        let key = client_key();
+        sign(
+            &key,
+            CoreJwsSigningAlgorithm::EdDsa,
+            serde_json::json!({
+                "typ": "dpop+jwt",
+                "alg": "EdDSA",
+                "jwk": key.as_verification_key()
+            }),
+            serde_json::json!({
+                "jti": jti,
+                "htm": method,
+                "htu": uri,
+                "iat": now(),
+                "ath": BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(access_token))
+            }),
+        )
+    }
+
+    fn verifier() -> DpopVerifier {
+        DpopVerifier::new(
+            &CustomAppDpopConfig {
+                public_url: Some(PUBLIC_URL.to_string()),
+                audience: Some("warehouse".to_string()),
+                ..Default::default()
+            },
+            Some("app"),
+        )
+        .unwrap()
+    }
+
+    fn authenticate(
+        verifier: &DpopVerifier,
+        access_token: &str,
+        proof: &str,
+    ) -> Result<OidcUser, DpopError> {
+        let oidc_config = CustomAppOidcConfig {
+            roles_idtoken_claims: Some(vec!["groups".to_string()]),
+            ..Default::default()
+        };
+        verifier.authenticate(
+            "GET",
+            "/ui-api/inventory",
+            &format!("DPoP {}", access_token),
+            &[proof],
+            &provider_metadata(),
+            &oidc_config,
+        )
+    }
+
+    #[test]
+    fn accepts_bound_token_and_rejects_replay() {
+        let verifier = verifier();
+        let access_token = access_token(&client_key().as_verification_key());
+        let proof = proof(
+            "GET",
+            "https://warehouse.example.org/ui-api/inventory?page=2",
+            &access_token,
+            "proof-1",
+        );
+        let user = authenticate(&verifier, &access_token, &proof).unwrap();
+        assert_eq!(user.subject.as_str(), "scanner-01");
+        assert_eq!(user.mapped_roles, vec!["scanner".to_string()]);
+        assert_eq!(
+            authenticate(&verifier, &access_token, &proof).err(),
+            Some(DpopError::ReplayedProof)
+        );
+    }
+
+    #[test]
+    fn rejects_token_bound_to_another_key() {
+        let access_token = access_token(&idp_key().as_verification_key());
+        let proof = proof(
+            "GET",
+            "https://warehouse.example.org/ui-api/inventory",
+            &access_token,
+            "proof-1",
+        );
+        assert_eq!(
+            authenticate(&verifier(), &access_token, &proof).err(),
+            Some(DpopError::TokenBindingMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_proof_for_other_request() {
+        let verifier = verifier();
+        let access_token = access_token(&client_key().as_verification_key());
+        let other_method = proof(
+            "POST",
+            "https://warehouse.example.org/ui-api/inventory",
+            &access_token,
+            "proof-1",
+        );
+        assert_eq!(
+            authenticate(&verifier, &access_token, &other_method).err(),
+            Some(DpopError::MethodMismatch)
+        );
+        let other_uri = proof(
+            "GET",
+            "https://warehouse.example.org/ui-api/order",
+            &access_token,
+            "proof-2",
+        );
+        assert_eq!(
+            authenticate(&verifier, &access_token, &other_uri).err(),
+            Some(DpopError::UriMismatch)
+        );
+        let other_token = proof(
+            "GET",
+            "https://warehouse.example.org/ui-api/inventory",
+            "other-access-token",
+            "proof-3",
+        );
+        assert_eq!(
+            authenticate(&verifier, &access_token, &other_token).err(),
+            Some(DpopError::AccessTokenHashMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_token_of_other_audience_or_type() {
+        let config = CustomAppDpopConfig {
+            public_url: Some(PUBLIC_URL.to_string()),
+            ..Default::default()
+        };
+        // the client ID is the audience by default
+        let verifier = DpopVerifier::new(&config, Some("warehouse")).unwrap();
+        let jwk = client_key().as_verification_key();
+        for (jti, access_token, err) in [
+            ("proof-1", token(&jwk, "at+jwt", "erp"), "aud"),
+            ("proof-2", token(&jwk, "JWT", "warehouse"), "typ"),
+        ] {
+            let proof = proof(
+                "GET",
+                "https://warehouse.example.org/ui-api/inventory",
+                &access_token,
+                jti,
+            );
+            assert_eq!(
+                authenticate(&verifier, &access_token, &proof).err(),
+                Some(DpopError::InvalidAccessToken(err.to_string()))
+            );
+        }
+        assert_eq!(
+            DpopVerifier::new(&config, None).err(),
+            Some(DpopConfigError::MissingAudience)
+        );
+    }
+
+    #[test]
+    fn rejects_unsigned_proof() {
+        let access_token = access_token(&client_key().as_verification_key());
+        let proof = proof(
+            "GET",
+            "https://warehouse.example.org/ui-api/inventory",
+            &access_token,
+            "proof-1",
+        );
+        let (signing_input, _) = proof.rsplit_once('.').unwrap();
+        assert_eq!(
+            authenticate(&verifier(), &access_token, &format!("{}.", signing_input)).err(),
+            Some(DpopError::InvalidSignature)
+        );
+    }
+}
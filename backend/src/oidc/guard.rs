@@ -1,23 +1,40 @@
 //! Request guard to ensure OIDC authentication to routes in Rocket
 
+use crate::configuration::config::CustomAppOidcConfig;
+use crate::mtls::principals::CertificatePrincipals;
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::SecurityEventLog;
+use crate::terms::acceptance::TermsOfUse;
+
+use super::cookies::OidcCookies;
+use super::dpop::DpopVerifier;
+use super::impersonation::{Impersonation, Impersonator};
+use super::keyring::CookieKeyRing;
 use super::oidcflow::{OidcFlow, OidcSessionCookie};
 
-use openidconnect::{ClaimsVerificationError, EndUserUsername, SubjectIdentifier};
+use openidconnect::{AccessToken, ClaimsVerificationError, EndUserUsername, SubjectIdentifier};
 use rocket::serde::json::serde_json;
 use rocket::{
     State,
-    http::{Cookie, Status},
+    http::{Method, Status},
+    mtls::Certificate,
     request::{self, FromRequest, Outcome, Request},
 };
 use serde::Serialize;
-use tracing::{Level, event};
 
 // Represents an authenticated user in a Rocket route
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct OidcUser {
     pub subject: SubjectIdentifier,
     pub preferred_username: Option<EndUserUsername>,
     pub mapped_roles: Vec<String>,
+    // access token of the OIDC session, e.g. to exchange it for calls of downstream APIs. It is not available for users authenticated with client certificates or DPoP
+    #[serde(skip)]
+    pub access_token: Option<AccessToken>,
+    // real identity of an admin who impersonates this user
+    pub impersonated_by: Option<Impersonator>,
 }
 
 // Loads user authentication information from the oidc session cookie
@@ -26,7 +43,8 @@ impl OidcUser {
         oidc: &OidcFlow,
         oidc_session: &OidcSessionCookie,
     ) -> Result<OidcUser, ClaimsVerificationError> {
-        let id_token_verifier = oidc.client.id_token_verifier();
+        let client = oidc.client();
+        let id_token_verifier = client.id_token_verifier();
         let id_token_claims = match oidc_session
             .id_token
             .claims(&id_token_verifier, &oidc.nonce)
@@ -45,53 +63,205 @@ impl OidcUser {
             subject,
             preferred_username,
             mapped_roles,
+            access_token: Some(oidc_session.access_token.clone()),
+            impersonated_by: None,
         })
     }
 }
 
+/// Returns true if the path is one of the prefixes or below one of them
+///
+/// # Arguments
+/// * `prefixes` - Path prefixes, e.g. /ui-api
+/// * `path` - Path of the request
+///
+pub(crate) fn path_in(prefixes: &[String], path: &str) -> bool {
+    prefixes.iter().any(|prefix| {
+        path.strip_prefix(prefix.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+// Marks a request on which a user was authenticated, e.g. so that responses with personal content are not cached
+pub struct AuthenticatedRequest(pub bool);
+
+// Marks a request whose user was authenticated with the access token of its Authorization header (DPoP), so that the token identifies the client, e.g. for the rate limiter
+pub struct TokenAuthenticatedRequest(pub bool);
+
+// Outcome of the authentication of a request, so that it is evaluated only once even if it is needed before the route (e.g. by the rate limiter)
+struct Authentication(request::Outcome<OidcUser, ()>);
+
 // Implementation of the request guard to ensure that the user is authenticated via OIDC
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for OidcUser {
     type Error = ();
 
-    /// Executed for each request on which route the OidcUser is included
-    /// Reads from the cookie the user information including the OIDC token
-    /// If they are not presented then a cookie is added from which route the user came from so the user is redirected there again after authentication
+    /// Executed for each request on which route the OidcUser is included. Marks the request as authenticated if a user is returned. The user is authenticated only once per request
     ///
     /// # Arguments
     /// * `req` - Request object
     ///
-    ///
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let mut cookies = req.cookies();
-        if let Some(serialized_session) = cookies.get_private("oidc_user_session") {
-            if let Ok(oidc_session) =
-                serde_json::from_str::<OidcSessionCookie>(serialized_session.value())
-            {
+        let outcome = req
+            .local_cache_async(async { Authentication(authenticate(req).await) })
+            .await
+            .0
+            .clone();
+        if outcome.is_success() {
+            req.local_cache(|| AuthenticatedRequest(true));
+        }
+        outcome
+    }
+}
+
+/// Authenticates the user of a request
+/// If client certificate principals are configured and the request presents a verified client certificate, the user is mapped from the certificate instead
+/// If DPoP is configured and the request contains an Authorization header, the user is mapped from the DPoP-bound access token instead
+/// If an admin impersonates another user, the impersonated user is returned
+/// If terms of use are configured and the user has not accepted the current version, the request is forwarded, so that the user is redirected to the terms of use
+/// Reads from the cookie the user information including the OIDC token. Cookies encrypted with a previous key of the key ring are re-issued with the active key
+/// If they are not presented then a cookie is added from which route the user came from so the user is redirected there again after authentication
+///
+/// # Arguments
+/// * `req` - Request object
+///
+///
+async fn authenticate(req: &Request<'_>) -> request::Outcome<OidcUser, ()> {
+    if let Some(certificate_principals) = req.rocket().state::<CertificatePrincipals>()
+        && certificate_principals.applies_to(req.uri().path().as_str())
+        && let Outcome::Success(certificate) = req.guard::<Certificate<'_>>().await
+    {
+        return match certificate_principals.authenticate(&certificate) {
+            Some(user) => Outcome::Success(user),
+            None => {
+                SecurityEventLog::of(req).record(
+                    SecurityEvent::new(
+                        SecurityEventType::AuthenticationFailed,
+                        SecuritySeverity::Warning,
+                        SecurityOutcome::Rejected,
+                        "Client certificate is not mapped to a principal".to_string(),
+                    )
+                    .with_request(req)
+                    .with_subject(certificate.subject().to_string()),
+                );
+                Outcome::Error((Status::Forbidden, ()))
+            }
+        };
+    }
+    if let Some(dpop_verifier) = req.rocket().state::<DpopVerifier>()
+        && dpop_verifier.applies_to(req.uri().path().as_str())
+        && let Some(authorization) = req.headers().get_one("Authorization")
+    {
+        let oidc = req.guard::<&State<OidcFlow>>().await.unwrap();
+        let oidc_config = req.guard::<&State<CustomAppOidcConfig>>().await.unwrap();
+        let proofs: Vec<&str> = req.headers().get("DPoP").collect();
+        return match dpop_verifier.authenticate(
+            req.method().as_str(),
+            req.uri().path().as_str(),
+            authorization,
+            &proofs,
+            &oidc.provider_metadata(),
+            oidc_config,
+        ) {
+            Ok(user) => {
+                req.local_cache(|| TokenAuthenticatedRequest(true));
+                Outcome::Success(user)
+            }
+            Err(err) => {
+                SecurityEventLog::of(req).record(
+                    SecurityEvent::new(
+                        SecurityEventType::AuthenticationFailed,
+                        SecuritySeverity::Warning,
+                        SecurityOutcome::Rejected,
+                        format!("Rejected DPoP-bound access token: {:?}", err),
+                    )
+                    .with_request(req),
+                );
+                Outcome::Error((Status::Unauthorized, ()))
+            }
+        };
+    }
+    let mut cookies = req.cookies();
+    let oidc_cookies = req.guard::<&State<OidcCookies>>().await.unwrap();
+    let cookie_key_ring = req.guard::<&State<CookieKeyRing>>().await.unwrap();
+    if let Some(serialized_session) =
+        cookie_key_ring.get_private(cookies, oidc_cookies, &oidc_cookies.session_cookie_name)
+    {
+        match serde_json::from_str::<OidcSessionCookie>(serialized_session.value()) {
+            Ok(oidc_session) => {
                 let oidc = req.guard::<&State<OidcFlow>>().await.unwrap();
 
                 match OidcUser::load_from_session(&oidc, &oidc_session) {
-                    Ok(user) => Outcome::Success(user),
-                    Err(_) => {
-                        cookies.remove_private(serialized_session);
+                    Ok(user) => {
+                        let user = match req.rocket().state::<Impersonation>() {
+                            Some(impersonation) => match impersonation.apply(req, user).await {
+                                Ok(user) => user,
+                                Err(status) => return Outcome::Error((status, ())),
+                            },
+                            None => user,
+                        };
+                        let Some(terms) = req.rocket().state::<TermsOfUse>() else {
+                            return Outcome::Success(user);
+                        };
+                        match terms.is_accepted_for(req, &user).await {
+                            Ok(true) => Outcome::Success(user),
+                            // requests of the frontend (e.g. POST to the API) cannot follow a redirect, they get a problem response (see terms_forbidden)
+                            Ok(false) if req.method() != Method::Get => {
+                                Outcome::Error((Status::Forbidden, ()))
+                            }
+                            Ok(false) => {
+                                cookie_key_ring.add_private(
+                                    cookies,
+                                    oidc_cookies.redirect_destination_cookie(req.uri().to_string()),
+                                );
+                                Outcome::Forward(Status::Ok)
+                            }
+                            Err(_) => Outcome::Error((Status::ServiceUnavailable, ())),
+                        }
+                    }
+                    Err(err) => {
+                        SecurityEventLog::of(req).record(
+                            SecurityEvent::new(
+                                SecurityEventType::AuthenticationFailed,
+                                SecuritySeverity::Warning,
+                                SecurityOutcome::Rejected,
+                                format!("Invalid OIDC session: {:?}", err),
+                            )
+                            .with_request(req),
+                        );
+                        cookies.remove_private(
+                            oidc_cookies.removal_cookie(&oidc_cookies.session_cookie_name),
+                        );
                         Outcome::Error((Status::UnprocessableEntity, ()))
                     }
                 }
-            } else {
-                cookies.remove_private(serialized_session);
-                cookies.add_private(Cookie::new(
-                    "oidc_redirect_destination",
-                    req.uri().to_string(),
-                ));
+            }
+            // e.g. a cookie of a previous version of the application or a forged cookie that was encrypted with a leaked key
+            Err(err) => {
+                SecurityEventLog::of(req).record(
+                    SecurityEvent::new(
+                        SecurityEventType::AuthenticationFailed,
+                        SecuritySeverity::Warning,
+                        SecurityOutcome::Rejected,
+                        format!("Invalid OIDC session cookie: {}", err),
+                    )
+                    .with_request(req),
+                );
+                cookies
+                    .remove_private(oidc_cookies.removal_cookie(&oidc_cookies.session_cookie_name));
+                cookie_key_ring.add_private(
+                    cookies,
+                    oidc_cookies.redirect_destination_cookie(req.uri().to_string()),
+                );
                 Outcome::Forward(Status::Ok)
             }
-        } else {
-            cookies.add_private(Cookie::new(
-                "oidc_redirect_destination",
-                req.uri().to_string(),
-            ));
-            Outcome::Forward(Status::Ok)
         }
+    } else {
+        cookie_key_ring.add_private(
+            cookies,
+            oidc_cookies.redirect_destination_cookie(req.uri().to_string()),
+        );
+        Outcome::Forward(Status::Ok)
     }
 }
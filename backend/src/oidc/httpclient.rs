@@ -0,0 +1,395 @@
+//! HTTP client for all requests to the OIDC IdP (e.g. discovery, token and UserInfo endpoint)
+//!
+//! The client is created once and shared. It can be configured with a custom CA, a client certificate, a proxy, timeouts and retries
+//! Both clients limit the time of a request from connecting until the response is read completely, so that a slow IdP cannot block a login or the startup
+
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use openidconnect::reqwest;
+use openidconnect::{AsyncHttpClient, HttpClientError, HttpRequest, HttpResponse, SyncHttpClient};
+use tracing::{Level, event};
+
+use crate::configuration::config::CustomAppOidcConfig;
+
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_RETRY_BACKOFF_MILLISECONDS: u64 = 200;
+
+// Errors when creating the HTTP client
+#[derive(Debug)]
+pub enum IdpHttpClientError {
+    CaFile(String),
+    ClientCertificate(String),
+    Proxy(String),
+    Build(String),
+}
+
+// Settings shared by the async and the blocking client
+struct IdpHttpClientSettings {
+    ca_certificates: Vec<reqwest::Certificate>,
+    identity: Option<reqwest::Identity>,
+    proxy: Option<reqwest::Proxy>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+// Async client used in Rocket routes and request guards
+#[derive(Clone)]
+pub struct IdpHttpClient {
+    client: reqwest::Client,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+// Blocking client used during startup (e.g. for discovery of the provider metadata)
+pub struct IdpBlockingHttpClient {
+    client: reqwest::blocking::Client,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+impl IdpHttpClientSettings {
+    fn new(config: &CustomAppOidcConfig) -> Result<IdpHttpClientSettings, IdpHttpClientError> {
+        let ca_certificates = match &config.http_ca_file {
+            Some(ca_file) => fs::read(ca_file)
+                .map_err(|err| err.to_string())
+                .and_then(|pem| {
+                    reqwest::Certificate::from_pem_bundle(&pem).map_err(|err| err.to_string())
+                })
+                .map_err(IdpHttpClientError::CaFile)?,
+            None => Vec::new(),
+        };
+        let identity = match (
+            &config.http_client_certificate_file,
+            &config.http_client_key_file,
+        ) {
+            (Some(certificate_file), Some(key_file)) => {
+                let mut pem = fs::read(certificate_file)
+                    .map_err(|err| IdpHttpClientError::ClientCertificate(err.to_string()))?;
+                pem.push(b'\n');
+                pem.extend(
+                    fs::read(key_file)
+                        .map_err(|err| IdpHttpClientError::ClientCertificate(err.to_string()))?,
+                );
+                Some(
+                    reqwest::Identity::from_pem(&pem)
+                        .map_err(|err| IdpHttpClientError::ClientCertificate(err.to_string()))?,
+                )
+            }
+            (None, None) => None,
+            _ => {
+                return Err(IdpHttpClientError::ClientCertificate(
+                    "http_client_certificate_file and http_client_key_file must be configured together"
+                        .to_string(),
+                ));
+            }
+        };
+        let proxy = match &config.http_proxy {
+            Some(proxy) => Some(
+                reqwest::Proxy::all(proxy)
+                    .map_err(|err| IdpHttpClientError::Proxy(err.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(IdpHttpClientSettings {
+            ca_certificates,
+            identity,
+            proxy,
+            connect_timeout: Duration::from_secs(
+                config
+                    .http_connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            timeout: Duration::from_secs(
+                config
+                    .http_timeout_seconds
+                    .unwrap_or(DEFAULT_TIMEOUT_SECONDS),
+            ),
+            retries: config.http_retries.unwrap_or(0),
+            retry_backoff: Duration::from_millis(
+                config
+                    .http_retry_backoff_milliseconds
+                    .unwrap_or(DEFAULT_RETRY_BACKOFF_MILLISECONDS),
+            ),
+        })
+    }
+}
+
+impl IdpHttpClient {
+    /// Creates the async HTTP client
+    ///
+    /// # Arguments
+    /// * `config` - Application-specific OIDC configuration
+    ///
+    /// # Returns
+    /// HTTP client or an error if the configuration is invalid (e.g. CA file cannot be read)
+    ///
+    pub fn new(config: &CustomAppOidcConfig) -> Result<IdpHttpClient, IdpHttpClientError> {
+        let settings = IdpHttpClientSettings::new(config)?;
+        let mut builder = reqwest::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF vulnerabilities.
+            .redirect(reqwest::redirect::Policy::none())
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.timeout);
+        for ca_certificate in settings.ca_certificates {
+            builder = builder.add_root_certificate(ca_certificate);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+            .build()
+            .map_err(|err| IdpHttpClientError::Build(err.to_string()))?;
+        Ok(IdpHttpClient {
+            client,
+            retries: settings.retries,
+            retry_backoff: settings.retry_backoff,
+        })
+    }
+
+    /// Returns the underlying reqwest client, e.g. to call APIs that are not supported by the openidconnect crate
+    pub fn inner(&self) -> &reqwest::Client {
+        &self.client
+    }
+}
+
+impl IdpBlockingHttpClient {
+    /// Creates the blocking HTTP client
+    ///
+    /// # Arguments
+    /// * `config` - Application-specific OIDC configuration
+    ///
+    /// # Returns
+    /// HTTP client or an error if the configuration is invalid (e.g. CA file cannot be read)
+    ///
+    pub fn new(config: &CustomAppOidcConfig) -> Result<IdpBlockingHttpClient, IdpHttpClientError> {
+        let settings = IdpHttpClientSettings::new(config)?;
+        let mut builder = reqwest::blocking::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF vulnerabilities.
+            .redirect(reqwest::redirect::Policy::none())
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.timeout);
+        for ca_certificate in settings.ca_certificates {
+            builder = builder.add_root_certificate(ca_certificate);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+            .build()
+            .map_err(|err| IdpHttpClientError::Build(err.to_string()))?;
+        Ok(IdpBlockingHttpClient {
+            client,
+            retries: settings.retries,
+            retry_backoff: settings.retry_backoff,
+        })
+    }
+}
+
+impl<'c> AsyncHttpClient<'c> for IdpHttpClient {
+    type Error = HttpClientError<reqwest::Error>;
+    type Future = Pin<Box<dyn Future<Output = Result<HttpResponse, Self::Error>> + Send + 'c>>;
+
+    fn call(&'c self, request: HttpRequest) -> Self::Future {
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                let result = self.client.call(copy_request(&request)?).await;
+                if attempt >= self.retries || !is_retryable(request.method(), &result) {
+                    return result;
+                }
+                attempt += 1;
+                event!(
+                    Level::WARN,
+                    "Request to IdP failed, retrying ({}/{})",
+                    attempt,
+                    self.retries
+                );
+                rocket::tokio::time::sleep(self.retry_backoff * attempt).await;
+            }
+        })
+    }
+}
+
+impl SyncHttpClient for IdpBlockingHttpClient {
+    type Error = HttpClientError<reqwest::Error>;
+
+    fn call(&self, request: HttpRequest) -> Result<HttpResponse, Self::Error> {
+        let mut attempt = 0;
+        loop {
+            let result = self.client.call(copy_request(&request)?);
+            if attempt >= self.retries || !is_retryable(request.method(), &result) {
+                return result;
+            }
+            attempt += 1;
+            event!(
+                Level::WARN,
+                "Request to IdP failed, retrying ({}/{})",
+                attempt,
+                self.retries
+            );
+            std::thread::sleep(self.retry_backoff * attempt);
+        }
+    }
+}
+
+/// Connection errors are retried for all requests, because the request has not been sent. Timeouts and server errors are only retried for idempotent requests (e.g. discovery, JWKS and UserInfo), because the IdP may have processed the request already, e.g. redeemed the single-use authorization code. Client errors (e.g. an invalid code) are never retried
+fn is_retryable(
+    method: &openidconnect::http::Method,
+    result: &Result<HttpResponse, HttpClientError<reqwest::Error>>,
+) -> bool {
+    let idempotent = [
+        openidconnect::http::Method::GET,
+        openidconnect::http::Method::HEAD,
+    ]
+    .contains(method);
+    match result {
+        Ok(response) => idempotent && response.status().is_server_error(),
+        Err(HttpClientError::Reqwest(err)) => err.is_connect() || (idempotent && err.is_timeout()),
+        Err(_) => false,
+    }
+}
+
+/// Copies a request so that it can be sent again (http::Request does not implement Clone)
+fn copy_request(request: &HttpRequest) -> Result<HttpRequest, HttpClientError<reqwest::Error>> {
+    let mut builder = openidconnect::http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version());
+    for (name, value) in request.headers() {
+        builder = builder.header(name, value);
+    }
+    builder
+        .body(request.body().clone())
+        .map_err(HttpClientError::Http)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use openidconnect::http::Method;
+    use rocket::serde::json::serde_json;
+
+    use super::*;
+    use crate::oidc::testing::serve;
+
+    /// Starts a server that answers /slow after two seconds, /invalid with 400 and all other paths with 503
+    ///
+    /// # Returns
+    /// URL of the server and the number of requests it received
+    ///
+    fn serve_failing_idp() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        serve(listener, move |request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            match path {
+                "/slow" => {
+                    std::thread::sleep(Duration::from_secs(2));
+                    ("200 OK", serde_json::json!({}))
+                }
+                "/invalid" => (
+                    "400 Bad Request",
+                    serde_json::json!({ "error": "invalid_grant" }),
+                ),
+                _ => ("503 Service Unavailable", serde_json::json!({})),
+            }
+        });
+        (url, requests)
+    }
+
+    fn config() -> CustomAppOidcConfig {
+        CustomAppOidcConfig {
+            http_timeout_seconds: Some(1),
+            http_retries: Some(2),
+            http_retry_backoff_milliseconds: Some(100),
+            ..Default::default()
+        }
+    }
+
+    fn request(method: Method, url: &str) -> HttpRequest {
+        openidconnect::http::Request::builder()
+            .method(method)
+            .uri(url)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn retries_idempotent_requests_with_backoff() {
+        let (url, requests) = serve_failing_idp();
+        let client = IdpHttpClient::new(&config()).unwrap();
+        let start = Instant::now();
+        let response = client
+            .call(request(Method::GET, &format!("{}/jwks", url)))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 503);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        // the backoff increases with each retry: 100 ms + 200 ms
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // client errors are not retried
+        let response = client
+            .call(request(Method::GET, &format!("{}/invalid", url)))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[rocket::async_test]
+    async fn does_not_retry_requests_the_idp_may_have_processed() {
+        let (url, requests) = serve_failing_idp();
+        let client = IdpHttpClient::new(&config()).unwrap();
+        let response = client
+            .call(request(Method::POST, &format!("{}/token", url)))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 503);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let start = Instant::now();
+        let result = client
+            .call(request(Method::POST, &format!("{}/slow", url)))
+            .await;
+        assert!(matches!(result, Err(HttpClientError::Reqwest(err)) if err.is_timeout()));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn blocking_client_limits_whole_request() {
+        let (url, requests) = serve_failing_idp();
+        let client = IdpBlockingHttpClient::new(&CustomAppOidcConfig {
+            http_retries: Some(1),
+            ..config()
+        })
+        .unwrap();
+        let start = Instant::now();
+        let result = client.call(request(Method::GET, &format!("{}/slow", url)));
+        assert!(matches!(result, Err(HttpClientError::Reqwest(err)) if err.is_timeout()));
+        // two attempts of one second each and the backoff
+        assert!(start.elapsed() >= Duration::from_millis(2000));
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
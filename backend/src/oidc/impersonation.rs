@@ -0,0 +1,503 @@
+//! Impersonation ("view as user") for support staff
+//!
+//! A user with an admin role can start to impersonate another subject with given roles out of the allowed roles. The guard then yields the impersonated user and keeps the real identity in impersonated_by. Each request during impersonation is recorded as security event with the real and the impersonated subject and each response carries a marker header. Write operations can be blocked while impersonating
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openidconnect::{EndUserUsername, SubjectIdentifier};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{CookieJar, Header, Method, Status};
+use rocket::serde::json::{Json, serde_json};
+use rocket::{Request, Response, State};
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::config::CustomAppImpersonationConfig;
+use crate::securityevents::event::{
+    SecurityEvent, SecurityEventType, SecurityOutcome, SecuritySeverity,
+};
+use crate::securityevents::log::{RequestSecurityEventLog, SecurityEventLog};
+
+use super::cookies::OidcCookies;
+use super::guard::OidcUser;
+use super::keyring::CookieKeyRing;
+
+const DEFAULT_COOKIE_NAME: &str = "oidc_impersonation";
+const DEFAULT_MAX_DURATION_SECONDS: u64 = 3600;
+pub const MARKER_HEADER: &str = "X-Impersonated-By";
+
+// Settings of impersonation. It is managed as state in Rocket, if admin roles are configured
+#[derive(Debug, Clone)]
+pub struct Impersonation {
+    admin_roles: Vec<String>,
+    // roles that impersonated users may have, e.g. the roles of warehouse users
+    allowed_roles: Vec<String>,
+    block_writes: bool,
+    pub cookie_name: String,
+    max_duration: u64,
+}
+
+// Real identity of a user who impersonates another user
+#[derive(Debug, Clone, Serialize)]
+pub struct Impersonator {
+    pub subject: SubjectIdentifier,
+    pub preferred_username: Option<EndUserUsername>,
+}
+
+// Request of an admin to impersonate a subject
+#[derive(Debug, Deserialize)]
+pub struct ImpersonationRequest {
+    subject: String,
+    preferred_username: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+// Impersonation stored in a private (encrypted and tamperproof) cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct ImpersonationCookie {
+    real_subject: String,
+    subject: String,
+    preferred_username: Option<String>,
+    roles: Vec<String>,
+    started: u64,
+}
+
+// Identities of the current request for the security events and the marker header
+#[derive(Default)]
+struct ImpersonationContext(Option<(String, String)>);
+
+// Fairing that records responses during impersonation as security events and adds the marker header
+pub struct ImpersonationAudit;
+
+impl Impersonation {
+    /// Creates the settings from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Impersonation configuration of the application
+    ///
+    pub fn new(config: &CustomAppImpersonationConfig) -> Impersonation {
+        Impersonation {
+            admin_roles: config.admin_roles.clone().unwrap_or_default(),
+            allowed_roles: config.allowed_roles.clone().unwrap_or_default(),
+            block_writes: config.block_writes.unwrap_or(false),
+            cookie_name: config
+                .cookie_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COOKIE_NAME.to_string()),
+            max_duration: config
+                .max_duration_seconds
+                .unwrap_or(DEFAULT_MAX_DURATION_SECONDS),
+        }
+    }
+
+    fn is_admin(&self, user: &OidcUser) -> bool {
+        user.mapped_roles
+            .iter()
+            .any(|role| self.admin_roles.contains(role))
+    }
+
+    /// Replaces the authenticated user by the impersonated user, if the user started an impersonation
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    /// * `user` - User authenticated via the OIDC session
+    ///
+    /// # Returns
+    /// Impersonated user, the authenticated user if there is no valid impersonation or Forbidden if writes are blocked
+    ///
+    pub(crate) async fn apply(
+        &self,
+        req: &Request<'_>,
+        user: OidcUser,
+    ) -> Result<OidcUser, Status> {
+        let cookies = req.cookies();
+        let oidc_cookies = req.guard::<&State<OidcCookies>>().await.unwrap();
+        let cookie_key_ring = req.guard::<&State<CookieKeyRing>>().await.unwrap();
+        let Some(cookie) = cookie_key_ring.get_private(cookies, oidc_cookies, &self.cookie_name)
+        else {
+            return Ok(user);
+        };
+        let security_event_log = SecurityEventLog::of(req);
+        let impersonation = match serde_json::from_str::<ImpersonationCookie>(cookie.value()) {
+            Ok(impersonation) => impersonation,
+            // e.g. a cookie of a previous version of the application
+            Err(err) => {
+                security_event_log.record(
+                    SecurityEvent::new(
+                        SecurityEventType::Impersonation,
+                        SecuritySeverity::Warning,
+                        SecurityOutcome::Rejected,
+                        format!("Invalid impersonation cookie: {}", err),
+                    )
+                    .with_request(req)
+                    .with_subject(user.subject.as_str()),
+                );
+                cookies.remove_private(oidc_cookies.removal_cookie(&self.cookie_name));
+                return Ok(user);
+            }
+        };
+        let ended = if impersonation.real_subject != user.subject.as_str() || !self.is_admin(&user)
+        {
+            Some((
+                SecuritySeverity::Warning,
+                SecurityOutcome::Rejected,
+                "is not permitted",
+            ))
+        } else if impersonation.started + self.max_duration < now() {
+            Some((SecuritySeverity::Info, SecurityOutcome::Logged, "expired"))
+        } else {
+            None
+        };
+        if let Some((severity, outcome, reason)) = ended {
+            security_event_log.record(
+                impersonation_event(
+                    severity,
+                    outcome,
+                    format!("Impersonation of {} {}", impersonation.subject, reason),
+                    user.subject.as_str(),
+                    &impersonation.subject,
+                )
+                .with_request(req),
+            );
+            cookies.remove_private(oidc_cookies.removal_cookie(&self.cookie_name));
+            return Ok(user);
+        }
+        req.local_cache(|| {
+            ImpersonationContext(Some((
+                impersonation.real_subject.clone(),
+                impersonation.subject.clone(),
+            )))
+        });
+        if self.block_writes
+            && !matches!(req.method(), Method::Get | Method::Head | Method::Options)
+        {
+            security_event_log.record(
+                impersonation_event(
+                    SecuritySeverity::Warning,
+                    SecurityOutcome::Blocked,
+                    format!("Blocked write {} {}", req.method(), req.uri()),
+                    &impersonation.real_subject,
+                    &impersonation.subject,
+                )
+                .with_request(req),
+            );
+            return Err(Status::Forbidden);
+        }
+        Ok(OidcUser {
+            subject: SubjectIdentifier::new(impersonation.subject),
+            preferred_username: impersonation.preferred_username.map(EndUserUsername::new),
+            mapped_roles: impersonation.roles,
+            // downstream APIs must not be called with the identity of the admin
+            access_token: None,
+            impersonated_by: Some(Impersonator {
+                subject: user.subject,
+                preferred_username: user.preferred_username,
+            }),
+        })
+    }
+}
+
+/// Starts to impersonate a subject with the given roles. Only admins can impersonate and the roles must not contain an admin role
+///
+/// # Arguments
+/// * `user` - Authenticated admin (injected by Rocket via the custom request guard)
+/// * `cookies` - Cookies of the user (injected by Rocket)
+/// * `impersonation` - Impersonation settings (injected by Rocket)
+/// * `oidc_cookies` - Cookie policy (injected by Rocket)
+/// * `cookie_key_ring` - Keys to encrypt the impersonation cookie (injected by Rocket)
+/// * `security_event_log` - Security event log of the request (injected by Rocket)
+/// * `request` - Subject and roles to impersonate
+///
+/// # Returns
+/// NoContent if the impersonation started, Forbidden if the user is not permitted or a role is not allowed or Conflict if the user impersonates already
+///
+#[post("/start", data = "<request>")]
+pub async fn impersonation_start(
+    user: OidcUser,
+    cookies: &CookieJar<'_>,
+    impersonation: &State<Impersonation>,
+    oidc_cookies: &State<OidcCookies>,
+    cookie_key_ring: &State<CookieKeyRing>,
+    security_event_log: RequestSecurityEventLog<'_>,
+    request: Json<ImpersonationRequest>,
+) -> Status {
+    if user.impersonated_by.is_some() {
+        return Status::Conflict;
+    }
+    // impersonation requires an OIDC session, ie it is not available for client certificates or DPoP
+    let denial = if user.access_token.is_none() || !impersonation.is_admin(&user) {
+        Some("the user is not an admin")
+    } else if request
+        .roles
+        .iter()
+        .any(|role| impersonation.admin_roles.contains(role))
+    {
+        Some("the roles contain an admin role")
+    } else if request
+        .roles
+        .iter()
+        .any(|role| !impersonation.allowed_roles.contains(role))
+    {
+        Some("the roles contain a role that is not allowed")
+    } else {
+        None
+    };
+    if let Some(reason) = denial {
+        security_event_log.record(impersonation_event(
+            SecuritySeverity::Warning,
+            SecurityOutcome::Rejected,
+            format!("Denied impersonation: {}", reason),
+            user.subject.as_str(),
+            &request.subject,
+        ));
+        return Status::Forbidden;
+    }
+    let request = request.into_inner();
+    let cookie = ImpersonationCookie {
+        real_subject: user.subject.to_string(),
+        subject: request.subject,
+        preferred_username: request.preferred_username,
+        roles: request.roles,
+        started: now(),
+    };
+    let Ok(value) = serde_json::to_string(&cookie) else {
+        return Status::InternalServerError;
+    };
+    security_event_log.record(impersonation_event(
+        SecuritySeverity::Info,
+        SecurityOutcome::Logged,
+        format!("Started impersonation with roles {:?}", cookie.roles),
+        &cookie.real_subject,
+        &cookie.subject,
+    ));
+    cookie_key_ring.add_private(
+        cookies,
+        oidc_cookies.cookie(&impersonation.cookie_name, value),
+    );
+    Status::NoContent
+}
+
+/// Stops the impersonation. It does not require authentication, because it only removes the impersonation cookie
+///
+/// # Arguments
+/// * `cookies` - Cookies of the user (injected by Rocket)
+/// * `impersonation` - Impersonation settings (injected by Rocket)
+/// * `oidc_cookies` - Cookie policy (injected by Rocket)
+/// * `cookie_key_ring` - Keys to decrypt the impersonation cookie (injected by Rocket)
+/// * `security_event_log` - Security event log of the request (injected by Rocket)
+///
+/// # Returns
+/// NoContent
+///
+#[post("/stop")]
+pub async fn impersonation_stop(
+    cookies: &CookieJar<'_>,
+    impersonation: &State<Impersonation>,
+    oidc_cookies: &State<OidcCookies>,
+    cookie_key_ring: &State<CookieKeyRing>,
+    security_event_log: RequestSecurityEventLog<'_>,
+) -> Status {
+    if let Some(cookie) =
+        cookie_key_ring.get_private(cookies, oidc_cookies, &impersonation.cookie_name)
+        && let Ok(impersonation) = serde_json::from_str::<ImpersonationCookie>(cookie.value())
+    {
+        security_event_log.record(impersonation_event(
+            SecuritySeverity::Info,
+            SecurityOutcome::Logged,
+            "Stopped impersonation".to_string(),
+            &impersonation.real_subject,
+            &impersonation.subject,
+        ));
+    }
+    cookies.remove_private(oidc_cookies.removal_cookie(&impersonation.cookie_name));
+    Status::NoContent
+}
+
+// Fairing implementation
+#[rocket::async_trait]
+impl Fairing for ImpersonationAudit {
+    fn info(&self) -> Info {
+        Info {
+            name: "OIDC - Impersonation Audit",
+            kind: Kind::Response,
+        }
+    }
+
+    /// Executed for each response. Records requests during impersonation as security events and adds the marker header
+    ///
+    /// # Arguments
+    /// * `self` - Struct Impersonation Audit
+    /// * `req` - Request object
+    /// * `res` - Response object
+    ///
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let ImpersonationContext(Some((real_subject, subject))) =
+            req.local_cache(ImpersonationContext::default)
+        {
+            SecurityEventLog::of(req).record(
+                impersonation_event(
+                    SecuritySeverity::Info,
+                    SecurityOutcome::Logged,
+                    format!("{} {}: {}", req.method(), req.uri(), res.status()),
+                    real_subject,
+                    subject,
+                )
+                .with_request(req),
+            );
+            res.set_header(Header::new(MARKER_HEADER, real_subject.clone()));
+        }
+    }
+}
+
+/// Creates a security event of an impersonation
+///
+/// # Arguments
+/// * `severity` - Severity of the event
+/// * `outcome` - Decision that was taken
+/// * `message` - Human readable description
+/// * `real_subject` - Subject of the admin
+/// * `subject` - Impersonated subject
+///
+fn impersonation_event(
+    severity: SecuritySeverity,
+    outcome: SecurityOutcome,
+    message: String,
+    real_subject: &str,
+    subject: &str,
+) -> SecurityEvent {
+    SecurityEvent::new(SecurityEventType::Impersonation, severity, outcome, message)
+        .with_subject(real_subject)
+        .with_impersonated_subject(subject)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::configuration::config::{CustomAppCookiesConfig, CustomAppKeyRingConfig};
+    use crate::oidc::routes::{oidc_redirect, oidc_user_info};
+    use crate::oidc::testing::{ProviderFiles, login, oidc_config, start_idp};
+
+    #[post("/write")]
+    fn write(user: OidcUser) -> String {
+        user.subject.to_string()
+    }
+
+    /// Client logged in as alice with the roles admin and viewer and the security events it recorded
+    fn client() -> (Client, ProviderFiles, Arc<Mutex<Vec<SecurityEvent>>>) {
+        let (files, oidc_flow) = start_idp();
+        let (security_event_log, security_events) = SecurityEventLog::capture();
+        let csrf_state = oidc_flow.csrf_state.secret().clone();
+        let rocket = rocket::build()
+            .manage(oidc_flow)
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .manage(oidc_config())
+            .manage(Impersonation::new(&CustomAppImpersonationConfig {
+                admin_roles: Some(vec!["admin".to_string()]),
+                allowed_roles: Some(vec!["viewer".to_string()]),
+                block_writes: Some(true),
+                ..Default::default()
+            }))
+            .manage(security_event_log)
+            .attach(ImpersonationAudit)
+            .mount("/oidc", routes![oidc_redirect, oidc_user_info])
+            .mount(
+                "/oidc/impersonation",
+                routes![impersonation_start, impersonation_stop],
+            )
+            .mount("/ui-api", routes![write]);
+        let client = Client::tracked(rocket).unwrap();
+        login(&client, &csrf_state);
+        (client, files, security_events)
+    }
+
+    fn user_info(client: &Client) -> (serde_json::Value, Option<String>) {
+        let response = client.get("/oidc/userinfo").dispatch();
+        let marker = response
+            .headers()
+            .get_one(MARKER_HEADER)
+            .map(str::to_string);
+        (
+            serde_json::from_str(&response.into_string().unwrap()).unwrap(),
+            marker,
+        )
+    }
+
+    #[test]
+    fn admin_views_as_user_until_stopped() {
+        let (client, _files, security_events) = client();
+        let response = client
+            .post("/oidc/impersonation/start")
+            .header(ContentType::JSON)
+            .body(r#"{"subject": "bob", "roles": ["viewer"]}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+
+        let (user, marker) = user_info(&client);
+        assert_eq!(user["subject"], "bob");
+        assert_eq!(user["mapped_roles"], serde_json::json!(["viewer"]));
+        assert_eq!(user["impersonated_by"]["subject"], "alice");
+        assert_eq!(marker.as_deref(), Some("alice"));
+
+        let response = client.post("/ui-api/write").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let blocked = security_events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|security_event| security_event.outcome == SecurityOutcome::Blocked)
+            .cloned()
+            .unwrap();
+        assert_eq!(blocked.event_type, SecurityEventType::Impersonation);
+        assert_eq!(blocked.subject.as_deref(), Some("alice"));
+        assert_eq!(blocked.impersonated_subject.as_deref(), Some("bob"));
+        assert!(blocked.request_id.is_some());
+
+        let response = client.post("/oidc/impersonation/stop").dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let (user, marker) = user_info(&client);
+        assert_eq!(user["subject"], "alice");
+        assert!(user["impersonated_by"].is_null());
+        assert_eq!(marker, None);
+        let response = client.post("/ui-api/write").dispatch();
+        assert_eq!(response.into_string().unwrap(), "alice");
+    }
+
+    #[test]
+    fn cannot_impersonate_with_roles_that_are_not_allowed() {
+        let (client, _files, security_events) = client();
+        for (roles, reason) in [
+            ("[\"admin\"]", "an admin role"),
+            (
+                "[\"viewer\", \"shipping-manager\"]",
+                "a role that is not allowed",
+            ),
+        ] {
+            let response = client
+                .post("/oidc/impersonation/start")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{"subject": "mallory", "roles": {}}}"#, roles))
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            let denied = security_events.lock().unwrap().pop().unwrap();
+            assert_eq!(denied.outcome, SecurityOutcome::Rejected);
+            assert!(denied.message.ends_with(reason));
+            assert_eq!(denied.subject.as_deref(), Some("alice"));
+            assert_eq!(denied.impersonated_subject.as_deref(), Some("mallory"));
+        }
+        let (user, _) = user_info(&client);
+        assert_eq!(user["subject"], "alice");
+    }
+}
@@ -0,0 +1,322 @@
+//! Key ring for private cookies that allows to rotate the secret key without logging out all users
+//!
+//! The active key encrypts and decrypts cookies. If no active key is configured, the Rocket secret key is used. Previous keys are only used to decrypt cookies that were issued before the rotation. Those cookies are re-issued encrypted with the active key
+
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use cookie::Key;
+use rocket::http::{Cookie, CookieJar};
+use tracing::{Level, event};
+
+use crate::configuration::config::CustomAppKeyRingConfig;
+use crate::configuration::reload::Reloadable;
+use crate::configuration::secrets::{Secret, SecretFile};
+
+use super::cookies::OidcCookies;
+
+// Errors in the key ring configuration
+#[derive(Debug, PartialEq)]
+pub enum KeyRingError {
+    InvalidEncoding(usize),
+    InvalidKeyLength(usize),
+    UnreadableFile(String),
+}
+
+// Keys of the key ring. It can be shared with the fairing that reloads the key files
+#[derive(Clone)]
+pub struct CookieKeyRing {
+    inner: Arc<CookieKeyRingInner>,
+}
+
+struct CookieKeyRingInner {
+    active_key_file: SecretFile,
+    previous_keys: Vec<Secret>,
+    previous_keys_file: Option<SecretFile>,
+    keys: RwLock<DecodedKeys>,
+}
+
+struct DecodedKeys {
+    active: Option<Key>,
+    previous: Vec<Key>,
+}
+
+impl CookieKeyRing {
+    /// Creates the key ring from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Key ring configuration of the application
+    ///
+    /// # Returns
+    /// Key ring or an error, if a key is not a base64-encoded key of 256 or 512 bit or a key file cannot be read. The error contains the position of the key (0 is the active key, previous keys start at 1)
+    ///
+    pub fn new(config: &CustomAppKeyRingConfig) -> Result<CookieKeyRing, KeyRingError> {
+        let active_key_file = SecretFile::new(
+            config.active_secret_key.as_ref(),
+            config.active_secret_key_file.as_deref(),
+        )
+        .map_err(|err| KeyRingError::UnreadableFile(err.to_string()))?;
+        let previous_keys_file = match &config.previous_secret_keys_file {
+            Some(path) => Some(
+                SecretFile::new(None, Some(path))
+                    .map_err(|err| KeyRingError::UnreadableFile(err.to_string()))?,
+            ),
+            None => None,
+        };
+        let mut inner = CookieKeyRingInner {
+            active_key_file,
+            previous_keys: config.previous_secret_keys.clone().unwrap_or_default(),
+            previous_keys_file,
+            keys: RwLock::new(DecodedKeys {
+                active: None,
+                previous: Vec::new(),
+            }),
+        };
+        inner.keys = RwLock::new(inner.decode()?);
+        Ok(CookieKeyRing {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Reads a private cookie. If it cannot be decrypted with the active key, the previous keys are tried and the cookie is re-issued with the active key
+    ///
+    /// # Arguments
+    /// * `cookies` - Cookies of the request
+    /// * `oidc_cookies` - Cookie policy to re-issue the cookie with the same attributes
+    /// * `name` - Name of the cookie
+    ///
+    /// # Returns
+    /// Decrypted cookie or None if it does not exist or cannot be decrypted with any key
+    ///
+    pub fn get_private(
+        &self,
+        cookies: &CookieJar<'_>,
+        oidc_cookies: &OidcCookies,
+        name: &str,
+    ) -> Option<Cookie<'static>> {
+        let encrypted = cookies.get(name)?.clone();
+        let active = self.inner.keys.read().unwrap().active.clone();
+        let decrypted = match &active {
+            Some(key) => decrypt(key, encrypted.clone()),
+            None => cookies.get_private(name),
+        };
+        if decrypted.is_some() {
+            return decrypted;
+        }
+        let cookie = self.decrypt(encrypted)?;
+        event!(
+            Level::DEBUG,
+            "Re-issuing cookie {} encrypted with a previous key",
+            name
+        );
+        self.add_private(
+            cookies,
+            oidc_cookies.cookie(name, cookie.value().to_string()),
+        );
+        Some(cookie)
+    }
+
+    /// Adds a private cookie encrypted with the active key
+    ///
+    /// # Arguments
+    /// * `cookies` - Cookies of the response
+    /// * `cookie` - Cookie to encrypt
+    ///
+    pub fn add_private(&self, cookies: &CookieJar<'_>, cookie: Cookie<'static>) {
+        let active = self.inner.keys.read().unwrap().active.clone();
+        match active {
+            Some(key) => {
+                let name = cookie.name().to_string();
+                let mut jar = cookie::CookieJar::new();
+                jar.private_mut(&key).add(cookie);
+                if let Some(encrypted) = jar.get(&name) {
+                    cookies.add(encrypted.clone());
+                }
+            }
+            None => cookies.add_private(cookie),
+        }
+    }
+
+    /// Decrypts a cookie with the previous keys
+    ///
+    /// # Arguments
+    /// * `cookie` - Encrypted cookie
+    ///
+    /// # Returns
+    /// Decrypted cookie or None if no previous key can decrypt it
+    ///
+    pub fn decrypt(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        self.inner
+            .keys
+            .read()
+            .unwrap()
+            .previous
+            .iter()
+            .find_map(|key| decrypt(key, cookie.clone()))
+    }
+}
+
+impl CookieKeyRingInner {
+    fn decode(&self) -> Result<DecodedKeys, KeyRingError> {
+        let active = if self.active_key_file.is_empty() {
+            None
+        } else {
+            Some(decode_key(self.active_key_file.value().expose()).map_err(|err| at(err, 0))?)
+        };
+        let file_keys = self
+            .previous_keys_file
+            .as_ref()
+            .map(|file| {
+                file.value()
+                    .expose()
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(|line| Secret::new(line.to_string()))
+                    .collect::<Vec<Secret>>()
+            })
+            .unwrap_or_default();
+        let mut previous = Vec::new();
+        for (index, encoded_key) in self
+            .previous_keys
+            .iter()
+            .chain(file_keys.iter())
+            .enumerate()
+        {
+            previous.push(decode_key(encoded_key.expose()).map_err(|err| at(err, index + 1))?);
+        }
+        Ok(DecodedKeys { active, previous })
+    }
+}
+
+impl Reloadable for CookieKeyRing {
+    fn name(&self) -> String {
+        "cookie key ring".to_string()
+    }
+
+    fn reload(&self, force: bool) -> io::Result<bool> {
+        let mut changed = self.inner.active_key_file.reload(force)?;
+        if let Some(previous_keys_file) = &self.inner.previous_keys_file {
+            changed |= previous_keys_file.reload(force)?;
+        }
+        if !changed {
+            return Ok(false);
+        }
+        let keys = self
+            .inner
+            .decode()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+        *self.inner.keys.write().unwrap() = keys;
+        event!(Level::INFO, "Reloaded cookie key ring");
+        Ok(true)
+    }
+}
+
+fn decrypt(key: &Key, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+    cookie::CookieJar::new().private(key).decrypt(cookie)
+}
+
+fn at(err: KeyRingError, index: usize) -> KeyRingError {
+    match err {
+        KeyRingError::InvalidEncoding(_) => KeyRingError::InvalidEncoding(index),
+        KeyRingError::InvalidKeyLength(_) => KeyRingError::InvalidKeyLength(index),
+        other => other,
+    }
+}
+
+/// Decodes a key in the same way as Rocket decodes the secret key, ie 512 bit keys are used directly and 256 bit keys are expanded. Keys of other lengths are rejected
+fn decode_key(encoded_key: &str) -> Result<Key, KeyRingError> {
+    let bytes = BASE64_STANDARD
+        .decode(encoded_key.trim())
+        .map_err(|_| KeyRingError::InvalidEncoding(0))?;
+    match bytes.len() {
+        32 => Ok(Key::derive_from(&bytes)),
+        64 => Ok(Key::from(&bytes)),
+        _ => Err(KeyRingError::InvalidKeyLength(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREVIOUS_KEY: &str = "hPRYyVRiMyxpw5sBB1XeCMN1kFsDCqKvBi2QJxBVHQk=";
+
+    #[test]
+    fn decrypts_cookie_of_previous_key() {
+        let ring = CookieKeyRing::new(&CustomAppKeyRingConfig {
+            previous_secret_keys: Some(vec![Secret::new(PREVIOUS_KEY.to_string())]),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut jar = cookie::CookieJar::new();
+        jar.private_mut(&decode_key(PREVIOUS_KEY).unwrap())
+            .add(Cookie::new("oidc_user_session", "session"));
+        let encrypted = jar.get("oidc_user_session").unwrap().clone();
+        assert_eq!(ring.decrypt(encrypted).unwrap().value(), "session");
+    }
+
+    #[test]
+    fn rejects_cookie_of_unknown_key() {
+        let ring = CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap();
+        let mut jar = cookie::CookieJar::new();
+        jar.private_mut(&Key::generate())
+            .add(Cookie::new("oidc_user_session", "session"));
+        let encrypted = jar.get("oidc_user_session").unwrap().clone();
+        assert!(ring.decrypt(encrypted).is_none());
+    }
+
+    #[test]
+    fn rejects_keys_of_invalid_length() {
+        let config = CustomAppKeyRingConfig {
+            previous_secret_keys: Some(vec![
+                Secret::new(PREVIOUS_KEY.to_string()),
+                Secret::new("c2hvcnQ=".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            CookieKeyRing::new(&config).err(),
+            Some(KeyRingError::InvalidKeyLength(2))
+        );
+        // Rocket only accepts keys of 32 or 64 bytes, longer keys are not truncated
+        assert!(decode_key(&BASE64_STANDARD.encode([7u8; 64])).is_ok());
+        for length in [33, 63, 65] {
+            assert_eq!(
+                decode_key(&BASE64_STANDARD.encode(vec![7u8; length])).err(),
+                Some(KeyRingError::InvalidKeyLength(0))
+            );
+        }
+    }
+
+    #[test]
+    fn reloads_rotated_key_file() {
+        let path = std::env::temp_dir().join(format!("keyring-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, PREVIOUS_KEY).unwrap();
+        let ring = CookieKeyRing::new(&CustomAppKeyRingConfig {
+            previous_secret_keys_file: path.to_str().map(str::to_string),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut jar = cookie::CookieJar::new();
+        let rotated_key = Key::generate();
+        jar.private_mut(&rotated_key)
+            .add(Cookie::new("oidc_user_session", "session"));
+        let encrypted = jar.get("oidc_user_session").unwrap().clone();
+        assert!(ring.decrypt(encrypted.clone()).is_none());
+        std::fs::write(
+            &path,
+            format!(
+                "{}\n{}\n",
+                PREVIOUS_KEY,
+                BASE64_STANDARD.encode(rotated_key.master())
+            ),
+        )
+        .unwrap();
+        assert!(ring.reload(true).unwrap());
+        assert_eq!(ring.decrypt(encrypted).unwrap().value(), "session");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,3 +1,11 @@
+pub mod cookies;
+pub mod dpop;
 pub mod guard;
+pub mod httpclient;
+pub mod impersonation;
+pub mod keyring;
 pub mod oidcflow;
 pub mod routes;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tokenexchange;
@@ -1,23 +1,29 @@
 //! OIDC client (flow) that performs the OIDC Authorization Code flow
 
 use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
 
 use rocket::serde::json::serde_json;
 
 use openidconnect::{
     AccessToken, AdditionalClaims, AuthenticationFlow, Client, ClientId, ClientSecret, CsrfToken,
-    IdToken, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
-    UserInfoClaims,
+    IdToken, IssuerUrl, JsonWebKeySet, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
+    Scope, UserInfoClaims,
 };
 
 use openidconnect::core::{
-    CoreGenderClaim, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm,
+    CoreGenderClaim, CoreJsonWebKeySet, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm,
     CoreProviderMetadata, CoreResponseType,
 };
 
-use openidconnect::reqwest;
 use openidconnect::url;
 use serde::{Deserialize, Serialize};
+
+use crate::configuration::reload::{Reloadable, WatchedFile};
+use crate::configuration::secrets::SecretFile;
+
+use super::httpclient::{IdpBlockingHttpClient, IdpHttpClient};
 use tracing::{Level, event};
 
 // Errors returned by the client
@@ -26,6 +32,7 @@ pub enum OAuth2Error {
     INVALID_ISSUER_URL,
     CLIENT_BUILD_ERROR,
     PROVIDER_METADATA_DISCOVERY,
+    ProviderMetadataFile,
 }
 
 // Structure to capture all claims in an IdToken or UserInfo endpoint response that are not part of the standard OIDC claims
@@ -71,13 +78,49 @@ type OidcAppClient = Client<
 // Basic data used by the OIDC Client
 
 pub struct OidcFlow {
-    pub client: OidcAppClient,
-    pub auth_url: url::Url,
+    provider: Arc<OidcProvider>,
+    pub client_secret: SecretFile,
+    pub http_client: IdpHttpClient,
     pub csrf_state: CsrfToken,
     pub nonce: Nonce,
     pub pkce_verifier_secret: String,
 }
 
+// Source of the provider metadata and keys of the IdP
+pub enum OidcProviderSource {
+    // discovery via the issuer url
+    Discovery(String),
+    // static files, e.g. in air-gapped environments. Without a JWKS file the keys are fetched once from the jwks_uri of the metadata
+    Files {
+        provider_metadata_file: String,
+        jwks_file: Option<String>,
+    },
+}
+
+// Provider metadata (including keys) of the IdP and the client derived from it. They are reloaded if they are read from static files
+pub struct OidcProvider {
+    metadata: RwLock<CoreProviderMetadata>,
+    client: RwLock<OidcAppClient>,
+    auth_url: RwLock<url::Url>,
+    settings: OidcClientSettings,
+    files: Option<OidcProviderFiles>,
+}
+
+// Settings of the client that do not change when the provider metadata is reloaded
+struct OidcClientSettings {
+    client_id: ClientId,
+    redirect_url: RedirectUrl,
+    scopes: Vec<String>,
+    csrf_state: CsrfToken,
+    nonce: Nonce,
+    pkce_challenge: PkceCodeChallenge,
+}
+
+struct OidcProviderFiles {
+    provider_metadata: WatchedFile,
+    jwks: Option<WatchedFile>,
+}
+
 // OIDC Session cookie stores OIDC tokens and additional information, such as roles, in a cookie.
 // These information are sensitive and MUST be stored ONLY in an encrypted cookie
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -111,81 +154,205 @@ pub fn handle_error<T: std::error::Error>(fail: &T, msg: &'static str) {
 
 // Implemetation of an OIDC client (flow) that represents the OIDC Authorization Code flow
 impl OidcFlow {
+    /// Returns the OIDC client with the current provider metadata and client secret (both can be reloaded at runtime)
+    pub fn client(&self) -> OidcAppClient {
+        self.provider
+            .client
+            .read()
+            .unwrap()
+            .clone()
+            .set_client_secret(ClientSecret::new(
+                self.client_secret.value().expose().to_string(),
+            ))
+    }
+
+    /// Returns the authorisation url of the OIDC IdP to which the user is redirected for login
+    pub fn auth_url(&self) -> url::Url {
+        self.provider.auth_url.read().unwrap().clone()
+    }
+
+    /// Returns the current provider metadata including the keys of the IdP
+    pub fn provider_metadata(&self) -> CoreProviderMetadata {
+        self.provider.metadata.read().unwrap().clone()
+    }
+
+    /// Returns the provider so that it can be reloaded from static files
+    pub fn provider(&self) -> Arc<OidcProvider> {
+        self.provider.clone()
+    }
+
     pub fn new(
-        issuer_url: String,
+        provider_source: OidcProviderSource,
         redirect_url: String,
         client_id: String,
-        client_secret: String,
+        client_secret: SecretFile,
         scopes: Vec<String>,
+        http_client: IdpHttpClient,
+        discovery_http_client: &IdpBlockingHttpClient,
     ) -> Result<OidcFlow, OAuth2Error> {
         // configure basic information
         let client_id = ClientId::new(client_id);
-        let client_secret = ClientSecret::new(client_secret);
-
-        let issuer_url = match IssuerUrl::new(issuer_url.to_string()) {
-            Ok(issuer_url) => issuer_url,
-            Err(err) => {
-                handle_error(&err, "Invalid issuer URL");
-                return Err(OAuth2Error::INVALID_ISSUER_URL);
-            }
-        };
-        // configure a HTTP client
-        let http_client = match reqwest::blocking::ClientBuilder::new()
-            // Following redirects opens the client up to SSRF vulnerabilities.
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-        {
-            Ok(client) => client,
-            Err(err) => {
-                handle_error(&err, "Cannot build client ");
-                return Err(OAuth2Error::CLIENT_BUILD_ERROR);
-            }
-        };
 
         // configure provider metadata
-        let provider_metadata = match CoreProviderMetadata::discover(&issuer_url, &http_client) {
-            Ok(provider_metadata) => provider_metadata,
-            Err(err) => {
-                handle_error(&err, "Could discover provider metadata ");
-                return Err(OAuth2Error::PROVIDER_METADATA_DISCOVERY);
+        let (provider_metadata, files) = match provider_source {
+            OidcProviderSource::Discovery(issuer_url) => {
+                let issuer_url = match IssuerUrl::new(issuer_url.to_string()) {
+                    Ok(issuer_url) => issuer_url,
+                    Err(err) => {
+                        handle_error(&err, "Invalid issuer URL");
+                        return Err(OAuth2Error::INVALID_ISSUER_URL);
+                    }
+                };
+                match CoreProviderMetadata::discover(&issuer_url, discovery_http_client) {
+                    Ok(provider_metadata) => (provider_metadata, None),
+                    Err(err) => {
+                        handle_error(&err, "Could discover provider metadata ");
+                        return Err(OAuth2Error::PROVIDER_METADATA_DISCOVERY);
+                    }
+                }
+            }
+            OidcProviderSource::Files {
+                provider_metadata_file,
+                jwks_file,
+            } => {
+                let files = OidcProviderFiles {
+                    provider_metadata: WatchedFile::new(&provider_metadata_file),
+                    jwks: jwks_file.as_deref().map(WatchedFile::new),
+                };
+                let provider_metadata = match files.read(None) {
+                    Ok(provider_metadata) => provider_metadata,
+                    Err(err) => {
+                        event!(Level::ERROR, "Cannot read provider metadata: {}", err);
+                        return Err(OAuth2Error::ProviderMetadataFile);
+                    }
+                };
+                let provider_metadata = match &files.jwks {
+                    Some(_) => provider_metadata,
+                    None => {
+                        let jwks = match JsonWebKeySet::fetch(
+                            provider_metadata.jwks_uri(),
+                            discovery_http_client,
+                        ) {
+                            Ok(jwks) => jwks,
+                            Err(err) => {
+                                handle_error(&err, "Cannot fetch JWKS");
+                                return Err(OAuth2Error::PROVIDER_METADATA_DISCOVERY);
+                            }
+                        };
+                        provider_metadata.set_jwks(jwks)
+                    }
+                };
+                (provider_metadata, Some(files))
             }
         };
-        // Set up the config for the OIDC flow
-        let client = OidcAppClient::from_provider_metadata(
-            provider_metadata,
+        // Generate a PKCE challenge.
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let settings = OidcClientSettings {
             client_id,
-            Some(client_secret),
-        )
-        // set redirect URI to which the OIDC IdP should send the code
-        .set_redirect_uri(
-            RedirectUrl::new(redirect_url.to_string()).unwrap_or_else(|err| {
+            // set redirect URI to which the OIDC IdP should send the code
+            redirect_url: RedirectUrl::new(redirect_url.to_string()).unwrap_or_else(|err| {
                 handle_error(&err, "Invalid redirect URL");
                 unreachable!();
             }),
-        );
+            scopes,
+            csrf_state: CsrfToken::new_random(),
+            nonce: Nonce::new_random(),
+            pkce_challenge,
+        };
+        let (client, auth_url) = settings.build(provider_metadata.clone());
+        let csrf_state = settings.csrf_state.clone();
+        let nonce = settings.nonce.clone();
+        Ok(OidcFlow {
+            provider: Arc::new(OidcProvider {
+                metadata: RwLock::new(provider_metadata),
+                client: RwLock::new(client),
+                auth_url: RwLock::new(auth_url),
+                settings,
+                files,
+            }),
+            client_secret,
+            http_client,
+            csrf_state,
+            nonce,
+            pkce_verifier_secret: pkce_verifier.into_secret(),
+        })
+    }
+}
+
+impl OidcClientSettings {
+    /// Set up the config for the OIDC flow from the provider metadata
+    fn build(&self, provider_metadata: CoreProviderMetadata) -> (OidcAppClient, url::Url) {
+        // the client secret is set when the client is used, because it can be reloaded
+        let client =
+            OidcAppClient::from_provider_metadata(provider_metadata, self.client_id.clone(), None)
+                .set_redirect_uri(self.redirect_url.clone());
 
         // configure authorisation url of the OIDC IdP
+        let csrf_state = self.csrf_state.clone();
+        let nonce = self.nonce.clone();
         let mut authorize_url = client.authorize_url(
             AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-            CsrfToken::new_random,
-            Nonce::new_random,
+            move || csrf_state,
+            move || nonce,
         );
         // configure scopes to be requested from the IdP
-        for scope in scopes {
-            authorize_url = authorize_url.add_scope(Scope::new(scope));
+        for scope in &self.scopes {
+            authorize_url = authorize_url.add_scope(Scope::new(scope.clone()));
         }
-        // Generate a PKCE challenge.
-        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-        let (auth_url, csrf_state, nonce) = authorize_url
+        let (auth_url, _, _) = authorize_url
             // Set the PKCE code challenge.
-            .set_pkce_challenge(pkce_challenge)
+            .set_pkce_challenge(self.pkce_challenge.clone())
             .url();
-        Ok(OidcFlow {
-            client,
-            auth_url,
-            csrf_state,
-            nonce,
-            pkce_verifier_secret: pkce_verifier.into_secret(),
-        })
+        (client, auth_url)
+    }
+}
+
+impl OidcProviderFiles {
+    /// Reads the provider metadata and the JWKS from the files
+    ///
+    /// # Arguments
+    /// * `previous_jwks` - Keys to use if no JWKS file is configured
+    ///
+    /// # Returns
+    /// Provider metadata including the keys
+    ///
+    fn read(&self, previous_jwks: Option<CoreJsonWebKeySet>) -> io::Result<CoreProviderMetadata> {
+        let provider_metadata: CoreProviderMetadata =
+            serde_json::from_str(&self.provider_metadata.read()?)?;
+        let jwks = match &self.jwks {
+            Some(jwks) => serde_json::from_str(&jwks.read()?)?,
+            None => previous_jwks.unwrap_or_else(|| JsonWebKeySet::new(Vec::new())),
+        };
+        Ok(provider_metadata.set_jwks(jwks))
+    }
+}
+
+impl Reloadable for OidcProvider {
+    fn name(&self) -> String {
+        match &self.files {
+            Some(files) => format!(
+                "provider metadata {}",
+                files.provider_metadata.path().display()
+            ),
+            None => "provider metadata".to_string(),
+        }
+    }
+
+    fn reload(&self, force: bool) -> io::Result<bool> {
+        let Some(files) = &self.files else {
+            return Ok(false);
+        };
+        let jwks_changed = files.jwks.as_ref().is_some_and(WatchedFile::has_changed);
+        if !force && !files.provider_metadata.has_changed() && !jwks_changed {
+            return Ok(false);
+        }
+        let previous_jwks = self.metadata.read().unwrap().jwks().clone();
+        let provider_metadata = files.read(Some(previous_jwks))?;
+        let (client, auth_url) = self.settings.build(provider_metadata.clone());
+        *self.metadata.write().unwrap() = provider_metadata;
+        *self.client.write().unwrap() = client;
+        *self.auth_url.write().unwrap() = auth_url;
+        event!(Level::INFO, "Reloaded {}", self.name());
+        Ok(true)
     }
 }
@@ -3,17 +3,17 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 
-use openidconnect::{
-    AuthorizationCode, OAuth2TokenResponse, PkceCodeVerifier, TokenResponse, reqwest,
-};
-use rocket::http::{Cookie, SameSite};
+use openidconnect::{AuthorizationCode, OAuth2TokenResponse, PkceCodeVerifier, TokenResponse};
 use rocket::serde::json::serde_json;
 use rocket::{State, http::CookieJar, response::Redirect};
 use tracing::{Level, event};
 
 use crate::configuration::config::CustomAppOidcConfig;
+use crate::terms::acceptance::PendingTerms;
 
+use super::cookies::OidcCookies;
 use super::guard::OidcUser;
+use super::keyring::CookieKeyRing;
 use super::oidcflow::{self, OidcAppUserInfoClaims, OidcFlow, OidcSessionCookie, handle_error};
 
 // Standard OIDC params that the OIDC IdP sends as part of its request to a route
@@ -27,8 +27,6 @@ pub struct OidcParams {
 // Errors during handling of OIDC requests
 #[derive(Responder)]
 enum OidcError {
-    #[response(status = 500)]
-    ClientBuildError(String),
     CodeExchangeError(String),
     IdTokenError(String),
     ClaimsError(String),
@@ -43,6 +41,8 @@ enum OidcError {
 /// * `cookies` - Cookies of the user  (injected by Rocket)
 /// * `oidc` -  OIDC client (injected by Rocket)
 /// * `oidc_config` - Application-specific OIDC configuration (injected by Rocket)
+/// * `oidc_cookies` - Cookie policy for the session and redirect cookies (injected by Rocket)
+/// * `cookie_key_ring` - Keys to encrypt the session cookie and decrypt the redirect cookie (injected by Rocket)
 /// * `params` - parmaters for this route
 ///
 /// # Returns
@@ -53,29 +53,20 @@ pub async fn oidc_redirect(
     cookies: &CookieJar<'_>,
     oidc: &State<OidcFlow>,
     oidc_config: &State<CustomAppOidcConfig>,
+    oidc_cookies: &State<OidcCookies>,
+    cookie_key_ring: &State<CookieKeyRing>,
     params: OidcParams,
 ) -> Result<Redirect, OidcError> {
-    // create http client to do openidconnect requests
-    let http_client = match reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-    {
-        Ok(client) => client,
-        Err(err) => {
-            handle_error(&err, "Cannot build client");
-            return Err(OidcError::ClientBuildError(
-                "Cannot build client".to_string(),
-            ));
-        }
-    };
+    // shared http client to do openidconnect requests
+    let http_client = &oidc.http_client;
     // exchange token
+    let client = oidc.client();
     let code = AuthorizationCode::new(params.code);
-    let token_response = match oidc.client.exchange_code(code) {
+    let token_response = match client.exchange_code(code) {
         Ok(code) => match code
             // Set the PKCE code verifier.
             .set_pkce_verifier(PkceCodeVerifier::new(oidc.pkce_verifier_secret.clone()))
-            .request_async(&http_client)
+            .request_async(http_client)
             .await
         {
             Ok(token_response) => token_response,
@@ -102,7 +93,7 @@ pub async fn oidc_redirect(
             return Err(OidcError::IdTokenError("Invalid IdToken".to_string()));
         }
     };
-    let id_token_verifier = oidc.client.id_token_verifier();
+    let id_token_verifier = client.id_token_verifier();
     // fetch claims and translate to roles
     let claims = match id_token.claims(&id_token_verifier, &oidc.nonce) {
         Ok(claims) => claims,
@@ -121,11 +112,8 @@ pub async fn oidc_redirect(
     ));
 
     // map  roles from UserInfo endpoint claims
-    match oidc
-        .client
-        .user_info(token_response.access_token().to_owned(), None)
-    {
-        Ok(user_info) => match user_info.request_async(&http_client).await {
+    match client.user_info(token_response.access_token().to_owned(), None) {
+        Ok(user_info) => match user_info.request_async(http_client).await {
             Ok(user_info_claims) => {
                 let user_info_claims: OidcAppUserInfoClaims = user_info_claims;
                 mapped_roles.append(&mut parse_claims(
@@ -160,22 +148,26 @@ pub async fn oidc_redirect(
         }
     };
 
-    // We need Samesite::Lax, because the cookie is set after a redirect to another web site. Setting it to strict can lead to infinite redirects or outdated sessions
-    let session_cookie = Cookie::build(("oidc_user_session", serialized_session_cookie))
-        .path("/")
-        .secure(true)
-        .same_site(SameSite::Lax);
-
-    cookies.add_private(session_cookie);
+    // Samesite defaults to Lax, because the cookie is set after a redirect to another web site. Setting it to strict can lead to infinite redirects or outdated sessions
+    cookie_key_ring.add_private(
+        cookies,
+        oidc_cookies.session_cookie(serialized_session_cookie),
+    );
 
     // redirect back to application
-    let redirect_url = cookies
-        .get_private("oidc_redirect_destination")
+    let redirect_url = cookie_key_ring
+        .get_private(
+            cookies,
+            oidc_cookies,
+            &oidc_cookies.redirect_destination_cookie_name,
+        )
         .map(|crumb| format!("{}", crumb.value()));
 
     match redirect_url {
         Some(url) => {
-            cookies.remove_private("oidc_redirect_destination");
+            cookies.remove_private(
+                oidc_cookies.removal_cookie(&oidc_cookies.redirect_destination_cookie_name),
+            );
             Ok(Redirect::to(url))
         }
         None => Ok(Redirect::to("/")),
@@ -192,7 +184,7 @@ pub async fn oidc_redirect(
 ///
 #[get("/login")]
 pub async fn oidc_goto_auth(oidc: &State<OidcFlow>) -> Redirect {
-    let redirect_url = oidc.auth_url.to_string();
+    let redirect_url = oidc.auth_url().to_string();
     Redirect::to(redirect_url)
 }
 
@@ -217,30 +209,38 @@ pub async fn oidc_user_info(user: OidcUser) -> String {
 /// # Arguments
 /// * `path` -  route the user tried to access
 /// * `user` -  OIDC User object (injected by Rocket via the custom request guard, only if user is authenticated)
+/// * `pending_terms` - Tells if the user is authenticated, but has not accepted the current terms of use (must be evaluated after the user)
 ///
 /// # Returns
-/// Redirect to the login of the OIDC IdP
+/// Redirect to the login of the OIDC IdP or to the terms of use
 ///
 #[get("/<path..>", rank = 3)]
-pub async fn redirect_auth(path: PathBuf, user: Option<OidcUser>) -> Result<(), Redirect> {
+pub async fn redirect_auth(
+    path: PathBuf,
+    user: Option<OidcUser>,
+    pending_terms: PendingTerms,
+) -> Result<(), Redirect> {
+    if pending_terms.0 {
+        return Err(Redirect::to(uri!("/terms")));
+    }
     let user = user.ok_or_else(|| Redirect::to(uri!("/oidc/login")))?;
     Ok(())
 }
 
-/// Parses claims and maps them to roles for IdTokens or UserInfo endpoint
+/// Parses claims and maps them to roles for IdTokens, access tokens or UserInfo endpoint
 ///
 /// # Arguments
 /// * `claims` -  claims from the IdToken or UserInfo endpoint
 /// * `claims_to_extract` -  Claims to be mapped to roles
-/// * `oidc_config` - Application-specific OIDC configuration (injected by Rocket) - contains also mapping configuration
+/// * `oidc_config` - Application-specific OIDC configuration - contains also mapping configuration
 ///
 /// # Returns
 /// Redirect to the login of the OIDC IdP
 ///
-fn parse_claims(
+pub(crate) fn parse_claims(
     claims: HashMap<String, serde_json::Value>,
     claims_to_extract: &Vec<String>,
-    oidc_config: &State<CustomAppOidcConfig>,
+    oidc_config: &CustomAppOidcConfig,
 ) -> Vec<String> {
     let mut mapped_roles: Vec<String> = Vec::new();
     for claim in claims_to_extract {
@@ -304,3 +304,64 @@ fn parse_claims(
     }
     return mapped_roles;
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::configuration::config::{CustomAppCookiesConfig, CustomAppKeyRingConfig};
+    use crate::configuration::reload::Reloadable;
+    use crate::oidc::testing::{ProviderFiles, login, oidc_config, oidc_flow, start_idp};
+
+    #[test]
+    fn login_with_static_provider_files() {
+        let (files, oidc_flow) = start_idp();
+        let csrf_state = oidc_flow.csrf_state.secret().clone();
+        let rocket = rocket::build()
+            .manage(oidc_flow)
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .manage(oidc_config())
+            .mount(
+                "/oidc",
+                routes![oidc_redirect, oidc_goto_auth, oidc_user_info],
+            );
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/oidc/login").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert!(
+            response
+                .headers()
+                .get_one("Location")
+                .unwrap()
+                .starts_with(&format!("{}/authorize", files.issuer))
+        );
+
+        login(&client, &csrf_state);
+
+        let response = client.get("/oidc/userinfo").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let user: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(user["subject"], "alice");
+        assert_eq!(user["mapped_roles"], serde_json::json!(["admin", "viewer"]));
+    }
+
+    #[test]
+    fn reloads_changed_provider_metadata() {
+        let files = ProviderFiles::new(9);
+        let oidc_flow = oidc_flow(&files);
+        assert!(oidc_flow.auth_url().path().ends_with("/authorize"));
+        files.write_provider_metadata("/v2/authorize");
+        assert!(oidc_flow.provider().reload(true).unwrap());
+        assert!(oidc_flow.auth_url().path().ends_with("/v2/authorize"));
+        assert_eq!(
+            oidc_flow.provider_metadata().jwks().keys().len(),
+            1,
+            "keys of the JWKS file are kept"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Stand-in IdP for tests of the OIDC flow, e.g. a complete login
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use openidconnect::core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey};
+use openidconnect::{
+    Audience, IdToken, IdTokenClaims, IssuerUrl, JsonWebKeyId, Nonce, PrivateSigningKey,
+    StandardClaims, SubjectIdentifier,
+};
+use rocket::http::Status;
+use rocket::local::blocking::Client;
+use rocket::serde::json::serde_json;
+
+use crate::configuration::config::CustomAppOidcConfig;
+use crate::configuration::secrets::{Secret, SecretFile};
+
+use super::httpclient::{IdpBlockingHttpClient, IdpHttpClient};
+use super::oidcflow::{AllOtherClaims, OidcFlow, OidcProviderSource};
+
+const SIGNING_KEY: &str = include_str!("../../tests/fixtures/idp_signing_key.pem");
+
+// Static files of the provider as they would be copied to an air-gapped site
+pub(crate) struct ProviderFiles {
+    pub issuer: String,
+    pub provider_metadata_file: String,
+    pub jwks_file: String,
+}
+
+impl ProviderFiles {
+    pub fn new(port: u16) -> ProviderFiles {
+        let issuer = format!("http://127.0.0.1:{}", port);
+        let dir = std::env::temp_dir();
+        let id = uuid::Uuid::new_v4();
+        let files = ProviderFiles {
+            issuer,
+            provider_metadata_file: dir
+                .join(format!("provider-metadata-{}.json", id))
+                .to_string_lossy()
+                .to_string(),
+            jwks_file: dir
+                .join(format!("jwks-{}.json", id))
+                .to_string_lossy()
+                .to_string(),
+        };
+        files.write_provider_metadata("/authorize");
+        let jwks = CoreJsonWebKeySet::new(vec![signing_key().as_verification_key()]);
+        std::fs::write(&files.jwks_file, serde_json::to_string(&jwks).unwrap()).unwrap();
+        files
+    }
+
+    pub fn write_provider_metadata(&self, authorization_path: &str) {
+        let provider_metadata = serde_json::json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": format!("{}{}", self.issuer, authorization_path),
+            "token_endpoint": format!("{}/token", self.issuer),
+            "userinfo_endpoint": format!("{}/userinfo", self.issuer),
+            "jwks_uri": format!("{}/jwks", self.issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"]
+        });
+        std::fs::write(&self.provider_metadata_file, provider_metadata.to_string()).unwrap();
+    }
+}
+
+impl Drop for ProviderFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.provider_metadata_file);
+        let _ = std::fs::remove_file(&self.jwks_file);
+    }
+}
+
+pub(crate) fn signing_key() -> CoreRsaPrivateSigningKey {
+    CoreRsaPrivateSigningKey::from_pem(SIGNING_KEY, Some(JsonWebKeyId::new("test".to_string())))
+        .unwrap()
+}
+
+pub(crate) fn oidc_config() -> CustomAppOidcConfig {
+    CustomAppOidcConfig {
+        roles_idtoken_claims: Some(vec!["groups".to_string()]),
+        roles_userinfoendpoint_claims: Some(vec!["groups".to_string()]),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn oidc_flow(files: &ProviderFiles) -> OidcFlow {
+    let config = oidc_config();
+    OidcFlow::new(
+        OidcProviderSource::Files {
+            provider_metadata_file: files.provider_metadata_file.clone(),
+            jwks_file: Some(files.jwks_file.clone()),
+        },
+        "http://localhost/oidc/redirect".to_string(),
+        "app".to_string(),
+        SecretFile::new(Some(&Secret::new("secret".to_string())), None).unwrap(),
+        vec!["openid".to_string()],
+        IdpHttpClient::new(&config).unwrap(),
+        &IdpBlockingHttpClient::new(&config).unwrap(),
+    )
+    .unwrap()
+}
+
+pub(crate) fn id_token(issuer: &str, nonce: &Nonce) -> String {
+    let mut groups = HashMap::new();
+    groups.insert("groups".to_string(), serde_json::json!(["admin"]));
+    let claims = IdTokenClaims::new(
+        IssuerUrl::new(issuer.to_string()).unwrap(),
+        vec![Audience::new("app".to_string())],
+        chrono::Utc::now() + chrono::Duration::minutes(5),
+        chrono::Utc::now(),
+        StandardClaims::new(SubjectIdentifier::new("alice".to_string())),
+        AllOtherClaims(groups),
+    )
+    .set_nonce(Some(nonce.clone()));
+    let id_token: IdToken<
+        AllOtherClaims,
+        openidconnect::core::CoreGenderClaim,
+        openidconnect::core::CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+    > = IdToken::new(
+        claims,
+        &signing_key(),
+        CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+        None,
+        None,
+    )
+    .unwrap();
+    id_token.to_string()
+}
+
+// Response of a stand-in server: status line (e.g. "200 OK") and JSON body
+pub(crate) type TestResponse = (&'static str, serde_json::Value);
+
+/// Answers each request with the response of the handler. Each connection is handled in its own thread, so that a slow response (e.g. to test timeouts) does not delay other requests
+///
+/// # Arguments
+/// * `listener` - Listener of the server
+/// * `handler` - Returns the response to a request (headers and body as text)
+///
+pub(crate) fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> TestResponse + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                return;
+            };
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while !is_complete(&request) {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let (status, body) = handler(&String::from_utf8_lossy(&request));
+                let body = body.to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            });
+        }
+    });
+}
+
+/// Returns true if the headers and the body (according to Content-Length) have been received
+fn is_complete(request: &[u8]) -> bool {
+    let request = String::from_utf8_lossy(request);
+    let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+        return false;
+    };
+    let content_length = headers
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+    body.len() >= content_length
+}
+
+/// Answers the token and UserInfo requests of the OIDC flow like an IdP would
+pub(crate) fn serve_idp(listener: TcpListener, id_token: String) {
+    serve(listener, move |request| {
+        let body = if request.starts_with("POST /token") {
+            serde_json::json!({
+                "access_token": "access-token",
+                "token_type": "Bearer",
+                "expires_in": 300,
+                "id_token": id_token
+            })
+        } else if request.starts_with("GET /userinfo") {
+            serde_json::json!({ "sub": "alice", "groups": ["viewer"] })
+        } else {
+            serde_json::json!({})
+        };
+        ("200 OK", body)
+    });
+}
+
+/// Starts a stand-in IdP on a random port that issues tokens for alice with the roles admin (IdToken) and viewer (UserInfo)
+///
+/// # Returns
+/// Static files of the provider (removed on drop) and OIDC client using them
+///
+pub(crate) fn start_idp() -> (ProviderFiles, OidcFlow) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let files = ProviderFiles::new(listener.local_addr().unwrap().port());
+    let oidc_flow = oidc_flow(&files);
+    serve_idp(listener, id_token(&files.issuer, &oidc_flow.nonce));
+    (files, oidc_flow)
+}
+
+/// Completes the login as if the IdP redirected the browser back to the application
+///
+/// # Arguments
+/// * `client` - Rocket client with the OIDC routes mounted under /oidc
+/// * `csrf_state` - state of the OIDC client
+///
+pub(crate) fn login(client: &Client, csrf_state: &str) {
+    let response = client
+        .get(format!("/oidc/redirect?code=code&state={}", csrf_state))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+}
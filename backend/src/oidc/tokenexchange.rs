@@ -0,0 +1,532 @@
+//! OAuth 2.0 Token Exchange (RFC 8693) to call downstream APIs (e.g. the ERP) on behalf of the user
+//!
+//! The access token of the session is issued for this application only. It is exchanged at the token endpoint of the IdP for an access token for the configured audience of the downstream API. Exchanged tokens are cached per session until they expire
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use openidconnect::http::{HeaderValue, Method, StatusCode, header};
+use openidconnect::url::{self, form_urlencoded};
+use openidconnect::{AccessToken, AsyncHttpClient, reqwest};
+use rocket::State;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::serde::json::serde_json;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{Level, event};
+
+use crate::configuration::config::{
+    CustomAppTokenExchangeAudienceConfig, CustomAppTokenExchangeConfig,
+};
+use crate::configuration::secrets::Secret;
+
+use super::guard::OidcUser;
+use super::httpclient::IdpHttpClient;
+use super::oidcflow::OidcFlow;
+
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const DEFAULT_EXPIRY_MARGIN_SECONDS: u64 = 30;
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+// Errors when exchanging a token
+#[derive(Debug, PartialEq)]
+pub enum TokenExchangeError {
+    UnknownAudience(String),
+    NoSubjectToken,
+    NoTokenEndpoint,
+    Request(String),
+    Rejected(u16, String),
+    InvalidResponse(String),
+}
+
+// Exchanges access tokens for downstream APIs. It is managed as state in Rocket, if audiences are configured
+pub struct TokenExchange {
+    audiences: HashMap<String, CustomAppTokenExchangeAudienceConfig>,
+    expiry_margin: Duration,
+    cache: Mutex<HashMap<(String, String), CachedToken>>,
+    cache_size: usize,
+    downstream_client: reqwest::Client,
+}
+
+struct CachedToken {
+    access_token: AccessToken,
+    expires: Instant,
+}
+
+// Credentials of this application at the IdP
+struct ClientCredentials {
+    client_id: String,
+    client_secret: Secret,
+}
+
+// Successful response of the token endpoint (RFC 8693, section 2.2.1)
+#[derive(Deserialize)]
+struct TokenExchangeResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: Option<u64>,
+}
+
+/// HTTP client for a downstream API that sends the exchanged access token with each request
+pub struct AuthenticatedClient {
+    client: reqwest::Client,
+    access_token: AccessToken,
+}
+
+/// Request guard for handlers that call downstream APIs on behalf of the authenticated user
+pub struct OnBehalfOf<'r> {
+    pub user: OidcUser,
+    token_exchange: &'r TokenExchange,
+    oidc: &'r OidcFlow,
+}
+
+impl TokenExchange {
+    /// Creates the token exchange from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Token exchange configuration of the application
+    ///
+    /// # Returns
+    /// Token exchange or an error if the HTTP client for downstream APIs cannot be created
+    ///
+    pub fn new(config: &CustomAppTokenExchangeConfig) -> Result<TokenExchange, TokenExchangeError> {
+        let downstream_client = reqwest::Client::builder()
+            // Following redirects could send the access token to another host
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|err| TokenExchangeError::Request(err.to_string()))?;
+        Ok(TokenExchange {
+            audiences: config.audiences.clone().unwrap_or_default(),
+            expiry_margin: Duration::from_secs(
+                config
+                    .expiry_margin_seconds
+                    .unwrap_or(DEFAULT_EXPIRY_MARGIN_SECONDS),
+            ),
+            cache: Mutex::new(HashMap::new()),
+            cache_size: config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+            downstream_client,
+        })
+    }
+
+    /// Returns an access token for a downstream API. It is taken from the cache or exchanged at the IdP
+    ///
+    /// # Arguments
+    /// * `oidc` - OIDC client with the token endpoint and the credentials of this application
+    /// * `subject_token` - Access token of the session of the user
+    /// * `audience` - Name of the configured audience (downstream API)
+    ///
+    /// # Returns
+    /// Access token for the downstream API or an error if the IdP rejected the exchange
+    ///
+    pub async fn access_token(
+        &self,
+        oidc: &OidcFlow,
+        subject_token: &AccessToken,
+        audience: &str,
+    ) -> Result<AccessToken, TokenExchangeError> {
+        let token_endpoint = oidc
+            .provider_metadata()
+            .token_endpoint()
+            .map(|token_endpoint| token_endpoint.url().clone())
+            .ok_or(TokenExchangeError::NoTokenEndpoint)?;
+        let client_credentials = ClientCredentials {
+            client_id: oidc.client().client_id().to_string(),
+            client_secret: oidc.client_secret.value(),
+        };
+        self.cached_exchange(
+            &oidc.http_client,
+            &token_endpoint,
+            &client_credentials,
+            subject_token,
+            audience,
+        )
+        .await
+    }
+
+    /// Returns an HTTP client for a downstream API that sends an access token for the audience
+    ///
+    /// # Arguments
+    /// * `oidc` - OIDC client with the token endpoint and the credentials of this application
+    /// * `subject_token` - Access token of the session of the user
+    /// * `audience` - Name of the configured audience (downstream API)
+    ///
+    /// # Returns
+    /// HTTP client or an error if the token cannot be exchanged
+    ///
+    pub async fn client(
+        &self,
+        oidc: &OidcFlow,
+        subject_token: &AccessToken,
+        audience: &str,
+    ) -> Result<AuthenticatedClient, TokenExchangeError> {
+        Ok(AuthenticatedClient {
+            client: self.downstream_client.clone(),
+            access_token: self.access_token(oidc, subject_token, audience).await?,
+        })
+    }
+
+    async fn cached_exchange(
+        &self,
+        http_client: &IdpHttpClient,
+        token_endpoint: &url::Url,
+        client_credentials: &ClientCredentials,
+        subject_token: &AccessToken,
+        audience: &str,
+    ) -> Result<AccessToken, TokenExchangeError> {
+        let audience_config = self
+            .audiences
+            .get(audience)
+            .ok_or_else(|| TokenExchangeError::UnknownAudience(audience.to_string()))?;
+        // the session is identified by its access token. Only its hash is kept in memory
+        let cache_key = (
+            BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(subject_token.secret())),
+            audience.to_string(),
+        );
+        if let Some(cached) = self.cache.lock().unwrap().get(&cache_key)
+            && cached.expires > Instant::now()
+        {
+            return Ok(cached.access_token.clone());
+        }
+        let response = exchange(
+            http_client,
+            token_endpoint,
+            client_credentials,
+            subject_token,
+            audience_config,
+        )
+        .await?;
+        let access_token = AccessToken::new(response.access_token);
+        if let Some(expires_in) = response.expires_in {
+            let now = Instant::now();
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= self.cache_size {
+                cache.retain(|_, cached| cached.expires > now);
+            }
+            if cache.len() < self.cache_size {
+                cache.insert(
+                    cache_key,
+                    CachedToken {
+                        access_token: access_token.clone(),
+                        expires: now + Duration::from_secs(expires_in)
+                            - self.expiry_margin.min(Duration::from_secs(expires_in)),
+                    },
+                );
+            }
+        }
+        Ok(access_token)
+    }
+}
+
+/// Sends the token exchange request to the token endpoint of the IdP. The application authenticates with client_secret_basic
+async fn exchange(
+    http_client: &IdpHttpClient,
+    token_endpoint: &url::Url,
+    client_credentials: &ClientCredentials,
+    subject_token: &AccessToken,
+    audience_config: &CustomAppTokenExchangeAudienceConfig,
+) -> Result<TokenExchangeResponse, TokenExchangeError> {
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.append_pair("grant_type", GRANT_TYPE)
+        .append_pair("subject_token", subject_token.secret())
+        .append_pair("subject_token_type", ACCESS_TOKEN_TYPE)
+        .append_pair("requested_token_type", ACCESS_TOKEN_TYPE);
+    if let Some(audience) = &audience_config.audience {
+        form.append_pair("audience", audience);
+    }
+    if let Some(resource) = &audience_config.resource {
+        form.append_pair("resource", resource);
+    }
+    if let Some(scopes) = &audience_config.scopes {
+        form.append_pair("scope", &scopes.join(" "));
+    }
+    let credentials = format!(
+        "{}:{}",
+        form_urlencoded::byte_serialize(client_credentials.client_id.as_bytes())
+            .collect::<String>(),
+        form_urlencoded::byte_serialize(client_credentials.client_secret.expose().as_bytes())
+            .collect::<String>()
+    );
+    let request = openidconnect::http::Request::builder()
+        .method(Method::POST)
+        .uri(token_endpoint.as_str())
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        )
+        .header(header::ACCEPT, HeaderValue::from_static("application/json"))
+        .header(
+            header::AUTHORIZATION,
+            format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+        )
+        .body(form.finish().into_bytes())
+        .map_err(|err| TokenExchangeError::Request(err.to_string()))?;
+    let response = http_client
+        .call(request)
+        .await
+        .map_err(|err| TokenExchangeError::Request(err.to_string()))?;
+    if response.status() != StatusCode::OK {
+        let error = serde_json::from_slice::<serde_json::Value>(response.body())
+            .ok()
+            .and_then(|body| body["error"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        event!(
+            Level::WARN,
+            "Token exchange rejected by IdP: {} {}",
+            response.status(),
+            error
+        );
+        return Err(TokenExchangeError::Rejected(
+            response.status().as_u16(),
+            error,
+        ));
+    }
+    let response: TokenExchangeResponse = serde_json::from_slice(response.body())
+        .map_err(|err| TokenExchangeError::InvalidResponse(err.to_string()))?;
+    // only bearer tokens can be sent to downstream APIs (RFC 8693 uses N_A for other token types)
+    if !response.token_type.eq_ignore_ascii_case("Bearer") {
+        return Err(TokenExchangeError::InvalidResponse(response.token_type));
+    }
+    Ok(response)
+}
+
+impl AuthenticatedClient {
+    /// Creates a request to the downstream API with the access token as bearer token
+    ///
+    /// # Arguments
+    /// * `method` - HTTP method
+    /// * `url` - URL of the downstream API
+    ///
+    /// # Returns
+    /// Request that can be extended (e.g. with a body) and sent
+    ///
+    pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, url)
+            .bearer_auth(self.access_token.secret())
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+    }
+
+    pub fn post(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::POST, url)
+    }
+
+    pub fn put(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::PUT, url)
+    }
+
+    pub fn delete(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::DELETE, url)
+    }
+}
+
+impl OnBehalfOf<'_> {
+    /// Returns an HTTP client for a downstream API that acts on behalf of the user
+    ///
+    /// # Arguments
+    /// * `audience` - Name of the configured audience (downstream API)
+    ///
+    /// # Returns
+    /// HTTP client or an error if the user has no OIDC session or the token cannot be exchanged
+    ///
+    pub async fn client(&self, audience: &str) -> Result<AuthenticatedClient, TokenExchangeError> {
+        let subject_token = self
+            .user
+            .access_token
+            .as_ref()
+            .ok_or(TokenExchangeError::NoSubjectToken)?;
+        self.token_exchange
+            .client(self.oidc, subject_token, audience)
+            .await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OnBehalfOf<'r> {
+    type Error = ();
+
+    /// Executed for each request on which route OnBehalfOf is included. Requires an authenticated user (see OidcUser)
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let user = match req.guard::<OidcUser>().await {
+            Outcome::Success(user) => user,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let Some(token_exchange) = req.rocket().state::<TokenExchange>() else {
+            event!(Level::ERROR, "Token exchange is not configured");
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let oidc = req.guard::<&State<OidcFlow>>().await.unwrap();
+        Outcome::Success(OnBehalfOf {
+            user,
+            token_exchange,
+            oidc,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::configuration::config::CustomAppOidcConfig;
+    use crate::oidc::testing::serve;
+
+    /// Token endpoint that answers token exchange requests for the audience erp-api and counts them
+    fn serve_token_endpoint() -> (url::Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_endpoint =
+            url::Url::parse(&format!("http://{}/token", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        serve(listener, move |request| {
+            let number = counter.fetch_add(1, Ordering::SeqCst);
+            if request.contains("audience=erp-api")
+                && request.contains(
+                    "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange",
+                )
+                && request.contains("Basic YXBwOnNlY3JldA==")
+            {
+                (
+                    "200 OK",
+                    serde_json::json!({
+                        "access_token": format!("erp-token-{}", number),
+                        "issued_token_type": ACCESS_TOKEN_TYPE,
+                        "token_type": "Bearer",
+                        "expires_in": 300
+                    }),
+                )
+            } else {
+                (
+                    "400 Bad Request",
+                    serde_json::json!({ "error": "invalid_target" }),
+                )
+            }
+        });
+        (token_endpoint, requests)
+    }
+
+    fn token_exchange() -> TokenExchange {
+        let mut audiences = HashMap::new();
+        audiences.insert(
+            "erp".to_string(),
+            CustomAppTokenExchangeAudienceConfig {
+                audience: Some("erp-api".to_string()),
+                scopes: Some(vec!["orders.read".to_string()]),
+                ..Default::default()
+            },
+        );
+        audiences.insert(
+            "crm".to_string(),
+            CustomAppTokenExchangeAudienceConfig {
+                audience: Some("crm-api".to_string()),
+                ..Default::default()
+            },
+        );
+        TokenExchange::new(&CustomAppTokenExchangeConfig {
+            audiences: Some(audiences),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn client_credentials() -> ClientCredentials {
+        ClientCredentials {
+            client_id: "app".to_string(),
+            client_secret: Secret::new("secret".to_string()),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn caches_exchanged_token_per_session() {
+        let (token_endpoint, requests) = serve_token_endpoint();
+        let token_exchange = token_exchange();
+        let http_client = IdpHttpClient::new(&CustomAppOidcConfig::default()).unwrap();
+        let session = AccessToken::new("session-1".to_string());
+        let first = token_exchange
+            .cached_exchange(
+                &http_client,
+                &token_endpoint,
+                &client_credentials(),
+                &session,
+                "erp",
+            )
+            .await
+            .unwrap();
+        let second = token_exchange
+            .cached_exchange(
+                &http_client,
+                &token_endpoint,
+                &client_credentials(),
+                &session,
+                "erp",
+            )
+            .await
+            .unwrap();
+        assert_eq!(first.secret(), second.secret());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let other_session = token_exchange
+            .cached_exchange(
+                &http_client,
+                &token_endpoint,
+                &client_credentials(),
+                &AccessToken::new("session-2".to_string()),
+                "erp",
+            )
+            .await
+            .unwrap();
+        assert_ne!(first.secret(), other_session.secret());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[rocket::async_test]
+    async fn reports_rejected_and_unknown_audiences() {
+        let (token_endpoint, _) = serve_token_endpoint();
+        let token_exchange = token_exchange();
+        let http_client = IdpHttpClient::new(&CustomAppOidcConfig::default()).unwrap();
+        let session = AccessToken::new("session-1".to_string());
+        assert_eq!(
+            token_exchange
+                .cached_exchange(
+                    &http_client,
+                    &token_endpoint,
+                    &client_credentials(),
+                    &session,
+                    "crm",
+                )
+                .await
+                .err(),
+            Some(TokenExchangeError::Rejected(
+                400,
+                "invalid_target".to_string()
+            ))
+        );
+        assert_eq!(
+            token_exchange
+                .cached_exchange(
+                    &http_client,
+                    &token_endpoint,
+                    &client_credentials(),
+                    &session,
+                    "billing",
+                )
+                .await
+                .err(),
+            Some(TokenExchangeError::UnknownAudience("billing".to_string()))
+        );
+    }
+}
@@ -0,0 +1,455 @@
+//! Collector for violation reports that browsers send for the Content-Security-Policy (application/csp-report) and via the Reporting API (application/reports+json), e.g. for COEP and COOP
+//!
+//! Reports are normalized, deduplicated by a hash of their content and stored in the database with a counter. Each client may only send a limited number of requests per minute and the stored reports are limited in number and age
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use rocket::data::ByteUnit;
+use rocket::http::uri::Origin;
+use rocket::serde::json::Value;
+use rocket_db_pools::sqlx::{self, SqliteConnection};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::config::CustomAppReportingConfig;
+use crate::httpfirewall::ratelimit::{self, BucketLimit, MemoryRateLimitStore, RateLimitStore};
+use crate::oidc::guard::OidcUser;
+
+pub const DEFAULT_ENDPOINT_NAME: &str = "default";
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 30;
+const DEFAULT_MAX_REPORT_SIZE: u64 = 64 * 1024;
+const DEFAULT_MAX_REPORTS: u32 = 10000;
+const DEFAULT_RETENTION_DAYS: u32 = 90;
+// browsers batch reports, but a single request should not create an arbitrary number of rows
+const MAX_REPORTS_PER_REQUEST: usize = 100;
+const MAX_FIELD_LENGTH: usize = 1024;
+// buckets of clients that did not send reports for a minute are removed if more clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 10000;
+const CSP_VIOLATION: &str = "csp-violation";
+
+// Errors in the reporting configuration
+#[derive(Debug)]
+pub enum ReportingConfigError {
+    InvalidEndpoint(String),
+    EmptyEndpointName,
+    InvalidRateLimit,
+    InvalidMaxReportSize,
+    InvalidMaxReports,
+    InvalidRetention,
+}
+
+// How the stored reports are aggregated for the admin query API
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportGrouping {
+    Directive,
+    BlockedUri,
+    DirectiveAndBlockedUri,
+}
+
+// Settings and rate limits of the report collector. It is managed as state in Rocket, if an endpoint is configured
+pub struct ReportCollector {
+    pub endpoint: String,
+    pub endpoint_name: String,
+    pub max_report_size: ByteUnit,
+    max_reports: u32,
+    retention_days: u32,
+    admin_roles: Vec<String>,
+    // token buckets of the clients like the ones of the rate limiter, refilled by the rate limit per minute
+    limit: BucketLimit,
+    clients: Box<dyn RateLimitStore>,
+}
+
+// Violation report normalized from the different report formats
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityReport {
+    pub report_type: String,
+    pub document_uri: String,
+    pub directive: String,
+    pub blocked_uri: String,
+    pub source_file: String,
+    pub line_number: Option<i64>,
+    pub disposition: String,
+}
+
+// Aggregated reports returned by the admin query API
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ReportSummary {
+    pub report_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocked_uri: Option<String>,
+    pub count: i64,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+impl ReportCollector {
+    /// Creates the collector from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Reporting configuration of the application
+    ///
+    /// # Returns
+    /// Collector or an error, if the endpoint is not an absolute path, the endpoint name is empty or a limit or the retention is 0
+    ///
+    pub fn new(config: &CustomAppReportingConfig) -> Result<ReportCollector, ReportingConfigError> {
+        let endpoint = config.endpoint.clone().unwrap_or_default();
+        if !endpoint.starts_with('/') || Origin::parse(&endpoint).is_err() {
+            return Err(ReportingConfigError::InvalidEndpoint(endpoint));
+        }
+        let endpoint_name = config
+            .endpoint_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ENDPOINT_NAME.to_string());
+        if endpoint_name.is_empty() {
+            return Err(ReportingConfigError::EmptyEndpointName);
+        }
+        let rate_limit_per_minute = config
+            .rate_limit_per_minute
+            .unwrap_or(DEFAULT_RATE_LIMIT_PER_MINUTE);
+        if rate_limit_per_minute == 0 {
+            return Err(ReportingConfigError::InvalidRateLimit);
+        }
+        let max_report_size = config.max_report_size.unwrap_or(DEFAULT_MAX_REPORT_SIZE);
+        if max_report_size == 0 {
+            return Err(ReportingConfigError::InvalidMaxReportSize);
+        }
+        let max_reports = config.max_reports.unwrap_or(DEFAULT_MAX_REPORTS);
+        if max_reports == 0 {
+            return Err(ReportingConfigError::InvalidMaxReports);
+        }
+        let retention_days = config.retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+        if retention_days == 0 {
+            return Err(ReportingConfigError::InvalidRetention);
+        }
+        Ok(ReportCollector {
+            endpoint,
+            endpoint_name,
+            max_report_size: ByteUnit::from(max_report_size),
+            max_reports,
+            retention_days,
+            admin_roles: config.admin_roles.clone().unwrap_or_default(),
+            limit: BucketLimit {
+                capacity: rate_limit_per_minute,
+                refill_per_second: f64::from(rate_limit_per_minute) / 60.0,
+            },
+            clients: Box::new(MemoryRateLimitStore::new(MAX_TRACKED_CLIENTS)),
+        })
+    }
+
+    /// Counts a request of a client and checks if it is within the rate limit
+    ///
+    /// # Arguments
+    /// * `client` - IP address of the client as resolved by the request firewall
+    ///
+    /// # Returns
+    /// true if the client has a token left, ie it has not exceeded the rate limit per minute
+    ///
+    pub async fn is_allowed(&self, client: IpAddr) -> bool {
+        self.clients
+            .take(&format!("ip:{}", client), &self.limit, ratelimit::now())
+            .await
+            .allowed
+    }
+
+    /// Returns true if the user has one of the roles that may query the reports
+    ///
+    /// # Arguments
+    /// * `user` - Authenticated user
+    ///
+    pub fn is_admin(&self, user: &OidcUser) -> bool {
+        user.mapped_roles
+            .iter()
+            .any(|role| self.admin_roles.contains(role))
+    }
+
+    /// Stores a report. If the same report was stored before, only its counter and the time it was last seen are updated
+    ///
+    /// # Arguments
+    /// * `db` - Connection to the database
+    /// * `report` - Normalized report
+    ///
+    pub async fn store(
+        &self,
+        db: &mut SqliteConnection,
+        report: &SecurityReport,
+    ) -> Result<(), sqlx::Error> {
+        let id = report.id();
+        sqlx::query!(
+            "INSERT INTO security_report (id, report_type, document_uri, directive, blocked_uri, source_file, line_number, disposition, first_seen, last_seen, count) VALUES (?, ?, ?, ?, ?, ?, ?, ?, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), 1) ON CONFLICT (id) DO UPDATE SET last_seen = excluded.last_seen, count = count + 1",
+            id,
+            report.report_type,
+            report.document_uri,
+            report.directive,
+            report.blocked_uri,
+            report.source_file,
+            report.line_number,
+            report.disposition
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes reports that were not seen within the retention and the reports seen longest ago over the maximum number of reports
+    ///
+    /// # Arguments
+    /// * `db` - Connection to the database
+    ///
+    pub async fn prune(&self, db: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        let retention = format!("-{} days", self.retention_days);
+        sqlx::query!(
+            "DELETE FROM security_report WHERE last_seen < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)",
+            retention
+        )
+        .execute(&mut *db)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM security_report WHERE rowid IN (SELECT rowid FROM security_report ORDER BY last_seen DESC, rowid DESC LIMIT -1 OFFSET ?)",
+            self.max_reports
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Aggregates the stored reports
+    ///
+    /// # Arguments
+    /// * `db` - Connection to the database
+    /// * `grouping` - Fields the reports are grouped by (in addition to the report type)
+    /// * `since` - Only reports last seen at or after this time (format: 2026-10-19T12:00:00Z)
+    ///
+    /// # Returns
+    /// Aggregated reports, the most frequent first
+    ///
+    pub async fn summarize(
+        &self,
+        db: &mut SqliteConnection,
+        grouping: ReportGrouping,
+        since: &str,
+    ) -> Result<Vec<ReportSummary>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"SELECT report_type AS "report_type!", directive AS "directive!", blocked_uri AS "blocked_uri!", SUM(count) AS "count!: i64", MIN(first_seen) AS "first_seen!: String", MAX(last_seen) AS "last_seen!: String" FROM security_report WHERE last_seen >= ? GROUP BY report_type, directive, blocked_uri"#,
+            since
+        )
+        .fetch_all(db)
+        .await?;
+        let mut summaries: BTreeMap<(String, Option<String>, Option<String>), ReportSummary> =
+            BTreeMap::new();
+        for row in rows {
+            let directive = (grouping != ReportGrouping::BlockedUri).then_some(row.directive);
+            let blocked_uri = (grouping != ReportGrouping::Directive).then_some(row.blocked_uri);
+            let summary = summaries
+                .entry((
+                    row.report_type.clone(),
+                    directive.clone(),
+                    blocked_uri.clone(),
+                ))
+                .or_insert_with(|| ReportSummary {
+                    report_type: row.report_type,
+                    directive,
+                    blocked_uri,
+                    count: 0,
+                    first_seen: row.first_seen.clone(),
+                    last_seen: row.last_seen.clone(),
+                });
+            summary.count += row.count;
+            summary.first_seen = summary.first_seen.clone().min(row.first_seen);
+            summary.last_seen = summary.last_seen.clone().max(row.last_seen);
+        }
+        let mut summaries: Vec<ReportSummary> = summaries.into_values().collect();
+        summaries.sort_by_key(|summary| Reverse(summary.count));
+        Ok(summaries)
+    }
+}
+
+impl fmt::Debug for ReportCollector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReportCollector")
+            .field("endpoint", &self.endpoint)
+            .field("endpoint_name", &self.endpoint_name)
+            .field("max_report_size", &self.max_report_size)
+            .field("max_reports", &self.max_reports)
+            .field("retention_days", &self.retention_days)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl ReportGrouping {
+    /// Parses the grouping of the admin query API
+    ///
+    /// # Arguments
+    /// * `by` - "directive", "blocked_uri" or None for both
+    ///
+    /// # Returns
+    /// Grouping or None if it is unknown
+    ///
+    pub fn parse(by: Option<&str>) -> Option<ReportGrouping> {
+        match by {
+            None => Some(ReportGrouping::DirectiveAndBlockedUri),
+            Some("directive") => Some(ReportGrouping::Directive),
+            Some("blocked_uri") => Some(ReportGrouping::BlockedUri),
+            Some(_) => None,
+        }
+    }
+}
+
+impl SecurityReport {
+    /// Normalizes a report of the Content-Security-Policy directive report-uri (application/csp-report)
+    ///
+    /// # Arguments
+    /// * `body` - JSON body of the request
+    ///
+    /// # Returns
+    /// The report or None if the body is not a CSP report
+    ///
+    pub fn from_csp_report(body: &Value) -> Option<Vec<SecurityReport>> {
+        let report = body.get("csp-report").filter(|report| report.is_object())?;
+        // violated-directive of older browsers contains the whole directive, e.g. "script-src 'self'"
+        let directive = text(report, &["effective-directive", "violated-directive"])
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        Some(vec![SecurityReport {
+            report_type: CSP_VIOLATION.to_string(),
+            document_uri: text(report, &["document-uri"]),
+            directive,
+            blocked_uri: text(report, &["blocked-uri"]),
+            source_file: text(report, &["source-file"]),
+            line_number: report.get("line-number").and_then(Value::as_i64),
+            disposition: text(report, &["disposition"]),
+        }])
+    }
+
+    /// Normalizes the reports of the Reporting API (application/reports+json). The directive is the effective directive of CSP violations, the effective policy of COOP violations or the type of COEP violations
+    ///
+    /// # Arguments
+    /// * `body` - JSON body of the request
+    ///
+    /// # Returns
+    /// The reports or None if the body is not a list of reports
+    ///
+    pub fn from_reports(body: &Value) -> Option<Vec<SecurityReport>> {
+        let reports = body
+            .as_array()?
+            .iter()
+            .take(MAX_REPORTS_PER_REQUEST)
+            .filter_map(|report| {
+                let report_type = report.get("type")?.as_str()?;
+                let body = report.get("body")?;
+                let mut document_uri = text(report, &["url"]);
+                if document_uri.is_empty() {
+                    document_uri = text(body, &["documentURL"]);
+                }
+                Some(SecurityReport {
+                    report_type: truncate(report_type),
+                    document_uri,
+                    directive: text(body, &["effectiveDirective", "effectivePolicy", "type"]),
+                    blocked_uri: text(
+                        body,
+                        &["blockedURL", "nextResponseURL", "previousResponseURL"],
+                    ),
+                    source_file: text(body, &["sourceFile"]),
+                    line_number: body.get("lineNumber").and_then(Value::as_i64),
+                    disposition: text(body, &["disposition"]),
+                })
+            })
+            .collect();
+        Some(reports)
+    }
+
+    /// Returns the hash of the normalized report that identifies duplicates
+    fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        for field in [
+            &self.report_type,
+            &self.document_uri,
+            &self.directive,
+            &self.blocked_uri,
+            &self.source_file,
+            &self.disposition,
+        ] {
+            hasher.update(field.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(self.line_number.unwrap_or(-1).to_be_bytes());
+        BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize())
+    }
+}
+
+/// Returns the first string of the given keys of a JSON object, truncated to a maximum length
+///
+/// # Arguments
+/// * `value` - JSON object
+/// * `keys` - Keys in order of preference
+///
+/// # Returns
+/// String or an empty string if none of the keys contains a string
+///
+fn text(value: &Value, keys: &[&str]) -> String {
+    keys.iter()
+        .find_map(|key| value.get(key).and_then(Value::as_str))
+        .map(truncate)
+        .unwrap_or_default()
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_FIELD_LENGTH).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::serde_json;
+
+    use super::*;
+
+    #[test]
+    fn normalizes_both_report_formats() {
+        let csp_report = serde_json::json!({"csp-report": {
+            "document-uri": "https://warehouse.example.com/ui/",
+            "violated-directive": "script-src 'self'",
+            "blocked-uri": "https://cdn.example.com/lib.js",
+            "line-number": 12,
+            "disposition": "enforce"
+        }});
+        let reports = serde_json::json!([
+            {"type": "csp-violation", "url": "https://warehouse.example.com/ui/", "body": {
+                "effectiveDirective": "script-src-elem",
+                "blockedURL": "https://cdn.example.com/lib.js",
+                "disposition": "enforce"
+            }},
+            {"type": "coep", "url": "https://warehouse.example.com/ui/", "body": {
+                "type": "corp",
+                "blockedURL": "https://images.example.com/logo.png",
+                "disposition": "enforce"
+            }},
+            {"type": "unknown"}
+        ]);
+
+        let csp_report = SecurityReport::from_csp_report(&csp_report).unwrap();
+        assert_eq!(csp_report[0].directive, "script-src");
+        assert_eq!(csp_report[0].line_number, Some(12));
+        let reports = SecurityReport::from_reports(&reports).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].directive, "script-src-elem");
+        assert_eq!(reports[1].report_type, "coep");
+        assert_eq!(reports[1].directive, "corp");
+        assert_eq!(
+            reports[1].blocked_uri,
+            "https://images.example.com/logo.png"
+        );
+        assert_ne!(csp_report[0].id(), reports[0].id());
+        assert_eq!(reports[1].id(), reports[1].clone().id());
+        assert!(SecurityReport::from_csp_report(&serde_json::json!([])).is_none());
+        assert!(SecurityReport::from_reports(&serde_json::json!({})).is_none());
+    }
+}
@@ -0,0 +1,280 @@
+//! Rocket routes to receive violation reports from browsers and to query them
+
+use std::net::SocketAddr;
+
+use rocket::State;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value, serde_json};
+use rocket_db_pools::Connection;
+use tracing::{Level, event};
+
+use crate::database::Db;
+use crate::oidc::guard::OidcUser;
+
+use super::collector::{ReportCollector, ReportGrouping, ReportSummary, SecurityReport};
+
+/// Receives a report of the Content-Security-Policy directive report-uri
+///
+/// # Arguments
+/// * `report` - Body of the request
+/// * `client` - Peer of the request as resolved by the request firewall (injected by Rocket)
+/// * `collector` - Report collector (injected by Rocket)
+/// * `db` - Async connection object to the database
+///
+/// # Returns
+/// NoContent if the report was stored, TooManyRequests if the client exceeded the rate limit or an error if the report is invalid
+///
+#[post("/", format = "application/csp-report", data = "<report>")]
+pub async fn receive_csp_report(
+    report: Data<'_>,
+    client: SocketAddr,
+    collector: &State<ReportCollector>,
+    db: Connection<Db>,
+) -> Status {
+    receive(
+        report,
+        client,
+        collector,
+        db,
+        SecurityReport::from_csp_report,
+    )
+    .await
+}
+
+/// Receives reports of the Reporting API, e.g. for the Content-Security-Policy directive report-to, COEP or COOP
+///
+/// # Arguments
+/// * `reports` - Body of the request
+/// * `client` - Peer of the request as resolved by the request firewall (injected by Rocket)
+/// * `collector` - Report collector (injected by Rocket)
+/// * `db` - Async connection object to the database
+///
+/// # Returns
+/// NoContent if the reports were stored, TooManyRequests if the client exceeded the rate limit or an error if the reports are invalid
+///
+#[post("/", format = "application/reports+json", data = "<reports>")]
+pub async fn receive_reports(
+    reports: Data<'_>,
+    client: SocketAddr,
+    collector: &State<ReportCollector>,
+    db: Connection<Db>,
+) -> Status {
+    receive(reports, client, collector, db, SecurityReport::from_reports).await
+}
+
+/// Aggregates the stored reports by directive and/or blocked URI
+///
+/// # Arguments
+/// * `by` - "directive", "blocked_uri" or none to aggregate by both
+/// * `since` - Only reports last seen at or after this time (format: 2026-10-19T12:00:00Z)
+/// * `user` - Authenticated user (no access for unauthenticated users)
+/// * `collector` - Report collector (injected by Rocket)
+/// * `db` - Async connection object to the database
+///
+/// # Returns
+/// Aggregated reports as JSON, the most frequent first, or an error if the user is not an admin
+///
+#[get("/security-reports?<by>&<since>")]
+pub async fn security_reports(
+    by: Option<&str>,
+    since: Option<&str>,
+    user: OidcUser,
+    collector: &State<ReportCollector>,
+    mut db: Connection<Db>,
+) -> Result<Json<Vec<ReportSummary>>, Status> {
+    if !collector.is_admin(&user) {
+        return Err(Status::Forbidden);
+    }
+    let grouping = ReportGrouping::parse(by).ok_or(Status::BadRequest)?;
+    match collector
+        .summarize(&mut db, grouping, since.unwrap_or_default())
+        .await
+    {
+        Ok(summaries) => Ok(Json(summaries)),
+        Err(err) => {
+            event!(Level::ERROR, "Cannot read security reports: {}", err);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Reads, normalizes and stores reports
+///
+/// # Arguments
+/// * `data` - Body of the request
+/// * `client` - Peer of the request as resolved by the request firewall
+/// * `collector` - Report collector
+/// * `db` - Async connection object to the database
+/// * `normalize` - Normalizes the reports of the format of the request
+///
+/// # Returns
+/// Status of the response
+///
+async fn receive(
+    data: Data<'_>,
+    client: SocketAddr,
+    collector: &ReportCollector,
+    mut db: Connection<Db>,
+    normalize: fn(&Value) -> Option<Vec<SecurityReport>>,
+) -> Status {
+    if !collector.is_allowed(client.ip()).await {
+        event!(
+            Level::WARN,
+            "Rate limit of security reports exceeded by {}",
+            client.ip()
+        );
+        return Status::TooManyRequests;
+    }
+    let body = match data
+        .open(collector.max_report_size.as_u64().bytes())
+        .into_bytes()
+        .await
+    {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Status::PayloadTooLarge,
+        Err(_) => return Status::BadRequest,
+    };
+    let Some(reports) = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .as_ref()
+        .and_then(normalize)
+    else {
+        return Status::BadRequest;
+    };
+    for report in reports {
+        if let Err(err) = collector.store(&mut db, &report).await {
+            event!(Level::ERROR, "Cannot store security report: {}", err);
+            return Status::InternalServerError;
+        }
+    }
+    if let Err(err) = collector.prune(&mut db).await {
+        event!(Level::ERROR, "Cannot remove old security reports: {}", err);
+    }
+    Status::NoContent
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::fairing::AdHoc;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use rocket_db_pools::Database;
+
+    use super::*;
+    use crate::configuration::config::{
+        CustomAppCookiesConfig, CustomAppKeyRingConfig, CustomAppReportingConfig,
+    };
+    use crate::oidc::cookies::OidcCookies;
+    use crate::oidc::keyring::CookieKeyRing;
+    use crate::oidc::routes::{oidc_redirect, redirect_auth};
+    use crate::oidc::testing::{login, oidc_config, start_idp};
+
+    #[test]
+    fn stores_deduplicated_reports_and_aggregates_them() {
+        let database =
+            std::env::temp_dir().join(format!("warehouse-{}.sqlite", uuid::Uuid::new_v4()));
+        let (_files, oidc_flow) = start_idp();
+        let csrf_state = oidc_flow.csrf_state.secret().clone();
+        let figment = rocket::Config::figment().merge((
+            "databases.warehouse.url",
+            database.to_string_lossy().to_string(),
+        ));
+        let rocket = rocket::custom(figment)
+            .attach(Db::init())
+            .attach(AdHoc::try_on_ignite(
+                "Migrations",
+                crate::database::run_migrations,
+            ))
+            .manage(oidc_flow)
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .manage(oidc_config())
+            .manage(
+                ReportCollector::new(&CustomAppReportingConfig {
+                    endpoint: Some("/reports".to_string()),
+                    rate_limit_per_minute: Some(3),
+                    max_reports: Some(2),
+                    admin_roles: Some(vec!["admin".to_string()]),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .mount("/oidc", routes![oidc_redirect])
+            .mount("/reports", routes![receive_csp_report, receive_reports])
+            .mount("/ui-api", routes![security_reports])
+            .mount("/", routes![redirect_auth]);
+        let client = Client::tracked(rocket).unwrap();
+        let csp_report = ContentType::new("application", "csp-report");
+        let reports = ContentType::new("application", "reports+json");
+        let script = r#"{"csp-report": {"document-uri": "https://warehouse.example.com/ui/", "violated-directive": "script-src", "blocked-uri": "https://cdn.example.com/lib.js"}}"#;
+        let image = r#"[{"type": "csp-violation", "url": "https://warehouse.example.com/ui/", "body": {"effectiveDirective": "img-src", "blockedURL": "https://cdn.example.com/logo.png"}}]"#;
+
+        for _ in 0..2 {
+            let response = client
+                .post("/reports")
+                .remote("192.0.2.1:50000".parse().unwrap())
+                .header(csp_report.clone())
+                .body(script)
+                .dispatch();
+            assert_eq!(response.status(), Status::NoContent);
+        }
+        let response = client
+            .post("/reports")
+            .remote("192.0.2.1:50000".parse().unwrap())
+            .header(reports.clone())
+            .body(image)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let response = client
+            .post("/reports")
+            .remote("192.0.2.1:50000".parse().unwrap())
+            .header(reports)
+            .body("[]")
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        let response = client.get("/ui-api/security-reports").dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("/oidc/login"));
+        login(&client, &csrf_state);
+        let summaries: Value = client
+            .get("/ui-api/security-reports?by=directive")
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let summaries = summaries.as_array().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0]["directive"], "script-src");
+        assert_eq!(summaries[0]["count"], 2);
+        assert!(summaries[0].get("blocked_uri").is_none());
+        assert_eq!(summaries[1]["directive"], "img-src");
+        assert_eq!(summaries[1]["count"], 1);
+        let response = client.get("/ui-api/security-reports?by=source").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // the report seen longest ago is removed over the maximum number of reports
+        let style = r#"{"csp-report": {"document-uri": "https://warehouse.example.com/ui/", "violated-directive": "style-src", "blocked-uri": "inline"}}"#;
+        let response = client
+            .post("/reports")
+            .remote("192.0.2.2:50000".parse().unwrap())
+            .header(csp_report)
+            .body(style)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let summaries: Value = client
+            .get("/ui-api/security-reports?by=directive")
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let mut directives: Vec<&str> = summaries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|summary| summary["directive"].as_str().unwrap())
+            .collect();
+        directives.sort();
+        assert_eq!(directives, vec!["img-src", "style-src"]);
+
+        let _ = std::fs::remove_file(database);
+    }
+}
@@ -1,16 +1,22 @@
 //! Rocket handler to redirect routes of the SPA frontend (not of Rocket/backend) to the SPA frontend and not handling them in the backend
 
-use crate::{configuration::config::CustomAppStaticFilesConfig, oidc::guard::OidcUser};
+use crate::{
+    compression::encoding::AcceptEncoding, configuration::config::CustomAppStaticFilesConfig,
+    httpfirewall::subresourceintegrity::SubresourceIntegrity, oidc::guard::OidcUser,
+};
+
+use super::static_serve::{StaticContent, open};
 
 use std::path::{Path, PathBuf};
 
-use rocket::{State, fs::NamedFile};
+use rocket::State;
 
 /// Handler to redirect routes of the SPA front end to the SPA frontend
 ///
 /// # Arguments
 /// * `path` - path of front-end routes to be routed to the SPA frontend
 /// * `fileserver_config` - configuration of the static file server
+/// * `subresource_integrity` - documents with integrity attributes (injected by Rocket)
 /// * `user` - Authenticated user (no access for unauthenticated users)
 ///
 /// # Returns
@@ -19,8 +25,10 @@ use rocket::{State, fs::NamedFile};
 pub async fn spa_redirect_frontend_route(
     path: PathBuf,
     fileserver_config: &State<CustomAppStaticFilesConfig>,
+    subresource_integrity: &State<SubresourceIntegrity>,
     user: OidcUser,
-) -> Option<NamedFile> {
+) -> Option<StaticContent> {
     let path = Path::new(&fileserver_config.location).join("index.html");
-    NamedFile::open(path).await.ok()
+    // the main page is HTML, which is never served precompressed
+    open(&path, subresource_integrity, &AcceptEncoding::default()).await
 }
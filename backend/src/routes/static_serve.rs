@@ -1,16 +1,61 @@
 //! Rocket handler to serve static content as FileServer in Rocket does not support authentication
 
-use crate::{configuration::config::CustomAppStaticFilesConfig, oidc::guard::OidcUser};
+use crate::{
+    compression::encoding::{AcceptEncoding, Encoding},
+    configuration::config::CustomAppStaticFilesConfig,
+    httpfirewall::subresourceintegrity::SubresourceIntegrity,
+    oidc::guard::OidcUser,
+};
 
 use std::path::{Path, PathBuf};
 
-use rocket::{State, fs::NamedFile};
+use rocket::{
+    Request, State,
+    fs::NamedFile,
+    http::ContentType,
+    response::{self, Responder, content::RawHtml},
+};
+
+// Encodings of files that are compressed at build time (e.g. app.js.br) in the order of preference
+const PRECOMPRESSED_ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+// Static file, an HTML document with Subresource Integrity attributes or a file that was compressed at build time
+#[derive(Responder)]
+pub enum StaticContent {
+    File(NamedFile),
+    Document(RawHtml<Vec<u8>>),
+    Precompressed(PrecompressedFile),
+}
+
+// File that was compressed at build time with the content type of the uncompressed file
+pub struct PrecompressedFile {
+    file: NamedFile,
+    content_type: Option<ContentType>,
+    encoding: Encoding,
+}
+
+impl<'r> Responder<'r, 'static> for PrecompressedFile {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.file.respond_to(req)?;
+        match self.content_type {
+            Some(content_type) => {
+                response.set_header(content_type);
+            }
+            None => response.remove_header("Content-Type"),
+        }
+        response.set_raw_header("Content-Encoding", self.encoding.name());
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        Ok(response)
+    }
+}
 
 /// Handler to serve static content
 ///
 /// # Arguments
 /// * `path` - path of front-end routes to be routed to the SPA frontend. Note: Rocket automatically makes sure that they do not allow path-traversal
 /// * `fileserver_config` - configuration of the static file server
+/// * `subresource_integrity` - documents with integrity attributes (injected by Rocket)
+/// * `accept_encoding` - encodings the client accepts (injected by Rocket)
 /// * `user` - provided by Rocket only if user is authenticated => otherwise handler cannot be accessed
 ///
 /// Returns the content of the file
@@ -19,11 +64,130 @@ use rocket::{State, fs::NamedFile};
 pub async fn serve_static(
     path: PathBuf,
     fileserver_config: &State<CustomAppStaticFilesConfig>,
+    subresource_integrity: &State<SubresourceIntegrity>,
+    accept_encoding: AcceptEncoding,
     user: OidcUser,
-) -> Option<NamedFile> {
+) -> Option<StaticContent> {
     let mut path = Path::new(&fileserver_config.location).join(path);
     if path.is_dir() {
         path = path.join("index.html");
     }
-    NamedFile::open(path).await.ok()
+    open(&path, subresource_integrity, &accept_encoding).await
+}
+
+/// Opens a static file. Documents that are configured for Subresource Integrity are served with integrity attributes. Other files are served compressed if a sibling that was compressed at build time exists (e.g. app.js.br) and the client accepts its encoding
+/// HTML documents are always served uncompressed, so that nonces and hashes can be inserted. They can be compressed afterwards by the compression fairing
+///
+/// # Arguments
+/// * `path` - path of the file in the file system
+/// * `subresource_integrity` - documents with integrity attributes
+/// * `accept_encoding` - encodings the client accepts
+///
+/// # Returns
+/// The content of the file or None if it does not exist
+///
+pub async fn open(
+    path: &Path,
+    subresource_integrity: &SubresourceIntegrity,
+    accept_encoding: &AcceptEncoding,
+) -> Option<StaticContent> {
+    if let Some(document) = subresource_integrity.document(path) {
+        return Some(StaticContent::Document(RawHtml(document.to_vec())));
+    }
+    let content_type = path
+        .extension()
+        .and_then(|extension| ContentType::from_extension(&extension.to_string_lossy()));
+    if !content_type
+        .as_ref()
+        .is_some_and(|content_type| content_type.is_html())
+        && path.is_file()
+    {
+        let precompressed: Vec<Encoding> = PRECOMPRESSED_ENCODINGS
+            .into_iter()
+            .filter(|encoding| precompressed_path(path, *encoding).is_file())
+            .collect();
+        if let Some(encoding) = accept_encoding.preferred(&precompressed)
+            && let Ok(file) = NamedFile::open(precompressed_path(path, encoding)).await
+        {
+            return Some(StaticContent::Precompressed(PrecompressedFile {
+                file,
+                content_type,
+                encoding,
+            }));
+        }
+    }
+    NamedFile::open(path).await.ok().map(StaticContent::File)
+}
+
+/// Returns the path of the sibling of a file that was compressed at build time, e.g. app.js.br
+fn precompressed_path(path: &Path, encoding: Encoding) -> PathBuf {
+    let mut precompressed = path.as_os_str().to_owned();
+    precompressed.push(".");
+    precompressed.push(encoding.extension());
+    PathBuf::from(precompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Header, Status};
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[get("/<path..>")]
+    async fn serve(
+        path: PathBuf,
+        location: &State<PathBuf>,
+        accept_encoding: AcceptEncoding,
+    ) -> Option<StaticContent> {
+        open(
+            &location.join(path),
+            &SubresourceIntegrity::default(),
+            &accept_encoding,
+        )
+        .await
+    }
+
+    #[test]
+    fn prefers_precompressed_siblings_except_for_html() {
+        let location = std::env::temp_dir().join(format!("static-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&location).unwrap();
+        for (name, content) in [
+            ("app.js", "console.log('app');"),
+            ("app.js.br", "brotli"),
+            ("app.js.gz", "gzip"),
+            ("index.html", "<html></html>"),
+            ("index.html.gz", "gzip"),
+        ] {
+            std::fs::write(location.join(name), content).unwrap();
+        }
+        let rocket = rocket::build()
+            .manage(location.clone())
+            .mount("/", routes![serve]);
+        let client = Client::tracked(rocket).unwrap();
+        let get = |path: &str, accept_encoding: &str| {
+            client
+                .get(path.to_string())
+                .header(Header::new("Accept-Encoding", accept_encoding.to_string()))
+                .dispatch()
+        };
+
+        let response = get("/app.js", "gzip, deflate, br");
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("br"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+        assert_eq!(response.into_string().unwrap(), "brotli");
+        let response = get("/app.js", "gzip, zstd");
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.into_string().unwrap(), "gzip");
+        let response = get("/app.js", "identity");
+        assert!(!response.headers().contains("Content-Encoding"));
+        assert_eq!(response.into_string().unwrap(), "console.log('app');");
+        let response = get("/index.html", "gzip");
+        assert!(!response.headers().contains("Content-Encoding"));
+        assert_eq!(response.into_string().unwrap(), "<html></html>");
+        assert_eq!(get("/missing.js", "gzip").status(), Status::NotFound);
+
+        std::fs::remove_dir_all(location).unwrap();
+    }
 }
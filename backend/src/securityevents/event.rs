@@ -0,0 +1,143 @@
+//! Security events with a stable schema, e.g. for firewall decisions and failed authentications
+//!
+//! Events are serialized as JSON objects with the fields timestamp, event_type, severity, request_id, client_ip, subject, impersonated_subject, rule_id, outcome and message. Fields that are unknown for an event are null. New fields may be added, but existing fields are not renamed or removed
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::Request;
+use serde::Serialize;
+
+use crate::httpfirewall::requestfilter::request_id;
+
+// Type of a security event
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventType {
+    FirewallRule,
+    IpAccessDenied,
+    RateLimitExceeded,
+    CsrfRejected,
+    CorsPreflightDenied,
+    AuthenticationFailed,
+    Impersonation,
+    ConfigurationError,
+}
+
+// Severity of a security event in ascending order
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecuritySeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+// Decision that was taken on the request or the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityOutcome {
+    Blocked,
+    Logged,
+    Tagged,
+    Rejected,
+    Aborted,
+}
+
+// Security event. The order of the fields is the order in the JSON object
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SecurityEvent {
+    // milliseconds since the Unix epoch
+    pub timestamp: u128,
+    pub event_type: SecurityEventType,
+    pub severity: SecuritySeverity,
+    pub request_id: Option<String>,
+    pub client_ip: Option<String>,
+    pub subject: Option<String>,
+    // user who is impersonated by the subject
+    pub impersonated_subject: Option<String>,
+    pub rule_id: Option<String>,
+    pub outcome: SecurityOutcome,
+    pub message: String,
+}
+
+impl SecuritySeverity {
+    /// Returns the severity of a name in the configuration, e.g. warning
+    ///
+    /// # Arguments
+    /// * `name` - Name of the severity
+    ///
+    pub fn parse(name: &str) -> Option<SecuritySeverity> {
+        match name.to_ascii_lowercase().as_str() {
+            "info" => Some(SecuritySeverity::Info),
+            "warning" => Some(SecuritySeverity::Warning),
+            "error" => Some(SecuritySeverity::Error),
+            _ => None,
+        }
+    }
+}
+
+impl SecurityEvent {
+    /// Creates a security event that is not related to a request
+    ///
+    /// # Arguments
+    /// * `event_type` - Type of the event
+    /// * `severity` - Severity of the event
+    /// * `outcome` - Decision that was taken
+    /// * `message` - Human readable description
+    ///
+    pub fn new(
+        event_type: SecurityEventType,
+        severity: SecuritySeverity,
+        outcome: SecurityOutcome,
+        message: String,
+    ) -> SecurityEvent {
+        SecurityEvent {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis()),
+            event_type,
+            severity,
+            request_id: None,
+            client_ip: None,
+            subject: None,
+            impersonated_subject: None,
+            rule_id: None,
+            outcome,
+            message,
+        }
+    }
+
+    /// Adds the request ID and the IP address of the client of a request, i.e. the peer as resolved by the request firewall
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    pub fn with_request(mut self, req: &Request<'_>) -> SecurityEvent {
+        self.request_id = Some(request_id(req).0);
+        self.client_ip = req.remote().map(|remote| remote.ip().to_string());
+        self
+    }
+
+    /// Adds the subject (e.g. user or certificate) that the event is about
+    pub fn with_subject(mut self, subject: impl Into<String>) -> SecurityEvent {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    /// Adds the user who is impersonated by the subject
+    pub fn with_impersonated_subject(mut self, subject: impl Into<String>) -> SecurityEvent {
+        self.impersonated_subject = Some(subject.into());
+        self
+    }
+
+    /// Adds the ID of the rule (e.g. firewall rule or rate limit group) that caused the event
+    pub fn with_rule(mut self, rule_id: impl Into<String>) -> SecurityEvent {
+        self.rule_id = Some(rule_id.into());
+        self
+    }
+
+    /// Returns the event as JSON object in a single line
+    pub fn to_json(&self) -> String {
+        rocket::serde::json::to_string(self).unwrap_or_default()
+    }
+}
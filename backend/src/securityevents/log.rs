@@ -0,0 +1,369 @@
+//! Security event log that writes security events to the configured sinks: the application log, a file with one JSON object per line, a syslog socket or memory (for tests)
+//!
+//! The log is managed as state in Rocket. Without configuration, events are written to the application log with the target "security"
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+
+use rocket::Request;
+use rocket::request::{self, FromRequest, Outcome};
+use tracing::{Level, event};
+
+use crate::configuration::config::CustomAppSecurityEventsConfig;
+use crate::httpfirewall::requestfilter::request_id;
+
+use super::event::{SecurityEvent, SecuritySeverity};
+
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+const DEFAULT_SYSLOG_FACILITY: &str = "authpriv";
+const SYSLOG_APP_NAME: &str = "rust-rocket-backend";
+
+// Errors in the security event configuration
+#[derive(Debug)]
+pub enum SecurityEventsConfigError {
+    EmptySinks,
+    UnknownSink(String),
+    MissingFile,
+    CannotOpenFile(String),
+    CannotConnectSyslog(String),
+    UnknownSyslogFacility(String),
+    UnknownSeverity(String),
+}
+
+// Destination of security events
+#[derive(Debug)]
+enum SecurityEventSink {
+    Log,
+    File(Mutex<File>),
+    Syslog { socket: UnixDatagram, facility: u8 },
+    Memory(Arc<Mutex<Vec<SecurityEvent>>>),
+}
+
+// Security event log. It is managed as state in Rocket
+#[derive(Debug)]
+pub struct SecurityEventLog {
+    // the application log if empty
+    sinks: Vec<SecurityEventSink>,
+    min_severity: SecuritySeverity,
+}
+
+// Security event log for routes. Events are recorded with the request ID and the IP address of the client of the request
+pub struct RequestSecurityEventLog<'r> {
+    log: &'r SecurityEventLog,
+    request_id: String,
+    client_ip: Option<String>,
+}
+
+// Security event log of Rocket instances without it, e.g. in tests
+static DEFAULT_LOG: SecurityEventLog = SecurityEventLog {
+    sinks: Vec::new(),
+    min_severity: SecuritySeverity::Info,
+};
+
+impl SecurityEventLog {
+    /// Creates the security event log from the configuration
+    ///
+    /// # Arguments
+    /// * `config` - Security event configuration of the application
+    ///
+    /// # Returns
+    /// Security event log or an error if a sink cannot be opened
+    ///
+    pub fn new(
+        config: &CustomAppSecurityEventsConfig,
+    ) -> Result<SecurityEventLog, SecurityEventsConfigError> {
+        let min_severity = SecurityEventLog::check(config)?;
+        let Some(sinks) = &config.sinks else {
+            return Ok(SecurityEventLog {
+                sinks: Vec::new(),
+                min_severity,
+            });
+        };
+        let sinks = sinks
+            .iter()
+            .map(|sink| match sink.as_str() {
+                "log" => Ok(SecurityEventSink::Log),
+                "file" => {
+                    let path = config.file.as_deref().unwrap_or_default();
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map(|file| SecurityEventSink::File(Mutex::new(file)))
+                        .map_err(|err| {
+                            SecurityEventsConfigError::CannotOpenFile(format!("{}: {}", path, err))
+                        })
+                }
+                "syslog" => {
+                    let facility = syslog_facility(
+                        config
+                            .syslog_facility
+                            .as_deref()
+                            .unwrap_or(DEFAULT_SYSLOG_FACILITY),
+                    )
+                    .unwrap_or_default();
+                    let path = config
+                        .syslog_socket
+                        .as_deref()
+                        .unwrap_or(DEFAULT_SYSLOG_SOCKET);
+                    UnixDatagram::unbound()
+                        .and_then(|socket| socket.connect(path).map(|_| socket))
+                        .map(|socket| SecurityEventSink::Syslog { socket, facility })
+                        .map_err(|err| {
+                            SecurityEventsConfigError::CannotConnectSyslog(format!(
+                                "{}: {}",
+                                path, err
+                            ))
+                        })
+                }
+                _ => Err(SecurityEventsConfigError::UnknownSink(sink.clone())),
+            })
+            .collect::<Result<Vec<SecurityEventSink>, SecurityEventsConfigError>>()?;
+        Ok(SecurityEventLog {
+            sinks,
+            min_severity,
+        })
+    }
+
+    /// Checks the configuration without opening the sinks, e.g. to check the configuration in a CI pipeline on another host
+    ///
+    /// # Arguments
+    /// * `config` - Security event configuration of the application
+    ///
+    /// # Returns
+    /// Minimum severity or an error if a sink, the syslog facility or the severity is invalid
+    ///
+    pub fn check(
+        config: &CustomAppSecurityEventsConfig,
+    ) -> Result<SecuritySeverity, SecurityEventsConfigError> {
+        let min_severity = match &config.min_severity {
+            Some(name) => SecuritySeverity::parse(name)
+                .ok_or_else(|| SecurityEventsConfigError::UnknownSeverity(name.clone()))?,
+            None => SecuritySeverity::Info,
+        };
+        let Some(sinks) = &config.sinks else {
+            return Ok(min_severity);
+        };
+        if sinks.is_empty() {
+            return Err(SecurityEventsConfigError::EmptySinks);
+        }
+        for sink in sinks {
+            match sink.as_str() {
+                "log" => (),
+                "file" if config.file.is_none() => {
+                    return Err(SecurityEventsConfigError::MissingFile);
+                }
+                "file" => (),
+                "syslog" => {
+                    let name = config
+                        .syslog_facility
+                        .as_deref()
+                        .unwrap_or(DEFAULT_SYSLOG_FACILITY);
+                    if syslog_facility(name).is_none() {
+                        return Err(SecurityEventsConfigError::UnknownSyslogFacility(
+                            name.to_string(),
+                        ));
+                    }
+                }
+                _ => return Err(SecurityEventsConfigError::UnknownSink(sink.clone())),
+            }
+        }
+        Ok(min_severity)
+    }
+
+    /// Creates a security event log that captures the events in memory, e.g. to check them in tests
+    ///
+    /// # Returns
+    /// Security event log and the captured events
+    ///
+    pub fn capture() -> (SecurityEventLog, Arc<Mutex<Vec<SecurityEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = SecurityEventLog {
+            sinks: vec![SecurityEventSink::Memory(events.clone())],
+            min_severity: SecuritySeverity::Info,
+        };
+        (log, events)
+    }
+
+    /// Returns the security event log of the Rocket instance that handles a request. Rocket instances without it (e.g. in tests) write to the application log
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r SecurityEventLog {
+        req.rocket()
+            .state::<SecurityEventLog>()
+            .unwrap_or(&DEFAULT_LOG)
+    }
+
+    /// Writes a security event to all sinks. Events below the minimum severity are dropped. Errors of a sink are written to the application log
+    ///
+    /// # Arguments
+    /// * `security_event` - The event
+    ///
+    pub fn record(&self, security_event: SecurityEvent) {
+        if security_event.severity < self.min_severity {
+            return;
+        }
+        if self.sinks.is_empty() {
+            log(&security_event);
+        }
+        for sink in self.sinks.iter() {
+            let result = match sink {
+                SecurityEventSink::Log => {
+                    log(&security_event);
+                    Ok(())
+                }
+                SecurityEventSink::File(file) => {
+                    let mut file = file.lock().unwrap();
+                    writeln!(file, "{}", security_event.to_json())
+                }
+                SecurityEventSink::Syslog { socket, facility } => {
+                    let priority = facility * 8 + syslog_severity(security_event.severity);
+                    socket
+                        .send(
+                            format!(
+                                "<{}>{}: {}",
+                                priority,
+                                SYSLOG_APP_NAME,
+                                security_event.to_json()
+                            )
+                            .as_bytes(),
+                        )
+                        .map(|_| ())
+                }
+                SecurityEventSink::Memory(events) => {
+                    events.lock().unwrap().push(security_event.clone());
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                event!(Level::ERROR, "Cannot write security event: {}", err);
+            }
+        }
+    }
+}
+
+impl RequestSecurityEventLog<'_> {
+    /// Writes a security event of the request to all sinks
+    ///
+    /// # Arguments
+    /// * `security_event` - The event
+    ///
+    pub fn record(&self, mut security_event: SecurityEvent) {
+        security_event.request_id = Some(self.request_id.clone());
+        security_event.client_ip = self.client_ip.clone();
+        self.log.record(security_event);
+    }
+}
+
+// Implementation of the request guard for routes that record security events
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSecurityEventLog<'r> {
+    type Error = ();
+
+    /// Returns the security event log of the Rocket instance with the request ID and the IP address of the client
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RequestSecurityEventLog {
+            log: SecurityEventLog::of(req),
+            request_id: request_id(req).0,
+            client_ip: req.remote().map(|remote| remote.ip().to_string()),
+        })
+    }
+}
+
+/// Writes a security event as JSON to the application log with the target "security"
+fn log(security_event: &SecurityEvent) {
+    let json = security_event.to_json();
+    match security_event.severity {
+        SecuritySeverity::Info => event!(target: "security", Level::INFO, "{}", json),
+        SecuritySeverity::Warning => event!(target: "security", Level::WARN, "{}", json),
+        SecuritySeverity::Error => event!(target: "security", Level::ERROR, "{}", json),
+    }
+}
+
+/// Returns the number of a syslog facility (RFC 5424), e.g. 10 for authpriv
+fn syslog_facility(name: &str) -> Option<u8> {
+    match name {
+        "user" => Some(1),
+        "daemon" => Some(3),
+        "auth" => Some(4),
+        "authpriv" => Some(10),
+        _ => name
+            .strip_prefix("local")
+            .and_then(|number| number.parse::<u8>().ok())
+            .filter(|number| *number <= 7)
+            .map(|number| 16 + number),
+    }
+}
+
+/// Returns the syslog severity (RFC 5424) of a security event
+fn syslog_severity(severity: SecuritySeverity) -> u8 {
+    match severity {
+        SecuritySeverity::Info => 6,
+        SecuritySeverity::Warning => 4,
+        SecuritySeverity::Error => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::securityevents::event::{SecurityEventType, SecurityOutcome};
+
+    #[test]
+    fn writes_json_lines_and_filters_severity() {
+        let path = std::env::temp_dir().join(format!("security-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = SecurityEventLog::new(&CustomAppSecurityEventsConfig {
+            sinks: Some(vec!["file".to_string()]),
+            file: Some(path.to_string_lossy().to_string()),
+            min_severity: Some("warning".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        log.record(SecurityEvent::new(
+            SecurityEventType::FirewallRule,
+            SecuritySeverity::Info,
+            SecurityOutcome::Tagged,
+            "tagged".to_string(),
+        ));
+        log.record(
+            SecurityEvent::new(
+                SecurityEventType::FirewallRule,
+                SecuritySeverity::Warning,
+                SecurityOutcome::Blocked,
+                "blocked".to_string(),
+            )
+            .with_rule("scanner")
+            .with_subject("alice"),
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let json: rocket::serde::json::Value = rocket::serde::json::from_str(lines[0]).unwrap();
+        assert_eq!(json["event_type"], "firewall_rule");
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["rule_id"], "scanner");
+        assert_eq!(json["subject"], "alice");
+        assert_eq!(json["outcome"], "blocked");
+        assert!(json["request_id"].is_null());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(
+            SecurityEventLog::new(&CustomAppSecurityEventsConfig {
+                sinks: Some(vec!["file".to_string()]),
+                ..Default::default()
+            }),
+            Err(SecurityEventsConfigError::MissingFile)
+        ));
+        assert_eq!(syslog_facility("local7"), Some(23));
+        assert_eq!(syslog_facility("local8"), None);
+    }
+}
@@ -13,3 +13,15 @@ use ammonia::Builder;
 pub fn clean_all_html(src: &str) -> String {
     Builder::default().clean(src).to_string()
 }
+
+/// Escape a String so that it can be embedded as text or attribute value in HTML
+///
+/// # Arguments
+/// * `src` - string to escape
+///
+/// # Returns
+/// An escaped string where all characters with a meaning in HTML have been replaced by entities
+///
+pub fn escape_html(src: &str) -> String {
+    ammonia::clean_text(src)
+}
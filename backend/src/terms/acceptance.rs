@@ -0,0 +1,278 @@
+//! Gate that sends users to the terms of use after login until they accepted the current version
+//!
+//! Acceptances are stored per subject and version in the database. Only sessions of users (not devices authenticated with client certificates or API clients with DPoP-bound access tokens) are gated
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::uri::Origin;
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, SqliteConnection};
+use tracing::{Level, event};
+
+use crate::configuration::config::CustomAppTermsConfig;
+use crate::database::Db;
+use crate::oidc::guard::{OidcUser, path_in};
+
+const DEFAULT_PATHS: [&str; 2] = ["/ui", "/ui-api"];
+// acceptances are read from the database again after this time, e.g. if they were removed
+const ACCEPTED_CACHE_DURATION: Duration = Duration::from_secs(300);
+// expired acceptances are removed if more subjects are cached, all of them if none expired
+const MAX_CACHED_SUBJECTS: usize = 10000;
+
+// Errors in the terms of use configuration
+#[derive(Debug)]
+pub enum TermsConfigError {
+    EmptyVersion,
+    DocumentRequired,
+    DocumentNotReadable(String),
+    InvalidPath(String),
+}
+
+// Current version of the terms of use. It is managed as state in Rocket, if a version is configured
+#[derive(Debug)]
+pub struct TermsOfUse {
+    pub version: String,
+    pub document: String,
+    paths: Vec<String>,
+    // subjects that accepted the current version, so that the database is not queried on every request
+    accepted: Mutex<AcceptanceCache>,
+}
+
+// Recently read acceptances per subject with the version and the time they were cached
+#[derive(Debug, Default)]
+struct AcceptanceCache {
+    subjects: HashMap<String, (String, Instant)>,
+}
+
+// The acceptance cannot be read, e.g. because the database is not available
+#[derive(Debug)]
+pub struct TermsUnavailable;
+
+// Marks a request of a user that has not accepted the current terms of use, so that they are redirected to the terms instead of the login
+pub struct PendingTerms(pub bool);
+
+impl TermsOfUse {
+    /// Reads the terms of use document
+    ///
+    /// # Arguments
+    /// * `config` - Terms of use configuration of the application
+    ///
+    /// # Returns
+    /// Terms of use or an error, if the version is empty or the document cannot be read
+    ///
+    pub fn new(config: &CustomAppTermsConfig) -> Result<TermsOfUse, TermsConfigError> {
+        let version = config.version.clone().unwrap_or_default();
+        if version.is_empty() {
+            return Err(TermsConfigError::EmptyVersion);
+        }
+        let document = match &config.document {
+            Some(document) => fs::read_to_string(document)
+                .map_err(|err| TermsConfigError::DocumentNotReadable(err.to_string()))?,
+            None => return Err(TermsConfigError::DocumentRequired),
+        };
+        let paths = match &config.paths {
+            Some(paths) => paths.clone(),
+            None => DEFAULT_PATHS.iter().map(|path| path.to_string()).collect(),
+        };
+        if let Some(path) = paths
+            .iter()
+            .find(|path| !path.starts_with('/') || Origin::parse(path).is_err())
+        {
+            return Err(TermsConfigError::InvalidPath(path.clone()));
+        }
+        Ok(TermsOfUse {
+            version,
+            document,
+            paths,
+            accepted: Mutex::new(AcceptanceCache::default()),
+        })
+    }
+
+    /// Returns the paths that are gated
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Checks if the user may access the route of the request. If not, the request is marked, so that the user is redirected to the terms of use
+    ///
+    /// # Arguments
+    /// * `req` - Request of the user
+    /// * `user` - Authenticated user
+    ///
+    /// # Returns
+    /// true if the route is not gated, the user is impersonated or the user accepted the current version. An error if the acceptance cannot be read, so that users that accepted the terms are not asked again during a database outage
+    ///
+    pub async fn is_accepted_for(
+        &self,
+        req: &Request<'_>,
+        user: &OidcUser,
+    ) -> Result<bool, TermsUnavailable> {
+        if !path_in(&self.paths, req.uri().path().as_str()) || user.impersonated_by.is_some() {
+            return Ok(true);
+        }
+        let subject = user.subject.to_string();
+        if self
+            .accepted
+            .lock()
+            .unwrap()
+            .contains(&subject, &self.version, Instant::now())
+        {
+            return Ok(true);
+        }
+        let accepted = match req.guard::<Connection<Db>>().await {
+            Outcome::Success(mut db) => match self.is_accepted(&mut db, &subject).await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    event!(Level::ERROR, "Cannot read terms of use acceptance: {}", err);
+                    return Err(TermsUnavailable);
+                }
+            },
+            _ => {
+                event!(
+                    Level::ERROR,
+                    "Cannot read terms of use acceptance: no database connection"
+                );
+                return Err(TermsUnavailable);
+            }
+        };
+        if accepted {
+            self.accepted
+                .lock()
+                .unwrap()
+                .insert(subject, &self.version, Instant::now());
+        } else {
+            req.local_cache(|| PendingTerms(true));
+        }
+        Ok(accepted)
+    }
+
+    /// Returns true if the subject accepted the current version
+    ///
+    /// # Arguments
+    /// * `db` - Connection to the database
+    /// * `subject` - Subject of the user
+    ///
+    pub async fn is_accepted(
+        &self,
+        db: &mut SqliteConnection,
+        subject: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let acceptance = sqlx::query!(
+            "SELECT accepted_at FROM terms_acceptance WHERE subject = ? AND version = ?",
+            subject,
+            self.version
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(acceptance.is_some())
+    }
+
+    /// Stores that the subject accepted the current version. Accepting the same version again keeps the time of the first acceptance
+    ///
+    /// # Arguments
+    /// * `db` - Connection to the database
+    /// * `subject` - Subject of the user
+    ///
+    pub async fn accept(
+        &self,
+        db: &mut SqliteConnection,
+        subject: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO terms_acceptance (subject, version, accepted_at) VALUES (?, ?, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))",
+            subject,
+            self.version
+        )
+        .execute(db)
+        .await?;
+        self.accepted
+            .lock()
+            .unwrap()
+            .insert(subject.to_string(), &self.version, Instant::now());
+        event!(
+            target: "audit",
+            Level::INFO,
+            "{} accepted terms of use version {}",
+            subject,
+            self.version
+        );
+        Ok(())
+    }
+}
+
+impl AcceptanceCache {
+    /// Returns true if the subject accepted the version and the acceptance was cached recently
+    ///
+    /// # Arguments
+    /// * `subject` - Subject of the user
+    /// * `version` - Current version of the terms of use
+    /// * `now` - Current time
+    ///
+    fn contains(&self, subject: &str, version: &str, now: Instant) -> bool {
+        self.subjects
+            .get(subject)
+            .is_some_and(|(accepted_version, cached)| {
+                accepted_version == version && now.duration_since(*cached) < ACCEPTED_CACHE_DURATION
+            })
+    }
+
+    /// Remembers that the subject accepted the version. Acceptances of other versions are removed
+    ///
+    /// # Arguments
+    /// * `subject` - Subject of the user
+    /// * `version` - Current version of the terms of use
+    /// * `now` - Current time
+    ///
+    fn insert(&mut self, subject: String, version: &str, now: Instant) {
+        if self.subjects.len() >= MAX_CACHED_SUBJECTS {
+            self.subjects.retain(|_, (accepted_version, cached)| {
+                accepted_version == version && now.duration_since(*cached) < ACCEPTED_CACHE_DURATION
+            });
+            if self.subjects.len() >= MAX_CACHED_SUBJECTS {
+                self.subjects.clear();
+            }
+        }
+        self.subjects.insert(subject, (version.to_string(), now));
+    }
+}
+
+// Implementation of the request guard that tells if the OIDC request guard found that the terms of use are not accepted
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PendingTerms {
+    type Error = ();
+
+    /// Must be evaluated after the OIDC request guard of the same route
+    ///
+    /// # Arguments
+    /// * `req` - Request object
+    ///
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(PendingTerms(req.local_cache(|| PendingTerms(false)).0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_acceptances_of_other_versions_and_old_ones() {
+        let mut cache = AcceptanceCache::default();
+        let now = Instant::now();
+        cache.insert("alice".to_string(), "2026-10", now);
+        assert!(cache.contains("alice", "2026-10", now));
+        assert!(!cache.contains("alice", "2026-11", now));
+        assert!(!cache.contains("bob", "2026-10", now));
+        assert!(!cache.contains("alice", "2026-10", now + ACCEPTED_CACHE_DURATION));
+
+        for subject in 0..MAX_CACHED_SUBJECTS {
+            cache.insert(subject.to_string(), "2026-10", now);
+        }
+        assert_eq!(cache.subjects.len(), 1);
+        assert!(cache.contains(&(MAX_CACHED_SUBJECTS - 1).to_string(), "2026-10", now));
+    }
+}
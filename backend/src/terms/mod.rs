@@ -0,0 +1,2 @@
+pub mod acceptance;
+pub mod routes;
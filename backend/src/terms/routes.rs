@@ -0,0 +1,258 @@
+//! Rocket routes to show the terms of use and to accept them
+
+use rocket::form::Form;
+use rocket::http::{CookieJar, Status};
+use rocket::response::{Redirect, content::RawHtml};
+use rocket::serde::json::{json, serde_json};
+use rocket::{Request, State};
+use rocket_db_pools::Connection;
+use tracing::{Level, event};
+
+use crate::database::Db;
+use crate::oidc::cookies::OidcCookies;
+use crate::oidc::guard::OidcUser;
+use crate::oidc::keyring::CookieKeyRing;
+use crate::services::sanitization;
+
+use super::acceptance::{PendingTerms, TermsOfUse};
+
+const DEFAULT_DESTINATION: &str = "/ui";
+
+// Form that is submitted when the user accepts the terms of use
+#[derive(FromForm)]
+pub struct TermsAcceptance {
+    version: String,
+}
+
+// Problem response (RFC 9457) of a forbidden request on the gated paths
+#[derive(Responder)]
+#[response(status = 403, content_type = "application/problem+json")]
+pub struct TermsProblem(String);
+
+/// Shows the current version of the terms of use with a form to accept them
+///
+/// # Arguments
+/// * `terms` - Current terms of use (injected by Rocket)
+/// * `_user` - Authenticated user (no access for unauthenticated users)
+///
+/// # Returns
+/// HTML page with the terms of use
+///
+#[get("/")]
+pub async fn terms_page(terms: &State<TermsOfUse>, _user: OidcUser) -> RawHtml<String> {
+    RawHtml(format!(
+        "<!DOCTYPE html><html><head><title>Terms of use</title></head><body><main>{}</main><form method=\"post\" action=\"/terms/accept\"><input type=\"hidden\" name=\"version\" value=\"{}\"><button type=\"submit\">Accept</button></form></body></html>",
+        sanitization::clean_all_html(&terms.document),
+        sanitization::escape_html(&terms.version)
+    ))
+}
+
+/// Stores that the user accepted the terms of use
+///
+/// # Arguments
+/// * `acceptance` - Submitted form with the version the user accepted
+/// * `terms` - Current terms of use (injected by Rocket)
+/// * `user` - Authenticated user (no access for unauthenticated users)
+/// * `db` - Async connection object to the database
+/// * `cookies` - Cookies of the user  (injected by Rocket)
+/// * `oidc_cookies` - Cookie policy for the redirect cookie (injected by Rocket)
+/// * `cookie_key_ring` - Keys to decrypt the redirect cookie (injected by Rocket)
+///
+/// # Returns
+/// Redirection to the original route the user requested, to the terms of use if they changed in the meantime or an error if an impersonating admin tries to accept them
+///
+#[post("/accept", data = "<acceptance>")]
+pub async fn terms_accept(
+    acceptance: Form<TermsAcceptance>,
+    terms: &State<TermsOfUse>,
+    user: OidcUser,
+    mut db: Connection<Db>,
+    cookies: &CookieJar<'_>,
+    oidc_cookies: &State<OidcCookies>,
+    cookie_key_ring: &State<CookieKeyRing>,
+) -> Result<Redirect, Status> {
+    if user.impersonated_by.is_some() {
+        return Err(Status::Forbidden);
+    }
+    if acceptance.version != terms.version {
+        return Ok(Redirect::to(uri!("/terms")));
+    }
+    if let Err(err) = terms.accept(&mut db, user.subject.as_str()).await {
+        event!(
+            Level::ERROR,
+            "Cannot store terms of use acceptance: {}",
+            err
+        );
+        return Err(Status::InternalServerError);
+    }
+    let destination = cookie_key_ring
+        .get_private(
+            cookies,
+            oidc_cookies,
+            &oidc_cookies.redirect_destination_cookie_name,
+        )
+        .map(|crumb| crumb.value().to_string());
+    match destination {
+        Some(destination) => {
+            cookies.remove_private(
+                oidc_cookies.removal_cookie(&oidc_cookies.redirect_destination_cookie_name),
+            );
+            Ok(Redirect::to(destination))
+        }
+        None => Ok(Redirect::to(DEFAULT_DESTINATION)),
+    }
+}
+
+/// Responds to forbidden requests on the gated paths, e.g. a POST to the API of a user that has not accepted the current terms of use, so that the frontend can send the user to the terms
+///
+/// # Arguments
+/// * `req` - Request object
+///
+/// # Returns
+/// 403 Forbidden with a problem response
+///
+#[catch(403)]
+pub fn terms_forbidden(req: &Request<'_>) -> TermsProblem {
+    let problem = match req.local_cache(|| PendingTerms(false)).0 {
+        true => json!({
+            "type": "about:blank",
+            "title": "Forbidden",
+            "status": 403,
+            "detail": "The current terms of use have not been accepted",
+            "terms": "/terms",
+        }),
+        false => json!({
+            "type": "about:blank",
+            "title": "Forbidden",
+            "status": 403,
+        }),
+    };
+    TermsProblem(serde_json::to_string(&problem).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::fairing::AdHoc;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+    use rocket_db_pools::Database;
+    use rocket_db_pools::sqlx;
+
+    use super::*;
+    use crate::configuration::config::{
+        CustomAppCookiesConfig, CustomAppKeyRingConfig, CustomAppTermsConfig,
+    };
+    use crate::oidc::routes::{oidc_redirect, redirect_auth};
+    use crate::oidc::testing::{login, oidc_config, start_idp};
+
+    #[get("/orders")]
+    fn orders(user: OidcUser) -> String {
+        user.subject.to_string()
+    }
+
+    #[post("/orders")]
+    fn create_order(user: OidcUser) -> String {
+        user.subject.to_string()
+    }
+
+    /// Renames the table of the acceptances to simulate a database error
+    fn rename_acceptance_table(database: &std::path::Path, from: &str, to: &str) {
+        rocket::execute(async {
+            let mut connection = <sqlx::SqliteConnection as sqlx::Connection>::connect(&format!(
+                "sqlite://{}",
+                database.display()
+            ))
+            .await
+            .unwrap();
+            sqlx::query(&format!("ALTER TABLE {} RENAME TO {}", from, to))
+                .execute(&mut connection)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn accepting_terms_returns_to_original_destination() {
+        let dir = std::env::temp_dir();
+        let id = uuid::Uuid::new_v4();
+        let database = dir.join(format!("warehouse-{}.sqlite", id));
+        let document = dir.join(format!("terms-{}.html", id));
+        std::fs::write(
+            &document,
+            "<p>Handle stock with care</p><script>alert(1)</script>",
+        )
+        .unwrap();
+        let (_files, oidc_flow) = start_idp();
+        let csrf_state = oidc_flow.csrf_state.secret().clone();
+        let figment = rocket::Config::figment().merge((
+            "databases.warehouse.url",
+            database.to_string_lossy().to_string(),
+        ));
+        let rocket = rocket::custom(figment)
+            .attach(Db::init())
+            .attach(AdHoc::try_on_ignite(
+                "Migrations",
+                crate::database::run_migrations,
+            ))
+            .manage(oidc_flow)
+            .manage(OidcCookies::new(&CustomAppCookiesConfig::default()).unwrap())
+            .manage(CookieKeyRing::new(&CustomAppKeyRingConfig::default()).unwrap())
+            .manage(oidc_config())
+            .manage(
+                TermsOfUse::new(&CustomAppTermsConfig {
+                    version: Some("2026-10".to_string()),
+                    document: Some(document.to_string_lossy().to_string()),
+                    paths: None,
+                })
+                .unwrap(),
+            )
+            .mount("/oidc", routes![oidc_redirect])
+            .mount("/terms", routes![terms_page, terms_accept])
+            .mount("/ui", routes![orders])
+            .mount("/ui-api", routes![create_order])
+            .register("/ui-api", catchers![terms_forbidden])
+            .mount("/", routes![redirect_auth]);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/ui/orders").dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("/oidc/login"));
+        login(&client, &csrf_state);
+
+        let response = client.get("/ui/orders").dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("/terms"));
+        let response = client.post("/ui-api/orders").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let problem: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(problem["terms"], "/terms");
+        // users are not asked to accept the terms again if the acceptance cannot be read
+        rename_acceptance_table(&database, "terms_acceptance", "terms_acceptance_moved");
+        assert_eq!(
+            client.get("/ui/orders").dispatch().status(),
+            Status::ServiceUnavailable
+        );
+        rename_acceptance_table(&database, "terms_acceptance_moved", "terms_acceptance");
+        let page = client.get("/terms").dispatch().into_string().unwrap();
+        assert!(page.contains("<p>Handle stock with care</p>"));
+        assert!(!page.contains("<script>"));
+
+        let response = client
+            .post("/terms/accept")
+            .header(ContentType::Form)
+            .body("version=2025-01")
+            .dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("/terms"));
+        let response = client
+            .post("/terms/accept")
+            .header(ContentType::Form)
+            .body("version=2026-10")
+            .dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("/ui/orders"));
+        let response = client.get("/ui/orders").dispatch();
+        assert_eq!(response.into_string().unwrap(), "alice");
+        let response = client.post("/ui-api/orders").dispatch();
+        assert_eq!(response.into_string().unwrap(), "alice");
+
+        let _ = std::fs::remove_file(database);
+        let _ = std::fs::remove_file(document);
+    }
+}
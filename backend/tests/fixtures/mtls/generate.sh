@@ -0,0 +1,38 @@
+#!/bin/sh
+# Generates the CA, server and client certificates for the mutual TLS tests. The keys of the CAs and of clients that only the local client presents are discarded
+set -e
+cd "$(dirname "$0")"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=Warehouse Test CA/O=Warehouse" \
+    -keyout "$tmp/ca.key" -out ca.pem
+# CA that is not trusted by the server, e.g. of another organization
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=Other Test CA/O=Other" \
+    -keyout "$tmp/other-ca.key" -out "$tmp/other-ca.pem"
+
+certificate() {
+    name=$1
+    subject=$2
+    extensions=$3
+    ca=$4
+    ca_key=$5
+    key=$6
+    openssl req -newkey rsa:2048 -nodes -subj "$subject" -keyout "$key" -out "$tmp/$name.csr"
+    printf "%s\n" "$extensions" > "$tmp/$name.ext"
+    openssl x509 -req -in "$tmp/$name.csr" -CA "$ca" -CAkey "$ca_key" -CAcreateserial -days 36500 \
+        -extfile "$tmp/$name.ext" -out "$name.pem"
+}
+
+certificate server "/CN=localhost/O=Warehouse" \
+    "subjectAltName=DNS:localhost,IP:127.0.0.1
+extendedKeyUsage=serverAuth" ca.pem "$tmp/ca.key" server-key.pem
+certificate scanner "/CN=scanner-01/O=Warehouse" "extendedKeyUsage=clientAuth" ca.pem "$tmp/ca.key" scanner-key.pem
+certificate gateway "/CN=plc-gateway/O=Warehouse" \
+    "subjectAltName=DNS:plc-gw-01.plant.local
+extendedKeyUsage=clientAuth" ca.pem "$tmp/ca.key" "$tmp/gateway.key"
+certificate unknown "/CN=laptop-17/O=Warehouse" "extendedKeyUsage=clientAuth" ca.pem "$tmp/ca.key" "$tmp/unknown.key"
+# same subject as the scanner, but issued by the other CA
+certificate foreign "/CN=scanner-01/O=Warehouse" "extendedKeyUsage=clientAuth" "$tmp/other-ca.pem" "$tmp/other-ca.key" \
+    foreign-key.pem
+rm -f ca.srl